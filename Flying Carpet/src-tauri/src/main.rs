@@ -4,7 +4,9 @@
 )]
 
 use flying_carpet_core::{
-    bluetooth, clean_up_transfer, network, start_transfer, utils, Transfer, WiFiInterface, UI,
+    backend::NetworkBackend, bluetooth, clean_up_transfer, diagnostics, hotspot::HotspotChannel,
+    network, qr, start_transfer, utils, BluetoothBond, BluetoothDevice, BluetoothInterface,
+    BluetoothRole, LanPeer, LanRole, PairingPolicy, Transfer, WiFiInterface, UI,
 };
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -75,6 +77,20 @@ impl UI for GUI {
             )
             .expect("could not emit event");
     }
+    fn show_bluetooth_devices(&self, devices: &[BluetoothDevice]) {
+        self.window
+            .lock()
+            .expect("Couldn't lock GUI mutex")
+            .emit("showBluetoothDevices", devices)
+            .expect("could not emit event");
+    }
+    fn show_lan_peers(&self, peers: &[LanPeer]) {
+        self.window
+            .lock()
+            .expect("Couldn't lock GUI mutex")
+            .emit("showLanPeers", peers)
+            .expect("could not emit event");
+    }
 }
 
 #[tauri::command]
@@ -103,7 +119,10 @@ fn cancel_transfer(window: Window, state: State<Transfer>) -> String {
     let hotspot = &*hotspot;
     let ssid = state.ssid.lock().expect("Couldn't lock state ssid mutex.");
     let ssid = &*ssid;
-    match network::stop_hotspot(hotspot.as_ref(), ssid.as_deref()) {
+    match state
+        .backend
+        .stop_hotspot(hotspot.as_ref(), ssid.as_deref())
+    {
         Err(e) => println!("Error stopping hotspot: {}", e),
         Ok(msg) => println!("{}", msg),
     };
@@ -120,10 +139,16 @@ fn start_async(
     mode: String,
     peer: Option<String>,
     password: Option<String>,
+    lan: Option<LanRole>,
+    port: u16,
+    hotspot: HotspotChannel,
     interface: WiFiInterface,
+    bluetooth_interface: Option<BluetoothInterface>,
     file_list: Option<Vec<String>>,
     receive_dir: Option<String>,
     using_bluetooth: bool,
+    bluetooth_role: String,
+    pairing_policy: String,
     window: Window,
 ) {
     let thread_window = window.clone();
@@ -133,17 +158,26 @@ fn start_async(
 
     let transfer_hotspot = state.hotspot.clone();
     let transfer_ssid = state.ssid.clone();
+    let mut backend = state.backend.with_hotspot(hotspot);
+    backend.set_pairing_policy(PairingPolicy::from(pairing_policy.as_str()));
 
     // used by windows because we have to implement our own UI for PIN confirmation in non-UWP apps.
     // sends the user's choice of whether the bluetooth PINs match to know whether to pair.
     let (ble_ui_tx, ble_ui_rx) = mpsc::channel(1);
+    // sends the address of the bluetooth device the user picked from the devices found while scanning.
+    let (ble_device_tx, ble_device_rx) = mpsc::channel(1);
 
     let cancel_handle = tokio::spawn(async move {
-        let stream: std::option::Option<tokio::net::TcpStream> = start_transfer(
+        let stream: std::option::Option<flying_carpet_core::TransferStream> = start_transfer(
+            &backend,
             mode,
             using_bluetooth,
+            BluetoothRole::from(bluetooth_role.as_str()),
+            bluetooth_interface,
             peer,
             password,
+            lan,
+            port,
             interface,
             file_list,
             receive_dir,
@@ -151,19 +185,29 @@ fn start_async(
             transfer_hotspot.clone(),
             transfer_ssid.clone(),
             ble_ui_rx,
+            ble_device_rx,
         )
         .await;
-        clean_up_transfer(stream, transfer_hotspot, transfer_ssid, &gui).await;
+        clean_up_transfer(&backend, stream, transfer_hotspot, transfer_ssid, &gui).await;
     });
     let mut state_cancel_handle = state.cancel_handle.lock().unwrap();
     *state_cancel_handle = Some(cancel_handle);
     let mut state_ble_ui_tx = state.ble_ui_tx.lock().unwrap();
     *state_ble_ui_tx = Some(ble_ui_tx);
+    let mut state_ble_device_tx = state.ble_device_tx.lock().unwrap();
+    *state_ble_device_tx = Some(ble_device_tx);
 }
 
 #[tokio::main]
 async fn main() {
     tauri::async_runtime::set(tokio::runtime::Handle::current());
+    // a crash leaves the hotspot's profile behind, so clear those out before any transfer starts
+    match network::remove_stale_hotspots() {
+        Ok(removed) => removed
+            .iter()
+            .for_each(|name| println!("Removed stale hotspot profile {}", name)),
+        Err(e) => println!("Error removing stale hotspot profiles: {}", e),
+    }
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_os::init())
@@ -174,9 +218,15 @@ async fn main() {
             is_dir,
             expand_files,
             generate_password,
+            pairing_qr_code,
             get_wifi_interfaces,
+            get_bluetooth_adapters,
             check_support,
             user_bluetooth_pair,
+            user_choose_bluetooth_device,
+            list_bluetooth_bonds,
+            remove_bluetooth_bond,
+            diagnose,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -234,6 +284,19 @@ fn generate_password() -> String {
     utils::generate_password()
 }
 
+// SVG of what the peer scans to join our transfer, or None if it couldn't be made
+#[tauri::command]
+fn pairing_qr_code(password: &str, peer: &str, port: u16) -> Option<String> {
+    let payload = qr::PairingCode::new(password, port).payload(peer);
+    match qr::to_svg(&payload) {
+        Ok(svg) => Some(svg),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
 #[tauri::command]
 fn get_wifi_interfaces() -> Vec<WiFiInterface> {
     match network::get_wifi_interfaces() {
@@ -242,6 +305,17 @@ fn get_wifi_interfaces() -> Vec<WiFiInterface> {
     }
 }
 
+#[tauri::command]
+async fn get_bluetooth_adapters() -> Vec<BluetoothInterface> {
+    match bluetooth::get_bluetooth_adapters().await {
+        Ok(adapters) => adapters,
+        Err(e) => {
+            println!("Error listing Bluetooth adapters: {}", e);
+            vec![]
+        }
+    }
+}
+
 #[tauri::command]
 fn user_bluetooth_pair(choice: bool, state: State<Transfer>) {
     println!("in user_bluetooth_pair");
@@ -260,3 +334,54 @@ fn user_bluetooth_pair(choice: bool, state: State<Transfer>) {
         println!("sent in user_bluetooth_pair");
     });
 }
+
+#[tauri::command]
+fn user_choose_bluetooth_device(address: Option<String>, state: State<Transfer>) {
+    let ble_device_tx = state
+        .ble_device_tx
+        .lock()
+        .expect("Could not lock ble_device_tx mutex");
+    let ble_device_tx = ble_device_tx
+        .as_ref()
+        .expect("State ble_device_tx was None");
+    let ble_device_tx = ble_device_tx.clone();
+
+    tokio::spawn(async move {
+        ble_device_tx
+            .send(address)
+            .await
+            .expect("Could not send on ble_device_tx");
+    });
+}
+
+#[tauri::command]
+async fn list_bluetooth_bonds(
+    bluetooth_interface: Option<BluetoothInterface>,
+) -> Vec<BluetoothBond> {
+    match bluetooth::list_bonds(bluetooth_interface.as_ref()).await {
+        Ok(bonds) => bonds,
+        Err(e) => {
+            println!("Error listing Bluetooth bonds: {}", e);
+            vec![]
+        }
+    }
+}
+
+// for javascript, None/null means no error and Some(String) means error message
+#[tauri::command]
+async fn remove_bluetooth_bond(
+    bluetooth_interface: Option<BluetoothInterface>,
+    address: String,
+) -> Option<String> {
+    bluetooth::remove_bond(bluetooth_interface.as_ref(), &address)
+        .await
+        .map_err(|e| e.to_string())
+        .err()
+}
+
+#[tauri::command]
+async fn diagnose(port: u16) -> diagnostics::Report {
+    let report = diagnostics::diagnose(port).await;
+    println!("{}", report);
+    report
+}
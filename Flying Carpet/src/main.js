@@ -1,10 +1,25 @@
 const { core, dialog, os } = window.__TAURI__;
-import { QRCode } from './deps/qrcode.js'
 
 let aboutButton;
 let canUseBluetooth = false;
 let usingBluetooth;
 let bluetoothSwitch;
+let bluetoothRoleBox;
+let bluetoothRole = 'automatic';
+let pairingPolicyBox;
+// kept across restarts, unlike the rest of the UI
+let pairingPolicy = localStorage.getItem('pairingPolicy') || 'forget';
+// also kept across restarts. empty uses the default port.
+let port = localStorage.getItem('port') || '';
+let portBox;
+// where to start the hotspot, also kept. empty lets the OS choose.
+let band = localStorage.getItem('band') || '';
+let channel = localStorage.getItem('channel') || '';
+let bandBox;
+let channelBox;
+let usingLan = false;
+let lanSwitch;
+let lanAddressBox;
 let peerLabel;
 let peerBox;
 let outputBox;
@@ -22,6 +37,9 @@ let selectedFolder;
 window.onunload = () => {
   let uiState = {
     usingBluetooth: usingBluetooth,
+    bluetoothRole: bluetoothRole,
+    usingLan: usingLan,
+    lanAddressBoxValue: lanAddressBox.value,
     // canUseBluetooth:
     selectedMode: selectedMode,
     selectedPeer: selectedPeer,
@@ -46,6 +64,17 @@ window.addEventListener('DOMContentLoaded', async () => {
   cancelButton = document.getElementById('cancelButton');
   progressBar = document.getElementById('progressBar');
   bluetoothSwitch = document.getElementById('bluetoothSwitch');
+  bluetoothRoleBox = document.getElementById('bluetoothRoleBox');
+  pairingPolicyBox = document.getElementById('pairingPolicyBox');
+  pairingPolicyBox.value = pairingPolicy;
+  lanSwitch = document.getElementById('lanSwitch');
+  lanAddressBox = document.getElementById('lanAddressBox');
+  portBox = document.getElementById('portBox');
+  portBox.value = port;
+  bandBox = document.getElementById('bandBox');
+  bandBox.value = band;
+  channelBox = document.getElementById('channelBox');
+  channelBox.value = channel;
 
   appWindow = window.__TAURI__.window.getCurrentWindow();
 
@@ -99,6 +128,40 @@ window.addEventListener('DOMContentLoaded', async () => {
     console.log('invoked user_bluetooth_pair');
   });
 
+  // show the other devices running Flying Carpet on the network while looking for the one with our password
+  await appWindow.listen('showLanPeers', (event) => {
+    for (let peer of event.payload) {
+      output(`Found ${peer.name} (${peer.peer_os}) at ${peer.address}, waiting for the device with the same password...`);
+    }
+  });
+
+  // let user pick which nearby device is the sender when receiving over bluetooth
+  await appWindow.listen('showBluetoothDevices', async (event) => {
+    let devices = event.payload;
+    let promptString = 'Enter the number of the device you are receiving from (e.g. "1" or "2"):\n';
+    for (let i = 0; i < devices.length; i++) {
+      let details = [devices[i].address];
+      if (devices[i].peer_os) {
+        details.push(devices[i].peer_os);
+      }
+      if (devices[i].rssi !== null) {
+        details.push(`signal ${devices[i].rssi} dBm`);
+      }
+      promptString += `${i+1}: ${devices[i].name} (${details.join(', ')})\n`;
+    }
+    let choice = parseInt(prompt(promptString));
+    let address = null;
+    if (choice && choice > 0 && choice <= devices.length) {
+      address = devices[choice - 1].address;
+      output(`Connecting to ${devices[choice - 1].name}`);
+    } else {
+      output('No device selected.');
+    }
+    await core.invoke('user_choose_bluetooth_device', {
+      address: address,
+    });
+  });
+
   // have Enter start/cancel transfer
   document.getElementById('mainContainer').addEventListener("keyup", event => {
     if (event.key !== "Enter") {
@@ -146,6 +209,12 @@ window.addEventListener('DOMContentLoaded', async () => {
   if (uiState) {
     usingBluetooth = uiState.usingBluetooth;
     bluetoothSwitch.checked = usingBluetooth;
+    bluetoothRole = uiState.bluetoothRole || 'automatic';
+    bluetoothRoleBox.value = bluetoothRole;
+    usingLan = uiState.usingLan || false;
+    lanSwitch.checked = usingLan;
+    bluetoothSwitch.disabled = usingLan || !canUseBluetooth;
+    lanAddressBox.value = uiState.lanAddressBoxValue || '';
     selectedMode = uiState.selectedMode;
     if (selectedMode === 'send') {
       document.getElementById('sendButton').checked = true;
@@ -178,14 +247,38 @@ function output(msg) {
   outputBox.scrollTop = outputBox.scrollHeight;
 }
 
-function makeQRCode(str) {
-  let elem = document.getElementById('qrcode');
-  elem.innerHTML = '';
-  new QRCode(elem, {
-    text: str,
-    width: 150,
-    height: 150,
+// name plus whatever the OS told us about the card
+function describeInterface(wifiInterface) {
+  let details = [];
+  if (wifiInterface.ap === false) {
+    details.push("can't host");
+  }
+  if (wifiInterface.bands.length > 0) {
+    details.push(`${wifiInterface.bands.join(', ')} GHz`);
+  }
+  if (wifiInterface.connection) {
+    details.push(`on ${wifiInterface.connection}`);
+  }
+  if (wifiInterface.driver) {
+    details.push(wifiInterface.driver);
+  }
+  return details.length > 0 ? `${wifiInterface.name} (${details.join(', ')})` : wifiInterface.name;
+}
+
+async function makeQRCode(password) {
+  let svg = await core.invoke('pairing_qr_code', {
+    password: password,
+    peer: selectedPeer,
+    port: port === '' ? 3290 : parseInt(port),
   });
+  if (svg === null) {
+    output(`Couldn't make QR code. Password: ${password}`);
+    return;
+  }
+  let elem = document.getElementById('qrcode');
+  elem.innerHTML = svg;
+  elem.firstElementChild.setAttribute('width', '150');
+  elem.firstElementChild.setAttribute('height', '150');
 }
 
 async function startTransfer(filesSelected) {
@@ -200,10 +293,27 @@ async function startTransfer(filesSelected) {
     }
   }
 
-  // make sure we have a wifi interface and prompt for which if more than one
-  let wifiInterface;
-  let interfaces = await core.invoke('get_wifi_interfaces');
+  // on the same network, the sending device connects to the address entered, or looks for the receiving device
+  let lan = null;
+  if (usingLan) {
+    if (selectedMode === 'send') {
+      let address = lanAddressBox.value.trim();
+      lan = address ? { Connect: address } : 'Discover';
+    } else {
+      lan = 'Listen';
+    }
+  }
+
+  // make sure we have a wifi interface and prompt for which if more than one. not needed on the same network.
+  let wifiInterface = { name: '', guid: '' };
+  let interfaces = usingLan ? [wifiInterface] : await core.invoke('get_wifi_interfaces');
   // console.log('interfaces:', interfaces);
+  // skip the prompt when only one card can host a hotspot on the chosen band. only Linux reports these.
+  let suitable = interfaces.filter((i) => i.ap !== false && (band === '' || i.bands.length === 0 || i.bands.includes(band)));
+  if (interfaces.length > 1 && suitable.length === 1) {
+    interfaces = suitable;
+    output(`Using interface: ${describeInterface(suitable[0])}`);
+  }
   switch (interfaces.length) {
     case 0:
       output('No WiFi interfaces found. Flying Carpet only works over WiFi.');
@@ -214,18 +324,36 @@ async function startTransfer(filesSelected) {
     default:
       let alertString = 'Enter the number for which WiFi interface to use (e.g. "1" or "2"):\n'
       for (let i = 0; i < interfaces.length; i++) {
-        alertString += `${i+1}: ${interfaces[i][0]}\n`
+        alertString += `${i+1}: ${describeInterface(interfaces[i])}\n`
       }
       let choice = parseInt(prompt(alertString));
       if (choice && choice > 0 && choice <= interfaces.length) {
         wifiInterface = interfaces[choice - 1];
-        output(`Using interface: ${wifiInterface[0]}`);
+        output(`Using interface: ${wifiInterface.name}`);
       } else {
         output('Invalid interface selected. Please enter just the number of the WiFi interface you would like to use, e.g. "1" or "3".');
         return;
       }
   }
+
+  // make sure the interface can start a hotspot where the user asked. an empty list means we can't tell.
+  if (!usingLan && band !== '') {
+    let bands = wifiInterface.bands;
+    if (bands.length > 0 && !bands.includes(band)) {
+      output(`${wifiInterface.name} doesn't support ${band} GHz. It supports ${bands.join(', ')} GHz.`);
+      return;
+    }
+  }
   
+  // prompt for which bluetooth adapter if more than one
+  let bluetoothInterface = null;
+  if (usingBluetooth) {
+    bluetoothInterface = await chooseBluetoothAdapter();
+    if (bluetoothInterface === undefined) {
+      return;
+    }
+  }
+
   // get files or folder
   if (!filesSelected) {
     if (selectedMode == 'send') {
@@ -248,11 +376,15 @@ async function startTransfer(filesSelected) {
   
   // if we're hosting, generate and display the password
   if (!await needPassword()) {
-    if (!usingBluetooth) {
+    if (usingLan) {
+      password = await core.invoke('generate_password');
+      output(`Password: ${password}`);
+      alert(`\nStart the transfer on the other device and enter this password when prompted:\n${password}`);
+    } else if (!usingBluetooth) {
       password = await core.invoke('generate_password');
       if (selectedPeer === 'ios' || selectedPeer === 'android') {
         output('\nStart the transfer on the other device and scan the QR code when prompted.');
-        makeQRCode(password);
+        await makeQRCode(password);
       } else {
         output(`Password: ${password}`);
         alert(`\nStart the transfer on the other device and enter this password when prompted:\n${password}`);
@@ -268,10 +400,19 @@ async function startTransfer(filesSelected) {
     mode: selectedMode,
     peer: selectedPeer,
     password: password,
+    lan: lan,
+    port: port === '' ? 3290 : parseInt(port),
+    hotspot: {
+      band: band === '' ? null : band,
+      channel: channel === '' ? null : parseInt(channel),
+    },
     interface: wifiInterface,
+    bluetoothInterface: bluetoothInterface,
     fileList: selectedFiles,
     receiveDir: selectedFolder,
     usingBluetooth: usingBluetooth,
+    bluetoothRole: bluetoothRole,
+    pairingPolicy: pairingPolicy,
     window: appWindow,
   });
 }
@@ -301,6 +442,108 @@ let bluetoothChange = () => {
   checkStatus();
 }
 
+// the receiving device listens on the network both devices are already on, and the sending device connects to it
+let lanChange = () => {
+  usingLan = lanSwitch.checked;
+  if (usingLan) {
+    usingBluetooth = false;
+    bluetoothSwitch.checked = false;
+  }
+  bluetoothSwitch.disabled = usingLan || !canUseBluetooth;
+  checkStatus();
+}
+
+// by default the sending device advertises and the receiving device scans
+let bluetoothRoleChange = () => {
+  bluetoothRole = bluetoothRoleBox.value;
+}
+
+let pairingPolicyChange = () => {
+  pairingPolicy = pairingPolicyBox.value;
+  localStorage.setItem('pairingPolicy', pairingPolicy);
+}
+
+let portChange = () => {
+  let value = parseInt(portBox.value);
+  if (portBox.value !== '' && !(value >= 0 && value <= 65535)) {
+    output('Port must be a number from 0 to 65535.');
+    portBox.value = port;
+    return;
+  }
+  port = portBox.value === '' ? '' : value.toString();
+  localStorage.setItem('port', port);
+}
+
+let hotspotChange = () => {
+  let value = parseInt(channelBox.value);
+  if (channelBox.value !== '' && !(value >= 1 && value <= 233)) {
+    output('Channel must be a number from 1 to 233.');
+    channelBox.value = channel;
+    return;
+  }
+  if (channelBox.value !== '' && bandBox.value === '') {
+    output('Choose a band for the channel.');
+  }
+  band = bandBox.value;
+  channel = channelBox.value === '' ? '' : value.toString();
+  localStorage.setItem('band', band);
+  localStorage.setItem('channel', channel);
+}
+
+// check what a transfer needs from this device and print what was found
+let diagnose = async () => {
+  output('Checking WiFi, Bluetooth, and port...');
+  let report = await core.invoke('diagnose', { port: port === '' ? 3290 : parseInt(port) });
+  for (let [section, checks] of [['WiFi', report.wifi], ['Bluetooth', report.bluetooth], ['Port', report.port]]) {
+    output(`${section}:`);
+    for (let check of checks) {
+      output(`  [${check.status}] ${check.name}: ${check.detail}`);
+    }
+  }
+}
+
+// null for the OS's default adapter when there's only one, undefined if the user didn't pick a valid one
+let chooseBluetoothAdapter = async () => {
+  let adapters = await core.invoke('get_bluetooth_adapters');
+  if (adapters.length <= 1) {
+    return null;
+  }
+  let alertString = 'Enter the number for which Bluetooth adapter to use (e.g. "1" or "2"):\n'
+  for (let i = 0; i < adapters.length; i++) {
+    let powered = adapters[i].powered ? '' : ', off';
+    alertString += `${i+1}: ${adapters[i].name} (${adapters[i].address}${powered})\n`
+  }
+  let choice = parseInt(prompt(alertString));
+  if (choice && choice > 0 && choice <= adapters.length) {
+    output(`Using Bluetooth adapter: ${adapters[choice - 1].name}`);
+    return adapters[choice - 1];
+  }
+  output('Invalid Bluetooth adapter selected. Please enter just the number of the adapter you would like to use, e.g. "1" or "3".');
+  return undefined;
+}
+
+// list the devices we're paired with that have run Flying Carpet, and let the user unpair one
+let showBonds = async () => {
+  let bluetoothInterface = await chooseBluetoothAdapter();
+  if (bluetoothInterface === undefined) {
+    return;
+  }
+  let bonds = await core.invoke('list_bluetooth_bonds', { bluetoothInterface: bluetoothInterface });
+  if (bonds.length === 0) {
+    output('Not paired with any Flying Carpet devices.');
+    return;
+  }
+  let promptString = 'Enter the number of the device to unpair (e.g. "1" or "2"), or cancel to keep them all:\n';
+  for (let i = 0; i < bonds.length; i++) {
+    promptString += `${i+1}: ${bonds[i].name} (${bonds[i].address})\n`;
+  }
+  let choice = parseInt(prompt(promptString));
+  if (choice && choice > 0 && choice <= bonds.length) {
+    let error = await core.invoke('remove_bluetooth_bond', { bluetoothInterface: bluetoothInterface, address: bonds[choice - 1].address });
+    output(error ? error : `Unpaired from ${bonds[choice - 1].name}`);
+  }
+}
+
 let modeChange = async (button) => {
   startButton.innerText = button === 'receive' ? 'Select Folder' : 'Select Files';
   selectedMode = button;
@@ -314,18 +557,36 @@ let peerChange = (button) => {
 
 let checkStatus = () => {
   showPassword();
-  if (usingBluetooth) {
+  lanAddressBox.style.display = usingLan && selectedMode === 'send' ? '' : 'none';
+  if (usingLan) {
     peerLabel.style.display = 'none';
     peerBox.style.display = 'none';
+    bluetoothRoleBox.style.display = 'none';
+    pairingPolicyBox.style.display = 'none';
+    document.getElementById('bondsButton').style.display = 'none';
+    startButton.disabled = !selectedMode;
+  } else if (usingBluetooth) {
+    peerLabel.style.display = 'none';
+    peerBox.style.display = 'none';
+    bluetoothRoleBox.style.display = 'inline-block';
+    pairingPolicyBox.style.display = 'inline-block';
+    document.getElementById('bondsButton').style.display = '';
     startButton.disabled = !selectedMode;
   } else {
     peerLabel.style.display = '';
     peerBox.style.display = '';
+    bluetoothRoleBox.style.display = 'none';
+    pairingPolicyBox.style.display = 'none';
+    document.getElementById('bondsButton').style.display = 'none';
     startButton.disabled = !(selectedMode && selectedPeer);
   }
 }
 
 let needPassword = async () => {
+  // the receiving device listens and shows the password
+  if (usingLan) {
+    return selectedMode === 'send';
+  }
   if (usingBluetooth) {
     return false;
   }
@@ -360,17 +621,24 @@ let enableUi = async () => {
   startButton.style.display = '';
   // hide cancel button
   cancelButton.style.display = 'none';
-  // enable bluetooth switch
-  if (canUseBluetooth) {
+  // enable bluetooth and same network switches
+  document.getElementById('lanSwitch').disabled = false;
+  document.getElementById('portBox').disabled = false;
+  document.getElementById('bandBox').disabled = false;
+  document.getElementById('channelBox').disabled = false;
+  if (canUseBluetooth && !usingLan) {
     document.getElementById('bluetoothSwitch').disabled = false;
+    document.getElementById('bluetoothRoleBox').disabled = false;
+    document.getElementById('pairingPolicyBox').disabled = false;
   }
   // enable radio buttons, file/folder selection buttons
   let radioButtons = ['sendButton', 'receiveButton', 'androidButton', 'iosButton', 'linuxButton', 'macButton', 'windowsButton'];
   for (let i in radioButtons) {
     document.getElementById(radioButtons[i]).disabled = false;
   }
-  // enable password box
+  // enable password and address boxes
   document.getElementById('passwordBox').disabled = false;
+  document.getElementById('lanAddressBox').disabled = false;
   // replace logo
   document.getElementById('qrcode').innerHTML = '<img src="assets/icon1024.png" style="width: 150px; height: 150px;">'
 }
@@ -380,15 +648,22 @@ let disableUi = async () => {
   startButton.style.display = 'none';
   // show cancel button
   cancelButton.style.display = '';
-  // disable bluetooth switch
+  // disable bluetooth and same network switches
   document.getElementById('bluetoothSwitch').disabled = true;
+  document.getElementById('lanSwitch').disabled = true;
+  document.getElementById('portBox').disabled = true;
+  document.getElementById('bandBox').disabled = true;
+  document.getElementById('channelBox').disabled = true;
+  document.getElementById('bluetoothRoleBox').disabled = true;
+  document.getElementById('pairingPolicyBox').disabled = true;
   // disable radio buttons, file/folder selection buttons
   let radioButtons = ['sendButton', 'receiveButton', 'androidButton', 'iosButton', 'linuxButton', 'macButton', 'windowsButton'];
   for (let i in radioButtons) {
     document.getElementById(radioButtons[i]).disabled = true;
   }
-  // disable password box
+  // disable password and address boxes
   document.getElementById('passwordBox').disabled = true;
+  document.getElementById('lanAddressBox').disabled = true;
 }
 
 window.startTransfer = startTransfer;
@@ -396,6 +671,12 @@ window.cancelTransfer = cancelTransfer;
 window.selectFiles = selectFiles;
 window.selectFolder = selectFolder;
 window.bluetoothChange = bluetoothChange;
+window.lanChange = lanChange;
+window.portChange = portChange;
+window.hotspotChange = hotspotChange;
+window.bluetoothRoleChange = bluetoothRoleChange;
+window.pairingPolicyChange = pairingPolicyChange;
+window.showBonds = showBonds;
 window.modeChange = modeChange;
 window.peerChange = peerChange;
 
@@ -0,0 +1,734 @@
+// the WiFi and Bluetooth halves of a transfer sit behind these traits so that the rest of the core doesn't care whether
+// it's talking to real hardware. Hardware calls into the platform's network and bluetooth modules. Simulated needs neither
+// a WiFi card nor BlueZ: its "hotspot" is just localhost, and its GATT exchange happens over channels inside the process,
+// so both ends of a simulated Bluetooth transfer must share clones of the same Simulated.
+
+use crate::{
+    bluetooth,
+    bluetooth_stream::BluetoothStream,
+    crypto::BluetoothKeyPair,
+    error::{fc_error, FCError},
+    hotspot::{HotspotChannel, WiFiBand},
+    negotiation::{is_ble_transfer, Capabilities, PeerHosting, Role, DEFAULT_BLE_TRANSFER_LIMIT},
+    network,
+    utils::{
+        confirm_pin, generate_password, get_key_and_ssid, peer_description, timeout_error,
+        AdvertisementInfo, BluetoothTimeouts, LEGACY_ADVERTISEMENT_LENGTH, PASSWORD_LABEL,
+        SSID_LABEL,
+    },
+    BluetoothDevice, BluetoothInterface, BluetoothRole, Mode, PairingPolicy, Peer, PeerResource,
+    WiFiInterface, DEFAULT_PORT, MAJOR_VERSION, UI,
+};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, split, DuplexStream},
+    sync::{mpsc, oneshot},
+    time::{sleep, timeout, Instant},
+};
+
+// set to "simulated" to run the app without WiFi or Bluetooth hardware
+pub const BACKEND_ENV_VAR: &str = "FLYING_CARPET_BACKEND";
+const SIMULATED_DEVICE_NAME: &str = "Simulated Flying Carpet";
+const SIMULATED_DEVICE_ADDRESS: &str = "00:00:00:00:00:00";
+
+pub trait NetworkBackend {
+    // start a hotspot if hosting, otherwise join the peer's. port is where we'll listen if hosting.
+    fn connect_to_peer<T: UI>(
+        &self,
+        hosting: bool,
+        ssid: String,
+        password: String,
+        interface: WiFiInterface,
+        port: u16,
+        ui: &T,
+    ) -> impl Future<Output = Result<PeerResource, FCError>>;
+
+    // the bands the interface could host a hotspot on, empty if we can't tell
+    fn supported_bands(&self, interface: &WiFiInterface) -> Result<Vec<WiFiBand>, FCError>;
+
+    fn stop_hotspot(
+        &self,
+        peer_resource: Option<&PeerResource>,
+        ssid: Option<&str>,
+    ) -> Result<String, FCError>;
+
+    // a copy that starts or joins the hotspot on this band and channel
+    fn with_hotspot(&self, hotspot: HotspotChannel) -> Self;
+}
+
+pub trait BluetoothBackend {
+    // returns peer's OS, SSID, password, whether it hosts and where it would listen and start a hotspot, and the stream to
+    // transfer over if the files are small enough to skip WiFi
+    fn negotiate_bluetooth<T: UI>(
+        &self,
+        mode: &Mode,
+        role: BluetoothRole,
+        interface: Option<&BluetoothInterface>,
+        ble_ui_rx: mpsc::Receiver<bool>,
+        ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> impl Future<
+        Output = Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError>,
+    >;
+
+    // a copy that tells the peer we'd listen on port, which may only be known once the transfer has started
+    fn with_port(&self, port: u16) -> Self;
+
+    // a copy that tells the peer which bands our WiFi interface supports
+    fn with_bands(&self, bands: Vec<WiFiBand>) -> Self;
+
+    // a copy that tells the peer which end of the WiFi connection we'd rather be
+    fn with_role(&self, role: Role) -> Self;
+}
+
+#[derive(Clone)]
+pub struct Hardware {
+    pub timeouts: BluetoothTimeouts,
+    // 0 always uses WiFi
+    pub ble_transfer_limit: u64,
+    pub pairing: PairingPolicy,
+    pub port: u16,
+    // where to start a hotspot, or where the peer said it would start one
+    pub hotspot: HotspotChannel,
+    pub bands: Vec<WiFiBand>,
+    pub role: Role,
+}
+
+impl Default for Hardware {
+    fn default() -> Self {
+        Hardware {
+            timeouts: BluetoothTimeouts::default(),
+            ble_transfer_limit: DEFAULT_BLE_TRANSFER_LIMIT,
+            pairing: PairingPolicy::default(),
+            port: DEFAULT_PORT,
+            hotspot: HotspotChannel::default(),
+            bands: vec![],
+            role: Role::Either,
+        }
+    }
+}
+
+impl NetworkBackend for Hardware {
+    async fn connect_to_peer<T: UI>(
+        &self,
+        hosting: bool,
+        ssid: String,
+        password: String,
+        interface: WiFiInterface,
+        port: u16,
+        ui: &T,
+    ) -> Result<PeerResource, FCError> {
+        network::connect_to_peer(hosting, ssid, password, interface, port, self.hotspot, ui).await
+    }
+
+    fn supported_bands(&self, interface: &WiFiInterface) -> Result<Vec<WiFiBand>, FCError> {
+        network::supported_bands(&interface.name)
+    }
+
+    fn stop_hotspot(
+        &self,
+        peer_resource: Option<&PeerResource>,
+        ssid: Option<&str>,
+    ) -> Result<String, FCError> {
+        network::stop_hotspot(peer_resource, ssid)
+    }
+
+    fn with_hotspot(&self, hotspot: HotspotChannel) -> Self {
+        Hardware {
+            hotspot,
+            ..self.clone()
+        }
+    }
+}
+
+impl BluetoothBackend for Hardware {
+    async fn negotiate_bluetooth<T: UI>(
+        &self,
+        mode: &Mode,
+        role: BluetoothRole,
+        interface: Option<&BluetoothInterface>,
+        ble_ui_rx: mpsc::Receiver<bool>,
+        ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+        bluetooth::negotiate_bluetooth(mode, role, self, interface, ble_ui_rx, ble_device_rx, ui)
+            .await
+    }
+
+    fn with_port(&self, port: u16) -> Self {
+        Hardware {
+            port,
+            ..self.clone()
+        }
+    }
+
+    fn with_bands(&self, bands: Vec<WiFiBand>) -> Self {
+        Hardware {
+            bands,
+            ..self.clone()
+        }
+    }
+
+    fn with_role(&self, role: Role) -> Self {
+        Hardware {
+            role,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Simulated {
+    // the advertisement currently on the air, taken by whichever central finds it first
+    radio: Arc<Mutex<Option<Advertisement>>>,
+    pub timeouts: BluetoothTimeouts,
+    pub ble_transfer_limit: u64,
+    pub port: u16,
+    pub hotspot: HotspotChannel,
+    pub bands: Vec<WiFiBand>,
+    pub role: Role,
+}
+
+impl Default for Simulated {
+    fn default() -> Self {
+        Simulated {
+            radio: Arc::default(),
+            timeouts: BluetoothTimeouts::default(),
+            ble_transfer_limit: DEFAULT_BLE_TRANSFER_LIMIT,
+            port: DEFAULT_PORT,
+            hotspot: HotspotChannel::default(),
+            bands: vec![],
+            role: Role::Either,
+        }
+    }
+}
+
+// what a central can see before connecting, what it can read from the peripheral's characteristics, and a way to write to them
+struct Advertisement {
+    manufacturer_data: Vec<u8>,
+    // from the scan response
+    local_name: Option<String>,
+    capabilities: Capabilities,
+    public_key: String,
+    os: String,
+    writes: mpsc::Sender<GattWrite>,
+    // the central's end of the data characteristic
+    data: DuplexStream,
+}
+
+enum GattWrite {
+    Capabilities(Capabilities),
+    PublicKey(String),
+    OS(String),
+    Credentials(String, String),
+    // the peripheral only knows the key to encrypt its credentials with once the central has written its public key
+    ReadCredentials(oneshot::Sender<(String, String)>),
+    Done,
+}
+
+impl Simulated {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the sender acts as peripheral unless the users chose otherwise, like the real implementations
+    async fn advertise<T: UI>(
+        &self,
+        mode: &Mode,
+        ble_ui_rx: &mut mpsc::Receiver<bool>,
+        ui: &T,
+    ) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+        let key_pair = BluetoothKeyPair::generate();
+        let capabilities = Capabilities::ours(SIMULATED_DEVICE_NAME.to_string())
+            .with_ble_limit(self.ble_transfer_limit, mode)
+            .with_port(self.port)
+            .with_hotspot(self.hotspot, &self.bands)
+            .with_role(self.role);
+        let (data, peer_data) = duplex(1024);
+        let (tx, mut rx) = mpsc::channel(2);
+        {
+            let mut radio = self.radio.lock().expect("Couldn't lock simulated radio");
+            let info = AdvertisementInfo {
+                os: bluetooth::OS.to_string(),
+                protocol_version: MAJOR_VERSION as u8,
+                sending: matches!(mode, Mode::Send(_)),
+                name: Some(SIMULATED_DEVICE_NAME.to_string()),
+            };
+            *radio = Some(Advertisement {
+                manufacturer_data: info.encode(),
+                local_name: info.local_name(LEGACY_ADVERTISEMENT_LENGTH),
+                capabilities: capabilities.clone(),
+                public_key: key_pair.public_hex(),
+                os: bluetooth::OS.to_string(),
+                writes: tx,
+                data: peer_data,
+            });
+        }
+        ui.output(&format!(
+            "Started simulated Bluetooth advertisement, waiting for {}...",
+            peer_description(mode)
+        ));
+
+        let peer_capabilities = match timeout(self.timeouts.scan, rx.recv()).await {
+            Ok(Some(GattWrite::Capabilities(c))) => c,
+            Ok(_) => Err(FCError {
+                message: "Simulated central did not write its capabilities".to_string(),
+            })?,
+            Err(_) => {
+                // stop advertising
+                self.radio
+                    .lock()
+                    .expect("Couldn't lock simulated radio")
+                    .take();
+                Err(timeout_error(
+                    self.timeouts.scan,
+                    &format!(
+                        "waiting for a {} to connect and write its capabilities",
+                        peer_description(mode)
+                    ),
+                ))?
+            }
+        };
+        peer_capabilities.check(ui)?;
+        let ble_transfer = is_ble_transfer(&capabilities, &peer_capabilities);
+
+        let step = self.timeouts.exchange_step;
+        let cipher = match next_write(&mut rx, step, "waiting for the peer's public key").await? {
+            GattWrite::PublicKey(k) => key_pair.agree(&k, false)?,
+            _ => Err(FCError {
+                message: "Simulated central did not write its public key".to_string(),
+            })?,
+        };
+        let peer_os = match next_write(&mut rx, step, "waiting for the peer's OS").await? {
+            GattWrite::OS(os) => os,
+            _ => Err(FCError {
+                message: "Simulated central did not write its OS".to_string(),
+            })?,
+        };
+        ui.output(&format!("Peer's OS is {}", peer_os));
+        confirm_pin(&cipher.pin, ble_ui_rx, self.timeouts.connect, ui).await?;
+
+        // the peer may still be confirming the PIN
+        let peer_hosting = PeerHosting::negotiate(
+            &capabilities,
+            Some(&peer_capabilities),
+            &Peer::from(peer_os.as_str()),
+            mode,
+        )?;
+        let hosting = !peer_hosting.hosts;
+        let info = match next_write(
+            &mut rx,
+            self.timeouts.connect,
+            "waiting for the peer to exchange WiFi credentials",
+        )
+        .await?
+        {
+            GattWrite::ReadCredentials(reply) if hosting => {
+                let password = generate_password();
+                let (_, ssid) = get_key_and_ssid(&password);
+                let encrypted = (
+                    cipher.encrypt(SSID_LABEL, &ssid)?,
+                    cipher.encrypt(PASSWORD_LABEL, &password)?,
+                );
+                if reply.send(encrypted).is_err() {
+                    fc_error("Simulated central disconnected")?;
+                }
+                ui.output("Peer read our SSID and password");
+                (peer_os, ssid, password)
+            }
+            GattWrite::Credentials(ssid, password) if !hosting => {
+                let ssid = cipher.decrypt(SSID_LABEL, &ssid)?;
+                let password = cipher.decrypt(PASSWORD_LABEL, &password)?;
+                ui.output(&format!("Peer's SSID is {}", ssid));
+                (peer_os, ssid, password)
+            }
+            _ => Err(FCError {
+                message: "Simulated central did not follow the hosting rules".to_string(),
+            })?,
+        };
+        let (peer_os, ssid, password) = info;
+        let stream = ble_transfer.then(|| {
+            let (reader, writer) = split(data);
+            BluetoothStream::new(reader, writer, Box::new(()))
+        });
+        match next_write(&mut rx, step, "waiting for the peer to finish").await? {
+            GattWrite::Done => Ok((peer_os, ssid, password, peer_hosting, stream)),
+            _ => Err(FCError {
+                message: "Simulated central did not say it was done".to_string(),
+            }),
+        }
+    }
+
+    // receiver acts as central, and lets the user pick the one simulated device like they would from a real scan
+    async fn scan<T: UI>(
+        &self,
+        mode: &Mode,
+        ble_ui_rx: &mut mpsc::Receiver<bool>,
+        mut ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+        ui.output(&format!(
+            "Started simulated Bluetooth scan, waiting for {}...",
+            peer_description(mode)
+        ));
+        let scan_end = Instant::now() + self.timeouts.scan;
+        let device = loop {
+            if let Some(a) = self
+                .radio
+                .lock()
+                .expect("Couldn't lock simulated radio")
+                .as_ref()
+            {
+                let info = AdvertisementInfo::decode(&a.manufacturer_data, a.local_name.clone())
+                    .expect("Simulated advertisement was malformed");
+                // an advertiser going the same direction as us is waiting for someone else, so it isn't listed
+                if info.sending != matches!(mode, Mode::Send(_)) {
+                    break BluetoothDevice {
+                        name: info.name.unwrap_or_default(),
+                        address: SIMULATED_DEVICE_ADDRESS.to_string(),
+                        rssi: None,
+                        peer_os: Some(info.os),
+                    };
+                }
+            }
+            if Instant::now() >= scan_end {
+                Err(timeout_error(
+                    self.timeouts.scan,
+                    &format!("scanning without finding a {}", peer_description(mode)),
+                ))?
+            }
+            sleep(Duration::from_millis(100)).await;
+        };
+        ui.show_bluetooth_devices(&[device]);
+        match ble_device_rx.recv().await {
+            Some(Some(address)) if address == SIMULATED_DEVICE_ADDRESS => (),
+            Some(Some(address)) => Err(FCError {
+                message: format!("Chosen device {} was not found", address),
+            })?,
+            _ => fc_error("User canceled.")?,
+        }
+        let advertisement = match self
+            .radio
+            .lock()
+            .expect("Couldn't lock simulated radio")
+            .take()
+        {
+            Some(a) => a,
+            None => Err(FCError {
+                message: "Simulated device stopped advertising".to_string(),
+            })?,
+        };
+        ui.output("Found device");
+
+        let disconnected = |_| FCError {
+            message: "Simulated peripheral disconnected".to_string(),
+        };
+        advertisement.capabilities.check(ui)?;
+        let capabilities = Capabilities::ours(SIMULATED_DEVICE_NAME.to_string())
+            .with_ble_limit(self.ble_transfer_limit, mode)
+            .with_port(self.port)
+            .with_hotspot(self.hotspot, &self.bands)
+            .with_role(self.role);
+        let ble_transfer = is_ble_transfer(&advertisement.capabilities, &capabilities);
+        advertisement
+            .writes
+            .send(GattWrite::Capabilities(capabilities.clone()))
+            .await
+            .map_err(disconnected)?;
+
+        let key_pair = BluetoothKeyPair::generate();
+        advertisement
+            .writes
+            .send(GattWrite::PublicKey(key_pair.public_hex()))
+            .await
+            .map_err(disconnected)?;
+        let cipher = key_pair.agree(&advertisement.public_key, true)?;
+
+        let peer_os = advertisement.os;
+        advertisement
+            .writes
+            .send(GattWrite::OS(bluetooth::OS.to_string()))
+            .await
+            .map_err(disconnected)?;
+        confirm_pin(&cipher.pin, ble_ui_rx, self.timeouts.connect, ui).await?;
+
+        let peer_hosting = PeerHosting::negotiate(
+            &capabilities,
+            Some(&advertisement.capabilities),
+            &Peer::from(peer_os.as_str()),
+            mode,
+        )?;
+        let info = if !peer_hosting.hosts {
+            let password = generate_password();
+            let (_, ssid) = get_key_and_ssid(&password);
+            advertisement
+                .writes
+                .send(GattWrite::Credentials(
+                    cipher.encrypt(SSID_LABEL, &ssid)?,
+                    cipher.encrypt(PASSWORD_LABEL, &password)?,
+                ))
+                .await
+                .map_err(disconnected)?;
+            (peer_os, ssid, password)
+        } else {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            advertisement
+                .writes
+                .send(GattWrite::ReadCredentials(reply_tx))
+                .await
+                .map_err(disconnected)?;
+            // the peer may still be confirming the PIN
+            let (ssid, password) = match timeout(self.timeouts.connect, reply_rx).await {
+                Ok(Ok(credentials)) => credentials,
+                Ok(Err(_)) => Err(FCError {
+                    message: "Simulated peripheral disconnected".to_string(),
+                })?,
+                Err(_) => Err(timeout_error(
+                    self.timeouts.connect,
+                    "waiting for the peer to share its WiFi credentials",
+                ))?,
+            };
+            (
+                peer_os,
+                cipher.decrypt(SSID_LABEL, &ssid)?,
+                cipher.decrypt(PASSWORD_LABEL, &password)?,
+            )
+        };
+        advertisement
+            .writes
+            .send(GattWrite::Done)
+            .await
+            .map_err(disconnected)?;
+        let (peer_os, ssid, password) = info;
+        let stream = ble_transfer.then(|| {
+            let (reader, writer) = split(advertisement.data);
+            BluetoothStream::new(reader, writer, Box::new(()))
+        });
+        Ok((peer_os, ssid, password, peer_hosting, stream))
+    }
+}
+
+async fn next_write(
+    rx: &mut mpsc::Receiver<GattWrite>,
+    duration: Duration,
+    step: &str,
+) -> Result<GattWrite, FCError> {
+    match timeout(duration, rx.recv()).await {
+        Ok(Some(write)) => Ok(write),
+        Ok(None) => Err(FCError {
+            message: "Simulated central disconnected".to_string(),
+        }),
+        Err(_) => Err(timeout_error(duration, step)),
+    }
+}
+
+impl NetworkBackend for Simulated {
+    async fn connect_to_peer<T: UI>(
+        &self,
+        hosting: bool,
+        ssid: String,
+        _password: String,
+        _interface: WiFiInterface,
+        _port: u16,
+        ui: &T,
+    ) -> Result<PeerResource, FCError> {
+        // the wrong password still gets caught, when the session key is verified
+        if hosting {
+            self.hotspot.check()?;
+            ui.output(&format!(
+                "Starting simulated hotspot {}{}",
+                ssid,
+                self.hotspot.describe()
+            ));
+            Ok(PeerResource::SimulatedHotspot)
+        } else {
+            ui.output(&format!(
+                "Joining simulated hotspot {}{}",
+                ssid,
+                self.hotspot.describe()
+            ));
+            Ok(PeerResource::WifiClient("127.0.0.1".to_string()))
+        }
+    }
+
+    fn supported_bands(&self, _interface: &WiFiInterface) -> Result<Vec<WiFiBand>, FCError> {
+        Ok(vec![WiFiBand::TwoPointFour, WiFiBand::Five, WiFiBand::Six])
+    }
+
+    fn stop_hotspot(
+        &self,
+        _peer_resource: Option<&PeerResource>,
+        _ssid: Option<&str>,
+    ) -> Result<String, FCError> {
+        Ok("Simulated hotspot stopped".to_string())
+    }
+
+    fn with_hotspot(&self, hotspot: HotspotChannel) -> Self {
+        Simulated {
+            hotspot,
+            ..self.clone()
+        }
+    }
+}
+
+impl BluetoothBackend for Simulated {
+    async fn negotiate_bluetooth<T: UI>(
+        &self,
+        mode: &Mode,
+        role: BluetoothRole,
+        _interface: Option<&BluetoothInterface>, // there's only the one simulated radio
+        mut ble_ui_rx: mpsc::Receiver<bool>,
+        ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+        if role.is_peripheral(mode) {
+            self.advertise(mode, &mut ble_ui_rx, ui).await
+        } else {
+            self.scan(mode, &mut ble_ui_rx, ble_device_rx, ui).await
+        }
+    }
+
+    // the copy shares the radio, so it can still find the other end
+    fn with_port(&self, port: u16) -> Self {
+        Simulated {
+            port,
+            ..self.clone()
+        }
+    }
+
+    fn with_bands(&self, bands: Vec<WiFiBand>) -> Self {
+        Simulated {
+            bands,
+            ..self.clone()
+        }
+    }
+
+    fn with_role(&self, role: Role) -> Self {
+        Simulated {
+            role,
+            ..self.clone()
+        }
+    }
+}
+
+// lets the app pick a backend when it starts rather than when it's compiled
+#[derive(Clone)]
+pub enum Backend {
+    Hardware(Hardware),
+    Simulated(Simulated),
+}
+
+impl Backend {
+    pub fn from_env() -> Self {
+        match std::env::var(BACKEND_ENV_VAR) {
+            Ok(b) if b == "simulated" => {
+                println!("Using simulated WiFi and Bluetooth");
+                Backend::Simulated(Simulated::new())
+            }
+            _ => Backend::Hardware(Hardware::default()),
+        }
+    }
+
+    // the simulated backend has no bonds to keep or forget
+    pub fn set_pairing_policy(&mut self, policy: PairingPolicy) {
+        if let Backend::Hardware(h) = self {
+            h.pairing = policy;
+        }
+    }
+}
+
+impl NetworkBackend for Backend {
+    async fn connect_to_peer<T: UI>(
+        &self,
+        hosting: bool,
+        ssid: String,
+        password: String,
+        interface: WiFiInterface,
+        port: u16,
+        ui: &T,
+    ) -> Result<PeerResource, FCError> {
+        match self {
+            Backend::Hardware(h) => {
+                h.connect_to_peer(hosting, ssid, password, interface, port, ui)
+                    .await
+            }
+            Backend::Simulated(s) => {
+                s.connect_to_peer(hosting, ssid, password, interface, port, ui)
+                    .await
+            }
+        }
+    }
+
+    fn supported_bands(&self, interface: &WiFiInterface) -> Result<Vec<WiFiBand>, FCError> {
+        match self {
+            Backend::Hardware(h) => h.supported_bands(interface),
+            Backend::Simulated(s) => s.supported_bands(interface),
+        }
+    }
+
+    fn stop_hotspot(
+        &self,
+        peer_resource: Option<&PeerResource>,
+        ssid: Option<&str>,
+    ) -> Result<String, FCError> {
+        match self {
+            Backend::Hardware(h) => h.stop_hotspot(peer_resource, ssid),
+            Backend::Simulated(s) => s.stop_hotspot(peer_resource, ssid),
+        }
+    }
+
+    fn with_hotspot(&self, hotspot: HotspotChannel) -> Self {
+        match self {
+            Backend::Hardware(h) => Backend::Hardware(h.with_hotspot(hotspot)),
+            Backend::Simulated(s) => Backend::Simulated(s.with_hotspot(hotspot)),
+        }
+    }
+}
+
+impl BluetoothBackend for Backend {
+    async fn negotiate_bluetooth<T: UI>(
+        &self,
+        mode: &Mode,
+        role: BluetoothRole,
+        interface: Option<&BluetoothInterface>,
+        ble_ui_rx: mpsc::Receiver<bool>,
+        ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+        match self {
+            Backend::Hardware(h) => {
+                h.negotiate_bluetooth(mode, role, interface, ble_ui_rx, ble_device_rx, ui)
+                    .await
+            }
+            Backend::Simulated(s) => {
+                s.negotiate_bluetooth(mode, role, interface, ble_ui_rx, ble_device_rx, ui)
+                    .await
+            }
+        }
+    }
+
+    fn with_port(&self, port: u16) -> Self {
+        match self {
+            Backend::Hardware(h) => Backend::Hardware(h.with_port(port)),
+            Backend::Simulated(s) => Backend::Simulated(s.with_port(port)),
+        }
+    }
+
+    fn with_bands(&self, bands: Vec<WiFiBand>) -> Self {
+        match self {
+            Backend::Hardware(h) => Backend::Hardware(h.with_bands(bands)),
+            Backend::Simulated(s) => Backend::Simulated(s.with_bands(bands)),
+        }
+    }
+
+    fn with_role(&self, role: Role) -> Self {
+        match self {
+            Backend::Hardware(h) => Backend::Hardware(h.with_role(role)),
+            Backend::Simulated(s) => Backend::Simulated(s.with_role(role)),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// the transfer's stream when it goes over the data characteristic instead of TCP
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{copy, duplex, split, AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    task::JoinHandle,
+};
+
+// how much the transfer can get ahead of the characteristic
+const BLUETOOTH_STREAM_BUFFER: usize = 64 * 1024;
+
+// a transfer over the data characteristic rather than TCP. the platform hands over a reader and writer for the
+// characteristic, and the bytes are copied between them and the stream that the transfer uses.
+pub struct BluetoothStream {
+    stream: DuplexStream,
+    incoming: JoinHandle<()>,
+    // shutting down waits for this, so that the last of what we wrote isn't lost
+    outgoing: Option<JoinHandle<()>>,
+    // whatever keeps the connection or GATT service up until the transfer is done
+    _connection: Box<dyn Send + Sync>,
+}
+
+impl BluetoothStream {
+    pub(crate) fn new<R, W>(mut reader: R, mut writer: W, connection: Box<dyn Send + Sync>) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (stream, theirs) = duplex(BLUETOOTH_STREAM_BUFFER);
+        let (mut from_transfer, mut to_transfer) = split(theirs);
+        let incoming = tokio::spawn(async move {
+            if let Err(e) = copy(&mut reader, &mut to_transfer).await {
+                println!("Error reading from Bluetooth characteristic: {}", e);
+            }
+            let _ = to_transfer.shutdown().await;
+        });
+        let outgoing = tokio::spawn(async move {
+            if let Err(e) = copy(&mut from_transfer, &mut writer).await {
+                println!("Error writing to Bluetooth characteristic: {}", e);
+            }
+            let _ = writer.shutdown().await;
+        });
+        BluetoothStream {
+            stream,
+            incoming,
+            outgoing: Some(outgoing),
+            _connection: connection,
+        }
+    }
+}
+
+impl Drop for BluetoothStream {
+    fn drop(&mut self) {
+        self.incoming.abort();
+        if let Some(outgoing) = &self.outgoing {
+            outgoing.abort();
+        }
+    }
+}
+
+impl AsyncRead for BluetoothStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BluetoothStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.stream).poll_shutdown(cx))?;
+        if let Some(outgoing) = &mut self.outgoing {
+            let _ = ready!(Pin::new(outgoing).poll(cx));
+            self.outgoing = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,670 @@
+use crate::{error::fc_error, FCError, Mode, Stream, CHUNKSIZE};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+// first protocol version that derives a key per session, uses counter nonces, and encrypts control messages.
+// peers older than this get the original framing: plaintext control messages and file details, and a random
+// nonce prepended to each chunk. the transcript can't protect the version exchange from being rewritten to
+// force that framing, so negotiate_session() also holds the peer to any version it advertised over Bluetooth or mDNS.
+pub(crate) const SESSION_PROTOCOL_VERSION: u64 = 10;
+
+const SALT_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+// filenames are the only variable-length control messages, and no filesystem allows paths anywhere near this long
+const MAX_CONTROL_SIZE: usize = 64 * 1024;
+// a full chunk plus its tag
+pub(crate) const MAX_CHUNK_SIZE: usize = CHUNKSIZE + TAG_SIZE;
+// legacy peers put the nonce in front of each chunk, and the mobile apps send chunks of 5 MB rather than 1
+pub(crate) const LEGACY_CHUNKSIZE: usize = 5_000_000;
+pub(crate) const LEGACY_MAX_CHUNK_SIZE: usize = LEGACY_CHUNKSIZE + NONCE_SIZE + TAG_SIZE;
+const SENDER_DIRECTION: u8 = 0;
+const RECEIVER_DIRECTION: u8 = 1;
+// associated data for control messages, which can't collide with the 17 bytes of chunk_aad()
+const CONTROL_AAD: &[u8] = b"control";
+const GUEST_FINISHED: &[u8] = b"guest finished";
+const HOST_FINISHED: &[u8] = b"host finished";
+
+pub(crate) struct Session {
+    cipher: Aes256Gcm,
+    legacy: bool,
+    send_direction: u8,
+    recv_direction: u8,
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+impl Session {
+    /// Session for peers older than SESSION_PROTOCOL_VERSION, using the password-derived key directly.
+    pub(crate) fn legacy(key: &[u8]) -> Self {
+        Session {
+            cipher: Aes256Gcm::new_from_slice(key).expect("Invalid AES-256-GCM key length"),
+            legacy: true,
+            send_direction: 0,
+            recv_direction: 0,
+            send_counter: 0,
+            recv_counter: 0,
+        }
+    }
+
+    /// Exchanges random salts with the peer and derives a key used only for this connection, bound to everything
+    /// sent in plaintext so far. Then each end proves it saw the same handshake. Must be called after mode
+    /// confirmation so that both ends agree on who sends.
+    pub(crate) async fn establish(
+        key: &[u8],
+        is_host: bool,
+        mode: &Mode,
+        transcript: &mut Transcript,
+        stream: &mut impl Stream,
+    ) -> Result<Self, FCError> {
+        let mut our_salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut our_salt);
+        let mut peer_salt = [0u8; SALT_SIZE];
+        // guest writes first, like the version and mode exchanges
+        if is_host {
+            transcript.read_exact(stream, &mut peer_salt).await?;
+            transcript.write_all(stream, &our_salt).await?;
+        } else {
+            transcript.write_all(stream, &our_salt).await?;
+            transcript.read_exact(stream, &mut peer_salt).await?;
+        }
+        let transcript_hash = transcript.hash();
+        let session_key = derive_session_key(key, &transcript_hash);
+        let mut session = Session::new(&session_key, mode);
+
+        // confirm the transcript. this fails if the passwords differ or if anything was modified in transit.
+        let (our_label, peer_label) = if is_host {
+            (HOST_FINISHED, GUEST_FINISHED)
+        } else {
+            (GUEST_FINISHED, HOST_FINISHED)
+        };
+        let expected = [peer_label, transcript_hash.as_slice()].concat();
+        let ours = [our_label, transcript_hash.as_slice()].concat();
+        if !is_host {
+            session.write_fixed(stream, &ours).await?;
+        }
+        match session.read_fixed(stream, expected.len()).await {
+            Ok(peer_finished) if peer_finished == expected => (),
+            _ => fc_error("Could not verify handshake with peer. Either the password is wrong or the connection was tampered with.")?,
+        }
+        if is_host {
+            session.write_fixed(stream, &ours).await?;
+        }
+        Ok(session)
+    }
+
+    pub(crate) fn new(session_key: &[u8], mode: &Mode) -> Self {
+        let (send_direction, recv_direction) = match mode {
+            Mode::Send(_) => (SENDER_DIRECTION, RECEIVER_DIRECTION),
+            Mode::Receive(_) => (RECEIVER_DIRECTION, SENDER_DIRECTION),
+        };
+        Session {
+            cipher: Aes256Gcm::new_from_slice(session_key).expect("Invalid AES-256-GCM key length"),
+            legacy: false,
+            send_direction,
+            recv_direction,
+            send_counter: 0,
+            recv_counter: 0,
+        }
+    }
+
+    pub(crate) fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Legacy sessions return the random nonce followed by the ciphertext and ignore `aad`.
+    /// Otherwise returns just the ciphertext, as the nonce is the next value of our counter.
+    pub(crate) fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, FCError> {
+        if self.legacy {
+            let nonce = Aes256Gcm::generate_nonce(rand::thread_rng());
+            let mut nonce_and_ciphertext = nonce.to_vec();
+            nonce_and_ciphertext.append(&mut self.cipher.encrypt(&nonce, plaintext)?);
+            return Ok(nonce_and_ciphertext);
+        }
+        let nonce = next_nonce(self.send_direction, &mut self.send_counter)?;
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        Ok(self.cipher.encrypt(Nonce::from_slice(&nonce), payload)?)
+    }
+
+    /// Fails if the message was tampered with, or if it isn't the next message the peer sent,
+    /// or if `aad` doesn't match what the sender authenticated.
+    pub(crate) fn decrypt(&mut self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, FCError> {
+        if self.legacy {
+            if data.len() < NONCE_SIZE {
+                Err(FCError {
+                    message: format!("Encrypted chunk of {} bytes is too short", data.len()),
+                })?
+            }
+            let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+            return Ok(self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext)?);
+        }
+        let nonce = next_nonce(self.recv_direction, &mut self.recv_counter)?;
+        let payload = Payload { msg: data, aad };
+        match self.cipher.decrypt(Nonce::from_slice(&nonce), payload) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(FCError {
+                message: "Could not authenticate data from peer: it was modified, replayed, or arrived out of order".to_string(),
+            }),
+        }
+    }
+
+    // control messages. for legacy peers these are written in plaintext exactly as versions 8 and 9 expect,
+    // otherwise each one is its own encrypted record.
+
+    pub(crate) async fn write_u64(
+        &mut self,
+        stream: &mut impl Stream,
+        value: u64,
+    ) -> Result<(), FCError> {
+        if self.legacy {
+            stream.write_u64(value).await?;
+            Ok(())
+        } else {
+            self.write_record(stream, &value.to_be_bytes()).await
+        }
+    }
+
+    pub(crate) async fn read_u64(&mut self, stream: &mut impl Stream) -> Result<u64, FCError> {
+        if self.legacy {
+            Ok(stream.read_u64().await?)
+        } else {
+            let record = self.read_record(stream).await?;
+            match <[u8; 8]>::try_from(record.as_slice()) {
+                Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+                Err(_) => Err(FCError {
+                    message: format!(
+                        "Expected 8-byte value from peer, got {} bytes",
+                        record.len()
+                    ),
+                }),
+            }
+        }
+    }
+
+    // variable-length value, preceded by its length for legacy peers
+    pub(crate) async fn write_bytes(
+        &mut self,
+        stream: &mut impl Stream,
+        value: &[u8],
+    ) -> Result<(), FCError> {
+        if self.legacy {
+            stream.write_u64(value.len() as u64).await?;
+            stream.write_all(value).await?;
+            Ok(())
+        } else {
+            self.write_record(stream, value).await
+        }
+    }
+
+    pub(crate) async fn read_bytes(
+        &mut self,
+        stream: &mut impl Stream,
+    ) -> Result<Vec<u8>, FCError> {
+        if self.legacy {
+            let size = check_length(stream.read_u64().await?, MAX_CONTROL_SIZE, "value")?;
+            let mut value = vec![0u8; size];
+            stream.read_exact(&mut value).await?;
+            Ok(value)
+        } else {
+            self.read_record(stream).await
+        }
+    }
+
+    // fixed-length value, written as-is for legacy peers
+    pub(crate) async fn write_fixed(
+        &mut self,
+        stream: &mut impl Stream,
+        value: &[u8],
+    ) -> Result<(), FCError> {
+        if self.legacy {
+            stream.write_all(value).await?;
+            Ok(())
+        } else {
+            self.write_record(stream, value).await
+        }
+    }
+
+    pub(crate) async fn read_fixed(
+        &mut self,
+        stream: &mut impl Stream,
+        size: usize,
+    ) -> Result<Vec<u8>, FCError> {
+        let value = if self.legacy {
+            let mut value = vec![0u8; size];
+            stream.read_exact(&mut value).await?;
+            value
+        } else {
+            self.read_record(stream).await?
+        };
+        if value.len() != size {
+            Err(FCError {
+                message: format!("Expected {} bytes from peer, got {}", size, value.len()),
+            })?
+        }
+        Ok(value)
+    }
+
+    async fn write_record(
+        &mut self,
+        stream: &mut impl Stream,
+        plaintext: &[u8],
+    ) -> Result<(), FCError> {
+        let record = self.encrypt(plaintext, CONTROL_AAD)?;
+        stream.write_u64(record.len() as u64).await?;
+        stream.write_all(&record).await?;
+        Ok(())
+    }
+
+    async fn read_record(&mut self, stream: &mut impl Stream) -> Result<Vec<u8>, FCError> {
+        let size = check_length(
+            stream.read_u64().await?,
+            MAX_CONTROL_SIZE,
+            "control message",
+        )?;
+        let mut record = vec![0u8; size];
+        stream.read_exact(&mut record).await?;
+        self.decrypt(&record, CONTROL_AAD)
+    }
+}
+
+/// Running hash of every byte exchanged before the session key exists: versions, modes, and salts.
+/// The handshake is lock-step, so both ends see the same bytes in the same order.
+pub(crate) struct Transcript {
+    hasher: Sha256,
+}
+
+impl Transcript {
+    pub(crate) fn new() -> Self {
+        Transcript {
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub(crate) async fn write_u64(
+        &mut self,
+        stream: &mut impl Stream,
+        value: u64,
+    ) -> std::io::Result<()> {
+        self.hasher.update(value.to_be_bytes());
+        stream.write_u64(value).await
+    }
+
+    pub(crate) async fn read_u64(&mut self, stream: &mut impl Stream) -> std::io::Result<u64> {
+        let value = stream.read_u64().await?;
+        self.hasher.update(value.to_be_bytes());
+        Ok(value)
+    }
+
+    async fn write_all(&mut self, stream: &mut impl Stream, value: &[u8]) -> std::io::Result<()> {
+        self.hasher.update(value);
+        stream.write_all(value).await
+    }
+
+    async fn read_exact(
+        &mut self,
+        stream: &mut impl Stream,
+        value: &mut [u8],
+    ) -> std::io::Result<()> {
+        stream.read_exact(value).await?;
+        self.hasher.update(&*value);
+        Ok(())
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+// every length the peer sends goes through here before we allocate anything for it
+pub(crate) fn check_length(length: u64, max: usize, what: &str) -> Result<usize, FCError> {
+    match usize::try_from(length) {
+        Ok(l) if l <= max => Ok(l),
+        _ => Err(FCError {
+            message: format!(
+                "Peer sent a {} of {} bytes, more than the maximum of {}",
+                what, length, max
+            ),
+        }),
+    }
+}
+
+// associated data binding a chunk to its place in the transfer
+pub(crate) fn chunk_aad(file_index: u64, chunk_index: u64, final_chunk: bool) -> [u8; 17] {
+    let mut aad = [0u8; 17];
+    aad[..8].copy_from_slice(&file_index.to_be_bytes());
+    aad[8..16].copy_from_slice(&chunk_index.to_be_bytes());
+    aad[16] = final_chunk as u8;
+    aad
+}
+
+fn derive_session_key(key: &[u8], transcript_hash: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"flying carpet session key");
+    hasher.update(key);
+    hasher.update(transcript_hash);
+    hasher.finalize().into()
+}
+
+// nonce is the direction byte, three zero bytes, and a big-endian message counter.
+// the direction keeps the two ends from ever using the same nonce with the shared session key.
+fn next_nonce(direction: u8, counter: &mut u64) -> Result<[u8; NONCE_SIZE], FCError> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = direction;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter = counter.checked_add(1).ok_or(FCError {
+        message: "Nonce counter exhausted".to_string(),
+    })?;
+    Ok(nonce)
+}
+
+// the WiFi credentials sent over BLE are encrypted with a key from an x25519 agreement between the central and the
+// peripheral, so that someone listening nearby can't read the password and derive the transfer key from it. the PIN is
+// derived from the same secret and both public keys. the users compare it on both screens, which is what stops a device
+// in the middle, since it would end up with a different secret on each side.
+const BLUETOOTH_KEY_LABEL: &[u8] = b"flying carpet bluetooth key";
+const BLUETOOTH_PIN_LABEL: &[u8] = b"flying carpet bluetooth pin";
+
+pub(crate) struct BluetoothKeyPair {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl BluetoothKeyPair {
+    pub(crate) fn generate() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        BluetoothKeyPair { secret, public }
+    }
+
+    /// Hex, as written to or served from the key characteristics.
+    pub(crate) fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /// Fails if the peer's key is malformed, or is one that would make the shared secret predictable.
+    pub(crate) fn agree(
+        self,
+        peer_public_hex: &str,
+        we_are_central: bool,
+    ) -> Result<CredentialCipher, FCError> {
+        let peer_public: [u8; 32] = match from_hex(peer_public_hex).map(<[u8; 32]>::try_from) {
+            Some(Ok(key)) => key,
+            _ => Err(FCError {
+                message: "Peer sent a malformed Bluetooth public key".to_string(),
+            })?,
+        };
+        let our_public = *self.public.as_bytes();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_public));
+        if !shared.was_contributory() {
+            fc_error("Peer sent an invalid Bluetooth public key")?;
+        }
+        let (central_public, peripheral_public) = if we_are_central {
+            (our_public, peer_public)
+        } else {
+            (peer_public, our_public)
+        };
+        let derive = |label: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(label);
+            hasher.update(shared.as_bytes());
+            hasher.update(central_public);
+            hasher.update(peripheral_public);
+            hasher.finalize()
+        };
+        let pin_hash = derive(BLUETOOTH_PIN_LABEL);
+        let pin = u32::from_be_bytes(pin_hash[..4].try_into().unwrap()) % 1_000_000;
+        Ok(CredentialCipher {
+            cipher: Aes256Gcm::new_from_slice(&derive(BLUETOOTH_KEY_LABEL))
+                .expect("Invalid AES-256-GCM key length"),
+            pin: format!("{:06}", pin),
+        })
+    }
+}
+
+pub(crate) struct CredentialCipher {
+    cipher: Aes256Gcm,
+    pub pin: String,
+}
+
+impl CredentialCipher {
+    /// Returns hex of a random nonce followed by the ciphertext. `label` names the characteristic the value is for,
+    /// so that the SSID and password can't be swapped.
+    pub(crate) fn encrypt(&self, label: &str, plaintext: &str) -> Result<String, FCError> {
+        let nonce = Aes256Gcm::generate_nonce(rand::thread_rng());
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: label.as_bytes(),
+        };
+        let mut data = nonce.to_vec();
+        data.append(&mut self.cipher.encrypt(&nonce, payload)?);
+        Ok(to_hex(&data))
+    }
+
+    pub(crate) fn decrypt(&self, label: &str, value: &str) -> Result<String, FCError> {
+        let data = match from_hex(value) {
+            Some(d) if d.len() >= NONCE_SIZE + TAG_SIZE => d,
+            _ => Err(FCError {
+                message: format!("Peer's encrypted {} was malformed", label),
+            })?,
+        };
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: label.as_bytes(),
+        };
+        match self.cipher.decrypt(Nonce::from_slice(nonce), payload) {
+            Ok(plaintext) => Ok(String::from_utf8(plaintext)?),
+            Err(_) => Err(FCError {
+                message: format!("Could not decrypt peer's {}", label),
+            }),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_aad, BluetoothKeyPair, Session, Transcript};
+    use crate::{FCError, Mode};
+    use sha2::Digest;
+    use std::path::PathBuf;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn session_pair() -> (Session, Session) {
+        let key = [7u8; 32];
+        let sender = Session::new(&key, &Mode::Send(vec![]));
+        let receiver = Session::new(&key, &Mode::Receive(PathBuf::new()));
+        (sender, receiver)
+    }
+
+    #[test]
+    fn chunks_in_order() {
+        let (mut sender, mut receiver) = session_pair();
+        for i in 0..3 {
+            let aad = chunk_aad(0, i, i == 2);
+            let ciphertext = sender.encrypt(b"chunk", &aad).unwrap();
+            assert_eq!(receiver.decrypt(&ciphertext, &aad).unwrap(), b"chunk");
+        }
+    }
+
+    #[test]
+    fn counter_nonces_are_unique() {
+        let (mut sender, _) = session_pair();
+        let aad = chunk_aad(0, 0, false);
+        let first = sender.encrypt(b"same plaintext", &aad).unwrap();
+        let second = sender.encrypt(b"same plaintext", &aad).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn reordered_chunk_rejected() {
+        let (mut sender, mut receiver) = session_pair();
+        let _first = sender.encrypt(b"first", &chunk_aad(0, 0, false)).unwrap();
+        let second = sender.encrypt(b"second", &chunk_aad(0, 1, false)).unwrap();
+        assert!(receiver.decrypt(&second, &chunk_aad(0, 1, false)).is_err());
+    }
+
+    #[test]
+    fn replayed_chunk_rejected() {
+        let (mut sender, mut receiver) = session_pair();
+        let aad = chunk_aad(0, 0, false);
+        let first = sender.encrypt(b"first", &aad).unwrap();
+        receiver.decrypt(&first, &aad).unwrap();
+        assert!(receiver.decrypt(&first, &chunk_aad(0, 1, false)).is_err());
+    }
+
+    #[test]
+    fn chunk_moved_between_files_rejected() {
+        let (mut sender, mut receiver) = session_pair();
+        let ciphertext = sender.encrypt(b"data", &chunk_aad(1, 0, true)).unwrap();
+        assert!(receiver
+            .decrypt(&ciphertext, &chunk_aad(0, 0, true))
+            .is_err());
+    }
+
+    #[test]
+    fn final_flag_authenticated() {
+        let (mut sender, mut receiver) = session_pair();
+        let ciphertext = sender.encrypt(b"data", &chunk_aad(0, 0, false)).unwrap();
+        assert!(receiver
+            .decrypt(&ciphertext, &chunk_aad(0, 0, true))
+            .is_err());
+    }
+
+    #[test]
+    fn directions_differ() {
+        let (mut sender, mut receiver) = session_pair();
+        // a message the receiver sent can't be reflected back to it as if the sender wrote it
+        let aad = chunk_aad(0, 0, false);
+        let reflected = receiver.encrypt(b"data", &aad).unwrap();
+        assert!(receiver.decrypt(&reflected, &aad).is_err());
+        assert!(sender.decrypt(&reflected, &aad).is_ok());
+    }
+
+    async fn establish_pair(
+        host_key: [u8; 32],
+        guest_key: [u8; 32],
+        tamper: bool,
+    ) -> (
+        Result<(Session, TcpStream), FCError>,
+        Result<(Session, TcpStream), FCError>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut transcript = Transcript::new();
+            // host's view of the plaintext handshake
+            transcript.read_u64(&mut stream).await.unwrap();
+            let mode = Mode::Receive(PathBuf::new());
+            Session::establish(&host_key, true, &mode, &mut transcript, &mut stream)
+                .await
+                .map(|s| (s, stream))
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut transcript = Transcript::new();
+        transcript.write_u64(&mut stream, 10).await.unwrap();
+        if tamper {
+            // simulate an on-path attacker changing what the host saw
+            transcript.hasher.update([0u8]);
+        }
+        let mode = Mode::Send(vec![]);
+        let guest = Session::establish(&guest_key, false, &mode, &mut transcript, &mut stream)
+            .await
+            .map(|s| (s, stream));
+        (host.await.unwrap(), guest)
+    }
+
+    #[tokio::test]
+    async fn handshake_verified() {
+        let (host, guest) = establish_pair([1u8; 32], [1u8; 32], false).await;
+        let (mut host, mut host_stream) = host.unwrap();
+        let (mut guest, mut guest_stream) = guest.unwrap();
+        guest
+            .write_bytes(&mut guest_stream, b"file.txt")
+            .await
+            .unwrap();
+        guest.write_u64(&mut guest_stream, 1234).await.unwrap();
+        assert_eq!(
+            host.read_bytes(&mut host_stream).await.unwrap(),
+            b"file.txt"
+        );
+        assert_eq!(host.read_u64(&mut host_stream).await.unwrap(), 1234);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_rejected() {
+        let (host, guest) = establish_pair([1u8; 32], [2u8; 32], false).await;
+        assert!(host.is_err());
+        assert!(guest.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_transcript_rejected() {
+        let (host, guest) = establish_pair([1u8; 32], [1u8; 32], true).await;
+        assert!(host.is_err());
+        assert!(guest.is_err());
+    }
+
+    #[test]
+    fn bluetooth_key_agreement() {
+        let central = BluetoothKeyPair::generate();
+        let peripheral = BluetoothKeyPair::generate();
+        let (central_public, peripheral_public) = (central.public_hex(), peripheral.public_hex());
+        let central = central.agree(&peripheral_public, true).unwrap();
+        let peripheral = peripheral.agree(&central_public, false).unwrap();
+        assert_eq!(central.pin, peripheral.pin);
+        assert_eq!(central.pin.len(), 6);
+
+        let ciphertext = central.encrypt("password", "hunter22").unwrap();
+        assert!(!ciphertext.contains(&super::to_hex(b"hunter22")));
+        assert_eq!(
+            peripheral.decrypt("password", &ciphertext).unwrap(),
+            "hunter22"
+        );
+        // can't be passed off as the SSID
+        assert!(peripheral.decrypt("ssid", &ciphertext).is_err());
+
+        // a device in the middle ends up with a different PIN
+        let central = BluetoothKeyPair::generate();
+        let mitm = BluetoothKeyPair::generate();
+        let mitm_public = mitm.public_hex();
+        let central_public = central.public_hex();
+        let central = central.agree(&mitm_public, true).unwrap();
+        let peripheral = BluetoothKeyPair::generate()
+            .agree(&central_public, false)
+            .unwrap();
+        assert_ne!(central.pin, peripheral.pin);
+    }
+
+    #[test]
+    fn bad_bluetooth_keys() {
+        // all zeroes is a low-order point, which would make the shared secret zero
+        let zeroes = "00".repeat(32);
+        for key in [zeroes.as_str(), "", "abc", "zz", &"ab".repeat(31)] {
+            assert!(BluetoothKeyPair::generate().agree(key, true).is_err());
+        }
+    }
+}
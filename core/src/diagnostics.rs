@@ -0,0 +1,149 @@
+// checks of what a transfer needs from this machine, so that a user can find out why one won't start before trying it.
+// each platform's network and bluetooth modules run their own checks, and the port check is shared. nothing here
+// changes any settings: a hotspot isn't started and the Bluetooth adapter isn't powered on.
+
+use crate::{bluetooth, network};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    // transfers may still work, or work only some ways
+    Warning,
+    Failed,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    pub(crate) fn ok(name: &str, detail: &str) -> Self {
+        Check::new(name, Status::Ok, detail)
+    }
+
+    pub(crate) fn warning(name: &str, detail: &str) -> Self {
+        Check::new(name, Status::Warning, detail)
+    }
+
+    pub(crate) fn failed(name: &str, detail: &str) -> Self {
+        Check::new(name, Status::Failed, detail)
+    }
+
+    fn new(name: &str, status: Status, detail: &str) -> Self {
+        Check {
+            name: name.to_string(),
+            status,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Report {
+    pub wifi: Vec<Check>,
+    pub bluetooth: Vec<Check>,
+    pub port: Vec<Check>,
+}
+
+impl Report {
+    // the worst of all the checks
+    pub fn status(&self) -> Status {
+        self.checks()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Status::Ok)
+    }
+
+    fn checks(&self) -> impl Iterator<Item = &Check> {
+        self.wifi
+            .iter()
+            .chain(self.bluetooth.iter())
+            .chain(self.port.iter())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (section, checks) in [
+            ("WiFi", &self.wifi),
+            ("Bluetooth", &self.bluetooth),
+            ("Port", &self.port),
+        ] {
+            writeln!(f, "{}:", section)?;
+            for check in checks {
+                let status = match check.status {
+                    Status::Ok => "ok",
+                    Status::Warning => "warning",
+                    Status::Failed => "failed",
+                };
+                writeln!(f, "  [{}] {}: {}", status, check.name, check.detail)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// port is the one the user entered, where 0 means any free port
+pub async fn diagnose(port: u16) -> Report {
+    Report {
+        wifi: network::diagnose(port),
+        bluetooth: bluetooth::diagnose().await,
+        port: vec![check_port(port)],
+    }
+}
+
+fn check_port(port: u16) -> Check {
+    let name = format!("TCP port {}", port);
+    if port == 0 {
+        return Check::ok(&name, "Transfers will listen on any free port.");
+    }
+    // dropped right away, so the port is free again for a transfer
+    match crate::listen(port) {
+        Ok(_) => Check::ok(&name, "Free to listen on."),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Check::warning(
+            &name,
+            "In use by another program. Transfers will move to a free port when the other device can be told about it, and otherwise wait for this one.",
+        ),
+        Err(e) => Check::failed(&name, &format!("Couldn't listen: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status() {
+        let mut report = Report {
+            wifi: vec![Check::ok("a", "")],
+            bluetooth: vec![],
+            port: vec![Check::ok("b", "")],
+        };
+        assert_eq!(report.status(), Status::Ok);
+        report.bluetooth.push(Check::warning("c", ""));
+        assert_eq!(report.status(), Status::Warning);
+        report.wifi.push(Check::failed("d", ""));
+        report.port.push(Check::ok("e", ""));
+        assert_eq!(report.status(), Status::Failed);
+
+        let text = report.to_string();
+        assert!(
+            text.starts_with("WiFi:\n  [ok] a: \n  [failed] d: \nBluetooth:\n  [warning] c: \n")
+        );
+    }
+
+    #[tokio::test]
+    async fn port() {
+        assert_eq!(check_port(0).status, Status::Ok);
+        let listener = crate::listen(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(check_port(port).status, Status::Warning);
+        drop(listener);
+        assert_eq!(check_port(port).status, Status::Ok);
+    }
+}
@@ -0,0 +1,232 @@
+// finding the other end of a same-network transfer over mDNS/DNS-SD. the listening end advertises a _flyingcarpet._tcp
+// service, and the connecting end browses for the one whose id matches the password they both have.
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{collections::HashMap, fs, net::IpAddr, time::Duration};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{bluetooth::OS, error::FCError, utils::timeout_error, LanPeer, MAJOR_VERSION, UI};
+
+const SERVICE_TYPE: &str = "_flyingcarpet._tcp.local.";
+pub(crate) const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl From<mdns_sd::Error> for FCError {
+    fn from(value: mdns_sd::Error) -> Self {
+        FCError {
+            message: format!("mDNS error: {}", value),
+        }
+    }
+}
+
+// stops the daemon's thread when we're done with it, which dropping the ServiceDaemon doesn't
+struct Daemon(ServiceDaemon);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            println!("Error stopping mDNS daemon: {}", e);
+        }
+    }
+}
+
+// advertises the transfer until dropped
+pub(crate) struct Advertisement {
+    fullname: String,
+    daemon: Daemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.0.unregister(&self.fullname) {
+            println!("Error unregistering mDNS service: {}", e);
+        }
+    }
+}
+
+// short enough to show, and no more of the password's hash than the hotspot's SSID already gives away
+pub(crate) fn transfer_id(ssid: &str) -> &str {
+    ssid.trim_start_matches("flyingCarpet_")
+}
+
+// what the peer shows for us
+fn device_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "Flying Carpet".to_string())
+}
+
+fn service_info(name: &str, id: &str, port: u16) -> Result<ServiceInfo, FCError> {
+    let host: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let version = MAJOR_VERSION.to_string();
+    let properties = [
+        ("name", name),
+        ("os", OS),
+        ("version", version.as_str()),
+        ("id", id),
+    ];
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        &format!("{} {}", name, id),
+        &format!("{}.local.", host),
+        "",
+        port,
+        &properties[..],
+    )?;
+    Ok(info.enable_addr_auto())
+}
+
+pub(crate) fn advertise(id: &str, port: u16) -> Result<Advertisement, FCError> {
+    let daemon = Daemon(ServiceDaemon::new()?);
+    let info = service_info(&device_name(), id, port)?;
+    let fullname = info.get_fullname().to_string();
+    daemon.0.register(info)?;
+    Ok(Advertisement { fullname, daemon })
+}
+
+// None if it's not a Flying Carpet we can reach
+fn lan_peer(info: &ServiceInfo) -> Option<LanPeer> {
+    // IPv4 where there's a choice. link-local IPv6 is no use without knowing which of our interfaces it's on.
+    let ip = info
+        .get_addresses()
+        .iter()
+        .filter(|ip| !matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local()))
+        .min_by_key(|ip| ip.is_ipv6())?;
+    Some(LanPeer {
+        name: info.get_property_val_str("name")?.to_string(),
+        address: ip.to_string(),
+        port: info.get_port(),
+        peer_os: info.get_property_val_str("os")?.to_string(),
+        version: info.get_property_val_str("version")?.parse().ok()?,
+        id: info.get_property_val_str("id")?.to_string(),
+    })
+}
+
+// browses until a device advertises the same id as ours, showing every device found along the way
+pub(crate) async fn find_peer<T: UI>(
+    id: &str,
+    timeout: Duration,
+    ui: &T,
+) -> Result<LanPeer, FCError> {
+    let daemon = Daemon(ServiceDaemon::new()?);
+    let events = daemon.0.browse(SERVICE_TYPE)?;
+    ui.output("Looking for the other device on this network...");
+    let deadline = Instant::now() + timeout;
+    let mut found: HashMap<String, LanPeer> = HashMap::new();
+    loop {
+        let event = match timeout_at(deadline, events.recv_async()).await {
+            Ok(Ok(e)) => e,
+            Ok(Err(e)) => Err(FCError {
+                message: format!("mDNS browsing stopped: {}", e),
+            })?,
+            Err(_) => Err(timeout_error(
+                timeout,
+                "looking for a device on this network with the same password",
+            ))?,
+        };
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                let Some(peer) = lan_peer(&info) else {
+                    continue;
+                };
+                println!("Found {:?}", peer);
+                if peer.id == id {
+                    return Ok(peer);
+                }
+                found.insert(info.get_fullname().to_string(), peer);
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                if found.remove(&fullname).is_none() {
+                    continue;
+                }
+            }
+            _ => continue,
+        }
+        let mut peers: Vec<LanPeer> = found.values().cloned().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        ui.show_lan_peers(&peers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_is_ssid_suffix() {
+        let (_, ssid) = crate::utils::get_key_and_ssid("password");
+        let id = transfer_id(&ssid);
+        assert_eq!(id.len(), 4);
+        assert_eq!(format!("flyingCarpet_{}", id), ssid);
+    }
+
+    #[test]
+    fn advertised_peer() {
+        let info = service_info("Lab PC #2", "1a2b", 3290).unwrap();
+        assert_eq!(
+            info.get_fullname(),
+            format!("Lab PC #2 1a2b.{}", SERVICE_TYPE)
+        );
+        assert_eq!(info.get_hostname(), "Lab-PC--2.local.");
+        assert_eq!(info.get_property_val_str("os"), Some(OS));
+
+        let properties = [
+            ("name", "laptop"),
+            ("os", "linux"),
+            ("version", "10"),
+            ("id", "1a2b"),
+        ];
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "laptop 1a2b",
+            "laptop.local.",
+            "192.168.1.2,fe80::1",
+            3290,
+            &properties[..],
+        )
+        .unwrap();
+        let peer = lan_peer(&info).unwrap();
+        assert_eq!(peer.address, "192.168.1.2");
+        assert_eq!(peer.port, 3290);
+        assert_eq!(peer.peer_os, "linux");
+        assert_eq!(peer.version, 10);
+        assert_eq!(peer.id, "1a2b");
+
+        // not something we can connect to
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "laptop 1a2b",
+            "laptop.local.",
+            "",
+            3290,
+            &properties[..],
+        )
+        .unwrap();
+        assert!(lan_peer(&info).is_none());
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "laptop 1a2b",
+            "laptop.local.",
+            "fe80::1",
+            3290,
+            &properties[..],
+        )
+        .unwrap();
+        assert!(lan_peer(&info).is_none());
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "printer",
+            "printer.local.",
+            "192.168.1.3",
+            3290,
+            &[("name", "printer")][..],
+        )
+        .unwrap();
+        assert!(lan_peer(&info).is_none());
+    }
+}
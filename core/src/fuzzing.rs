@@ -0,0 +1,147 @@
+// entry points for the cargo-fuzz targets in fuzz/. each one feeds arbitrary bytes to a decoder as if the peer had sent
+// them, and throws away anything written back. the first byte picks between legacy and session framing, or for the
+// handshake, which role we play. decoders are expected to return errors, never panic or allocate without bound.
+
+use crate::{
+    crypto::Session,
+    negotiate_session,
+    receiving::{receive_and_decrypt_chunk, receive_file_details},
+    Mode, MAJOR_VERSION,
+};
+use futures::executor::block_on;
+use std::{
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const KEY: [u8; 32] = [7u8; 32];
+
+/// File details: filename and size.
+pub fn header(data: &[u8]) {
+    let Some((selector, input)) = data.split_first() else {
+        return;
+    };
+    let mut stream = FuzzStream { input };
+    let _ = block_on(receive_file_details(&mut session(*selector), &mut stream));
+}
+
+/// One chunk of a file.
+pub fn chunk(data: &[u8]) {
+    let Some((selector, input)) = data.split_first() else {
+        return;
+    };
+    let mut stream = FuzzStream { input };
+    let _ = block_on(receive_and_decrypt_chunk(
+        0,
+        0,
+        &mut session(*selector),
+        &mut stream,
+    ));
+}
+
+/// Number of files followed by the details of each.
+pub fn manifest(data: &[u8]) {
+    let Some((selector, input)) = data.split_first() else {
+        return;
+    };
+    let mut stream = FuzzStream { input };
+    let mut session = session(*selector);
+    let _ = block_on(async {
+        let num_files = session.read_u64(&mut stream).await?;
+        for _ in 0..num_files {
+            receive_file_details(&mut session, &mut stream).await?;
+        }
+        Ok::<(), crate::FCError>(())
+    });
+}
+
+/// Version and mode confirmation, then the session key exchange.
+pub fn handshake(data: &[u8]) {
+    let Some((selector, input)) = data.split_first() else {
+        return;
+    };
+    let is_host = selector & 1 == 0;
+    let mode = if selector & 2 == 0 {
+        Mode::Receive(PathBuf::new())
+    } else {
+        Mode::Send(vec![])
+    };
+    let mut stream = FuzzStream { input };
+    let _ = block_on(negotiate_session(
+        MAJOR_VERSION,
+        None,
+        &mode,
+        is_host,
+        &KEY,
+        &mut stream,
+    ));
+}
+
+fn session(selector: u8) -> Session {
+    if selector & 1 == 0 {
+        Session::legacy(&KEY)
+    } else {
+        Session::new(&KEY, &Mode::Receive(PathBuf::new()))
+    }
+}
+
+// reads from the fuzzer's input, then reports EOF
+struct FuzzStream<'a> {
+    input: &'a [u8],
+}
+
+impl AsyncRead for FuzzStream<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FuzzStream<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk, handshake, header, manifest};
+
+    // lengths that used to be allocated as-is, under every framing and role
+    #[test]
+    fn hostile_lengths() {
+        for selector in 0..4u8 {
+            for length in [0, 12, 1 << 20, 1 << 40, u64::MAX] {
+                let mut data = vec![selector];
+                data.extend_from_slice(&length.to_be_bytes());
+                data.extend_from_slice(&[0u8; 64]);
+                header(&data);
+                manifest(&data);
+                handshake(&data);
+                // v10 chunks start with the final flag
+                let mut chunk_data = vec![selector, 1];
+                chunk_data.extend_from_slice(&length.to_be_bytes());
+                chunk(&chunk_data);
+                chunk(&data);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// where the hotspot goes, as chosen by the user. the platforms' network modules read this when they start one.
+
+use crate::error::FCError;
+
+// in GHz
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum WiFiBand {
+    #[serde(rename = "2.4")]
+    TwoPointFour,
+    #[serde(rename = "5")]
+    Five,
+    #[serde(rename = "6")]
+    Six,
+}
+
+impl WiFiBand {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            WiFiBand::TwoPointFour => "2.4",
+            WiFiBand::Five => "5",
+            WiFiBand::Six => "6",
+        }
+    }
+
+    pub(crate) fn parse(band: &str) -> Option<Self> {
+        match band {
+            "2.4" => Some(WiFiBand::TwoPointFour),
+            "5" => Some(WiFiBand::Five),
+            "6" => Some(WiFiBand::Six),
+            _ => None,
+        }
+    }
+
+    fn has_channel(&self, channel: u8) -> bool {
+        match self {
+            WiFiBand::TwoPointFour => (1..=14).contains(&channel),
+            WiFiBand::Five => (32..=177).contains(&channel),
+            WiFiBand::Six => (1..=233).contains(&channel),
+        }
+    }
+}
+
+// where the hotspot goes. None leaves the choice to the OS, and a channel needs its band, since the same number means
+// different frequencies on 2.4 and 6 GHz.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct HotspotChannel {
+    pub band: Option<WiFiBand>,
+    pub channel: Option<u8>,
+}
+
+impl HotspotChannel {
+    pub fn check(&self) -> Result<(), FCError> {
+        match (self.band, self.channel) {
+            (None, Some(channel)) => Err(FCError {
+                message: format!("Choose a band for channel {}", channel),
+            })?,
+            (Some(band), Some(channel)) if !band.has_channel(channel) => Err(FCError {
+                message: format!("There's no channel {} on {} GHz", channel, band.as_str()),
+            })?,
+            _ => Ok(()),
+        }
+    }
+
+    // for the user, empty if the OS chooses
+    pub(crate) fn describe(&self) -> String {
+        match (self.band, self.channel) {
+            (Some(band), Some(channel)) => format!(" on {} GHz channel {}", band.as_str(), channel),
+            (Some(band), None) => format!(" on {} GHz", band.as_str()),
+            _ => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HotspotChannel, WiFiBand};
+
+    #[test]
+    fn hotspot_channels() {
+        assert!(HotspotChannel::default().check().is_ok());
+        let hotspot = HotspotChannel {
+            band: Some(WiFiBand::Six),
+            channel: Some(5),
+        };
+        assert!(hotspot.check().is_ok());
+        assert_eq!(hotspot.describe(), " on 6 GHz channel 5");
+        let hotspot = HotspotChannel {
+            band: None,
+            ..hotspot
+        };
+        assert!(hotspot.check().is_err());
+        let hotspot = HotspotChannel {
+            band: Some(WiFiBand::Five),
+            channel: Some(14),
+        };
+        assert!(hotspot.check().is_err());
+        let hotspot = HotspotChannel {
+            band: Some(WiFiBand::TwoPointFour),
+            channel: None,
+        };
+        assert!(hotspot.check().is_ok());
+        assert_eq!(hotspot.describe(), " on 2.4 GHz");
+    }
+}
@@ -6,28 +6,54 @@ pub mod network;
 #[cfg_attr(target_os = "windows", path = "windows/bluetooth.rs")]
 pub mod bluetooth;
 
+pub mod backend;
+pub mod bluetooth_stream;
+mod crypto;
+pub mod diagnostics;
+mod discovery;
 pub mod error;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing;
+pub mod hotspot;
+pub mod negotiation;
+pub mod qr;
 mod receiving;
 mod sending;
+#[cfg(test)]
+mod tests;
 pub mod utils;
 
-use bluetooth::negotiate_bluetooth;
+use backend::{Backend, BluetoothBackend, NetworkBackend};
+use bluetooth_stream::BluetoothStream;
+use crypto::{Session, Transcript, SESSION_PROTOCOL_VERSION};
 use error::{fc_error, FCError};
+use negotiation::{peer_hosts, Role};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
-    net::SocketAddr,
+    io::ErrorKind,
+    net::{Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
+    pin::Pin,
     str::FromStr,
     sync::{Arc, Mutex},
+    task::{Context, Poll},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{TcpListener, TcpStream},
     sync::mpsc,
 };
 use utils::get_key_and_ssid;
 
+// anything a transfer can run over. a TransferStream outside of tests and fuzzing.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin {}
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for S {}
+
 const CHUNKSIZE: usize = 1_000_000; // 1 MB
-const MAJOR_VERSION: u64 = 9;
+const MAJOR_VERSION: u64 = 10;
+// where the host listens unless the user picks another port. the mobile apps and older versions always use it.
+pub const DEFAULT_PORT: u16 = 3290;
 
 pub trait UI: Clone + Send + 'static {
     fn output(&self, msg: &str);
@@ -35,6 +61,10 @@ pub trait UI: Clone + Send + 'static {
     fn update_progress_bar(&self, percent: u8);
     fn enable_ui(&self);
     fn show_pin(&self, pin: &str);
+    // user's choice goes back through Transfer::ble_device_tx
+    fn show_bluetooth_devices(&self, devices: &[BluetoothDevice]);
+    // everything found so far on the network that isn't the device we're looking for
+    fn show_lan_peers(&self, peers: &[LanPeer]);
 }
 
 #[derive(Clone)]
@@ -65,21 +95,181 @@ impl From<&str> for Peer {
     }
 }
 
+// which end of the Bluetooth LE connection to be. by default the sender advertises and the receiver scans, which is what
+// older versions and the mobile apps expect. users on both ends can pick the other way around instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BluetoothRole {
+    Automatic,
+    Peripheral,
+    Central,
+}
+
+impl From<&str> for BluetoothRole {
+    fn from(role: &str) -> Self {
+        match role {
+            "peripheral" => BluetoothRole::Peripheral,
+            "central" => BluetoothRole::Central,
+            _ => BluetoothRole::Automatic,
+        }
+    }
+}
+
+impl BluetoothRole {
+    pub(crate) fn is_peripheral(self, mode: &Mode) -> bool {
+        match self {
+            BluetoothRole::Automatic => matches!(mode, Mode::Send(_)),
+            BluetoothRole::Peripheral => true,
+            BluetoothRole::Central => false,
+        }
+    }
+}
+
+// what to do about the Bluetooth bond with the peer. only the central unpairs, since unpairing on the peripheral's end
+// would leave the central holding a stale bond that fails the next time it connects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PairingPolicy {
+    // unpair after every transfer, unless the peer is macOS, which has to be paired by hand from its system menu
+    #[default]
+    Forget,
+    // stay paired so that the next transfer with the same device connects faster
+    Keep,
+    // refuse to transfer with a device that wasn't already paired, and stay paired
+    Require,
+}
+
+impl From<&str> for PairingPolicy {
+    fn from(policy: &str) -> Self {
+        match policy {
+            "keep" => PairingPolicy::Keep,
+            "require" => PairingPolicy::Require,
+            _ => PairingPolicy::Forget,
+        }
+    }
+}
+
 pub enum PeerResource {
     WifiClient(String), // used if joining, .0 is ip of gateway/peer/host
     WindowsHotspot(network::WindowsHotspot),
     LinuxHotspot,
+    SimulatedHotspot,
+    Lan(LanRole), // both ends were already on the same network, so there's no hotspot to stop
+}
+
+// for transfers between devices that are already on the same WiFi or Ethernet network, which skip the hotspot.
+// one end listens and advertises itself over mDNS, and the other connects to the address it shows or finds it.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum LanRole {
+    Listen,
+    Connect(String),
+    // looks for the listening end with the same password
+    Discover,
+}
+
+// what start_transfer() ran the transfer over, to be shut down by clean_up_transfer()
+pub enum TransferStream {
+    Tcp(TcpStream),
+    Bluetooth(BluetoothStream),
+}
+
+impl AsyncRead for TransferStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TransferStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            TransferStream::Bluetooth(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TransferStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            TransferStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            TransferStream::Bluetooth(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TransferStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            TransferStream::Bluetooth(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TransferStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            TransferStream::Bluetooth(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// guid is a base-10 representation of the u128 representation of the GUID of the interface, and is only used on Windows.
+// the rest is only filled in on Linux, where None or an empty list means NetworkManager doesn't say.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct WiFiInterface {
+    pub name: String,
+    pub guid: String,
+    // whether it can host a hotspot
+    pub ap: Option<bool>,
+    pub bands: Vec<hotspot::WiFiBand>,
+    // the NetworkManager connection it's on, if any
+    pub connection: Option<String>,
+    pub mac: Option<String>,
+    pub driver: Option<String>,
+}
+
+// address identifies the adapter. on Linux it's formatted like 00:1A:7D:DA:71:13, on Windows it's 12 hex digits.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct BluetoothInterface {
+    pub name: String,
+    pub address: String,
+    pub powered: bool,
+}
+
+// a device advertising the Flying Carpet service, found while scanning as central
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BluetoothDevice {
+    pub name: String,
+    pub address: String,
+    pub rssi: Option<i16>,
+    pub peer_os: Option<String>,
 }
 
-// first String is the interface's name, second String is a base-10 representation of the u128 representation of the GUID of the interface. GUID is only used on Windows.
-#[derive(serde::Deserialize, serde::Serialize)]
-pub struct WiFiInterface(pub String, pub String);
+// a device offering a same-network transfer, found over mDNS
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct LanPeer {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub peer_os: String,
+    pub version: u64,
+    // derived from the password, so it matches ours when the peer is the other end of our transfer
+    pub id: String,
+}
+
+// a device this computer is paired with that has offered the Flying Carpet service
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BluetoothBond {
+    pub name: String,
+    pub address: String,
+}
 
 pub struct Transfer {
     pub cancel_handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
     pub hotspot: Arc<Mutex<Option<PeerResource>>>,
     pub ssid: Arc<Mutex<Option<String>>>,
     pub ble_ui_tx: Mutex<Option<mpsc::Sender<bool>>>, // used by javascript to report user's choice about whether to pair with bluetooth device to windows custom pairing callback.
+    pub ble_device_tx: Mutex<Option<mpsc::Sender<Option<String>>>>, // used by javascript to report the address of the bluetooth device the user chose to connect to, or None if they canceled.
+    pub backend: Backend,
 }
 
 impl Transfer {
@@ -89,15 +279,22 @@ impl Transfer {
             hotspot: Arc::new(Mutex::new(None)),
             ssid: Arc::new(Mutex::new(None)),
             ble_ui_tx: Mutex::new(None),
+            ble_device_tx: Mutex::new(None),
+            backend: Backend::from_env(),
         }
     }
 }
 
-pub async fn start_transfer<T: UI>(
+pub async fn start_transfer<T: UI, B: NetworkBackend + BluetoothBackend>(
+    backend: &B,
     mode: String,
     using_bluetooth: bool,
+    bluetooth_role: BluetoothRole,
+    bluetooth_interface: Option<BluetoothInterface>, // None uses the OS's default adapter
     mut peer: Option<String>,
     mut password: Option<String>,
+    lan: Option<LanRole>, // None starts or joins a hotspot
+    port: u16,            // to listen on if we're the host, 0 for any free port
     interface: WiFiInterface,
     file_list: Option<Vec<String>>,
     receive_dir: Option<String>,
@@ -105,7 +302,8 @@ pub async fn start_transfer<T: UI>(
     hotspot: Arc<Mutex<Option<PeerResource>>>,
     state_ssid: Arc<Mutex<Option<String>>>,
     ble_ui_rx: mpsc::Receiver<bool>,
-) -> Option<TcpStream> {
+    ble_device_rx: mpsc::Receiver<Option<String>>,
+) -> Option<TransferStream> {
     // get files or receive directory
     let mode = if mode == "send" {
         let paths = file_list
@@ -121,17 +319,83 @@ pub async fn start_transfer<T: UI>(
         panic!("Bad mode: {}", mode);
     };
 
+    // unless the peer tells us otherwise, it listens where we would
+    let mut peer_port = if port == 0 { DEFAULT_PORT } else { port };
+    // the version the peer advertised over Bluetooth or mDNS, which it has to speak once we're connected
+    let mut peer_version = None;
+
+    // when the peer may hear which port we got, over Bluetooth or mDNS, listen before telling it, and take any free port
+    // if ours is taken. we go back to DEFAULT_PORT if the Bluetooth peer turns out to be too old to hear it.
+    let mut listener = None;
+    let mut port = port;
+    if using_bluetooth || lan == Some(LanRole::Listen) {
+        match bind(port, true, ui).await {
+            Ok(l) => {
+                port = l.local_addr().map_or(port, |a| a.port());
+                listener = Some(l);
+            }
+            Err(e) => {
+                ui.output(&format!("Error listening for connections: {}", e));
+                return None;
+            }
+        }
+    }
+
     // if bluetooth, make that connection here first
     // for windows and linux, the central/client api can read and write synchronously, and we always know the ssid before starting hotspot, so we can just do that here before connecting to peer?
     // for servers/peripherals, does it matter? callbacks in both cases?
 
+    let mut bluetooth_stream = None;
+    // who hosts, and where the peer said it would start a hotspot, if we worked it out over Bluetooth
+    let mut peer_hosting = None;
     if using_bluetooth {
-        match negotiate_bluetooth(&mode, ble_ui_rx, ui).await {
-            Ok((p, _ssid, pw)) => {
+        let bands = backend.supported_bands(&interface).unwrap_or_else(|e| {
+            println!("Couldn't get WiFi bands: {}", e);
+            vec![]
+        });
+        match backend
+            .with_port(port)
+            .with_bands(bands)
+            .with_role(Role::for_interface(&interface))
+            .negotiate_bluetooth(
+                &mode,
+                bluetooth_role,
+                bluetooth_interface.as_ref(),
+                ble_ui_rx,
+                ble_device_rx,
+                ui,
+            )
+            .await
+        {
+            Ok((p, _ssid, pw, hosting, s)) => {
                 peer = Some(p);
                 if password.is_none() {
                     password = Some(pw);
                 }
+                peer_port = hosting.port;
+                peer_version = hosting.transfer_version;
+                // peers without capabilities, older versions and the mobile apps, can't be told our port and dial
+                // DEFAULT_PORT, so if we host for them we can't have moved to another one
+                if s.is_none()
+                    && !hosting.hosts
+                    && hosting.transfer_version.is_none()
+                    && port != DEFAULT_PORT
+                {
+                    drop(listener.take());
+                    match bind(DEFAULT_PORT, false, ui).await {
+                        Ok(l) => {
+                            port = DEFAULT_PORT;
+                            listener = Some(l);
+                        }
+                        Err(e) => {
+                            println!("Error listening on port {}: {}", DEFAULT_PORT, e);
+                            ui.output(&format!("Port {} is in use by another program. The other device's version of Flying Carpet only connects to that port, so close the other program and try again.", DEFAULT_PORT));
+                            return None;
+                        }
+                    }
+                }
+                peer_hosting = Some(hosting);
+                bluetooth_stream = s;
             }
             Err(e) => {
                 ui.output(&format!("Could not establish Bluetooth connection: {}", e));
@@ -141,91 +405,233 @@ pub async fn start_transfer<T: UI>(
         }
     }
 
-    let peer = Peer::from(
-        peer.expect("Neither UI nor Bluetooth peer present.")
-            .as_str(),
-    );
     let password = password.expect("Missing password in start_transfer().");
 
     let (key, ssid) = get_key_and_ssid(&password);
 
-    {
-        let mut _state_ssid = state_ssid.lock().expect("Couldn't lock state_ssid");
-        *_state_ssid = Some(ssid.clone());
-    }
-
-    // start hotspot or connect to peer's
-    let peer_resource =
-        match network::connect_to_peer(peer, mode.clone(), ssid, password, interface, ui).await {
-            Ok(p) => p,
-            Err(e) => {
-                ui.output(&format!("Error connecting to peer: {}", e));
-                return None;
+    let (mut stream, is_host) = match bluetooth_stream {
+        // the files are small enough that both ends agreed to skip WiFi. the sender acts as host either way.
+        Some(s) => {
+            ui.output("Transferring over Bluetooth");
+            (TransferStream::Bluetooth(s), matches!(mode, Mode::Send(_)))
+        }
+        None => {
+            // no hotspot ssid to clean up when we're using the network we're already on
+            {
+                let mut _state_ssid = state_ssid.lock().expect("Couldn't lock state_ssid");
+                *_state_ssid = lan.is_none().then(|| ssid.clone());
             }
-        };
 
-    tokio::task::yield_now().await;
+            // advertised until the peer connects
+            let mut advertisement = None;
+            let peer_resource = match lan {
+                Some(LanRole::Listen) => {
+                    match discovery::advertise(discovery::transfer_id(&ssid), port) {
+                        Ok(a) => advertisement = Some(a),
+                        Err(e) => ui.output(&format!(
+                            "Couldn't advertise on this network, enter our address on the other device: {}",
+                            e
+                        )),
+                    }
+                    PeerResource::Lan(LanRole::Listen)
+                }
+                Some(LanRole::Discover) => {
+                    let id = discovery::transfer_id(&ssid);
+                    match discovery::find_peer(id, discovery::DISCOVERY_TIMEOUT, ui).await {
+                        Ok(p) => {
+                            ui.output(&format!("Found {} at {}", p.name, p.address));
+                            peer_port = p.port;
+                            peer_version = Some(p.version);
+                            PeerResource::Lan(LanRole::Connect(p.address))
+                        }
+                        Err(e) => {
+                            ui.output(&format!("Error finding peer: {}", e));
+                            return None;
+                        }
+                    }
+                }
+                Some(role) => PeerResource::Lan(role),
+                None => {
+                    let peer = Peer::from(
+                        peer.expect("Neither UI nor Bluetooth peer present.")
+                            .as_str(),
+                    );
+                    // without Bluetooth, there's no way to tell the peer we can't host, so we both follow the table
+                    let hosting = match peer_hosting {
+                        Some(h) => !h.hosts,
+                        None => {
+                            match peer_hosts(Role::for_interface(&interface), None, &peer, &mode) {
+                                Ok(hosts) => !hosts,
+                                // the mobile apps never host for us
+                                Err(e) if matches!(peer, Peer::Linux | Peer::Windows) => {
+                                    ui.output(&format!("{}. Turn on Bluetooth on both devices so that the other one can host instead.", e));
+                                    return None;
+                                }
+                                Err(e) => {
+                                    ui.output(&e.to_string());
+                                    return None;
+                                }
+                            }
+                        }
+                    };
+                    // nothing tells the peer our port here. the mobile apps only use DEFAULT_PORT, and between desktops
+                    // the user enters the same port on both, which a free port picked when we listen can't be.
+                    if peer_hosting.is_none() {
+                        if matches!(peer, Peer::Android | Peer::IOS) {
+                            if port != DEFAULT_PORT {
+                                println!("The mobile apps only use port {}", DEFAULT_PORT);
+                            }
+                            port = DEFAULT_PORT;
+                            peer_port = DEFAULT_PORT;
+                        } else if hosting && port == 0 {
+                            ui.output("Port 0 picks a free port, which the other device can only be told about over Bluetooth or on the same network. Enter the same port on both devices instead.");
+                            return None;
+                        }
+                    }
+                    // the band and channel are the host's choice, so a guest looks where the host said it would be
+                    let joining = peer_hosting
+                        .filter(|_| !hosting)
+                        .map(|h| backend.with_hotspot(h.hotspot));
+                    // start hotspot or connect to peer's
+                    match joining
+                        .as_ref()
+                        .unwrap_or(backend)
+                        .connect_to_peer(hosting, ssid, password, interface, port, ui)
+                        .await
+                    {
+                        Ok(p) => p,
+                        Err(e) => {
+                            ui.output(&format!("Error connecting to peer: {}", e));
+                            return None;
+                        }
+                    }
+                }
+            };
 
-    // start tcp connection
-    let mut stream = match start_tcp(&peer_resource, ui).await {
-        Ok(s) => s,
-        Err(e) => {
-            ui.output(&format!("Error starting TCP connection: {}", e));
-            return None;
+            tokio::task::yield_now().await;
+
+            // start tcp connection
+            let stream = match start_tcp(&peer_resource, listener, port, peer_port, ui).await {
+                Ok(s) => s,
+                Err(e) => {
+                    ui.output(&format!("Error starting TCP connection: {}", e));
+                    return None;
+                }
+            };
+
+            drop(advertisement);
+
+            let is_host = !matches!(
+                peer_resource,
+                PeerResource::WifiClient(_) | PeerResource::Lan(LanRole::Connect(_))
+            );
+
+            // store the hotspot in tauri's state
+            // has to be in its own block here or tokio complains that this "mutex guard" is held across an await... who knows
+            {
+                let mut hotspot_value = hotspot.lock().expect("Couldn't lock hotspot mutex");
+                *hotspot_value = Some(peer_resource);
+            }
+            (TransferStream::Tcp(stream), is_host)
         }
     };
 
-    // make sure the versions are compatible
-    match confirm_version(&peer_resource, &mut stream).await {
-        Ok(()) => (),
+    // confirm versions and modes and derive the session key
+    let mut session = match negotiate_session(
+        MAJOR_VERSION,
+        peer_version,
+        &mode,
+        is_host,
+        &key,
+        &mut stream,
+    )
+    .await
+    {
+        Ok(s) => s,
         Err(e) => {
-            ui.output(&format!("Error confirming version: {}", e));
+            ui.output(&e.to_string());
             return Some(stream);
         }
     };
 
-    // confirm that one end is sending and the other is receiving
-    match confirm_mode(mode.clone(), &peer_resource, &mut stream).await {
-        Ok(()) => (),
-        Err(e) => {
-            ui.output(&format!("Error confirming mode: {}", e));
-            return Some(stream);
-        }
+    if let Err(e) = transfer_files(mode, &mut session, &mut stream, ui).await {
+        ui.output(&e.to_string());
+        return Some(stream);
+    }
+
+    ui.output("=========================");
+    ui.output("Transfer complete");
+    Some(stream)
+}
+
+// everything after the TCP connection is made. takes our version as a parameter so that tests can act as older peers.
+// advertised_version is what the peer said it speaks before we connected, if it said.
+async fn negotiate_session(
+    our_version: u64,
+    advertised_version: Option<u64>,
+    mode: &Mode,
+    is_host: bool,
+    key: &[u8],
+    stream: &mut impl Stream,
+) -> Result<Session, FCError> {
+    // everything exchanged before the session key is derived is hashed and verified once it exists
+    let mut transcript = Transcript::new();
+
+    // make sure the versions are compatible
+    let peer_version = match confirm_version(our_version, is_host, &mut transcript, stream).await {
+        Ok(v) => v,
+        Err(e) => Err(FCError {
+            message: format!("Error confirming version: {}", e),
+        })?,
     };
 
-    // store the hotspot in tauri's state
-    // has to be in its own block here or tokio complains that this "mutex guard" is held across an await... who knows
-    {
-        let mut hotspot_value = hotspot.lock().expect("Couldn't lock hotspot mutex");
-        *hotspot_value = Some(peer_resource);
+    // the transcript is only verified once there's a session key, so it can't catch both versions being rewritten to
+    // force the legacy framing. a peer that advertised a version it then didn't speak was tampered with.
+    if let Some(advertised) = advertised_version {
+        if peer_version.min(our_version) < advertised.min(our_version) {
+            fc_error(&format!(
+                "Peer advertised version {} but connected with version {}. The connection may have been tampered with.",
+                advertised, peer_version
+            ))?
+        }
+    }
+
+    // confirm that one end is sending and the other is receiving
+    if let Err(e) = confirm_mode(mode, is_host, &mut transcript, stream).await {
+        Err(FCError {
+            message: format!("Error confirming mode: {}", e),
+        })?
     }
 
+    // derive a key for this session, unless either end predates session keys
+    if peer_version.min(our_version) >= SESSION_PROTOCOL_VERSION {
+        match Session::establish(key, is_host, mode, &mut transcript, stream).await {
+            Ok(s) => Ok(s),
+            Err(e) => Err(FCError {
+                message: format!("Error establishing session key: {}", e),
+            }),
+        }
+    } else {
+        Ok(Session::legacy(key))
+    }
+}
+
+async fn transfer_files<T: UI>(
+    mode: Mode,
+    session: &mut Session,
+    stream: &mut impl Stream,
+    ui: &T,
+) -> Result<(), FCError> {
     match mode {
         Mode::Send(files) => {
             // tell receiving end how many files we're sending
-            match stream.write_u64(files.len() as u64).await {
-                Ok(()) => (),
-                Err(e) => {
-                    ui.output(&format!("Error writing number of files: {}", e));
-                    return Some(stream);
-                }
+            if let Err(e) = session.write_u64(stream, files.len() as u64).await {
+                Err(FCError {
+                    message: format!("Error writing number of files: {}", e),
+                })?
             }
             // find folder common to all files
-            let mut common_folder = files[0].parent().or(Some(Path::new(""))).unwrap();
-            if files.len() > 1 {
-                for file in &files[1..] {
-                    let current = file.parent().or(Some(Path::new(""))).unwrap();
-                    let current_len = current.components().collect::<Vec<_>>().len();
-                    let common_len = common_folder.components().collect::<Vec<_>>().len();
-                    if current_len < common_len {
-                        common_folder = current;
-                    }
-                    // this puts two files in the same directory in a directory on the other side, which doesn't match other versions' behavior
-                    // else if current_len == common_len {
-                    //     common_folder = current.parent().or(Some(Path::new(""))).unwrap();
-                    // }
-                }
-            }
+            let common_folder = find_common_folder(&files);
             // send files
             for (i, file) in files.iter().enumerate() {
                 let file_name = file
@@ -239,62 +645,79 @@ pub async fn start_transfer<T: UI>(
                     files.len(),
                     file_name
                 ));
-                match sending::send_file(file, common_folder, &key, &mut stream, ui).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        ui.output(&format!("Error sending file: {}", e));
-                        return Some(stream);
-                    }
-                };
+                if let Err(e) =
+                    sending::send_file(file, &common_folder, i as u64, session, stream, ui).await
+                {
+                    Err(FCError {
+                        message: format!("Error sending file: {}", e),
+                    })?
+                }
             }
         }
         Mode::Receive(folder) => {
             // find out how many files we're receiving
-            let num_files = match stream.read_u64().await {
+            let num_files = match session.read_u64(stream).await {
                 Ok(num) => num,
-                Err(e) => {
-                    ui.output(&format!("Error reading number of files: {}", e));
-                    return Some(stream);
-                }
+                Err(e) => Err(FCError {
+                    message: format!("Error reading number of files: {}", e),
+                })?,
             };
             // receive files
             for i in 0..num_files {
                 ui.output("=========================");
                 ui.output(&format!("Receiving file {} of {}.", i + 1, num_files,));
                 let last_file = i == num_files - 1;
-                match receiving::receive_file(&folder, &key, &mut stream, ui, last_file).await {
-                    Ok(_) => (),
-                    Err(e) => {
-                        ui.output(&format!("Error receiving file: {}", e));
-                        return Some(stream);
-                    }
+                if let Err(e) =
+                    receiving::receive_file(&folder, i, session, stream, ui, last_file).await
+                {
+                    Err(FCError {
+                        message: format!("Error receiving file: {}", e),
+                    })?
                 }
             }
         }
     }
+    Ok(())
+}
 
-    ui.output("=========================");
-    ui.output("Transfer complete");
-    Some(stream)
+// deepest folder containing all of the files, so that the receiving end recreates the directory structure below it.
+// two files in the same directory are received directly into the destination folder, matching other versions' behavior.
+fn find_common_folder(files: &[PathBuf]) -> PathBuf {
+    let mut parents = files
+        .iter()
+        .map(|file| file.parent().unwrap_or(Path::new("")));
+    let mut common_folder = match parents.next() {
+        Some(parent) => parent.to_path_buf(),
+        None => return PathBuf::new(),
+    };
+    for parent in parents {
+        while !parent.starts_with(&common_folder) {
+            if !common_folder.pop() {
+                break;
+            }
+        }
+    }
+    common_folder
 }
 
-pub async fn clean_up_transfer<T: UI>(
-    stream: Option<TcpStream>,
+pub async fn clean_up_transfer<T: UI, N: NetworkBackend>(
+    backend: &N,
+    stream: Option<TransferStream>,
     hotspot: Arc<Mutex<Option<PeerResource>>>,
     ssid: Arc<Mutex<Option<String>>>,
     ui: &T,
 ) {
-    // shut down tcp stream
-    match stream {
-        Some(mut s) => {
-            if s.shutdown().await.is_err() {
-                ui.output("Failed to shut down TCP stream.")
-            };
+    // shut down tcp or bluetooth stream
+    if let Some(mut s) = stream {
+        if s.shutdown().await.is_err() {
+            match s {
+                TransferStream::Tcp(_) => ui.output("Failed to shut down TCP stream."),
+                TransferStream::Bluetooth(_) => ui.output("Failed to shut down Bluetooth stream."),
+            }
         }
-        None => (),
     }
     // shut down hotspot
-    shut_down_hotspot(&hotspot, &ssid, ui);
+    shut_down_hotspot(backend, &hotspot, &ssid, ui);
     // make sure hotspot gets dropped
     let mut hotspot_value = hotspot.lock().expect("Couldn't lock hotspot mutex");
     *hotspot_value = None;
@@ -302,7 +725,8 @@ pub async fn clean_up_transfer<T: UI>(
     ui.enable_ui();
 }
 
-fn shut_down_hotspot<T: UI>(
+fn shut_down_hotspot<T: UI, N: NetworkBackend>(
+    backend: &N,
     hotspot: &Arc<Mutex<Option<PeerResource>>>,
     ssid: &Arc<Mutex<Option<String>>>,
     _ui: &T,
@@ -310,23 +734,58 @@ fn shut_down_hotspot<T: UI>(
     let peer_resource = hotspot.lock().expect("Couldn't lock hotspot mutex.");
     let peer_resource = peer_resource.as_ref();
     let ssid = ssid.lock().expect("Couldn't lock SSID mutex.");
-    match network::stop_hotspot(peer_resource, ssid.as_deref()) {
+    match backend.stop_hotspot(peer_resource, ssid.as_deref()) {
         Err(e) => println!("{}", e),
         Ok(msg) => println!("{}", msg),
     };
 }
 
-async fn start_tcp<T: UI>(peer_resource: &PeerResource, ui: &T) -> Result<TcpStream, FCError> {
+// listener is already bound to port if the peer was told about it
+async fn start_tcp<T: UI>(
+    peer_resource: &PeerResource,
+    listener: Option<TcpListener>,
+    port: u16,
+    peer_port: u16,
+    ui: &T,
+) -> Result<TcpStream, FCError> {
     let stream;
     match peer_resource {
-        PeerResource::WifiClient(gateway) => {
-            let addr = format!("{}:3290", gateway).parse::<SocketAddr>()?;
-            stream = TcpStream::connect(addr).await?;
+        PeerResource::WifiClient(gateway) | PeerResource::Lan(LanRole::Connect(gateway)) => {
+            let addr = utils::peer_socket_addr(gateway, peer_port)?;
+            // the host may not be listening yet, which mostly happens when both ends are simulated on one machine
+            let mut attempts = 0;
+            stream = loop {
+                match TcpStream::connect(addr).await {
+                    Ok(s) => break s,
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused && attempts < 25 => {
+                        attempts += 1;
+                        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+                    }
+                    Err(e) => Err(e)?,
+                }
+            };
         }
         _ => {
-            // linux or windows hotspot
-            let addr = "0.0.0.0:3290".parse::<SocketAddr>()?;
-            let listener = TcpListener::bind(&addr).await?;
+            // linux or windows hotspot, or listening on the network we're already on
+            let listener = match listener {
+                Some(l) => l,
+                None => bind(port, false, ui).await?,
+            };
+            let port = listener.local_addr()?.port();
+            if let PeerResource::Lan(_) = peer_resource {
+                match utils::lan_address() {
+                    Some(ip) if port == DEFAULT_PORT => {
+                        ui.output(&format!("Enter {} on the other device", ip))
+                    }
+                    Some(ip) => ui.output(&format!(
+                        "Enter {} on the other device",
+                        SocketAddr::new(ip, port)
+                    )),
+                    None => ui.output("Couldn't find our address on this network"),
+                }
+            } else if port != DEFAULT_PORT {
+                ui.output(&format!("Listening on port {}", port));
+            }
             ui.output("Waiting for connection...");
             let (_stream, _socket_addr) = listener.accept().await?;
             ui.output("Connection accepted");
@@ -336,88 +795,136 @@ async fn start_tcp<T: UI>(peer_resource: &PeerResource, ui: &T) -> Result<TcpStr
     Ok(stream)
 }
 
+// with fallback, a taken port is swapped for any free one, since the peer will be told which we got. otherwise another
+// transfer that just ended may still be holding it, so give it a few seconds before blaming another program.
+async fn bind<T: UI>(port: u16, fallback: bool, ui: &T) -> Result<TcpListener, FCError> {
+    let mut attempts = 0;
+    loop {
+        match listen(port) {
+            Ok(l) => return Ok(l),
+            Err(e) if e.kind() == ErrorKind::AddrInUse && fallback => {
+                println!("Port {} is in use, listening on any free port", port);
+                return Ok(listen(0)?);
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse && attempts < 10 => {
+                if attempts == 0 {
+                    ui.output(&format!("Port {} is in use, waiting for it...", port));
+                }
+                attempts += 1;
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => Err(FCError {
+                message: format!(
+                    "Port {} is in use by another program. Close it, or choose another port on both devices.",
+                    port
+                ),
+            })?,
+            Err(e) => Err(e)?,
+        }
+    }
+}
+
+// both IPv4 and IPv6 where the system has IPv6, so a peer can reach us at whichever address it has
+fn listen(port: u16) -> std::io::Result<TcpListener> {
+    let socket = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("IPv6 unavailable, listening on IPv4 only: {}", e);
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            let listener = std::net::TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            return TcpListener::from_std(listener);
+        }
+    };
+    // windows defaults to IPv6 only
+    socket.set_only_v6(false)?;
+    // as tokio's bind does, so a restarted transfer doesn't wait out TIME_WAIT. on windows this would let others steal the port.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
 async fn confirm_mode(
-    mode: Mode,
-    peer_resource: &PeerResource,
-    stream: &mut TcpStream,
+    mode: &Mode,
+    is_host: bool,
+    transcript: &mut Transcript,
+    stream: &mut impl Stream,
 ) -> Result<(), FCError> {
     let our_mode = match mode {
         Mode::Send(..) => 1,
         Mode::Receive(..) => 0,
     };
 
-    match peer_resource {
-        PeerResource::WifiClient(..) => {
-            // tell host what mode we selected and wait for confirmation that they don't match
-            match mode {
-                Mode::Send(_) => stream.write_u64(1).await?,
-                Mode::Receive(_) => stream.write_u64(0).await?,
-            };
-            // wait to ensure host responds that mode selection was correct
-            if stream.read_u64().await? != 1 {
-                let message = format!(
-                    "Both ends of the transfer selected {}",
-                    if our_mode == 0 { "receive" } else { "send" }
-                );
-                fc_error(&message)?
-            }
+    if is_host {
+        // wait for guest to say what mode they selected, compare to our own, and report back
+        let peer_mode = transcript.read_u64(stream).await?;
+        if peer_mode == our_mode {
+            let msg = format!(
+                "Both ends of the transfer selected {}",
+                if our_mode == 0 { "receive" } else { "send" }
+            );
+            // write failure to guest
+            transcript.write_u64(stream, 0).await?;
+            fc_error(&msg)?
+        } else {
+            // write success to guest
+            transcript.write_u64(stream, 1).await?;
         }
-        PeerResource::WindowsHotspot(_) | PeerResource::LinuxHotspot => {
-            // wait for guest to say what mode they selected, compare to our own, and report back
-            let peer_mode = stream.read_u64().await?;
-            if peer_mode == our_mode {
-                let msg = format!(
-                    "Both ends of the transfer selected {}",
-                    if our_mode == 0 { "receive" } else { "send" }
-                );
-                // write failure to guest
-                stream.write_u64(0).await?;
-                fc_error(&msg)?
-            } else {
-                // write success to guest
-                stream.write_u64(1).await?;
-            }
+    } else {
+        // tell host what mode we selected and wait for confirmation that they don't match
+        transcript.write_u64(stream, our_mode).await?;
+        // wait to ensure host responds that mode selection was correct
+        if transcript.read_u64(stream).await? != 1 {
+            let message = format!(
+                "Both ends of the transfer selected {}",
+                if our_mode == 0 { "receive" } else { "send" }
+            );
+            fc_error(&message)?
         }
     }
     Ok(())
 }
 
+// returns the peer's version so that we can speak the older protocol if necessary
 async fn confirm_version(
-    peer_resource: &PeerResource,
-    stream: &mut TcpStream,
-) -> Result<(), FCError> {
+    our_version: u64,
+    is_host: bool,
+    transcript: &mut Transcript,
+    stream: &mut impl Stream,
+) -> Result<u64, FCError> {
     // only really have to worry about version 6 as that's the only one online and in app store. it will do mode confirmation first,
     // and obey hotspot host/guest rule, and it will write 0 or 1 for mode, so we shouldn't deadlock with both ends waiting.
-    let peer_version = match peer_resource {
-        PeerResource::WifiClient(..) => {
-            // send version to hotspot host
-            stream.write_u64(MAJOR_VERSION).await?;
-            // receive version of host
-            stream.read_u64().await?
-        }
-        _ => {
-            // wait for guest to say what version they're using, then send our version
-            let _peer_version = stream.read_u64().await?;
-            stream.write_u64(MAJOR_VERSION).await?;
-            _peer_version
-        }
+    let peer_version = if is_host {
+        // wait for guest to say what version they're using, then send our version
+        let _peer_version = transcript.read_u64(stream).await?;
+        transcript.write_u64(stream, our_version).await?;
+        _peer_version
+    } else {
+        // send version to hotspot host
+        transcript.write_u64(stream, our_version).await?;
+        // receive version of host
+        transcript.read_u64(stream).await?
     };
 
-    if peer_version < MAJOR_VERSION {
+    if peer_version < our_version {
         // we make decision
         if utils::is_compatible(peer_version) {
-            stream.write_u64(1).await?; // report that versions are compatible
+            transcript.write_u64(stream, 1).await?; // report that versions are compatible
         } else {
-            stream.write_u64(0).await?;
+            transcript.write_u64(stream, 0).await?;
             fc_error(&format!("Peer's version {} not compatible, please update Flying Carpet to the latest version on both devices.", peer_version))?;
         }
-    } else if peer_version > MAJOR_VERSION {
+    } else if peer_version > our_version {
         // peer makes decision
-        if stream.read_u64().await? == 0 {
+        if transcript.read_u64(stream).await? == 0 {
             fc_error(&format!("Peer's version {} not compatible, please update Flying Carpet to the latest version on both devices.", peer_version))?;
         }
     } // otherwise, versions match, implicitly compatible
-    Ok(())
+    Ok(peer_version)
 }
 
 // TODO:
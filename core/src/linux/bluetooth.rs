@@ -1,16 +1,27 @@
 mod central;
 mod peripheral;
 
-use bluer::{Adapter, Address, Session};
+use bluer::{Adapter, Address, Session, Uuid};
 use central::{exchange_info, find_characteristics};
-use std::{mem::discriminant, time::Duration};
+use peripheral::PeripheralState;
+use std::time::Duration;
 use tokio::{spawn, sync::mpsc, time::sleep};
 
 use crate::{
-    error::{fc_error, FCError},
-    network::is_hosting,
-    utils::{generate_password, get_key_and_ssid, BluetoothMessage},
-    Mode, Peer, UI,
+    backend::Hardware,
+    bluetooth_stream::BluetoothStream,
+    crypto::BluetoothKeyPair,
+    diagnostics::Check,
+    error::FCError,
+    negotiation::{
+        check_key_exchange, is_ble_transfer, BluetoothSession, Capabilities, Exchange,
+        NegotiationState, PeerHosting,
+    },
+    utils::{
+        confirm_pin, generate_password, get_key_and_ssid, peer_description, unbonded_error,
+        with_timeout, PASSWORD_LABEL, SSID_LABEL,
+    },
+    BluetoothBond, BluetoothInterface, BluetoothRole, Mode, PairingPolicy, Peer, UI,
 };
 
 impl From<bluer::Error> for FCError {
@@ -26,7 +37,10 @@ const SERVICE_UUID: &str = "A70BF3CA-F708-4314-8A0E-5E37C259BE5C";
 pub(crate) const OS_CHARACTERISTIC_UUID: &str = "BEE14848-CC55-4FDE-8E9D-2E0F9EC45946";
 pub(crate) const SSID_CHARACTERISTIC_UUID: &str = "0D820768-A329-4ED4-8F53-BDF364EDAC75";
 pub(crate) const PASSWORD_CHARACTERISTIC_UUID: &str = "E1FA8F66-CF88-4572-9527-D5125A2E0762";
-// const NO_SSID: &str = "NONE";
+pub(crate) const CAPABILITIES_CHARACTERISTIC_UUID: &str = "8C6F5D2E-4B1A-4E3F-9D7C-2A5B8E1F6C34";
+pub(crate) const PERIPHERAL_KEY_CHARACTERISTIC_UUID: &str = "3F9A2C71-5E84-4B0D-A6C3-91D7E2F45B18";
+pub(crate) const CENTRAL_KEY_CHARACTERISTIC_UUID: &str = "C4E81B36-7A2F-4D95-8B1E-6F03A9D2C757";
+pub(crate) const DATA_CHARACTERISTIC_UUID: &str = "5B7E2A94-1C3D-4F68-9E0A-B2D4C6F81A3E";
 
 pub async fn check_support() -> Result<(), FCError> {
     let session = Session::new().await?;
@@ -36,36 +50,193 @@ pub async fn check_support() -> Result<(), FCError> {
     Ok(())
 }
 
-pub async fn get_adapter() -> Result<Adapter, FCError> {
+// BlueZ, the default adapter, and whether it can advertise, which the peripheral role needs. unlike check_support(),
+// leaves the adapter off if it is.
+pub async fn diagnose() -> Vec<Check> {
+    let mut checks = vec![];
+    let session = match Session::new().await {
+        Ok(s) => {
+            checks.push(Check::ok("BlueZ", "Running."));
+            s
+        }
+        Err(e) => {
+            checks.push(Check::failed(
+                "BlueZ",
+                &format!("Couldn't reach bluetoothd: {}", e),
+            ));
+            return checks;
+        }
+    };
+    let adapter = match session.default_adapter().await {
+        Ok(a) => a,
+        Err(e) => {
+            checks.push(Check::failed(
+                "Bluetooth adapter",
+                &format!("No adapter found: {}", e),
+            ));
+            return checks;
+        }
+    };
+    let name = format!("Bluetooth adapter {}", adapter.name());
+    match adapter.is_powered().await {
+        Ok(true) => checks.push(Check::ok(&name, "Powered on.")),
+        Ok(false) => checks.push(Check::warning(
+            &name,
+            "Powered off. Flying Carpet turns it on when a transfer starts.",
+        )),
+        Err(e) => checks.push(Check::failed(&name, &e.to_string())),
+    }
+    // only present when the adapter supports LE advertising
+    match adapter.supported_advertising_instances().await {
+        Ok(0) => checks.push(Check::warning(
+            "LE advertising",
+            "Supported, but other programs are using every advertising slot, so this device can only scan.",
+        )),
+        Ok(_) => checks.push(Check::ok("LE advertising", "Supported.")),
+        Err(_) => checks.push(Check::failed(
+            "LE advertising",
+            "Not supported, so this device can only scan for the other device.",
+        )),
+    }
+    checks
+}
+
+pub async fn get_bluetooth_adapters() -> Result<Vec<BluetoothInterface>, FCError> {
     let session = Session::new().await?;
-    let adapter = session.default_adapter().await?;
+    let mut adapters = vec![];
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        adapters.push(BluetoothInterface {
+            name: adapter.alias().await?,
+            address: adapter.address().await?.to_string(),
+            powered: adapter.is_powered().await?,
+        });
+    }
+    Ok(adapters)
+}
+
+// None gets the default adapter
+pub async fn get_adapter(interface: Option<&BluetoothInterface>) -> Result<Adapter, FCError> {
+    let adapter = find_adapter(interface).await?;
     adapter.set_powered(true).await?;
     println!("Bluetooth is supported");
     Ok(adapter)
 }
 
+// the adapter as it is, without powering it on
+async fn find_adapter(interface: Option<&BluetoothInterface>) -> Result<Adapter, FCError> {
+    let session = Session::new().await?;
+    let adapter = match interface {
+        Some(interface) => {
+            let mut chosen = None;
+            for name in session.adapter_names().await? {
+                let adapter = session.adapter(&name)?;
+                if adapter.address().await?.to_string() == interface.address {
+                    chosen = Some(adapter);
+                    break;
+                }
+            }
+            match chosen {
+                Some(adapter) => adapter,
+                None => Err(FCError {
+                    message: format!(
+                        "Could not find Bluetooth adapter {} ({})",
+                        interface.name, interface.address
+                    ),
+                })?,
+            }
+        }
+        None => session.default_adapter().await?,
+    };
+    Ok(adapter)
+}
+
+// devices we're paired with that have offered the Flying Carpet service
+pub async fn list_bonds(
+    interface: Option<&BluetoothInterface>,
+) -> Result<Vec<BluetoothBond>, FCError> {
+    let adapter = find_adapter(interface).await?;
+    let mut bonds = vec![];
+    for address in paired_addresses(&adapter).await? {
+        if is_bond(&adapter, address).await? {
+            bonds.push(BluetoothBond {
+                name: adapter.device(address)?.alias().await?,
+                address: address.to_string(),
+            });
+        }
+    }
+    Ok(bonds)
+}
+
+// only unpairs devices that list_bonds() would show
+pub async fn remove_bond(
+    interface: Option<&BluetoothInterface>,
+    address: &str,
+) -> Result<(), FCError> {
+    let adapter = find_adapter(interface).await?;
+    let address: Address = match address.parse() {
+        Ok(a) => a,
+        Err(_) => Err(FCError {
+            message: format!("Invalid Bluetooth address: {}", address),
+        })?,
+    };
+    if !paired_addresses(&adapter).await?.contains(&address) || !is_bond(&adapter, address).await? {
+        Err(FCError {
+            message: format!("{} isn't a Flying Carpet device we're paired with", address),
+        })?;
+    }
+    adapter.remove_device(address).await?;
+    println!("Removed device {}", address);
+    Ok(())
+}
+
+async fn is_bond(adapter: &Adapter, address: Address) -> Result<bool, FCError> {
+    let service_uuid = Uuid::parse_str(SERVICE_UUID).unwrap();
+    Ok(adapter
+        .device(address)?
+        .uuids()
+        .await?
+        .is_some_and(|uuids| uuids.contains(&service_uuid)))
+}
+
+async fn paired_addresses(adapter: &Adapter) -> Result<Vec<Address>, FCError> {
+    let mut paired = vec![];
+    for address in adapter.device_addresses().await? {
+        if adapter.device(address)?.is_paired().await? {
+            paired.push(address);
+        }
+    }
+    Ok(paired)
+}
+
 pub async fn negotiate_bluetooth<T: UI>(
     mode: &Mode,
-    _ble_ui_rx: mpsc::Receiver<bool>, // only used on windows
+    role: BluetoothRole,
+    hardware: &Hardware,
+    interface: Option<&BluetoothInterface>,
+    mut ble_ui_rx: mpsc::Receiver<bool>,
+    ble_device_rx: mpsc::Receiver<Option<String>>,
     ui: &T,
-) -> Result<(String, String, String), FCError> {
+) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+    let timeouts = &hardware.timeouts;
+    let pairing = hardware.pairing;
     // TODO: dedup with check_support(), but can't return adapter from it because windows doesn't, unless we stub which is annoying to pass it back into this.
-    let session = Session::new().await?;
-    let adapter = session.default_adapter().await?;
-    adapter.set_powered(true).await?;
+    let adapter = get_adapter(interface).await?;
+    let capabilities = Capabilities::ours(adapter.alias().await?)
+        .with_ble_limit(hardware.ble_transfer_limit, mode)
+        .with_port(hardware.port)
+        .with_hotspot(hardware.hotspot, &hardware.bands)
+        .with_role(hardware.role);
 
     struct ConnectedPeripheral {
         adapter: Adapter,
         address: Address,
-        is_macos: bool,
+        forget: bool,
     }
 
     impl Drop for ConnectedPeripheral {
         fn drop(&mut self) {
-            // don't want to unpair from the peripheral if it's macOS. macOS won't allow linux to enumerate services if linux as central initiates the connection,
-            // so users must pair from the macOS system menu manually if they want to send to linux with bluetooth. if we unpair here, they'd have to manually pair
-            // for each transfer.
-            if self.is_macos {
+            if !self.forget {
                 return;
             }
             let adapter = self.adapter.clone();
@@ -82,148 +253,247 @@ pub async fn negotiate_bluetooth<T: UI>(
         }
     }
 
-    if let Mode::Send(_) = mode {
+    if role.is_peripheral(mode) {
         // acting as peripheral
-        let (tx, mut rx) = mpsc::channel(1);
-        let mut password = generate_password();
-        let (_, mut ssid) = get_key_and_ssid(&password);
-        let (app_handle, adv_handle) = peripheral::advertise(tx, &ssid, &password).await?;
-        ui.output("Started Bluetooth advertisement, waiting for receiving device...");
-        let peer_os =
-            match process_bluetooth_message(BluetoothMessage::PeerOS("".to_string()), &mut rx, ui)
-                .await?
-            {
-                BluetoothMessage::PeerOS(os) => os,
-                other => Err(FCError {
-                    message: format!(
-                        "Received unexpected BluetoothMessage when waiting for peer OS: {:?}",
-                        other
-                    ),
-                })?,
-            };
+        // advertise() has already started advertising by the time it returns
+        let session = BluetoothSession::new(NegotiationState::Advertising);
+        let key_pair = BluetoothKeyPair::generate();
+        let state = PeripheralState::default();
+        // the central pairs as soon as it touches our characteristics, so note who was paired beforehand
+        let bonded = match pairing {
+            PairingPolicy::Require => paired_addresses(&adapter).await?,
+            _ => vec![],
+        };
+        let (app_handle, adv_handle, data_control) = peripheral::advertise(
+            &adapter,
+            session.clone(),
+            mode,
+            &capabilities,
+            key_pair.public_hex(),
+            state.clone(),
+        )
+        .await?;
+        ui.output(&format!(
+            "Started Bluetooth advertisement, waiting for {}...",
+            peer_description(mode)
+        ));
+        let peer_os = session
+            .wait_for_exchange(
+                |_| true,
+                "the peer to connect and write its OS",
+                timeouts.scan,
+                ui,
+            )
+            .await?
+            .peer_os;
 
         println!("Removing advertisement");
         drop(adv_handle);
 
-        if is_hosting(&Peer::from(peer_os.as_str()), mode) {
+        if pairing == PairingPolicy::Require {
+            let address = state
+                .peer_address
+                .lock()
+                .expect("Couldn't lock peer address")
+                .take();
+            match address {
+                Some(a) if bonded.contains(&a) => (),
+                Some(a) => {
+                    // otherwise the bond it just made would let it through next time
+                    let name = adapter.device(a)?.alias().await?;
+                    adapter.remove_device(a).await?;
+                    Err(unbonded_error(&name))?
+                }
+                None => Err(unbonded_error("The peer"))?,
+            }
+        }
+
+        // the central writes its capabilities before its OS, unless it's too old to have them
+        let peer_capabilities = state
+            .peer_capabilities
+            .lock()
+            .expect("Couldn't lock peer capabilities")
+            .take();
+        if let Some(c) = &peer_capabilities {
+            c.check(ui)?;
+        }
+        let ble_transfer = peer_capabilities
+            .as_ref()
+            .is_some_and(|c| is_ble_transfer(&capabilities, c));
+        let peer_hosting = PeerHosting::negotiate(
+            &capabilities,
+            peer_capabilities.as_ref(),
+            &Peer::from(peer_os.as_str()),
+            mode,
+        )?;
+
+        // and its public key, unless it's too old to encrypt the credentials
+        let peer_key = state
+            .peer_key
+            .lock()
+            .expect("Couldn't lock peer key")
+            .take();
+        check_key_exchange(peer_capabilities.as_ref(), peer_key.is_some())?;
+        let cipher = match peer_key {
+            Some(k) => {
+                let cipher = key_pair.agree(&k, false)?;
+                confirm_pin(&cipher.pin, &mut ble_ui_rx, timeouts.connect, ui).await?;
+                Some(cipher)
+            }
+            None => None,
+        };
+        // the peer may still be confirming the PIN before its first read or write
+        let first_step = match cipher {
+            Some(_) => timeouts.connect,
+            None => timeouts.exchange_step,
+        };
+
+        let (ssid, password) = if !peer_hosting.hosts {
+            let password = generate_password();
+            let (_, ssid) = get_key_and_ssid(&password);
+            let (served_ssid, served_password) = match &cipher {
+                Some(c) => (
+                    c.encrypt(SSID_LABEL, &ssid)?,
+                    c.encrypt(PASSWORD_LABEL, &password)?,
+                ),
+                None => (ssid.clone(), password.clone()),
+            };
+            *state.ssid.lock().expect("Couldn't lock SSID") = Some(served_ssid);
+            *state.password.lock().expect("Couldn't lock password") = Some(served_password);
             // wait for peer to read our ssid and password
-            process_bluetooth_message(BluetoothMessage::PeerReadSsid, &mut rx, ui).await?;
+            session
+                .wait_for_exchange(|e| e.read_ssid, "the peer to read our SSID", first_step, ui)
+                .await?;
             println!("Peer read SSID");
-            process_bluetooth_message(BluetoothMessage::PeerReadPassword, &mut rx, ui).await?;
+            session
+                .wait_for_exchange(
+                    |e| e.read_password,
+                    "the peer to read our password",
+                    timeouts.exchange_step,
+                    ui,
+                )
+                .await?;
             println!("Peer read password");
+            (ssid, password)
         } else {
             // wait for peer to write its ssid and password
-            ssid = match process_bluetooth_message(
-                BluetoothMessage::SSID("".to_string()),
-                &mut rx,
-                ui,
-            )
-            .await?
-            {
-                BluetoothMessage::SSID(s) => s,
-                other => Err(FCError {
-                    message: format!(
-                        "Received unexpected BluetoothMessage when waiting for peer OS: {:?}",
-                        other
-                    ),
-                })?,
-            };
-            println!("Peer's SSID: {}", ssid);
-            password = match process_bluetooth_message(
-                BluetoothMessage::Password("".to_string()),
-                &mut rx,
-                ui,
-            )
-            .await?
-            {
-                BluetoothMessage::Password(p) => p,
-                other => Err(FCError {
-                    message: format!(
-                        "Received unexpected BluetoothMessage when waiting for peer OS: {:?}",
-                        other
-                    ),
-                })?,
-            };
-            println!("Peer's password: {}", password);
-        }
+            session
+                .wait_for_exchange(
+                    |e| e.ssid.is_some(),
+                    "the peer to write its SSID",
+                    first_step,
+                    ui,
+                )
+                .await?;
+            let exchange = session
+                .wait_for_exchange(
+                    Exchange::finished,
+                    "the peer to write its password",
+                    timeouts.exchange_step,
+                    ui,
+                )
+                .await?;
+            let ssid = exchange.ssid.expect("Peer's SSID missing");
+            let password = exchange.password.expect("Peer's password missing");
+            match &cipher {
+                Some(c) => (
+                    c.decrypt(SSID_LABEL, &ssid)?,
+                    c.decrypt(PASSWORD_LABEL, &password)?,
+                ),
+                None => (ssid, password),
+            }
+        };
+        println!("Peer's SSID: {}", ssid);
 
-        sleep(Duration::from_secs(1)).await;
-        println!("Removing GATT service");
-        drop(app_handle);
+        if peer_capabilities.is_some() {
+            session
+                .wait_for(
+                    |s| matches!(s, NegotiationState::Done(_)),
+                    "the peer to finish the Bluetooth exchange",
+                    timeouts.exchange_step,
+                    ui,
+                )
+                .await?;
+        } else {
+            // older centrals don't say when they're done, so give them a chance to finish the last read or write
+            sleep(Duration::from_secs(1)).await;
+        }
+        if !ble_transfer {
+            println!("Removing GATT service");
+            drop(app_handle);
+            return Ok((peer_os, ssid, password, peer_hosting, None));
+        }
 
-        Ok((peer_os, ssid, password))
+        // keep serving until the transfer is done
+        let (reader, writer) = with_timeout(
+            timeouts.connect,
+            "waiting for the peer to open the data characteristic",
+            peripheral::accept_data(data_control),
+        )
+        .await?;
+        let stream = BluetoothStream::new(reader, writer, Box::new(app_handle));
+        Ok((peer_os, ssid, password, peer_hosting, Some(stream)))
     } else {
         // acting as central
-        ui.output("Started Bluetooth scan, waiting for sending device...");
-        let device = central::scan(&adapter).await?;
-        ui.output("Found device");
+        ui.output(&format!(
+            "Started Bluetooth scan, waiting for {}...",
+            peer_description(mode)
+        ));
+        let device = central::scan(&adapter, mode, timeouts, ble_device_rx, ui).await?;
+        if pairing == PairingPolicy::Require && !device.is_paired().await? {
+            Err(unbonded_error(&device.alias().await?))?
+        }
+        ui.output("Connecting to chosen device...");
 
-        let mut connected_peripheral = ConnectedPeripheral{adapter, address: device.address(), is_macos: false};
+        let mut connected_peripheral = ConnectedPeripheral {
+            adapter,
+            address: device.address(),
+            forget: pairing == PairingPolicy::Forget,
+        };
 
-        let characteristics = match find_characteristics(&device).await {
+        let characteristics = match find_characteristics(&device, timeouts).await {
             Ok(c) => c,
             Err(e) => {
                 println!("    Device failed: {}", e);
                 Err(e)?
             }
         };
-        let info = match exchange_info(characteristics, mode).await {
+        let (peer_os, ssid, password, peer_hosting, ble_transfer) = match exchange_info(
+            &characteristics,
+            mode,
+            &capabilities,
+            timeouts,
+            &mut ble_ui_rx,
+            ui,
+        )
+        .await
+        {
             Ok(i) => i,
-            Err(e) => {
-                Err(e)?
-            }
+            Err(e) => Err(e)?,
         };
-        connected_peripheral.is_macos = info.0 == "mac".to_string();
-        Ok(info)
-    }
-}
-
-// TODO: make linux-appropriate
-pub async fn process_bluetooth_message<T: UI>(
-    looking_for: BluetoothMessage,
-    rx: &mut mpsc::Receiver<BluetoothMessage>,
-    ui: &T,
-) -> Result<BluetoothMessage, FCError> {
-    loop {
-        println!("waiting for bluetooth message...");
-        let msg = rx
-            .recv()
-            .await
-            .expect("Bluetooth message channel unexpectedly closed.");
-        println!("received {:?}", msg);
-        match &msg {
-            BluetoothMessage::PairApproved => ui.output("Pairing approved."),
-            BluetoothMessage::PairSuccess => {
-                // can use this to represent AlreadyPaired on windows? don't need to emit pin, just need to proceed.
-                // and nothing will be blocked in central because the pairing_handler won't be called.
-                ui.output("Successfully paired");
-            }
-            BluetoothMessage::PairFailure => fc_error("Pairing failed.")?,
-            BluetoothMessage::AlreadyPaired => {
-                ui.output("Already BLE paired with Bluetooth device");
-                if looking_for == BluetoothMessage::PairSuccess {
-                    return Ok(msg);
-                }
-            }
-            BluetoothMessage::UserCanceled => fc_error("User canceled.")?,
-            BluetoothMessage::StartedAdvertising => {
-                ui.output("Started advertising Bluetooth service")
-            }
-            BluetoothMessage::PeerOS(os) => ui.output(&format!("Peer's OS is {}", os)),
-            BluetoothMessage::SSID(ssid) => ui.output(&format!("Peer's SSID is {}", ssid)),
-            BluetoothMessage::Password(password) => {
-                ui.output(&format!("Peer's password is {}", password))
-            }
-            BluetoothMessage::PeerReadSsid => ui.output("Peer read our SSID"),
-            BluetoothMessage::PeerReadPassword => ui.output("Peer read our password"),
-            BluetoothMessage::OtherError(s) => fc_error(s.as_str())?, // ui.output(&format!("Bluetooth peering result: {}", s)),
-            other_message => println!(
-                "Other Bluetooth message not used on Linux: {:?}",
-                other_message
-            ),
-        };
-        if discriminant(&msg) == discriminant(&looking_for) {
-            return Ok(msg);
+        // don't want to unpair from the peripheral if it's macOS. macOS won't allow linux to enumerate services if linux as central initiates the connection,
+        // so users must pair from the macOS system menu manually if they want to send to linux with bluetooth. if we unpair here, they'd have to manually pair
+        // for each transfer.
+        connected_peripheral.forget &= peer_os != "mac";
+        if !ble_transfer {
+            return Ok((peer_os, ssid, password, peer_hosting, None));
         }
+
+        // stay connected until the transfer is done
+        let data_char = &characteristics[DATA_CHARACTERISTIC_UUID];
+        let reader = with_timeout(
+            timeouts.exchange_step,
+            "subscribing to the data characteristic",
+            data_char.notify_io(),
+        )
+        .await?;
+        let writer = with_timeout(
+            timeouts.exchange_step,
+            "opening the data characteristic",
+            data_char.write_io(),
+        )
+        .await?;
+        let stream = BluetoothStream::new(reader, writer, Box::new(connected_peripheral));
+        Ok((peer_os, ssid, password, peer_hosting, Some(stream)))
     }
 }
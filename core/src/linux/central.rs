@@ -3,7 +3,7 @@ use bluer::{
         remote::{Characteristic, CharacteristicWriteRequest},
         WriteOp,
     },
-    Adapter, AdapterEvent, Device, DiscoveryFilter, DiscoveryTransport, ErrorKind, Result,
+    Adapter, AdapterEvent, Address, Device, DiscoveryFilter, DiscoveryTransport, ErrorKind, Result,
     Uuid,
 };
 use futures::{pin_mut, StreamExt};
@@ -11,25 +11,47 @@ use std::{
     collections::{HashMap, HashSet},
     time::Duration,
 };
-use tokio::time::sleep;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout_at, Instant},
+};
 
 use super::SERVICE_UUID;
 use crate::{
     bluetooth::{
-        OS, OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID, SSID_CHARACTERISTIC_UUID,
+        CAPABILITIES_CHARACTERISTIC_UUID, CENTRAL_KEY_CHARACTERISTIC_UUID,
+        DATA_CHARACTERISTIC_UUID, OS, OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID,
+        PERIPHERAL_KEY_CHARACTERISTIC_UUID, SSID_CHARACTERISTIC_UUID,
+    },
+    crypto::BluetoothKeyPair,
+    error::{fc_error, FCError},
+    negotiation::{
+        check_key_exchange, is_ble_transfer, Capabilities, PeerHosting, GATT_DONE, NO_SSID,
     },
-    network::is_hosting,
-    utils::{generate_password, get_key_and_ssid},
-    Mode, Peer,
+    utils::{
+        confirm_pin, generate_password, get_key_and_ssid, is_compatible, peer_description,
+        timeout_error, with_timeout, AdvertisementInfo, BluetoothTimeouts,
+        ADVERTISEMENT_COMPANY_ID, PASSWORD_LABEL, SCAN_WINDOW, SSID_LABEL,
+    },
+    BluetoothDevice, Mode, Peer, MAJOR_VERSION, UI,
 };
 
-pub async fn find_characteristics(device: &Device) -> Result<HashMap<&str, Characteristic>> {
+pub async fn find_characteristics(
+    device: &Device,
+    timeouts: &BluetoothTimeouts,
+) -> std::result::Result<HashMap<&'static str, Characteristic>, FCError> {
     let addr = device.address();
     let uuids = device.uuids().await?.unwrap_or_default();
 
     let os_characteristic_uuid = Uuid::parse_str(OS_CHARACTERISTIC_UUID).unwrap();
     let ssid_characteristic_uuid = Uuid::parse_str(SSID_CHARACTERISTIC_UUID).unwrap();
     let password_characteristic_uuid = Uuid::parse_str(PASSWORD_CHARACTERISTIC_UUID).unwrap();
+    let capabilities_characteristic_uuid =
+        Uuid::parse_str(CAPABILITIES_CHARACTERISTIC_UUID).unwrap();
+    let peripheral_key_characteristic_uuid =
+        Uuid::parse_str(PERIPHERAL_KEY_CHARACTERISTIC_UUID).unwrap();
+    let central_key_characteristic_uuid = Uuid::parse_str(CENTRAL_KEY_CHARACTERISTIC_UUID).unwrap();
+    let data_characteristic_uuid = Uuid::parse_str(DATA_CHARACTERISTIC_UUID).unwrap();
     println!("Discovered device {} with service UUIDs {:?}", addr, &uuids);
     let md = device.manufacturer_data().await?;
     println!("    Manufacturer data: {:x?}", &md);
@@ -38,20 +60,22 @@ pub async fn find_characteristics(device: &Device) -> Result<HashMap<&str, Chara
         println!("    Device provides our service!");
         let mut characteristics = HashMap::new();
 
-        sleep(Duration::from_secs(2)).await;
         if !device.is_connected().await? {
             println!("    Connecting...");
-            let mut retries = 2;
-            loop {
-                match device.connect().await {
-                    Ok(()) => break,
-                    Err(err) if retries > 0 => {
-                        println!("    Connect error: {}", &err);
-                        retries -= 1;
+            let connect = async {
+                let mut retries = 2;
+                loop {
+                    match device.connect().await {
+                        Ok(()) => break Ok(()),
+                        Err(err) if retries > 0 => {
+                            println!("    Connect error: {}", &err);
+                            retries -= 1;
+                        }
+                        Err(err) => break Err(err),
                     }
-                    Err(err) => return Err(err),
                 }
-            }
+            };
+            with_timeout(timeouts.connect, "connecting to peer", connect).await?;
             println!("    Connected");
         } else {
             println!("    Already connected");
@@ -95,13 +119,25 @@ pub async fn find_characteristics(device: &Device) -> Result<HashMap<&str, Chara
         // while let Some(ev) = events.next().await {
         //     println!("Received event {:?}", ev);
         // }
-        for service in device.services().await? {
+        let services = with_timeout(
+            timeouts.discovery,
+            "discovering the peer's Bluetooth services",
+            device.services(),
+        )
+        .await?;
+        for service in services {
             let uuid = service.uuid().await?;
             println!("    Service UUID: {}", &uuid);
             println!("    Service data: {:?}", service.all_properties().await?);
             if uuid == Uuid::parse_str(SERVICE_UUID).unwrap() {
                 println!("    Found our service!");
-                for char in service.characteristics().await? {
+                let service_characteristics = with_timeout(
+                    timeouts.discovery,
+                    "discovering the peer's Bluetooth characteristics",
+                    service.characteristics(),
+                )
+                .await?;
+                for char in service_characteristics {
                     let uuid = char.uuid().await?;
                     println!("    Characteristic UUID: {}", &uuid);
                     println!(
@@ -117,6 +153,18 @@ pub async fn find_characteristics(device: &Device) -> Result<HashMap<&str, Chara
                     } else if uuid == password_characteristic_uuid {
                         characteristics.insert(PASSWORD_CHARACTERISTIC_UUID, char);
                         println!("found password characteristic")
+                    } else if uuid == capabilities_characteristic_uuid {
+                        characteristics.insert(CAPABILITIES_CHARACTERISTIC_UUID, char);
+                        println!("found capabilities characteristic")
+                    } else if uuid == peripheral_key_characteristic_uuid {
+                        characteristics.insert(PERIPHERAL_KEY_CHARACTERISTIC_UUID, char);
+                        println!("found peripheral key characteristic")
+                    } else if uuid == central_key_characteristic_uuid {
+                        characteristics.insert(CENTRAL_KEY_CHARACTERISTIC_UUID, char);
+                        println!("found central key characteristic")
+                    } else if uuid == data_characteristic_uuid {
+                        characteristics.insert(DATA_CHARACTERISTIC_UUID, char);
+                        println!("found data characteristic")
                     }
                 }
             }
@@ -132,18 +180,26 @@ pub async fn find_characteristics(device: &Device) -> Result<HashMap<&str, Chara
                 kind: bluer::ErrorKind::ServicesUnresolved,
                 message: "Did not read all Flying Carpet characteristics from peer.".to_string(),
             };
-            Err(e)
+            Err(e)?
         }
     } else {
         let err = bluer::Error {
             kind: ErrorKind::ServicesUnresolved,
             message: "Could not find service UUID on scanned device".to_string(),
         };
-        Err(err)
+        Err(err)?
     }
 }
 
-pub async fn scan(adapter: &Adapter) -> bluer::Result<Device> {
+// scans for SCAN_WINDOW, or until at least one device is found after that, then asks the user which one is the peer.
+// gives up if nobody has shown up by the scan timeout.
+pub async fn scan<T: UI>(
+    adapter: &Adapter,
+    mode: &Mode,
+    timeouts: &BluetoothTimeouts,
+    mut ble_device_rx: mpsc::Receiver<Option<String>>,
+    ui: &T,
+) -> std::result::Result<Device, FCError> {
     let mut uuids = HashSet::new();
     uuids.insert(Uuid::parse_str(SERVICE_UUID).expect("Could not parse service UUID"));
 
@@ -158,6 +214,7 @@ pub async fn scan(adapter: &Adapter) -> bluer::Result<Device> {
         adapter.discovery_filter().await
     );
 
+    let mut found: Vec<Address> = vec![];
     {
         println!(
             "Discovering on Bluetooth adapter {} with address {}\n",
@@ -166,36 +223,122 @@ pub async fn scan(adapter: &Adapter) -> bluer::Result<Device> {
         );
         let discover = adapter.discover_devices().await?;
         pin_mut!(discover);
-        while let Some(evt) = discover.next().await {
-            match evt {
-                AdapterEvent::DeviceAdded(addr) => {
-                    // let device = adapter.connect_device(addr, bluer::AddressType::LePublic).await?;
-                    let device = adapter.device(addr)?;
-                    return Ok(device);
-                    // match device.disconnect().await {
-                    //     Ok(()) => println!("    Device disconnected"),
-                    //     Err(err) => println!("    Device disconnection failed: {}", &err),
-                    // }
-                    // println!();
+        let scan_end = Instant::now() + timeouts.scan;
+        let mut window_end = Instant::now() + SCAN_WINDOW;
+        loop {
+            match timeout_at(window_end, discover.next()).await {
+                Ok(Some(AdapterEvent::DeviceAdded(addr))) => {
+                    println!("Device added {addr}");
+                    let info = advertised_info(&adapter.device(addr)?).await?;
+                    if !found.contains(&addr) && is_listed(addr, info.as_ref(), mode) {
+                        found.push(addr);
+                    }
                 }
-                AdapterEvent::DeviceRemoved(addr) => {
+                Ok(Some(AdapterEvent::DeviceRemoved(addr))) => {
                     println!("Device removed {addr}");
+                    found.retain(|a| *a != addr);
                 }
-                other_event => println!("Processed other event: {:?}", other_event),
+                Ok(Some(other_event)) => println!("Processed other event: {:?}", other_event),
+                Ok(None) => break,
+                // keep scanning until someone shows up
+                Err(_) if found.is_empty() => {
+                    if Instant::now() >= scan_end {
+                        Err(timeout_error(
+                            timeouts.scan,
+                            &format!("scanning without finding a {}", peer_description(mode)),
+                        ))?
+                    }
+                    window_end = scan_end.min(Instant::now() + SCAN_WINDOW);
+                }
+                Err(_) => break,
             }
         }
         println!("Stopping discovery");
     }
-    Err(bluer::Error {
-        kind: ErrorKind::NotFound,
-        message: "Exited scan() without finding device".to_string(),
-    })
+    if found.is_empty() {
+        fc_error("Exited scan() without finding device")?
+    }
+
+    let mut devices = vec![];
+    for addr in &found {
+        let device = adapter.device(*addr)?;
+        let info = advertised_info(&device).await?;
+        let name = match info.as_ref().and_then(|i| i.name.clone()) {
+            Some(name) => name,
+            None => device.alias().await?,
+        };
+        devices.push(BluetoothDevice {
+            name,
+            address: addr.to_string(),
+            rssi: device.rssi().await?,
+            peer_os: info.map(|i| i.os),
+        });
+    }
+    // strongest signal first
+    devices.sort_by_key(|d| std::cmp::Reverse(d.rssi));
+    ui.show_bluetooth_devices(&devices);
+
+    let chosen = match ble_device_rx.recv().await {
+        Some(Some(address)) => address,
+        _ => Err(FCError {
+            message: "User canceled.".to_string(),
+        })?,
+    };
+    match found.iter().find(|a| a.to_string() == chosen) {
+        Some(addr) => Ok(adapter.device(*addr)?),
+        None => Err(FCError {
+            message: format!("Chosen device {} was not found", chosen),
+        }),
+    }
 }
 
-pub async fn exchange_info(
-    characteristics: HashMap<&str, Characteristic>,
+// the name comes from the scan response, which bluez asks for while discovering
+async fn advertised_info(device: &Device) -> Result<Option<AdvertisementInfo>> {
+    let name = device.name().await?;
+    Ok(device
+        .manufacturer_data()
+        .await?
+        .and_then(|data| AdvertisementInfo::decode(data.get(&ADVERTISEMENT_COMPANY_ID)?, name)))
+}
+
+// older versions and the mobile apps don't advertise any info, so they're always listed
+fn is_listed(addr: Address, info: Option<&AdvertisementInfo>, mode: &Mode) -> bool {
+    let info = match info {
+        Some(i) => i,
+        None => return true,
+    };
+    if info.sending == matches!(mode, Mode::Send(_)) {
+        println!(
+            "Not listing {}, it's not a {}",
+            addr,
+            peer_description(mode)
+        );
+        return false;
+    }
+    let version = info.protocol_version as u64;
+    if version < MAJOR_VERSION && !is_compatible(version) {
+        println!(
+            "Not listing {}, its version {} is not compatible",
+            addr, version
+        );
+        return false;
+    }
+    true
+}
+
+// peers with the capabilities characteristic confirm each step, so we only pause between steps for older peers.
+// peers with the key characteristics get the credentials encrypted, once both users have confirmed the PIN.
+// also returns whether the peer hosts and the port it would listen on, and whether the files are small enough to transfer over the data
+// characteristic instead of WiFi.
+pub async fn exchange_info<T: UI>(
+    characteristics: &HashMap<&str, Characteristic>,
     mode: &Mode,
-) -> bluer::Result<(String, String, String)> {
+    capabilities: &Capabilities,
+    timeouts: &BluetoothTimeouts,
+    ble_ui_rx: &mut mpsc::Receiver<bool>,
+    ui: &T,
+) -> std::result::Result<(String, String, String, PeerHosting, bool), FCError> {
+    let step = timeouts.exchange_step;
     // have to use this with write_ext() for the write requests: iOS wouldn't receive unconfirmed writes, which WriteOp::Request provides.
     // not sure if iOS requires it or if i did somehow. bluer seems to default to WriteOp::Command which has no confirmation.
     let write_req = CharacteristicWriteRequest {
@@ -205,42 +348,168 @@ pub async fn exchange_info(
         ..Default::default()
     };
 
+    // swap capabilities
+    let capabilities_char = characteristics.get(CAPABILITIES_CHARACTERISTIC_UUID);
+    let mut ble_transfer = false;
+    let mut peer_capabilities = None;
+    if let Some(capabilities_char) = capabilities_char {
+        let value = with_timeout(
+            step,
+            "reading the peer's capabilities",
+            capabilities_char.read(),
+        )
+        .await?;
+        let decoded = match Capabilities::decode(&String::from_utf8(value)?) {
+            Some(c) => c,
+            None => Err(FCError {
+                message: "Peer sent malformed capabilities".to_string(),
+            })?,
+        };
+        decoded.check(ui)?;
+        ble_transfer = characteristics.contains_key(DATA_CHARACTERISTIC_UUID)
+            && is_ble_transfer(&decoded, capabilities);
+        peer_capabilities = Some(decoded);
+        with_timeout(
+            step,
+            "writing our capabilities",
+            capabilities_char.write_ext(capabilities.encode().as_bytes(), &write_req),
+        )
+        .await?;
+    }
+    let pause = || async {
+        if capabilities_char.is_none() {
+            sleep(Duration::from_secs(1)).await;
+        }
+    };
+
+    // swap public keys
+    check_key_exchange(
+        peer_capabilities.as_ref(),
+        characteristics.contains_key(PERIPHERAL_KEY_CHARACTERISTIC_UUID)
+            && characteristics.contains_key(CENTRAL_KEY_CHARACTERISTIC_UUID),
+    )?;
+    let cipher = match (
+        characteristics.get(PERIPHERAL_KEY_CHARACTERISTIC_UUID),
+        characteristics.get(CENTRAL_KEY_CHARACTERISTIC_UUID),
+    ) {
+        (Some(peripheral_key_char), Some(central_key_char)) => {
+            let key_pair = BluetoothKeyPair::generate();
+            let value = with_timeout(
+                step,
+                "reading the peer's public key",
+                peripheral_key_char.read(),
+            )
+            .await?;
+            with_timeout(
+                step,
+                "writing our public key",
+                central_key_char.write_ext(key_pair.public_hex().as_bytes(), &write_req),
+            )
+            .await?;
+            Some(key_pair.agree(&String::from_utf8(value)?, true)?)
+        }
+        _ => None,
+    };
+
     // read peer's OS
     let os_char = &characteristics[OS_CHARACTERISTIC_UUID];
-    let value = os_char.read().await?;
+    let value = with_timeout(step, "reading the peer's OS", os_char.read()).await?;
     let peer_os = String::from_utf8(value).expect("Peer OS value was not utf-8");
     println!("Peer OS: {}", peer_os);
-    sleep(Duration::from_secs(1)).await;
+    pause().await;
     // write our OS
-    os_char.write_ext(OS.as_bytes(), &write_req).await?;
+    with_timeout(
+        step,
+        "writing our OS",
+        os_char.write_ext(OS.as_bytes(), &write_req),
+    )
+    .await?;
     println!("Wrote OS to peer");
-    sleep(Duration::from_secs(1)).await;
+    pause().await;
+
+    if let Some(c) = &cipher {
+        confirm_pin(&c.pin, ble_ui_rx, timeouts.connect, ui).await?;
+    }
 
     let ssid_char = &characteristics[SSID_CHARACTERISTIC_UUID];
     let password_char = &characteristics[PASSWORD_CHARACTERISTIC_UUID];
-    if is_hosting(&Peer::from(peer_os.as_str()), mode) {
+    let peer_hosting = PeerHosting::negotiate(
+        capabilities,
+        peer_capabilities.as_ref(),
+        &Peer::from(peer_os.as_str()),
+        mode,
+    )?;
+    let info = if !peer_hosting.hosts {
         // write ssid and password
         let password = generate_password();
         let (_, ssid) = get_key_and_ssid(&password);
-        ssid_char.write_ext(ssid.as_bytes(), &write_req).await?;
-        // let CharacteristicWriteRequest
-        // ssid_char.write_ext(value, req);
+        let (sent_ssid, sent_password) = match &cipher {
+            Some(c) => (
+                c.encrypt(SSID_LABEL, &ssid)?,
+                c.encrypt(PASSWORD_LABEL, &password)?,
+            ),
+            None => (ssid.clone(), password.clone()),
+        };
+        with_timeout(
+            step,
+            "writing our SSID",
+            ssid_char.write_ext(sent_ssid.as_bytes(), &write_req),
+        )
+        .await?;
         println!("Wrote SSID to peer");
-        sleep(Duration::from_secs(1)).await;
-        password_char
-            .write_ext(password.as_bytes(), &write_req)
-            .await?;
+        pause().await;
+        with_timeout(
+            step,
+            "writing our password",
+            password_char.write_ext(sent_password.as_bytes(), &write_req),
+        )
+        .await?;
         println!("Wrote password to peer");
-        sleep(Duration::from_secs(1)).await;
-        Ok((peer_os, ssid, password))
+        pause().await;
+        (peer_os, ssid, password)
     } else {
-        // read ssid and password
-        let ssid = ssid_char.read().await?;
-        let ssid = String::from_utf8(ssid).expect("SSID was not UTF-8");
-        println!("Peer's SSID: {}", ssid);
-        let password = password_char.read().await?;
+        // read ssid and password. the peer serves NO_SSID until it's ready, which may be while its user confirms the PIN.
+        let deadline = Instant::now() + timeouts.connect;
+        let ssid = loop {
+            let ssid = with_timeout(step, "reading the peer's SSID", ssid_char.read()).await?;
+            let ssid = String::from_utf8(ssid).expect("SSID was not UTF-8");
+            if ssid != NO_SSID {
+                break ssid;
+            }
+            if Instant::now() >= deadline {
+                Err(timeout_error(
+                    timeouts.connect,
+                    "waiting for the peer to share its SSID",
+                ))?
+            }
+            sleep(Duration::from_millis(500)).await;
+        };
+        let password =
+            with_timeout(step, "reading the peer's password", password_char.read()).await?;
         let password = String::from_utf8(password).expect("Password was not UTF-8");
-        println!("Peer's password: {}", password);
-        Ok((peer_os, ssid, password))
+        let (ssid, password) = match &cipher {
+            Some(c) => (
+                c.decrypt(SSID_LABEL, &ssid)?,
+                c.decrypt(PASSWORD_LABEL, &password)?,
+            ),
+            None => (ssid, password),
+        };
+        println!("Peer's SSID: {}", ssid);
+        (peer_os, ssid, password)
+    };
+
+    // the peripheral stops serving as soon as it sees this, so it may not get to respond
+    if let Some(capabilities_char) = capabilities_char {
+        if let Err(e) = with_timeout(
+            step,
+            "telling the peer we're done",
+            capabilities_char.write_ext(GATT_DONE.as_bytes(), &write_req),
+        )
+        .await
+        {
+            println!("Error telling peer we're done: {}", e);
+        }
     }
+    let (peer_os, ssid, password) = info;
+    Ok((peer_os, ssid, password, peer_hosting, ble_transfer))
 }
@@ -1,8 +1,17 @@
+use crate::diagnostics::Check;
 use crate::error::{fc_error, FCError};
-use crate::utils::run_command;
+use crate::hotspot::{HotspotChannel, WiFiBand};
+use crate::utils::{run_command, IPV6_GATEWAY_DELAY};
 use crate::{Mode, Peer, PeerResource, WiFiInterface, UI};
+use std::fs;
+use std::time::Instant;
 use tokio::task;
 
+// our profiles are named after the hotspot's SSID
+const PROFILE_PREFIX: &str = "flyingCarpet_";
+// keeps a modified profile from being written to disk
+const TEMPORARY: &str = "--temporary";
+
 // stub
 pub struct WindowsHotspot {
     _inner: (),
@@ -20,26 +29,40 @@ pub fn is_hosting(peer: &Peer, mode: &Mode) -> bool {
 }
 
 pub async fn connect_to_peer<T: UI>(
-    peer: Peer,
-    mode: Mode,
+    hosting: bool,
     ssid: String,
     password: String,
     interface: WiFiInterface,
+    // only Windows opens it in the firewall
+    _port: u16,
+    hotspot: HotspotChannel, // ours if we're hosting, otherwise where the peer said it would be
     ui: &T,
 ) -> Result<PeerResource, FCError> {
-    if is_hosting(&peer, &mode) {
+    if hosting {
         // start hotspot
-        ui.output(&format!("Starting hotspot {}", ssid));
-        start_hotspot(&ssid, &password, &interface.0)?;
+        hotspot.check()?;
+        if let Some(band) = hotspot.band {
+            let bands = supported_bands(&interface.name)?;
+            if !bands.is_empty() && !bands.contains(&band) {
+                fc_error(&format!(
+                    "{} doesn't support {} GHz",
+                    interface.name,
+                    band.as_str()
+                ))?;
+            }
+        }
+        ui.output(&format!("Starting hotspot {}{}", ssid, hotspot.describe()));
+        start_hotspot(&ssid, &password, &interface.name, hotspot)?;
         Ok(PeerResource::LinuxHotspot)
     } else {
         // join hotspot and find gateway
-        ui.output(&format!("Joining hotspot {}", ssid));
-        join_hotspot(&ssid, &password, &interface.0, ui).await?;
+        ui.output(&format!("Joining hotspot {}{}", ssid, hotspot.describe()));
+        join_hotspot(&ssid, &password, &interface.name, hotspot, ui).await?;
+        let joined = Instant::now();
         loop {
             // println!("looking for gateway");
             task::yield_now().await;
-            match find_gateway(&interface.0) {
+            match find_gateway(&interface.name) {
                 Ok(gateway) => {
                     if gateway != "" {
                         return Ok(PeerResource::WifiClient(gateway));
@@ -47,14 +70,24 @@ pub async fn connect_to_peer<T: UI>(
                 }
                 Err(e) => Err(e)?,
             }
+            if joined.elapsed() > IPV6_GATEWAY_DELAY {
+                if let Some(gateway) = find_ipv6_gateway(&interface.name)? {
+                    return Ok(PeerResource::WifiClient(gateway));
+                }
+            }
             tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
         }
     }
 }
 
-fn start_hotspot(ssid: &str, password: &str, interface: &str) -> Result<(), FCError> {
+fn start_hotspot(
+    ssid: &str,
+    password: &str,
+    interface: &str,
+    hotspot: HotspotChannel,
+) -> Result<(), FCError> {
     let nmcli = "nmcli";
-    let commands = vec![
+    let mut commands = vec![
         vec![
             "con",
             "add",
@@ -65,30 +98,50 @@ fn start_hotspot(ssid: &str, password: &str, interface: &str) -> Result<(), FCEr
             "con-name",
             ssid,
             "autoconnect",
-            "yes",
+            "no",
             "ssid",
             ssid,
+            // in memory only, so that a crash can't leave a profile behind to connect to later
+            "save",
+            "no",
         ],
         vec![
             "con",
             "modify",
+            TEMPORARY,
             ssid,
             "802-11-wireless.mode",
             "ap",
             "ipv4.method",
             "shared",
         ],
-        vec!["con", "modify", ssid, "wifi-sec.key-mgmt", "wpa-psk"],
+        vec![
+            "con",
+            "modify",
+            TEMPORARY,
+            ssid,
+            "wifi-sec.key-mgmt",
+            "wpa-psk",
+        ],
         // disable Protected Management Frames, which disables WPA3/SAE, which is necessary for M1 Macs to join Linux
-        vec!["con", "modify", ssid, "wifi-sec.pmf", "disable"],
+        vec!["con", "modify", TEMPORARY, ssid, "wifi-sec.pmf", "disable"],
         // use AES, not TKIP
-        vec!["con", "modify", ssid, "wifi-sec.pairwise", "ccmp"],
-        vec!["con", "modify", ssid, "wifi-sec.group", "ccmp"],
+        vec![
+            "con",
+            "modify",
+            TEMPORARY,
+            ssid,
+            "wifi-sec.pairwise",
+            "ccmp",
+        ],
+        vec!["con", "modify", TEMPORARY, ssid, "wifi-sec.group", "ccmp"],
         // use WPA2, not WPA
-        vec!["con", "modify", ssid, "wifi-sec.proto", "rsn"],
-        vec!["con", "modify", ssid, "wifi-sec.psk", password],
-        vec!["con", "up", ssid],
+        vec!["con", "modify", TEMPORARY, ssid, "wifi-sec.proto", "rsn"],
+        vec!["con", "modify", TEMPORARY, ssid, "wifi-sec.psk", password],
     ];
+    let channel = hotspot.channel.map(|c| c.to_string());
+    commands.extend(channel_settings(ssid, hotspot.band, channel.as_deref()));
+    commands.push(vec!["con", "up", ssid]);
     for command in commands {
         let res = run_command(nmcli, Some(command))?;
         if !res.status.success() {
@@ -123,9 +176,15 @@ pub fn stop_hotspot(
     }
 }
 
-async fn join_hotspot<T: UI>(ssid: &str, password: &str, interface: &str, ui: &T) -> Result<(), FCError> {
+async fn join_hotspot<T: UI>(
+    ssid: &str,
+    password: &str,
+    interface: &str,
+    hotspot: HotspotChannel,
+    ui: &T,
+) -> Result<(), FCError> {
     let nmcli = "nmcli";
-    let commands = vec![
+    let mut commands = vec![
         vec![
             "con",
             "add",
@@ -136,13 +195,26 @@ async fn join_hotspot<T: UI>(ssid: &str, password: &str, interface: &str, ui: &T
             "con-name",
             ssid,
             "autoconnect",
-            "yes",
+            "no",
             "ssid",
             ssid,
+            // in memory only, so that a crash can't leave a profile behind to connect to later
+            "save",
+            "no",
         ],
-        vec!["con", "modify", ssid, "wifi-sec.key-mgmt", "wpa-psk"],
-        vec!["con", "modify", ssid, "wifi-sec.psk", password],
+        vec![
+            "con",
+            "modify",
+            TEMPORARY,
+            ssid,
+            "wifi-sec.key-mgmt",
+            "wpa-psk",
+        ],
+        vec!["con", "modify", TEMPORARY, ssid, "wifi-sec.psk", password],
     ];
+    // only scan where the host said it would be
+    let channel = hotspot.channel.map(|c| c.to_string());
+    commands.extend(channel_settings(ssid, hotspot.band, channel.as_deref()));
     for command in commands {
         let res = run_command(nmcli, Some(command))?;
         if !res.status.success() {
@@ -170,23 +242,283 @@ async fn join_hotspot<T: UI>(ssid: &str, password: &str, interface: &str, ui: &T
     Ok(())
 }
 
+// nmcli names the bands a, bg, and 6GHz. without a band, NetworkManager picks one, often a crowded 2.4 GHz channel.
+fn channel_settings<'a>(
+    ssid: &'a str,
+    band: Option<WiFiBand>,
+    channel: Option<&'a str>,
+) -> Vec<Vec<&'a str>> {
+    let Some(band) = band else {
+        return vec![];
+    };
+    let band = match band {
+        WiFiBand::TwoPointFour => "bg",
+        WiFiBand::Five => "a",
+        WiFiBand::Six => "6GHz",
+    };
+    let mut command = vec![
+        "con",
+        "modify",
+        TEMPORARY,
+        ssid,
+        "802-11-wireless.band",
+        band,
+    ];
+    if let Some(channel) = channel {
+        command.extend(["802-11-wireless.channel", channel]);
+    }
+    vec![command]
+}
+
+// the bands the interface can use, empty if NetworkManager doesn't say
+pub fn supported_bands(interface: &str) -> Result<Vec<WiFiBand>, FCError> {
+    Ok(parse_bands(&wifi_properties(interface)?))
+}
+
+fn wifi_properties(interface: &str) -> Result<String, FCError> {
+    let options = vec!["-t", "-f", "WIFI-PROPERTIES", "device", "show", interface];
+    let command_output = run_command("nmcli", Some(options))?;
+    if !command_output.status.success() {
+        let stderr = String::from_utf8_lossy(&command_output.stderr);
+        fc_error(&format!("Could not get WiFi properties: {}", stderr))?;
+    }
+    Ok(String::from_utf8_lossy(&command_output.stdout).to_string())
+}
+
+fn parse_bands(properties: &str) -> Vec<WiFiBand> {
+    properties
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(_, supported)| *supported == "yes")
+        .filter_map(|(property, _)| match property {
+            "WIFI-PROPERTIES.2GHZ" => Some(WiFiBand::TwoPointFour),
+            "WIFI-PROPERTIES.5GHZ" => Some(WiFiBand::Five),
+            "WIFI-PROPERTIES.6GHZ" => Some(WiFiBand::Six),
+            _ => None,
+        })
+        .collect()
+}
+
+// None if NetworkManager doesn't say whether the interface can run an access point
+fn parse_ap(properties: &str) -> Option<bool> {
+    properties
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(property, _)| *property == "WIFI-PROPERTIES.AP")
+        .map(|(_, supported)| supported == "yes")
+}
+
+// NetworkManager, each WiFi interface's hotspot support, and firewalls that could block the peer's connection
+pub fn diagnose(port: u16) -> Vec<Check> {
+    let mut checks = vec![];
+    match run_command("nmcli", Some(vec!["-t", "-f", "RUNNING", "general"])) {
+        Ok(output) if String::from_utf8_lossy(&output.stdout).trim() == "running" => {
+            checks.push(Check::ok("NetworkManager", "Running."))
+        }
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            checks.push(Check::failed(
+                "NetworkManager",
+                &format!(
+                    "Not running, and Flying Carpet uses it to start and join hotspots. {}",
+                    stderr.trim()
+                ),
+            ));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::failed(
+                "NetworkManager",
+                &format!(
+                    "Couldn't run nmcli, which Flying Carpet uses to start and join hotspots: {}",
+                    e
+                ),
+            ));
+            return checks;
+        }
+    }
+
+    match get_wifi_interfaces() {
+        Ok(interfaces) if interfaces.is_empty() => {
+            checks.push(Check::failed("WiFi interface", "No WiFi interface found."))
+        }
+        Ok(interfaces) => {
+            for interface in interfaces {
+                checks.push(check_interface(&interface));
+            }
+        }
+        Err(e) => checks.push(Check::failed(
+            "WiFi interface",
+            &format!("Couldn't list WiFi interfaces: {}", e),
+        )),
+    }
+
+    let listening = match port {
+        0 => "the free port a transfer picks".to_string(),
+        _ => format!("TCP port {}", port),
+    };
+    for firewall in ["ufw", "firewalld"] {
+        // is-active exits with 0 only when the unit is running
+        let Ok(output) = run_command("systemctl", Some(vec!["is-active", "--quiet", firewall]))
+        else {
+            continue;
+        };
+        if output.status.success() {
+            let detail = format!(
+                "{} is running. When hosting, it must allow incoming connections on {}, and for same-network transfers, mDNS on UDP port 5353.",
+                firewall, listening
+            );
+            checks.push(Check::warning("Firewall", &detail));
+        }
+    }
+    checks
+}
+
+fn check_interface(interface: &WiFiInterface) -> Check {
+    let name = format!("WiFi interface {}", interface.name);
+    let bands = interface
+        .bands
+        .iter()
+        .map(|band| band.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let bands = if bands.is_empty() {
+        "NetworkManager doesn't list its bands.".to_string()
+    } else {
+        format!("Bands: {} GHz.", bands)
+    };
+    match interface.ap {
+        Some(true) => Check::ok(&name, &format!("Can host a hotspot. {}", bands)),
+        Some(false) => Check::warning(
+            &name,
+            &format!(
+                "Can't host a hotspot, so it can only join the other device's. {}",
+                bands
+            ),
+        ),
+        None => Check::warning(
+            &name,
+            &format!(
+                "NetworkManager doesn't say whether it can host a hotspot. {}",
+                bands
+            ),
+        ),
+    }
+}
+
+// profiles left by a run that crashed before stop_hotspot(). they're only in memory, but last until NetworkManager
+// restarts, and would still connect or start a hotspot if brought up. call at startup, before this instance starts a
+// transfer. returns the names of the ones removed.
+pub fn remove_stale_hotspots() -> Result<Vec<String>, FCError> {
+    let list = run_command(
+        "nmcli",
+        Some(vec!["-t", "-f", "UUID,NAME,DEVICE", "connection", "show"]),
+    )?;
+    if !list.status.success() {
+        let stderr = String::from_utf8_lossy(&list.stderr);
+        fc_error(&format!("Could not list connections: {}", stderr))?;
+    }
+    let mut removed = vec![];
+    for (uuid, name) in stale_profiles(&String::from_utf8_lossy(&list.stdout)) {
+        let command_output = run_command("nmcli", Some(vec!["connection", "delete", &uuid]))?;
+        if !command_output.status.success() {
+            let stderr = String::from_utf8_lossy(&command_output.stderr);
+            fc_error(&format!("Error removing {}: {}", name, stderr))?;
+        }
+        removed.push(name);
+    }
+    Ok(removed)
+}
+
+// by UUID, since a crash and a retry with the same password leaves two with the same name. one that's up on a device
+// belongs to a transfer another instance is running, so it isn't stale.
+fn stale_profiles(connections: &str) -> Vec<(String, String)> {
+    connections
+        .lines()
+        .map(terse_fields)
+        .filter_map(|fields| match fields.as_slice() {
+            [uuid, name, device] if name.starts_with(PROFILE_PREFIX) && device.is_empty() => {
+                Some((uuid.clone(), name.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 pub fn get_wifi_interfaces() -> Result<Vec<WiFiInterface>, FCError> {
-    let command = "nmcli";
-    let options = vec!["-t", "device"];
-    let command_output = run_command(command, Some(options))?;
+    let options = vec!["-t", "-f", "DEVICE,TYPE,CONNECTION", "device"];
+    let command_output = run_command("nmcli", Some(options))?;
     let output = String::from_utf8_lossy(&command_output.stdout);
     let mut interfaces: Vec<WiFiInterface> = vec![];
-    output
-        .lines()
-        .map(|line| line.split(":").collect())
-        .for_each(|split_line: Vec<&str>| {
-            if split_line[1] == "wifi" {
-                interfaces.push(WiFiInterface(split_line[0].to_string(), "".to_string()));
-            }
-        });
+    for fields in output.lines().map(terse_fields) {
+        let [name, kind, connection] = fields.as_slice() else {
+            continue;
+        };
+        if kind != "wifi" {
+            continue;
+        }
+        // a device that vanished since the listing still gets listed, just without the details
+        let options = vec![
+            "-t",
+            "-f",
+            "GENERAL.HWADDR,GENERAL.DRIVER,WIFI-PROPERTIES",
+            "device",
+            "show",
+            name,
+        ];
+        let details = run_command("nmcli", Some(options))?;
+        let details = match details.status.success() {
+            true => String::from_utf8_lossy(&details.stdout).to_string(),
+            false => String::new(),
+        };
+        interfaces.push(parse_interface(name, connection, &details));
+    }
     Ok(interfaces)
 }
 
+fn parse_interface(name: &str, connection: &str, details: &str) -> WiFiInterface {
+    let detail = |key: &str| {
+        details
+            .lines()
+            .map(terse_fields)
+            .find(|fields| fields[0] == key)
+            .and_then(|fields| fields.get(1).and_then(|value| known(value)))
+    };
+    WiFiInterface {
+        name: name.to_string(),
+        guid: String::new(),
+        ap: parse_ap(details),
+        bands: parse_bands(details),
+        connection: known(connection),
+        mac: detail("GENERAL.HWADDR"),
+        driver: detail("GENERAL.DRIVER"),
+    }
+}
+
+// nmcli shows a missing value as empty or "--"
+fn known(value: &str) -> Option<String> {
+    match value {
+        "" | "--" => None,
+        _ => Some(value.to_string()),
+    }
+}
+
+// nmcli's terse output separates fields with ':' and escapes colons and backslashes within them, as in MAC addresses
+fn terse_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => field.extend(chars.next()),
+            ':' => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
 fn find_gateway(interface: &str) -> Result<String, FCError> {
     let route_command = format!(
         "route -n | grep {} | grep UG | awk '{{print $2}}'",
@@ -197,8 +529,41 @@ fn find_gateway(interface: &str) -> Result<String, FCError> {
     Ok(stdout.trim().to_string())
 }
 
+// scoped to the interface, since the gateway on a hotspot with no IPv4 is usually only link-local
+fn find_ipv6_gateway(interface: &str) -> Result<Option<String>, FCError> {
+    let output = run_command(
+        "ip",
+        Some(vec!["-6", "route", "show", "default", "dev", interface]),
+    )?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_ipv6_gateway(&stdout, interface))
+}
+
+// e.g. "default via fe80::1 proto ra metric 600 pref medium"
+fn parse_ipv6_gateway(routes: &str, interface: &str) -> Option<String> {
+    routes.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("default"), Some("via"), Some(gateway)) => {
+                Some(format!("{}%{}", gateway, interface))
+            }
+            _ => None,
+        }
+    })
+}
+
+pub fn interface_index(name: &str) -> Result<u32, FCError> {
+    fs::read_to_string(format!("/sys/class/net/{}/ifindex", name))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(|| FCError {
+            message: format!("No network interface named {}", name),
+        })
+}
+
 #[cfg(test)]
 mod test {
+    use crate::hotspot::{HotspotChannel, WiFiBand};
     use crate::{PeerResource, UI};
 
     use super::get_wifi_interfaces;
@@ -208,8 +573,9 @@ mod test {
         let ssid = "flyingCarpet_1234";
         let password = "password";
         let _pr = PeerResource::WifiClient("".to_string());
-        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].0;
-        crate::network::start_hotspot(ssid, password, interface).unwrap();
+        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].name;
+        crate::network::start_hotspot(ssid, password, interface, HotspotChannel::default())
+            .unwrap();
         std::thread::sleep(std::time::Duration::from_secs(5));
         crate::network::stop_hotspot(Some(&_pr), Some(ssid)).unwrap();
     }
@@ -225,16 +591,26 @@ mod test {
             fn update_progress_bar(&self, _percent: u8) {}
             fn enable_ui(&self) {}
             fn show_pin(&self, _pin: &str) {}
+            fn show_bluetooth_devices(&self, _devices: &[crate::BluetoothDevice]) {}
+            fn show_lan_peers(&self, _peers: &[crate::LanPeer]) {}
         }
 
         let ssid = "";
         let password = "";
         let pr = PeerResource::WifiClient("".to_string());
-        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].0;
+        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].name;
         let interface = interface.to_string();
         let (tx, mut rx) = tokio::sync::mpsc::channel::<()>(1);
         tokio::spawn(async move {
-            crate::network::join_hotspot(ssid, password, &interface, &TestUI{}).await.unwrap();
+            crate::network::join_hotspot(
+                ssid,
+                password,
+                &interface,
+                HotspotChannel::default(),
+                &TestUI {},
+            )
+            .await
+            .unwrap();
             std::thread::sleep(std::time::Duration::from_secs(20));
             crate::network::stop_hotspot(Some(&pr), Some(ssid)).unwrap();
             tx.send(()).await.unwrap();
@@ -242,9 +618,84 @@ mod test {
         rx.blocking_recv().unwrap();
     }
 
+    #[test]
+    fn ipv6_gateway_is_scoped() {
+        let routes = "default via fe80::1 proto ra metric 600 pref medium\n";
+        assert_eq!(
+            super::parse_ipv6_gateway(routes, "wlan0"),
+            Some("fe80::1%wlan0".to_string())
+        );
+        assert_eq!(super::parse_ipv6_gateway("", "wlan0"), None);
+    }
+
+    #[test]
+    fn bands() {
+        let properties = "WIFI-PROPERTIES.WEP:yes\nWIFI-PROPERTIES.AP:yes\nWIFI-PROPERTIES.2GHZ:yes\nWIFI-PROPERTIES.5GHZ:yes\nWIFI-PROPERTIES.6GHZ:no\n";
+        assert_eq!(
+            super::parse_bands(properties),
+            vec![WiFiBand::TwoPointFour, WiFiBand::Five]
+        );
+        assert_eq!(super::parse_bands(""), vec![]);
+        assert_eq!(super::parse_ap(properties), Some(true));
+        assert_eq!(super::parse_ap("WIFI-PROPERTIES.AP:no\n"), Some(false));
+        assert_eq!(super::parse_ap(""), None);
+
+        assert_eq!(
+            super::channel_settings("ssid", Some(WiFiBand::Five), Some("36")),
+            vec![vec![
+                "con",
+                "modify",
+                "--temporary",
+                "ssid",
+                "802-11-wireless.band",
+                "a",
+                "802-11-wireless.channel",
+                "36"
+            ]]
+        );
+        assert!(super::channel_settings("ssid", None, None).is_empty());
+    }
+
+    #[test]
+    fn stale_profiles() {
+        // the last one is another instance's hotspot, still up on wlan1
+        let connections = "6f1a2b3c-0000-4000-8000-000000000001:Home WiFi:wlan0\n6f1a2b3c-0000-4000-8000-000000000002:flyingCarpet_1a2b:\n6f1a2b3c-0000-4000-8000-000000000003:flyingCarpet_1a2b:\n6f1a2b3c-0000-4000-8000-000000000004:lo:lo\n6f1a2b3c-0000-4000-8000-000000000005:flyingCarpet_9f8e:wlan1\n";
+        let stale = |uuid: &str, name: &str| (uuid.to_string(), name.to_string());
+        assert_eq!(
+            super::stale_profiles(connections),
+            vec![
+                stale("6f1a2b3c-0000-4000-8000-000000000002", "flyingCarpet_1a2b"),
+                stale("6f1a2b3c-0000-4000-8000-000000000003", "flyingCarpet_1a2b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn interfaces() {
+        let details = "GENERAL.HWADDR:A4\\:C3\\:F0\\:85\\:AC\\:2D\nGENERAL.DRIVER:iwlwifi\nWIFI-PROPERTIES.AP:yes\nWIFI-PROPERTIES.2GHZ:yes\nWIFI-PROPERTIES.5GHZ:yes\n";
+        let interface = super::parse_interface("wlan0", "", details);
+        assert_eq!(interface.name, "wlan0");
+        assert_eq!(interface.ap, Some(true));
+        assert_eq!(
+            interface.bands,
+            vec![WiFiBand::TwoPointFour, WiFiBand::Five]
+        );
+        assert_eq!(interface.connection, None);
+        assert_eq!(interface.mac.as_deref(), Some("A4:C3:F0:85:AC:2D"));
+        assert_eq!(interface.driver.as_deref(), Some("iwlwifi"));
+
+        let fields = super::terse_fields("wlan0:wifi:Home\\:WiFi");
+        assert_eq!(fields, vec!["wlan0", "wifi", "Home:WiFi"]);
+        let interface = super::parse_interface("wlan1", &fields[2], "");
+        assert_eq!(interface.connection.as_deref(), Some("Home:WiFi"));
+        assert_eq!(interface.ap, None);
+        assert_eq!(interface.mac, None);
+        assert_eq!(super::parse_interface("wlan1", "--", "").connection, None);
+    }
+
     #[test]
     fn find_gateway() {
-        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].0;
+        let interface = &get_wifi_interfaces().expect("no wifi interface present")[0].name;
         let gateway = crate::network::find_gateway(interface).unwrap();
         println!("interface: {}", interface);
         println!("gateway: {}", gateway);
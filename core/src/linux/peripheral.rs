@@ -1,27 +1,53 @@
 use crate::{
     bluetooth::{
-        OS, OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID, SERVICE_UUID,
-        SSID_CHARACTERISTIC_UUID,
+        CAPABILITIES_CHARACTERISTIC_UUID, CENTRAL_KEY_CHARACTERISTIC_UUID,
+        DATA_CHARACTERISTIC_UUID, OS, OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID,
+        PERIPHERAL_KEY_CHARACTERISTIC_UUID, SERVICE_UUID, SSID_CHARACTERISTIC_UUID,
     },
-    utils::BluetoothMessage,
+    error::FCError,
+    negotiation::{BluetoothMessage, BluetoothSession, Capabilities, GATT_DONE, NO_SSID},
+    utils::{AdvertisementInfo, ADVERTISEMENT_COMPANY_ID, LEGACY_ADVERTISEMENT_LENGTH},
+    Mode, MAJOR_VERSION,
 };
 
 use bluer::{
     adv::{Advertisement, AdvertisementHandle},
-    gatt::local::{
-        Application, ApplicationHandle, Characteristic, CharacteristicRead, CharacteristicWrite,
-        CharacteristicWriteMethod, ReqError, Service,
+    gatt::{
+        local::{
+            characteristic_control, Application, ApplicationHandle, Characteristic,
+            CharacteristicControl, CharacteristicControlEvent, CharacteristicControlHandle,
+            CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead,
+            CharacteristicWrite, CharacteristicWriteMethod, ReqError, Service,
+        },
+        CharacteristicReader, CharacteristicWriter,
     },
-    Uuid,
+    Adapter, Address, Uuid,
 };
-use futures::FutureExt;
-use tokio::sync::mpsc;
+use futures::{FutureExt, StreamExt};
+use std::sync::{Arc, Mutex};
 
-fn get_os_characteristic(tx: mpsc::Sender<BluetoothMessage>) -> Characteristic {
+// values shared between the characteristics' callbacks and the main thread
+#[derive(Clone, Default)]
+pub(crate) struct PeripheralState {
+    // set when the central writes its capabilities, which older versions and the mobile apps don't
+    pub peer_capabilities: Arc<Mutex<Option<Capabilities>>>,
+    // set when the central writes its public key, which older versions and the mobile apps don't
+    pub peer_key: Arc<Mutex<Option<String>>>,
+    // set by the main thread if we're hosting, once the peer may read them
+    pub ssid: Arc<Mutex<Option<String>>>,
+    pub password: Arc<Mutex<Option<String>>>,
+    // set when the central writes its OS
+    pub peer_address: Arc<Mutex<Option<Address>>>,
+}
+
+fn get_os_characteristic(
+    session: BluetoothSession,
+    peer_address: Arc<Mutex<Option<Address>>>,
+) -> Characteristic {
     // when the OS characteristic is read, return the constant
-    // when it's written to, return that to calling thread, so we need tx
-    let read_tx = tx.clone();
-    let write_tx = tx.clone();
+    // when it's written to, return that to calling thread, so we need the session
+    let read_session = session.clone();
+    let write_session = session.clone();
     Characteristic {
         uuid: Uuid::parse_str(OS_CHARACTERISTIC_UUID).unwrap(),
         read: Some(CharacteristicRead {
@@ -30,13 +56,12 @@ fn get_os_characteristic(tx: mpsc::Sender<BluetoothMessage>) -> Characteristic {
             // so this is a pub type CharacteristicReadFun = Box<dyn Fn(CharacteristicReadRequest) -> Pin<Box<dyn Future<Output = ReqResult<Vec<u8>>> + Send>> + Send + Sync>;
             // a box containing function, that takes a characteristicreadrequest, and returns a pin box containing an async future, that returns a byte vec
             fun: Box::new(move |req| {
-                let thread_tx = read_tx.clone();
+                let thread_session = read_session.clone();
                 async move {
                     let value = OS.as_bytes().to_vec();
                     println!("Read request {:?} with value {:x?}", &req, &value);
-                    if thread_tx
-                        .send(BluetoothMessage::PeerReadSsid)
-                        .await
+                    if thread_session
+                        .advance(BluetoothMessage::PeerReadOS)
                         .is_err()
                     {
                         return Err(ReqError::Failed);
@@ -53,13 +78,14 @@ fn get_os_characteristic(tx: mpsc::Sender<BluetoothMessage>) -> Characteristic {
             secure_write: true,
             method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
                 // let value = value_write.clone();
-                let thread_tx = write_tx.clone();
+                let thread_session = write_session.clone();
+                *peer_address.lock().expect("Couldn't lock peer address") =
+                    Some(req.device_address);
                 async move {
                     println!("Write request {:?} with value {:x?}", &req, &new_value);
                     let peer_os = String::from_utf8(new_value).expect("Peer OS was not UTF-8");
-                    if thread_tx
-                        .send(BluetoothMessage::PeerOS(peer_os))
-                        .await
+                    if thread_session
+                        .advance(BluetoothMessage::PeerOS(peer_os))
                         .is_err()
                     {
                         return Err(ReqError::Failed);
@@ -74,28 +100,33 @@ fn get_os_characteristic(tx: mpsc::Sender<BluetoothMessage>) -> Characteristic {
     }
 }
 
-fn get_ssid_characteristic(tx: mpsc::Sender<BluetoothMessage>, ssid: String) -> Characteristic {
-    let read_tx = tx.clone();
-    let write_tx = tx.clone();
+fn get_ssid_characteristic(
+    session: BluetoothSession,
+    ssid: Arc<Mutex<Option<String>>>,
+) -> Characteristic {
+    let read_session = session.clone();
+    let write_session = session.clone();
     Characteristic {
         uuid: Uuid::parse_str(SSID_CHARACTERISTIC_UUID).unwrap(),
         read: Some(CharacteristicRead {
             read: true,
             secure_read: true,
             fun: Box::new(move |req| {
-                let ssid = ssid.clone();
-                let thread_tx = read_tx.clone();
+                let ssid = ssid.lock().expect("Couldn't lock SSID").clone();
+                let thread_session = read_session.clone();
                 async move {
-                    let value = ssid.as_bytes().to_vec();
-                    println!("Read request {:?} with value {:x?}", &req, &value);
-                    if thread_tx
-                        .send(BluetoothMessage::PeerReadSsid)
-                        .await
+                    println!("Read request {:?}", &req);
+                    let ssid = match ssid {
+                        Some(s) => s,
+                        None => return Ok(NO_SSID.as_bytes().to_vec()),
+                    };
+                    if thread_session
+                        .advance(BluetoothMessage::PeerReadSsid)
                         .is_err()
                     {
                         return Err(ReqError::Failed);
                     }
-                    Ok(value)
+                    Ok(ssid.into_bytes())
                 }
                 .boxed()
             }),
@@ -106,13 +137,12 @@ fn get_ssid_characteristic(tx: mpsc::Sender<BluetoothMessage>, ssid: String) ->
             write_without_response: false,
             secure_write: true,
             method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
-                let thread_tx = write_tx.clone();
+                let thread_session = write_session.clone();
                 async move {
-                    println!("Write request {:?} with value {:x?}", &req, &new_value);
+                    println!("Write request {:?}", &req);
                     let peer_ssid = String::from_utf8(new_value).expect("Peer OS was not UTF-8");
-                    if thread_tx
-                        .send(BluetoothMessage::SSID(peer_ssid))
-                        .await
+                    if thread_session
+                        .advance(BluetoothMessage::SSID(peer_ssid))
                         .is_err()
                     {
                         return Err(ReqError::Failed);
@@ -128,30 +158,32 @@ fn get_ssid_characteristic(tx: mpsc::Sender<BluetoothMessage>, ssid: String) ->
 }
 
 fn get_password_characteristic(
-    tx: mpsc::Sender<BluetoothMessage>,
-    password: String,
+    session: BluetoothSession,
+    password: Arc<Mutex<Option<String>>>,
 ) -> Characteristic {
-    let read_tx = tx.clone();
-    let write_tx = tx.clone();
+    let read_session = session.clone();
+    let write_session = session.clone();
     Characteristic {
         uuid: Uuid::parse_str(PASSWORD_CHARACTERISTIC_UUID).unwrap(),
         read: Some(CharacteristicRead {
             read: true,
             secure_read: true,
             fun: Box::new(move |req| {
-                let password = password.clone();
-                let thread_tx = read_tx.clone();
+                let password = password.lock().expect("Couldn't lock password").clone();
+                let thread_session = read_session.clone();
                 async move {
-                    let value = password.as_bytes().to_vec();
-                    println!("Read request {:?} with value {:x?}", &req, &value);
-                    if thread_tx
-                        .send(BluetoothMessage::PeerReadPassword)
-                        .await
+                    println!("Read request {:?}", &req);
+                    let password = match password {
+                        Some(p) => p,
+                        None => return Ok(Vec::new()),
+                    };
+                    if thread_session
+                        .advance(BluetoothMessage::PeerReadPassword)
                         .is_err()
                     {
                         return Err(ReqError::Failed);
                     }
-                    Ok(value)
+                    Ok(password.into_bytes())
                 }
                 .boxed()
             }),
@@ -162,14 +194,13 @@ fn get_password_characteristic(
             write_without_response: false,
             secure_write: true,
             method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
-                let thread_tx = write_tx.clone();
+                let thread_session = write_session.clone();
                 async move {
-                    println!("Write request {:?} with value {:x?}", &req, &new_value);
+                    println!("Write request {:?}", &req);
                     let peer_password =
                         String::from_utf8(new_value).expect("Peer OS was not UTF-8");
-                    if thread_tx
-                        .send(BluetoothMessage::Password(peer_password))
-                        .await
+                    if thread_session
+                        .advance(BluetoothMessage::Password(peer_password))
                         .is_err()
                     {
                         return Err(ReqError::Failed);
@@ -184,25 +215,181 @@ fn get_password_characteristic(
     }
 }
 
+// reads get our capabilities. the central writes its own capabilities, which we keep for the main thread, then GATT_DONE.
+fn get_capabilities_characteristic(
+    session: BluetoothSession,
+    capabilities: Capabilities,
+    peer_capabilities: Arc<Mutex<Option<Capabilities>>>,
+) -> Characteristic {
+    Characteristic {
+        uuid: Uuid::parse_str(CAPABILITIES_CHARACTERISTIC_UUID).unwrap(),
+        read: Some(CharacteristicRead {
+            read: true,
+            secure_read: true,
+            fun: Box::new(move |req| {
+                let value = capabilities.encode().into_bytes();
+                async move {
+                    println!("Read request {:?} with value {:x?}", &req, &value);
+                    Ok(value)
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        write: Some(CharacteristicWrite {
+            write: true,
+            write_without_response: false,
+            secure_write: true,
+            method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
+                let thread_session = session.clone();
+                let peer_capabilities = peer_capabilities.clone();
+                async move {
+                    println!("Write request {:?} with value {:x?}", &req, &new_value);
+                    let value = String::from_utf8(new_value).map_err(|_| ReqError::Failed)?;
+                    if value == GATT_DONE {
+                        if thread_session.advance(BluetoothMessage::PeerDone).is_err() {
+                            return Err(ReqError::Failed);
+                        }
+                        return Ok(());
+                    }
+                    let capabilities = Capabilities::decode(&value).ok_or(ReqError::Failed)?;
+                    *peer_capabilities
+                        .lock()
+                        .expect("Couldn't lock peer capabilities") = Some(capabilities);
+                    Ok(())
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+// reads get our public key
+fn get_peripheral_key_characteristic(public_key: String) -> Characteristic {
+    Characteristic {
+        uuid: Uuid::parse_str(PERIPHERAL_KEY_CHARACTERISTIC_UUID).unwrap(),
+        read: Some(CharacteristicRead {
+            read: true,
+            secure_read: true,
+            fun: Box::new(move |req| {
+                let value = public_key.clone().into_bytes();
+                async move {
+                    println!("Read request {:?} with value {:x?}", &req, &value);
+                    Ok(value)
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+// the central writes its public key, which we keep for the main thread
+fn get_central_key_characteristic(peer_key: Arc<Mutex<Option<String>>>) -> Characteristic {
+    Characteristic {
+        uuid: Uuid::parse_str(CENTRAL_KEY_CHARACTERISTIC_UUID).unwrap(),
+        write: Some(CharacteristicWrite {
+            write: true,
+            write_without_response: false,
+            secure_write: true,
+            method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
+                let peer_key = peer_key.clone();
+                async move {
+                    println!("Write request {:?} with value {:x?}", &req, &new_value);
+                    let value = String::from_utf8(new_value).map_err(|_| ReqError::Failed)?;
+                    *peer_key.lock().expect("Couldn't lock peer key") = Some(value);
+                    Ok(())
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+// the central writes what it sends, and subscribes to notifications for what we send. only used if the files are small enough to skip WiFi.
+fn get_data_characteristic(control_handle: CharacteristicControlHandle) -> Characteristic {
+    Characteristic {
+        uuid: Uuid::parse_str(DATA_CHARACTERISTIC_UUID).unwrap(),
+        write: Some(CharacteristicWrite {
+            write_without_response: true,
+            secure_write: true,
+            method: CharacteristicWriteMethod::Io,
+            ..Default::default()
+        }),
+        notify: Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Io,
+            ..Default::default()
+        }),
+        control_handle,
+        ..Default::default()
+    }
+}
+
+// waits for the central to start writing to the data characteristic and to subscribe to it
+pub(crate) async fn accept_data(
+    mut control: CharacteristicControl,
+) -> Result<(CharacteristicReader, CharacteristicWriter), FCError> {
+    let mut reader = None;
+    let mut writer = None;
+    while reader.is_none() || writer.is_none() {
+        match control.next().await {
+            Some(CharacteristicControlEvent::Write(req)) => {
+                println!("Peer started writing to the data characteristic");
+                reader = Some(req.accept()?);
+            }
+            Some(CharacteristicControlEvent::Notify(w)) => {
+                println!("Peer subscribed to the data characteristic");
+                writer = Some(w);
+            }
+            None => Err(FCError {
+                message: "Data characteristic closed before the peer opened it".to_string(),
+            })?,
+        }
+    }
+    Ok((reader.unwrap(), writer.unwrap()))
+}
+
 pub(crate) async fn advertise(
-    tx: mpsc::Sender<BluetoothMessage>,
-    ssid: &str,
-    password: &str,
-) -> bluer::Result<(ApplicationHandle, AdvertisementHandle)> {
+    adapter: &Adapter,
+    session: BluetoothSession,
+    mode: &Mode,
+    capabilities: &Capabilities,
+    public_key: String,
+    state: PeripheralState,
+) -> bluer::Result<(
+    ApplicationHandle,
+    AdvertisementHandle,
+    CharacteristicControl,
+)> {
     let service_uuid = Uuid::parse_str(SERVICE_UUID).unwrap();
-    let session = bluer::Session::new().await?;
-    let adapter = session.default_adapter().await?;
-    adapter.set_powered(true).await?;
 
     println!(
         "Advertising on Bluetooth adapter {} with address {}",
         adapter.name(),
         adapter.address().await?
     );
+    // bluez puts the local name in the scan response
+    let max_length = match adapter.supported_advertising_capabilities().await? {
+        Some(c) => c.max_scan_response_length as usize,
+        None => LEGACY_ADVERTISEMENT_LENGTH,
+    };
+    let info = AdvertisementInfo {
+        os: OS.to_string(),
+        protocol_version: MAJOR_VERSION as u8,
+        sending: matches!(mode, Mode::Send(_)),
+        name: Some(capabilities.name.clone()),
+    };
     let le_advertisement = Advertisement {
         service_uuids: vec![service_uuid].into_iter().collect(),
+        manufacturer_data: [(ADVERTISEMENT_COMPANY_ID, info.encode())].into(),
         discoverable: Some(true),
-        local_name: Some("Flying Carpet".to_string()),
+        local_name: info.local_name(max_length),
         ..Default::default()
     };
     let adv_handle = adapter.advertise(le_advertisement).await?;
@@ -211,19 +398,28 @@ pub(crate) async fn advertise(
         "Serving GATT service on Bluetooth adapter {}",
         adapter.name()
     );
+    let (control, control_handle) = characteristic_control();
     let app = Application {
         services: vec![Service {
             uuid: service_uuid,
             primary: true,
             characteristics: vec![
-                get_os_characteristic(tx.clone()),
-                get_ssid_characteristic(tx.clone(), ssid.to_string()),
-                get_password_characteristic(tx, password.to_string()),
+                get_capabilities_characteristic(
+                    session.clone(),
+                    capabilities.clone(),
+                    state.peer_capabilities,
+                ),
+                get_peripheral_key_characteristic(public_key),
+                get_central_key_characteristic(state.peer_key),
+                get_os_characteristic(session.clone(), state.peer_address),
+                get_ssid_characteristic(session.clone(), state.ssid),
+                get_password_characteristic(session, state.password),
+                get_data_characteristic(control_handle),
             ],
             ..Default::default()
         }],
         ..Default::default()
     };
     let app_handle = adapter.serve_gatt_application(app).await?;
-    Ok((app_handle, adv_handle))
+    Ok((app_handle, adv_handle, control))
 }
@@ -0,0 +1,958 @@
+// the Bluetooth negotiation as a state machine shared by the Linux and Windows backends. the platforms' GATT and pairing
+// callbacks report what the peer did by advancing a BluetoothSession, which rejects anything that can't happen next, and
+// the negotiation waits for the session to reach the state it needs before taking its next step.
+// also the capabilities that both ends swap over GATT, and what they decide from them.
+
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::watch,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    error::FCError,
+    hotspot::{HotspotChannel, WiFiBand},
+    network,
+    utils::{is_compatible, timeout_error},
+    Mode, Peer, WiFiInterface, DEFAULT_PORT, MAJOR_VERSION, UI,
+};
+
+// what the platform callbacks report
+#[derive(Debug, PartialEq)]
+pub enum BluetoothMessage {
+    // central
+    Pin(String),
+    PairApproved,
+    PairSuccess,
+    AlreadyPaired,
+    UserCanceled,
+    // peripheral
+    StartedAdvertising,
+    PeerOS(String),
+    PeerReadOS,
+    SSID(String),
+    Password(String),
+    PeerReadSsid,
+    PeerReadPassword,
+    PeerDone,
+    // either
+    OtherError(String),
+}
+
+impl BluetoothMessage {
+    // for errors, so that they don't show the peer's credentials
+    fn name(&self) -> &'static str {
+        match self {
+            BluetoothMessage::Pin(_) => "a pairing PIN",
+            BluetoothMessage::PairApproved => "pairing approval",
+            BluetoothMessage::PairSuccess => "pairing success",
+            BluetoothMessage::AlreadyPaired => "already being paired",
+            BluetoothMessage::UserCanceled => "the user canceling",
+            BluetoothMessage::StartedAdvertising => "advertising starting",
+            BluetoothMessage::PeerOS(_) => "the peer writing its OS",
+            BluetoothMessage::PeerReadOS => "the peer reading our OS",
+            BluetoothMessage::SSID(_) => "the peer writing its SSID",
+            BluetoothMessage::Password(_) => "the peer writing its password",
+            BluetoothMessage::PeerReadSsid => "the peer reading our SSID",
+            BluetoothMessage::PeerReadPassword => "the peer reading our password",
+            BluetoothMessage::PeerDone => "the peer finishing",
+            BluetoothMessage::OtherError(_) => "an error",
+        }
+    }
+}
+
+// the peripheral's half of the GATT exchange, from when the central writes its OS. the central either reads our SSID and
+// password if we're hosting, or writes its own if it is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Exchange {
+    pub peer_os: String,
+    pub ssid: Option<String>,
+    pub password: Option<String>,
+    pub read_ssid: bool,
+    pub read_password: bool,
+}
+
+impl Exchange {
+    fn peer_hosting(&self) -> bool {
+        self.ssid.is_some() || self.password.is_some()
+    }
+
+    fn peer_joining(&self) -> bool {
+        self.read_ssid || self.read_password
+    }
+
+    pub fn finished(&self) -> bool {
+        (self.ssid.is_some() && self.password.is_some()) || (self.read_ssid && self.read_password)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NegotiationState {
+    // central
+    Scanning,
+    ConfirmingPin(String),
+    Pairing,
+    Paired,
+    // peripheral
+    StartingAdvertisement,
+    Advertising,
+    Exchanging(Exchange),
+    // the central said it's finished, which older versions and the mobile apps don't
+    Done(Exchange),
+    Failed(String),
+}
+
+impl NegotiationState {
+    fn description(&self) -> &'static str {
+        match self {
+            NegotiationState::Scanning => "scanning",
+            NegotiationState::ConfirmingPin(_) => "confirming the pairing PIN",
+            NegotiationState::Pairing => "pairing",
+            NegotiationState::Paired => "paired",
+            NegotiationState::StartingAdvertisement => "starting to advertise",
+            NegotiationState::Advertising => "advertising",
+            NegotiationState::Exchanging(_) => "exchanging WiFi details",
+            NegotiationState::Done(_) => "done",
+            NegotiationState::Failed(_) => "failed",
+        }
+    }
+
+    // what the central has done so far, once it's written its OS
+    pub fn exchange(&self) -> Option<&Exchange> {
+        match self {
+            NegotiationState::Exchanging(e) | NegotiationState::Done(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    // errors are for messages that can't happen in this state
+    fn next(&self, message: BluetoothMessage) -> Result<NegotiationState, String> {
+        use BluetoothMessage as M;
+        use NegotiationState as S;
+        match (self, message) {
+            (S::Failed(reason), _) => Err(reason.clone()),
+            (_, M::UserCanceled) => Ok(S::Failed("User canceled.".to_string())),
+            (_, M::OtherError(e)) => Ok(S::Failed(e)),
+            (S::Scanning, M::Pin(pin)) => Ok(S::ConfirmingPin(pin)),
+            (S::ConfirmingPin(_), M::PairApproved) => Ok(S::Pairing),
+            (S::Scanning | S::ConfirmingPin(_) | S::Pairing, M::PairSuccess | M::AlreadyPaired) => {
+                Ok(S::Paired)
+            }
+            // windows may report the advertisement starting more than once
+            (S::StartingAdvertisement | S::Advertising, M::StartedAdvertising) => {
+                Ok(S::Advertising)
+            }
+            // and a central that already knows us may connect before it reports it at all
+            (S::StartingAdvertisement | S::Advertising, M::PeerOS(os)) => {
+                Ok(S::Exchanging(Exchange {
+                    peer_os: os,
+                    ..Default::default()
+                }))
+            }
+            (S::StartingAdvertisement | S::Advertising | S::Exchanging(_), M::PeerReadOS) => {
+                Ok(self.clone())
+            }
+            (S::Exchanging(e), M::PeerReadSsid) if !e.peer_hosting() => {
+                Ok(S::Exchanging(Exchange {
+                    read_ssid: true,
+                    ..e.clone()
+                }))
+            }
+            (S::Exchanging(e), M::PeerReadPassword) if !e.peer_hosting() => {
+                Ok(S::Exchanging(Exchange {
+                    read_password: true,
+                    ..e.clone()
+                }))
+            }
+            (S::Exchanging(e), M::SSID(ssid)) if !e.peer_joining() => Ok(S::Exchanging(Exchange {
+                ssid: Some(ssid),
+                ..e.clone()
+            })),
+            (S::Exchanging(e), M::Password(password)) if !e.peer_joining() => {
+                Ok(S::Exchanging(Exchange {
+                    password: Some(password),
+                    ..e.clone()
+                }))
+            }
+            (S::Exchanging(e), M::PeerDone) if e.finished() => Ok(S::Done(e.clone())),
+            (state, message) => Err(format!(
+                "Unexpected Bluetooth event: {} while {}",
+                message.name(),
+                state.description()
+            )),
+        }
+    }
+
+    // tells the user what changed since the last state they were told about
+    fn announce<T: UI>(&self, previous: &NegotiationState, ui: &T) {
+        match self {
+            NegotiationState::ConfirmingPin(pin) => ui.show_pin(pin),
+            NegotiationState::Pairing => ui.output("Pairing approved."),
+            NegotiationState::Paired => ui.output("Successfully paired"),
+            NegotiationState::Advertising => ui.output("Started advertising Bluetooth service"),
+            NegotiationState::Exchanging(e) | NegotiationState::Done(e) => {
+                let before = previous.exchange().cloned().unwrap_or_default();
+                if before.peer_os.is_empty() {
+                    ui.output(&format!("Peer's OS is {}", e.peer_os));
+                }
+                if e.ssid.is_some() && before.ssid.is_none() {
+                    ui.output("Received peer's SSID");
+                }
+                if e.password.is_some() && before.password.is_none() {
+                    ui.output("Received peer's password");
+                }
+                if e.read_ssid && !before.read_ssid {
+                    ui.output("Peer read our SSID");
+                }
+                if e.read_password && !before.read_password {
+                    ui.output("Peer read our password");
+                }
+                if matches!(self, NegotiationState::Done(_)) {
+                    println!("Peer finished the Bluetooth exchange");
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+// cloned into every callback that reports on the peer
+#[derive(Clone)]
+pub struct BluetoothSession {
+    state: Arc<watch::Sender<NegotiationState>>,
+    // the last state the user was told about
+    announced: Arc<Mutex<NegotiationState>>,
+}
+
+impl BluetoothSession {
+    pub fn new(initial: NegotiationState) -> Self {
+        BluetoothSession {
+            state: Arc::new(watch::Sender::new(initial.clone())),
+            announced: Arc::new(Mutex::new(initial)),
+        }
+    }
+
+    pub fn state(&self) -> NegotiationState {
+        self.state.borrow().clone()
+    }
+
+    // a message that can't happen in the current state fails the session, since a peer that's out of step with us
+    // can't be trusted to finish the exchange
+    pub fn advance(&self, message: BluetoothMessage) -> Result<(), FCError> {
+        println!("Bluetooth event: {}", message.name());
+        let mut result = Ok(());
+        self.state.send_modify(|state| match state.next(message) {
+            Ok(next) => *state = next,
+            Err(reason) => {
+                println!("{}", reason);
+                result = Err(FCError {
+                    message: reason.clone(),
+                });
+                *state = NegotiationState::Failed(reason);
+            }
+        });
+        result
+    }
+
+    // waits until reached() is true of the session's state, or it fails
+    pub async fn wait_for<T: UI>(
+        &self,
+        reached: impl Fn(&NegotiationState) -> bool,
+        waiting_for: &str,
+        timeout: Duration,
+        ui: &T,
+    ) -> Result<NegotiationState, FCError> {
+        let deadline = Instant::now() + timeout;
+        let mut rx = self.state.subscribe();
+        loop {
+            let state = rx.borrow_and_update().clone();
+            {
+                let mut announced = self
+                    .announced
+                    .lock()
+                    .expect("Couldn't lock announced state");
+                if *announced != state {
+                    state.announce(&announced, ui);
+                    *announced = state.clone();
+                }
+            }
+            if let NegotiationState::Failed(reason) = &state {
+                Err(FCError {
+                    message: reason.clone(),
+                })?
+            }
+            if reached(&state) {
+                return Ok(state);
+            }
+            if timeout_at(deadline, rx.changed()).await.is_err() {
+                Err(timeout_error(
+                    timeout,
+                    &format!("waiting for {}", waiting_for),
+                ))?
+            }
+        }
+    }
+
+    // waits until reached() is true of what the central has done, once it's written its OS
+    pub async fn wait_for_exchange<T: UI>(
+        &self,
+        reached: impl Fn(&Exchange) -> bool,
+        waiting_for: &str,
+        timeout: Duration,
+        ui: &T,
+    ) -> Result<Exchange, FCError> {
+        let state = self
+            .wait_for(
+                |s| s.exchange().is_some_and(&reached),
+                waiting_for,
+                timeout,
+                ui,
+            )
+            .await?;
+        Ok(state
+            .exchange()
+            .cloned()
+            .expect("Exchange missing from state"))
+    }
+}
+
+// value of the capabilities characteristic, which peers speaking GATT_PROTOCOL_VERSION 1 or later serve. the central reads
+// the peripheral's and writes its own back before the OS exchange. once it has the WiFi credentials, it writes GATT_DONE
+// so that the peripheral knows when it can stop serving, instead of both sides sleeping between steps. peers without the
+// characteristic are older versions or the mobile apps, and get the old flow.
+// encoded as key=value lines so that later versions can add keys. unknown keys are ignored.
+pub(crate) const GATT_PROTOCOL_VERSION: u8 = 1;
+pub(crate) const GATT_DONE: &str = "done";
+// android serves this before its hotspot is up and it knows the SSID. we serve it until our user has confirmed the PIN.
+pub(crate) const NO_SSID: &str = "NONE";
+
+// which end of the WiFi connection a device can be
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    // can't start a hotspot
+    Join,
+    Either,
+}
+
+impl Role {
+    pub fn for_interface(interface: &WiFiInterface) -> Self {
+        match interface.ap {
+            Some(false) => Role::Join,
+            _ => Role::Either,
+        }
+    }
+}
+
+// who starts the hotspot, and where the peer would listen and start its hotspot if that's the peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerHosting {
+    pub hosts: bool,
+    pub port: u16,
+    pub hotspot: HotspotChannel,
+    // from the peer's capabilities, None for peers without them
+    pub transfer_version: Option<u64>,
+}
+
+impl PeerHosting {
+    // both ends decide this from the capabilities they swapped, as with is_ble_transfer(), so they agree without another
+    // step
+    pub(crate) fn negotiate(
+        ours: &Capabilities,
+        peer: Option<&Capabilities>,
+        peer_os: &Peer,
+        mode: &Mode,
+    ) -> Result<Self, FCError> {
+        Ok(PeerHosting {
+            hosts: peer_hosts(
+                ours.preferred_role,
+                peer.map(|p| p.preferred_role),
+                peer_os,
+                mode,
+            )?,
+            port: peer.map_or(DEFAULT_PORT, |p| p.port),
+            hotspot: peer.map_or(HotspotChannel::default(), |p| p.hotspot),
+            transfer_version: peer.map(|p| p.transfer_version),
+        })
+    }
+}
+
+// a device that can't host joins. otherwise both follow network::is_hosting(), as the mobile apps do. peer_role is None
+// for peers without capabilities, which don't act on ours.
+pub(crate) fn peer_hosts(
+    ours: Role,
+    peer_role: Option<Role>,
+    peer_os: &Peer,
+    mode: &Mode,
+) -> Result<bool, FCError> {
+    let by_table = !network::is_hosting(peer_os, mode);
+    Ok(match (ours, peer_role) {
+        (Role::Join, Some(Role::Join)) => Err(FCError {
+            message: "Neither device's WiFi card can host a hotspot".to_string(),
+        })?,
+        (Role::Join, Some(_)) => true,
+        (Role::Join, None) if !by_table => Err(FCError {
+            message: "This device's WiFi card can't host a hotspot, and the other device's version of Flying Carpet can't host in its place".to_string(),
+        })?,
+        (Role::Either, Some(Role::Join)) => false,
+        _ => by_table,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Capabilities {
+    pub gatt_version: u8,
+    pub transfer_version: u64,
+    // bands we can host a hotspot on, empty if we don't know
+    pub bands: Vec<WiFiBand>,
+    pub name: String,
+    pub preferred_role: Role,
+    // largest transfer in bytes we'll make over Bluetooth alone, 0 if we won't
+    pub ble_limit: u64,
+    // set by a sender whose files fit under its own ble_limit
+    pub payload: Option<u64>,
+    // the TCP port we'd listen on if we end up hosting. peers that don't say listen on DEFAULT_PORT.
+    pub port: u16,
+    // where we'd start a hotspot if we end up hosting, so that the peer only has to look there
+    pub hotspot: HotspotChannel,
+}
+
+impl Capabilities {
+    pub(crate) fn ours(name: String) -> Self {
+        Capabilities {
+            gatt_version: GATT_PROTOCOL_VERSION,
+            transfer_version: MAJOR_VERSION,
+            bands: vec![],
+            name,
+            preferred_role: Role::Either,
+            ble_limit: 0,
+            payload: None,
+            port: DEFAULT_PORT,
+            hotspot: HotspotChannel::default(),
+        }
+    }
+
+    pub(crate) fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub(crate) fn with_role(mut self, role: Role) -> Self {
+        self.preferred_role = role;
+        self
+    }
+
+    pub(crate) fn with_hotspot(mut self, hotspot: HotspotChannel, bands: &[WiFiBand]) -> Self {
+        self.hotspot = hotspot;
+        self.bands = bands.to_vec();
+        self
+    }
+
+    // offers our files for a Bluetooth-only transfer if we're sending and they're small enough
+    pub(crate) fn with_ble_limit(mut self, ble_limit: u64, mode: &Mode) -> Self {
+        self.ble_limit = ble_limit;
+        if let Mode::Send(files) = mode {
+            let size = files
+                .iter()
+                .map(|f| fs::metadata(f).map_or(u64::MAX, |m| m.len()))
+                .fold(0u64, u64::saturating_add);
+            if size <= ble_limit {
+                self.payload = Some(size);
+            }
+        }
+        self
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        let bands: Vec<&str> = self.bands.iter().map(WiFiBand::as_str).collect();
+        let role = match self.preferred_role {
+            Role::Join => "join",
+            Role::Either => "either",
+        };
+        let mut value = format!(
+            "gatt={}\ntransfer={}\nbands={}\nrole={}\nble_limit={}\nport={}\n",
+            self.gatt_version,
+            self.transfer_version,
+            bands.join(","),
+            role,
+            self.ble_limit,
+            self.port,
+        );
+        if let Some(payload) = self.payload {
+            value += &format!("payload={}\n", payload);
+        }
+        if let Some(band) = self.hotspot.band {
+            value += &format!("hotspot_band={}\n", band.as_str());
+        }
+        if let Some(channel) = self.hotspot.channel {
+            value += &format!("hotspot_channel={}\n", channel);
+        }
+        value + "name=" + &self.name.replace(['\r', '\n'], " ")
+    }
+
+    // None unless both versions are there
+    pub(crate) fn decode(value: &str) -> Option<Self> {
+        let mut gatt_version = None;
+        let mut transfer_version = None;
+        let mut capabilities = Capabilities::ours(String::new());
+        for (key, value) in value.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "gatt" => gatt_version = value.parse().ok(),
+                "transfer" => transfer_version = value.parse().ok(),
+                "bands" => {
+                    capabilities.bands = value.split(',').filter_map(WiFiBand::parse).collect()
+                }
+                "role" => {
+                    capabilities.preferred_role = match value {
+                        "join" => Role::Join,
+                        _ => Role::Either,
+                    }
+                }
+                "ble_limit" => capabilities.ble_limit = value.parse().unwrap_or(0),
+                "payload" => capabilities.payload = value.parse().ok(),
+                "port" => capabilities.port = value.parse().unwrap_or(DEFAULT_PORT),
+                "hotspot_band" => capabilities.hotspot.band = WiFiBand::parse(value),
+                "hotspot_channel" => capabilities.hotspot.channel = value.parse().ok(),
+                "name" => capabilities.name = value.to_string(),
+                _ => (),
+            }
+        }
+        capabilities.gatt_version = gatt_version?;
+        capabilities.transfer_version = transfer_version?;
+        // a channel we can't place is no help to a peer looking for the hotspot
+        if capabilities.hotspot.check().is_err() {
+            capabilities.hotspot = HotspotChannel::default();
+        }
+        Some(capabilities)
+    }
+
+    // tells the user who they're connected to, and fails now rather than after setting up WiFi if we can't transfer
+    pub(crate) fn check<T: UI>(&self, ui: &T) -> Result<(), FCError> {
+        ui.output(&format!(
+            "Peer {} supports transfer protocol version {}",
+            self.name, self.transfer_version
+        ));
+        if self.transfer_version < MAJOR_VERSION && !is_compatible(self.transfer_version) {
+            Err(FCError {
+                message: format!("Peer's version {} not compatible, please update Flying Carpet to the latest version on both devices.", self.transfer_version),
+            })?
+        }
+        Ok(())
+    }
+}
+
+// every peer with capabilities swaps public keys, so one that has capabilities but no key characteristics, or doesn't
+// write its key, had them stripped by someone in between who wants the WiFi credentials in the clear. only peers too
+// old for capabilities get them unencrypted.
+pub(crate) fn check_key_exchange(
+    peer_capabilities: Option<&Capabilities>,
+    peer_has_key: bool,
+) -> Result<(), FCError> {
+    if peer_capabilities.is_some() && !peer_has_key {
+        Err(FCError {
+            message: "The peer's version of Flying Carpet encrypts the WiFi details, but it didn't exchange keys. The connection may have been tampered with.".to_string(),
+        })?
+    }
+    Ok(())
+}
+
+// both ends decide this from the capabilities they swapped, so they agree without another step. only the sender has a
+// payload, and it may be either end of the Bluetooth connection.
+pub(crate) fn is_ble_transfer(ours: &Capabilities, peer: &Capabilities) -> bool {
+    match (ours.payload, peer.payload) {
+        (Some(size), None) | (None, Some(size)) => size <= ours.ble_limit && size <= peer.ble_limit,
+        _ => false,
+    }
+}
+
+// transfers up to this many bytes skip WiFi and go over Bluetooth when both ends allow it
+pub const DEFAULT_BLE_TRANSFER_LIMIT: u64 = 64 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_key_exchange, is_ble_transfer, peer_hosts, BluetoothMessage as M, BluetoothSession,
+        Capabilities, Exchange, NegotiationState as S, PeerHosting, Role,
+    };
+    use crate::hotspot::{HotspotChannel, WiFiBand};
+    use crate::{Mode, Peer, DEFAULT_PORT, UI};
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct TestUI {
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl UI for TestUI {
+        fn output(&self, msg: &str) {
+            self.messages.lock().unwrap().push(msg.to_string());
+        }
+        fn show_progress_bar(&self) {}
+        fn update_progress_bar(&self, _percent: u8) {}
+        fn enable_ui(&self) {}
+        fn show_pin(&self, pin: &str) {
+            self.output(&format!("Showing PIN {}", pin));
+        }
+        fn show_bluetooth_devices(&self, _devices: &[crate::BluetoothDevice]) {}
+        fn show_lan_peers(&self, _peers: &[crate::LanPeer]) {}
+    }
+
+    // runs the messages through the state machine, returning the last state or the first rejection
+    fn run(start: S, messages: Vec<M>) -> Result<S, String> {
+        messages
+            .into_iter()
+            .try_fold(start, |state, m| state.next(m))
+    }
+
+    fn exchanged(peer_os: &str) -> Exchange {
+        Exchange {
+            peer_os: peer_os.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn central_pairs() {
+        let state = run(
+            S::Scanning,
+            vec![
+                M::Pin("123456".to_string()),
+                M::PairApproved,
+                M::PairSuccess,
+            ],
+        );
+        assert_eq!(state, Ok(S::Paired));
+        assert_eq!(run(S::Scanning, vec![M::AlreadyPaired]), Ok(S::Paired));
+        let state = run(S::Scanning, vec![M::Pin("123456".to_string())]);
+        assert_eq!(state, Ok(S::ConfirmingPin("123456".to_string())));
+    }
+
+    #[test]
+    fn central_canceled() {
+        let state = run(
+            S::Scanning,
+            vec![M::Pin("123456".to_string()), M::UserCanceled],
+        );
+        assert_eq!(state, Ok(S::Failed("User canceled.".to_string())));
+        let state = run(
+            S::Scanning,
+            vec![M::OtherError("ConnectionRejected".to_string())],
+        );
+        assert_eq!(state, Ok(S::Failed("ConnectionRejected".to_string())));
+    }
+
+    #[test]
+    fn peripheral_hosts() {
+        let state = run(
+            S::StartingAdvertisement,
+            vec![
+                M::StartedAdvertising,
+                M::PeerReadOS,
+                M::PeerOS("android".to_string()),
+                M::PeerReadSsid,
+                M::PeerReadPassword,
+                M::PeerDone,
+            ],
+        );
+        assert_eq!(
+            state,
+            Ok(S::Done(Exchange {
+                read_ssid: true,
+                read_password: true,
+                ..exchanged("android")
+            }))
+        );
+    }
+
+    #[test]
+    fn peripheral_joins() {
+        let state = run(
+            S::Advertising,
+            vec![
+                M::PeerOS("windows".to_string()),
+                M::SSID("flyingCarpet_1234".to_string()),
+                M::Password("password".to_string()),
+            ],
+        );
+        let exchange = Exchange {
+            ssid: Some("flyingCarpet_1234".to_string()),
+            password: Some("password".to_string()),
+            ..exchanged("windows")
+        };
+        assert!(exchange.finished());
+        assert_eq!(state, Ok(S::Exchanging(exchange)));
+    }
+
+    #[test]
+    fn illegal_transitions() {
+        // reading our OS isn't reading our SSID
+        let state = run(S::Advertising, vec![M::PeerReadOS, M::PeerReadSsid]);
+        assert!(state
+            .unwrap_err()
+            .contains("the peer reading our SSID while advertising"));
+        // done before the WiFi details are exchanged
+        let state = run(
+            S::Advertising,
+            vec![M::PeerOS("linux".to_string()), M::PeerReadSsid, M::PeerDone],
+        );
+        assert!(state.is_err());
+        // writing its own SSID after reading ours
+        let state = run(
+            S::Exchanging(exchanged("linux")),
+            vec![M::PeerReadSsid, M::SSID("flyingCarpet_1234".to_string())],
+        );
+        assert!(state.is_err());
+        // writing its OS twice
+        let state = run(
+            S::Advertising,
+            vec![
+                M::PeerOS("linux".to_string()),
+                M::PeerOS("linux".to_string()),
+            ],
+        );
+        assert!(state.is_err());
+        // a central's message to a peripheral, and the other way around
+        assert!(run(S::Advertising, vec![M::Pin("123456".to_string())]).is_err());
+        assert!(run(S::Scanning, vec![M::PeerOS("linux".to_string())]).is_err());
+        assert!(run(S::Paired, vec![M::PairApproved]).is_err());
+        // nothing gets a session out of failing
+        let state = run(
+            S::Failed("Pairing failed.".to_string()),
+            vec![M::AlreadyPaired],
+        );
+        assert_eq!(state, Err("Pairing failed.".to_string()));
+    }
+
+    #[test]
+    fn credentials_not_in_errors() {
+        // a peer that's written its password can't then read ours
+        let reason = run(
+            S::Advertising,
+            vec![
+                M::PeerOS("linux".to_string()),
+                M::Password("hunter22".to_string()),
+                M::PeerReadSsid,
+            ],
+        )
+        .unwrap_err();
+        assert!(!reason.contains("hunter22"));
+        let reason = run(S::Scanning, vec![M::Password("hunter22".to_string())]).unwrap_err();
+        assert!(!reason.contains("hunter22"));
+    }
+
+    #[tokio::test]
+    async fn session_waits() {
+        let session = BluetoothSession::new(S::StartingAdvertisement);
+        let ui = TestUI::default();
+        let callback = session.clone();
+        tokio::spawn(async move {
+            callback.advance(M::StartedAdvertising).unwrap();
+            callback.advance(M::PeerOS("ios".to_string())).unwrap();
+            callback
+                .advance(M::SSID("flyingCarpet_1234".to_string()))
+                .unwrap();
+            callback
+                .advance(M::Password("password".to_string()))
+                .unwrap();
+        });
+        let exchange = session
+            .wait_for_exchange(
+                Exchange::finished,
+                "the peer to write its WiFi details",
+                Duration::from_secs(5),
+                &ui,
+            )
+            .await
+            .unwrap();
+        assert_eq!(exchange.peer_os, "ios");
+        assert_eq!(exchange.password, Some("password".to_string()));
+        let messages = ui.messages.lock().unwrap();
+        assert!(messages.contains(&"Peer's OS is ios".to_string()));
+        assert!(messages.contains(&"Received peer's password".to_string()));
+    }
+
+    #[tokio::test]
+    async fn session_shows_pin() {
+        let session = BluetoothSession::new(S::Scanning);
+        let ui = TestUI::default();
+        session.advance(M::Pin("654321".to_string())).unwrap();
+        let state = session
+            .wait_for(
+                |s| matches!(s, S::ConfirmingPin(_) | S::Paired),
+                "a pairing PIN",
+                Duration::from_secs(5),
+                &ui,
+            )
+            .await
+            .unwrap();
+        assert_eq!(state, S::ConfirmingPin("654321".to_string()));
+        assert_eq!(*ui.messages.lock().unwrap(), vec!["Showing PIN 654321"]);
+    }
+
+    #[tokio::test]
+    async fn session_fails() {
+        let session = BluetoothSession::new(S::Advertising);
+        let ui = TestUI::default();
+        assert!(session.advance(M::PeerDone).is_err());
+        assert!(matches!(session.state(), S::Failed(_)));
+        let error = session
+            .wait_for(
+                |s| s.exchange().is_some(),
+                "the peer to connect and write its OS",
+                Duration::from_secs(5),
+                &ui,
+            )
+            .await
+            .unwrap_err();
+        assert!(error
+            .message
+            .contains("the peer finishing while advertising"));
+    }
+
+    #[tokio::test]
+    async fn session_times_out() {
+        let session = BluetoothSession::new(S::Advertising);
+        let error = session
+            .wait_for(
+                |s| s.exchange().is_some(),
+                "the peer to connect and write its OS",
+                Duration::from_millis(50),
+                &TestUI::default(),
+            )
+            .await
+            .unwrap_err();
+        assert!(error
+            .message
+            .ends_with("waiting for the peer to connect and write its OS"));
+    }
+
+    #[test]
+    fn capabilities() {
+        let capabilities = Capabilities {
+            gatt_version: 1,
+            transfer_version: 10,
+            bands: vec![WiFiBand::TwoPointFour, WiFiBand::Five],
+            name: "théron's laptop".to_string(),
+            preferred_role: Role::Join,
+            ble_limit: 64 * 1024,
+            payload: Some(1000),
+            port: 49152,
+            hotspot: HotspotChannel {
+                band: Some(WiFiBand::Five),
+                channel: Some(36),
+            },
+        };
+        assert_eq!(
+            Capabilities::decode(&capabilities.encode()),
+            Some(capabilities)
+        );
+
+        // newer peers may add keys and bands we don't know about
+        let decoded =
+            Capabilities::decode("gatt=2\ntransfer=11\nbands=5,60\nlatency=low\nname=a=b").unwrap();
+        assert_eq!(decoded.gatt_version, 2);
+        assert_eq!(decoded.transfer_version, 11);
+        assert_eq!(decoded.bands, vec![WiFiBand::Five]);
+        assert_eq!(decoded.preferred_role, Role::Either);
+        assert_eq!(decoded.name, "a=b");
+        // and older ones never skip WiFi
+        assert_eq!(decoded.ble_limit, 0);
+        assert_eq!(decoded.payload, None);
+        assert_eq!(decoded.port, DEFAULT_PORT);
+        assert_eq!(decoded.hotspot, HotspotChannel::default());
+        // nor send us looking for channels that aren't there
+        let decoded =
+            Capabilities::decode("gatt=1\ntransfer=10\nhotspot_band=2.4\nhotspot_channel=36");
+        assert_eq!(decoded.unwrap().hotspot, HotspotChannel::default());
+
+        // a newline in the name can't inject a key
+        let capabilities = Capabilities {
+            name: "laptop\ntransfer=1".to_string(),
+            ..Capabilities::ours(String::new())
+        };
+        let decoded = Capabilities::decode(&capabilities.encode()).unwrap();
+        assert_eq!(decoded.transfer_version, capabilities.transfer_version);
+
+        assert_eq!(
+            Capabilities::decode("transfer=10\nname=no gatt version"),
+            None
+        );
+        assert_eq!(Capabilities::decode("windows"), None);
+    }
+
+    #[test]
+    fn key_downgrade() {
+        let peer = Capabilities::ours("peer".to_string());
+        assert!(check_key_exchange(Some(&peer), true).is_ok());
+        // stripping the key characteristics from a peer with capabilities doesn't get the credentials in the clear
+        let error = check_key_exchange(Some(&peer), false).unwrap_err();
+        assert!(error.message.contains("tampered"));
+        // older versions and the mobile apps have neither
+        assert!(check_key_exchange(None, false).is_ok());
+    }
+
+    #[test]
+    fn hosting() {
+        let receive = Mode::Receive(PathBuf::new());
+        let send = Mode::Send(vec![]);
+        // between two desktops on the same OS, the receiver hosts unless it can't
+        let desktop = Peer::from(crate::bluetooth::OS);
+        assert!(!peer_hosts(Role::Either, Some(Role::Either), &desktop, &receive).unwrap());
+        assert!(peer_hosts(Role::Either, Some(Role::Either), &desktop, &send).unwrap());
+        assert!(peer_hosts(Role::Join, Some(Role::Either), &desktop, &receive).unwrap());
+        assert!(!peer_hosts(Role::Either, Some(Role::Join), &desktop, &send).unwrap());
+        assert!(peer_hosts(Role::Join, Some(Role::Join), &desktop, &send).is_err());
+        // a peer that can't hear our role hosts only if it would anyway
+        assert!(peer_hosts(Role::Join, None, &desktop, &send).unwrap());
+        assert!(peer_hosts(Role::Join, None, &desktop, &receive).is_err());
+        assert!(peer_hosts(Role::Join, None, &Peer::Android, &send).is_err());
+        assert!(peer_hosts(Role::Either, None, &desktop, &send).unwrap());
+
+        // a peer with capabilities hosts in our place, on the port it said
+        let ours = Capabilities::ours(String::new()).with_role(Role::Join);
+        let peer = Capabilities::ours(String::new()).with_port(50000);
+        let hosting = PeerHosting::negotiate(&ours, Some(&peer), &desktop, &receive).unwrap();
+        assert!(hosting.hosts);
+        assert_eq!(hosting.port, 50000);
+        assert_eq!(hosting.transfer_version, Some(peer.transfer_version));
+
+        // one without listens on DEFAULT_PORT
+        let ours = Capabilities::ours(String::new());
+        let hosting = PeerHosting::negotiate(&ours, None, &Peer::Android, &send).unwrap();
+        assert_eq!(hosting.port, DEFAULT_PORT);
+        assert_eq!(hosting.transfer_version, None);
+    }
+
+    #[test]
+    fn ble_transfer() {
+        let sender = Capabilities {
+            ble_limit: 2000,
+            payload: Some(1000),
+            ..Capabilities::ours(String::new())
+        };
+        let receiver = Capabilities {
+            ble_limit: 1000,
+            ..Capabilities::ours(String::new())
+        };
+        assert!(is_ble_transfer(&sender, &receiver));
+        // the receiver may be the peripheral
+        assert!(is_ble_transfer(&receiver, &sender));
+        let receiver = Capabilities {
+            ble_limit: 999,
+            ..receiver
+        };
+        assert!(!is_ble_transfer(&sender, &receiver));
+        let sender = Capabilities {
+            payload: None,
+            ..sender
+        };
+        assert!(!is_ble_transfer(&sender, &sender));
+    }
+}
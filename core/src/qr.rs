@@ -0,0 +1,258 @@
+// the QR code a hosting device shows so that the other end can start the transfer without typing anything. it holds a URI:
+//
+//     flyingcarpet://pair?v=10&os=linux&ssid=flyingCarpet_1a2b&password=aB3dE5fG&port=3290
+//
+// v is the transfer protocol version (MAJOR_VERSION) and os is the OS of the device showing the code, as in
+// Peer::from(). ssid is the hotspot it starts, derived from the password unless the host picked its own, and port is
+// where it listens for the TCP connection. values are percent-encoded, and unknown keys are ignored so that later
+// versions can add them. the mobile apps predate the URI: they show and scan either the password alone or
+// "ssid;password", so parse() accepts those too, and payload() still gives them the password alone.
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{
+    render::{svg, unicode::Dense1x2},
+    Color, QrCode,
+};
+
+use crate::{bluetooth::OS, error::FCError, utils::get_key_and_ssid, DEFAULT_PORT, MAJOR_VERSION};
+
+const SCHEME: &str = "flyingcarpet://pair?";
+// modules of white space around the code, which scanners need to find it
+const QUIET_ZONE: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairingCode {
+    // None when scanned from a mobile app, which doesn't say
+    pub version: Option<u64>,
+    pub os: Option<String>,
+    pub ssid: String,
+    pub password: String,
+    pub port: u16,
+}
+
+impl PairingCode {
+    // ours, for the hotspot the password gives
+    pub fn new(password: &str, port: u16) -> Self {
+        let (_, ssid) = get_key_and_ssid(password);
+        PairingCode {
+            version: Some(MAJOR_VERSION),
+            os: Some(OS.to_string()),
+            ssid,
+            password: password.to_string(),
+            port,
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        let encode = |value: &str| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
+        let mut params = vec![];
+        if let Some(version) = self.version {
+            params.push(format!("v={}", version));
+        }
+        if let Some(os) = &self.os {
+            params.push(format!("os={}", encode(os)));
+        }
+        params.push(format!("ssid={}", encode(&self.ssid)));
+        params.push(format!("password={}", encode(&self.password)));
+        params.push(format!("port={}", self.port));
+        SCHEME.to_string() + &params.join("&")
+    }
+
+    // what to put in the QR code for the peer. the mobile apps only understand the password.
+    pub fn payload(&self, peer: &str) -> String {
+        match peer {
+            "android" | "ios" => self.password.clone(),
+            _ => self.to_uri(),
+        }
+    }
+
+    // takes whatever was scanned: our URI, or the mobile apps' password or "ssid;password"
+    pub fn parse(text: &str) -> Result<Self, FCError> {
+        let text = text.trim();
+        let Some(query) = text.strip_prefix(SCHEME) else {
+            let (ssid, password) = match text.split_once(';') {
+                Some((ssid, password)) => (ssid.to_string(), password),
+                None => (get_key_and_ssid(text).1, text),
+            };
+            if password.is_empty() {
+                Err(FCError {
+                    message: "QR code has no password".to_string(),
+                })?
+            }
+            return Ok(PairingCode {
+                version: None,
+                os: None,
+                ssid,
+                password: password.to_string(),
+                port: DEFAULT_PORT,
+            });
+        };
+
+        let mut version = None;
+        let mut os = None;
+        let mut ssid = None;
+        let mut password = None;
+        let mut port = DEFAULT_PORT;
+        for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            let value = match percent_decode_str(value).decode_utf8() {
+                Ok(v) => v.to_string(),
+                Err(_) => Err(FCError {
+                    message: format!("QR code has an invalid {}", key),
+                })?,
+            };
+            match key {
+                "v" => version = value.parse().ok(),
+                "os" => os = Some(value),
+                "ssid" => ssid = Some(value),
+                "password" => password = Some(value),
+                "port" => {
+                    port = match value.parse() {
+                        Ok(p) if p != 0 => p,
+                        _ => Err(FCError {
+                            message: format!("QR code has an invalid port: {}", value),
+                        })?,
+                    }
+                }
+                _ => (),
+            }
+        }
+        let password = match password {
+            Some(p) if !p.is_empty() => p,
+            _ => Err(FCError {
+                message: "QR code has no password".to_string(),
+            })?,
+        };
+        Ok(PairingCode {
+            version,
+            os,
+            ssid: ssid.unwrap_or_else(|| get_key_and_ssid(&password).1),
+            password,
+            port,
+        })
+    }
+}
+
+impl From<qrcode::types::QrError> for FCError {
+    fn from(value: qrcode::types::QrError) -> Self {
+        FCError {
+            message: format!("Couldn't make QR code: {}", value),
+        }
+    }
+}
+
+impl From<png::EncodingError> for FCError {
+    fn from(value: png::EncodingError) -> Self {
+        FCError {
+            message: format!("Couldn't encode QR code as PNG: {}", value),
+        }
+    }
+}
+
+// scales to fit whatever displays it
+pub fn to_svg(text: &str) -> Result<String, FCError> {
+    let code = QrCode::new(text)?;
+    Ok(code.render::<svg::Color>().min_dimensions(150, 150).build())
+}
+
+// grayscale, module_size pixels per module
+pub fn to_png(text: &str, module_size: u32) -> Result<Vec<u8>, FCError> {
+    let code = QrCode::new(text)?;
+    let colors = code.to_colors();
+    let width = code.width();
+    let module_size = module_size.max(1) as usize;
+    let side = (width + 2 * QUIET_ZONE) * module_size;
+    let mut pixels = vec![255u8; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let (x, y) = (i % width + QUIET_ZONE, i / width + QUIET_ZONE);
+        for row in y * module_size..(y + 1) * module_size {
+            pixels[row * side + x * module_size..row * side + (x + 1) * module_size].fill(0);
+        }
+    }
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(png)
+}
+
+// two modules per character for a terminal. the colors are inverted, so that it scans on the usual dark background.
+pub fn to_unicode(text: &str) -> Result<String, FCError> {
+    let code = QrCode::new(text)?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_round_trip() {
+        let code = PairingCode {
+            port: 50000,
+            ..PairingCode::new("aB3 dE&5=fG", DEFAULT_PORT)
+        };
+        let uri = code.to_uri();
+        assert!(uri.starts_with("flyingcarpet://pair?v=10&os="));
+        assert!(uri.contains("&password=aB3%20dE%265%3DfG&port=50000"));
+        assert_eq!(PairingCode::parse(&uri).unwrap(), code);
+        assert_eq!(code.payload("ios"), "aB3 dE&5=fG");
+        assert_eq!(code.payload("windows"), uri);
+
+        // later versions may add keys, and leave out ones that can be derived
+        let parsed =
+            PairingCode::parse("flyingcarpet://pair?password=password&hint=x&v=11").unwrap();
+        assert_eq!(parsed.ssid, get_key_and_ssid("password").1);
+        assert_eq!(parsed.version, Some(11));
+        assert_eq!(parsed.port, DEFAULT_PORT);
+
+        assert!(PairingCode::parse("flyingcarpet://pair?v=10&ssid=x").is_err());
+        assert!(PairingCode::parse("flyingcarpet://pair?password=a&port=0").is_err());
+        assert!(PairingCode::parse("flyingcarpet://pair?password=%FF").is_err());
+    }
+
+    #[test]
+    fn mobile_payloads() {
+        let parsed = PairingCode::parse("aB3dE5fG").unwrap();
+        assert_eq!(parsed.ssid, get_key_and_ssid("aB3dE5fG").1);
+        assert_eq!(parsed.password, "aB3dE5fG");
+        assert_eq!(parsed.version, None);
+
+        let parsed = PairingCode::parse("DIRECT-xy-Android;aB3dE5fG").unwrap();
+        assert_eq!(parsed.ssid, "DIRECT-xy-Android");
+        assert_eq!(parsed.password, "aB3dE5fG");
+
+        assert!(PairingCode::parse("ssid;").is_err());
+        assert!(PairingCode::parse("").is_err());
+    }
+
+    #[test]
+    fn renders() {
+        let uri = PairingCode::new("aB3dE5fG", DEFAULT_PORT).to_uri();
+        let width = QrCode::new(&uri).unwrap().width();
+
+        assert!(to_svg(&uri).unwrap().starts_with("<?xml"));
+
+        let png = to_png(&uri, 3).unwrap();
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width as usize, (width + 2 * QUIET_ZONE) * 3);
+
+        // half as many lines as modules, with the quiet zone
+        let unicode = to_unicode(&uri).unwrap();
+        assert_eq!(
+            unicode.lines().count(),
+            (width + 2 * QUIET_ZONE).div_ceil(2)
+        );
+    }
+}
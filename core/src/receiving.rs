@@ -1,34 +1,55 @@
-use crate::{utils, FCError, UI};
-use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use crate::{
+    crypto::{check_length, chunk_aad, Session, LEGACY_MAX_CHUNK_SIZE, MAX_CHUNK_SIZE},
+    utils, FCError, Stream, UI,
+};
 use core::time;
 use std::{
     fs,
     io::Write,
-    path::Path,
+    path::{Component, Path, PathBuf},
     time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::AsyncReadExt,
     time::{sleep, timeout},
 };
 
+// deletes a file that's still being received if the transfer fails or is canceled, so we never leave a truncated file behind
+struct PartialFile {
+    path: PathBuf,
+    complete: bool,
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.complete && self.path.is_file() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                println!(
+                    "Could not remove partial file {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
 pub async fn receive_file<T: UI>(
     folder: &Path,
-    key: &[u8],
-    stream: &mut TcpStream,
+    file_index: u64,
+    session: &mut Session,
+    stream: &mut impl Stream,
     ui: &T,
     last_file: bool,
 ) -> Result<(), FCError> {
     let folder = folder.to_owned();
-    let cipher = Aes256Gcm::new_from_slice(key).expect("Invalid AES-256-GCM key length");
     let start = Instant::now();
 
     // check destination folder
     fs::read_dir(&folder)?;
 
     // receive file details
-    let (filename, file_size) = receive_file_details(stream).await?;
+    let (filename, file_size) = receive_file_details(session, stream).await?;
     ui.output(&format!("Filename: {}", filename));
     ui.output(&format!(
         "File size: {}",
@@ -39,7 +60,7 @@ pub async fn receive_file<T: UI>(
     // see if we already have the file being sent
     let mut full_path = folder.clone();
     full_path.push(&filename);
-    let need_transfer = check_for_file(&full_path, file_size, stream).await?;
+    let need_transfer = check_for_file(&full_path, file_size, session, stream).await?;
     if !need_transfer {
         ui.output("Recipient already has this file, skipping.");
         return Ok(());
@@ -62,27 +83,50 @@ pub async fn receive_file<T: UI>(
         i += 1;
     }
 
-    // open output file
+    // open output file. declared after the guard so that it's closed before the guard deletes it.
+    let mut partial_file = PartialFile {
+        path: full_path.clone(),
+        complete: false,
+    };
     let mut out_file = fs::File::create(&full_path)?;
 
     // show progress bar
     ui.show_progress_bar();
 
     // receive file
+    let mut chunk_index = 0;
     loop {
         tokio::task::yield_now().await;
-        let decrypted_bytes = receive_and_decrypt_chunk(&cipher, stream).await?;
-        if decrypted_bytes.len() == 0 {
-            break;
+        let (decrypted_bytes, final_chunk) =
+            receive_and_decrypt_chunk(file_index, chunk_index, session, stream).await?;
+        chunk_index += 1;
+        if decrypted_bytes.len() as u64 > bytes_left {
+            Err(FCError {
+                message: format!("Peer sent more than the {} bytes it announced", file_size),
+            })?;
         }
         bytes_left -= decrypted_bytes.len() as u64;
         out_file.write_all(&decrypted_bytes)?;
-        let percent_done = ((file_size - bytes_left) as f64 / file_size as f64) * 100.0;
-        ui.update_progress_bar(percent_done as u8);
+        if file_size > 0 {
+            let percent_done = ((file_size - bytes_left) as f64 / file_size as f64) * 100.0;
+            ui.update_progress_bar(percent_done as u8);
+        }
+        if final_chunk {
+            break;
+        }
     }
+    if bytes_left != 0 {
+        Err(FCError {
+            message: format!(
+                "Transfer ended {} bytes short of the expected file size",
+                bytes_left
+            ),
+        })?;
+    }
+    partial_file.complete = true;
 
     // tell sending end we're finished
-    stream.write_u64(1).await?;
+    session.write_u64(stream, 1).await?;
 
     // stats
     ui.update_progress_bar(100);
@@ -109,7 +153,7 @@ pub async fn receive_file<T: UI>(
 
     // wait for double confirmation
     if last_file {
-        match timeout(Duration::from_secs(2), stream.read_u64()).await {
+        match timeout(Duration::from_secs(2), session.read_u64(stream)).await {
             Ok(res) => {
                 res?;
             }
@@ -118,42 +162,66 @@ pub async fn receive_file<T: UI>(
             }
         };
     } else {
-        let _reply = stream.read_u64().await?;
+        let _reply = session.read_u64(stream).await?;
     }
 
     Ok(())
 }
 
-async fn receive_and_decrypt_chunk(
-    cipher: &Aes256Gcm,
-    stream: &mut TcpStream,
-) -> Result<Vec<u8>, FCError> {
-    // receive chunk size
-    let chunk_size = stream.read_u64().await? as usize;
-    if chunk_size == 0 {
-        Ok(vec![])
-    } else {
-        // receive chunk
+// returns the decrypted chunk and whether it was the last one of the file
+pub(crate) async fn receive_and_decrypt_chunk(
+    file_index: u64,
+    chunk_index: u64,
+    session: &mut Session,
+    stream: &mut impl Stream,
+) -> Result<(Vec<u8>, bool), FCError> {
+    if session.is_legacy() {
+        // older peers mark the end of the file with a chunk size of 0
+        let chunk_size = check_length(stream.read_u64().await?, LEGACY_MAX_CHUNK_SIZE, "chunk")?;
+        if chunk_size == 0 {
+            return Ok((vec![], true));
+        }
         let mut chunk = vec![0u8; chunk_size];
         stream.read_exact(&mut chunk).await?;
-        // decrypt
-        let nonce = &chunk[..12];
-        let ciphertext = &chunk[12..];
-        let nonce = aes_gcm::Nonce::from_slice(nonce);
-        let decrypted_chunk = cipher.decrypt(nonce, ciphertext)?;
-        Ok(decrypted_chunk)
+        return Ok((session.decrypt(&chunk, &[])?, false));
     }
+
+    // receive final flag and chunk size
+    let final_chunk = match stream.read_u8().await? {
+        0 => false,
+        1 => true,
+        other => Err(FCError {
+            message: format!("Invalid final chunk flag: {}", other),
+        })?,
+    };
+    let chunk_size = check_length(stream.read_u64().await?, MAX_CHUNK_SIZE, "chunk")?;
+    // receive chunk
+    let mut chunk = vec![0u8; chunk_size];
+    stream.read_exact(&mut chunk).await?;
+    // decrypt, which fails if the chunk isn't the one we expect next
+    let aad = chunk_aad(file_index, chunk_index, final_chunk);
+    let decrypted_chunk = session.decrypt(&chunk, &aad)?;
+    Ok((decrypted_chunk, final_chunk))
 }
 
-async fn receive_file_details(stream: &mut TcpStream) -> std::io::Result<(String, u64)> {
-    // receive size of filename
-    let filename_size = stream.read_u64().await? as usize;
+pub(crate) async fn receive_file_details(
+    session: &mut Session,
+    stream: &mut impl Stream,
+) -> Result<(String, u64), FCError> {
     // receive filename
-    let mut filename_bytes = vec![0; filename_size];
-    stream.read_exact(&mut filename_bytes).await?;
+    let filename_bytes = session.read_bytes(stream).await?;
     let filename = String::from_utf8_lossy(&filename_bytes).to_string();
+    // the filename gets joined to the destination folder, so it must not be able to point anywhere outside of it
+    let is_relative = Path::new(&filename)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if filename.is_empty() || !is_relative {
+        Err(FCError {
+            message: format!("Peer sent an invalid filename: {}", filename),
+        })?
+    }
     // receive file size
-    let file_size = stream.read_u64().await?;
+    let file_size = session.read_u64(stream).await?;
     Ok((filename, file_size))
 }
 
@@ -161,7 +229,8 @@ async fn receive_file_details(stream: &mut TcpStream) -> std::io::Result<(String
 async fn check_for_file(
     filename: &Path,
     size: u64,
-    stream: &mut TcpStream,
+    session: &mut Session,
+    stream: &mut impl Stream,
 ) -> Result<bool, FCError> {
     // check if file by this name and size exists
     if filename.is_file() {
@@ -169,28 +238,80 @@ async fn check_for_file(
         let metadata = fs::metadata(filename)?;
         let local_size = metadata.len();
         if size == local_size {
-            stream.write_u64(1).await?;
+            session.write_u64(stream, 1).await?;
             let mut hashes_match = true;
             let local_hash = utils::hash_file(filename)?;
-            let mut peer_hash = vec![0; 32];
-            stream.read_exact(&mut peer_hash).await?;
+            let peer_hash = session.read_fixed(stream, local_hash.len()).await?;
             for i in 0..local_hash.len() {
                 if local_hash[i] != peer_hash[i] {
                     hashes_match = false;
                 }
             }
-            stream.write_u64(if hashes_match { 1 } else { 0 }).await?;
+            session
+                .write_u64(stream, if hashes_match { 1 } else { 0 })
+                .await?;
             Ok(!hashes_match)
         } else {
-            stream.write_u64(0).await?;
+            session.write_u64(stream, 0).await?;
             // TODO: ugly hack to get around lifetime issue? sending end didn't receive this last reply when calculating hash of large file.
             sleep(time::Duration::from_secs(1)).await;
             Ok(true)
         }
     } else {
-        stream.write_u64(0).await?;
+        session.write_u64(stream, 0).await?;
         // TODO: ugly hack to get around lifetime issue? sending end didn't receive this last reply when calculating hash of large file.
         sleep(time::Duration::from_secs(1)).await;
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{receive_and_decrypt_chunk, receive_file_details};
+    use crate::crypto::Session;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    async fn details_from(filename: &[u8], filename_size: u64) -> String {
+        let (mut peer, mut stream) = duplex(1024);
+        peer.write_u64(filename_size).await.unwrap();
+        peer.write_all(filename).await.unwrap();
+        peer.write_u64(10).await.unwrap();
+        let mut session = Session::legacy(&[0u8; 32]);
+        match receive_file_details(&mut session, &mut stream).await {
+            Ok((filename, _)) => filename,
+            Err(e) => e.message,
+        }
+    }
+
+    #[tokio::test]
+    async fn filename_bounds() {
+        assert_eq!(details_from(b"a/b.txt", 7).await, "a/b.txt");
+        assert!(details_from(b"", 1 << 40)
+            .await
+            .contains("more than the maximum"));
+        for bad in [&b"../escape"[..], b"/etc/passwd", b"a/../../b", b""] {
+            let message = details_from(bad, bad.len() as u64).await;
+            assert!(message.contains("invalid filename"), "{}", message);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunk_bounds() {
+        for legacy in [true, false] {
+            let (mut peer, mut stream) = duplex(1024);
+            if !legacy {
+                peer.write_u8(0).await.unwrap();
+            }
+            peer.write_u64(u64::MAX).await.unwrap();
+            let mut session = if legacy {
+                Session::legacy(&[0u8; 32])
+            } else {
+                Session::new(&[0u8; 32], &crate::Mode::Receive("".into()))
+            };
+            let e = receive_and_decrypt_chunk(0, 0, &mut session, &mut stream)
+                .await
+                .unwrap_err();
+            assert!(e.message.contains("more than the maximum"), "{}", e);
+        }
+    }
+}
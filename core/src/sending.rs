@@ -1,25 +1,24 @@
-use crate::{utils, FCError, CHUNKSIZE, UI};
-use aes_gcm::{aead::Aead, AeadCore, Aes256Gcm, KeyInit};
+use crate::{
+    crypto::{chunk_aad, Session},
+    utils, FCError, Stream, CHUNKSIZE, UI,
+};
 use std::{
     fs::{metadata, File},
     io::Read,
     path::Path,
     time::Instant,
 };
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::AsyncWriteExt;
 
 pub async fn send_file<T: UI>(
     file: &Path,
     prefix: &Path,
-    key: &[u8],
-    stream: &mut TcpStream,
+    file_index: u64,
+    session: &mut Session,
+    stream: &mut impl Stream,
     ui: &T,
 ) -> Result<(), FCError> {
     let start = Instant::now();
-    let cipher = Aes256Gcm::new_from_slice(key).expect("Invalid AES-256-GCM key length");
     let mut handle = File::open(file)?;
     let metadata = metadata(file)?;
     let size = metadata.len();
@@ -31,10 +30,10 @@ pub async fn send_file<T: UI>(
     if cfg!(windows) {
         filename = filename.replace("\\", "/");
     }
-    send_file_details(&filename, size, stream).await?;
+    send_file_details(&filename, size, session, stream).await?;
 
     // check to see if receiving end already has the file
-    let need_transfer = check_for_file(&file, stream).await?;
+    let need_transfer = check_for_file(&file, session, stream).await?;
     if !need_transfer {
         ui.output("Recipient already has this file, skipping.");
         return Ok(());
@@ -44,28 +43,46 @@ pub async fn send_file<T: UI>(
     ui.show_progress_bar();
 
     let mut buffer = vec![0u8; CHUNKSIZE];
+    let mut chunk_index = 0;
 
-    while bytes_left > 0 {
+    // the last chunk is flagged as final so the receiving end can tell a complete file from a truncated stream.
+    // an empty file is sent as a single, empty, final chunk.
+    loop {
         tokio::task::yield_now().await;
-        match handle.read(&mut buffer) {
-            Ok(bytes_read) if bytes_read == 0 => {
-                // EOF, shouldn't hit this due to while loop condition
-                ui.output("Hit EOF");
-                break;
-            }
-            Ok(bytes_read) => {
-                bytes_left -= bytes_read as u64;
-                encrypt_and_send_chunk(&buffer[..bytes_read], &cipher, stream).await?;
-                let percent_done = ((size - bytes_left) as f64 / size as f64) * 100.;
-                ui.update_progress_bar(percent_done as u8);
+        let bytes_read = if bytes_left > 0 {
+            match handle.read(&mut buffer) {
+                Ok(0) => Err(FCError {
+                    message:
+                        "File ended before its expected size, was it modified during the transfer?"
+                            .to_string(),
+                })?,
+                Ok(bytes_read) => bytes_read,
+                Err(e) => Err(e)?,
             }
-            Err(e) => Err(e)?,
+        } else {
+            0
+        };
+        bytes_left = bytes_left.saturating_sub(bytes_read as u64);
+        let final_chunk = bytes_left == 0;
+        encrypt_and_send_chunk(
+            &buffer[..bytes_read],
+            file_index,
+            chunk_index,
+            final_chunk,
+            session,
+            stream,
+        )
+        .await?;
+        chunk_index += 1;
+        if size > 0 {
+            let percent_done = ((size - bytes_left) as f64 / size as f64) * 100.;
+            ui.update_progress_bar(percent_done as u8);
+        }
+        if final_chunk {
+            break;
         }
     }
 
-    // send chunkSize of 0
-    stream.write_u64(0).await?;
-
     // stats
     ui.update_progress_bar(100);
     let finish = Instant::now();
@@ -77,34 +94,46 @@ pub async fn send_file<T: UI>(
     ui.output(&format!("Speed: {:.2}mbps", mbps));
 
     // listen for receiving end to tell us they have everything
-    stream.read_u64().await?;
+    session.read_u64(stream).await?;
 
     // send double confirmation
     // std::thread::sleep(std::time::Duration::from_secs(5));
-    stream.write_u64(1).await?;
+    session.write_u64(stream, 1).await?;
 
     Ok(())
 }
 
 async fn encrypt_and_send_chunk(
     chunk: &[u8],
-    cipher: &Aes256Gcm,
-    stream: &mut TcpStream,
+    file_index: u64,
+    chunk_index: u64,
+    final_chunk: bool,
+    session: &mut Session,
+    stream: &mut impl Stream,
 ) -> Result<(), FCError> {
-    // generate nonce
-    let nonce = aes_gcm::Aes256Gcm::generate_nonce(rand::thread_rng());
-
-    // encrypt
-    let mut encrypted_chunk = cipher.encrypt(&nonce, chunk)?;
+    if session.is_legacy() {
+        // older peers get the nonce prepended to each chunk and a chunk size of 0 to mark the end of the file
+        if !chunk.is_empty() {
+            let nonce_and_chunk = session.encrypt(chunk, &[])?;
+            stream.write_u64(nonce_and_chunk.len() as u64).await?;
+            stream.write_all(&nonce_and_chunk).await?;
+        }
+        if final_chunk {
+            stream.write_u64(0).await?;
+        }
+        return Ok(());
+    }
 
-    let mut nonce_and_chunk = nonce.to_vec();
-    nonce_and_chunk.append(&mut encrypted_chunk);
+    // encrypt, authenticating the chunk's position in the transfer
+    let aad = chunk_aad(file_index, chunk_index, final_chunk);
+    let encrypted_chunk = session.encrypt(chunk, &aad)?;
 
-    // send size
-    stream.write_u64(nonce_and_chunk.len() as u64).await?;
+    // send final flag and size
+    stream.write_u8(final_chunk as u8).await?;
+    stream.write_u64(encrypted_chunk.len() as u64).await?;
 
     // write chunk
-    stream.write_all(&nonce_and_chunk).await?;
+    stream.write_all(&encrypted_chunk).await?;
 
     Ok(())
 }
@@ -112,24 +141,27 @@ async fn encrypt_and_send_chunk(
 async fn send_file_details(
     filename: &str,
     size: u64,
-    stream: &mut TcpStream,
-) -> std::io::Result<()> {
-    // send size of filename
-    stream.write_u64(filename.len() as u64).await?;
+    session: &mut Session,
+    stream: &mut impl Stream,
+) -> Result<(), FCError> {
     // send filename
-    stream.write_all(filename.as_bytes()).await?;
+    session.write_bytes(stream, filename.as_bytes()).await?;
     // send file size
-    stream.write_u64(size).await?;
+    session.write_u64(stream, size).await?;
     Ok(())
 }
 
 // returns Ok(true) if we need to perform the transfer
-async fn check_for_file(filename: &Path, stream: &mut TcpStream) -> Result<bool, FCError> {
-    let has_file = stream.read_u64().await?;
+async fn check_for_file(
+    filename: &Path,
+    session: &mut Session,
+    stream: &mut impl Stream,
+) -> Result<bool, FCError> {
+    let has_file = session.read_u64(stream).await?;
     if has_file == 1 {
         let hash = utils::hash_file(filename)?;
-        stream.write(&hash).await?;
-        let hashes_match = stream.read_u64().await?;
+        session.write_fixed(stream, &hash).await?;
+        let hashes_match = session.read_u64(stream).await?;
         Ok(hashes_match != 1) // if hashes match, return false because we don't need transfer
     } else {
         Ok(true)
@@ -0,0 +1,1372 @@
+// end-to-end tests of the transfer protocol: both halves of start_transfer() run against each other over localhost, so
+// no WiFi card or hotspot is needed. the protocol tests start from the TCP connection, and the rest run all of
+// start_transfer() with the simulated backend.
+
+use crate::{
+    backend::Simulated,
+    bind, clean_up_transfer,
+    crypto::LEGACY_CHUNKSIZE,
+    error::FCError,
+    find_common_folder,
+    hotspot::{HotspotChannel, WiFiBand},
+    negotiate_session, start_transfer, transfer_files,
+    utils::{get_key_and_ssid, BluetoothTimeouts},
+    BluetoothDevice, BluetoothRole, LanPeer, LanRole, Mode, PeerResource, TransferStream,
+    WiFiInterface, CHUNKSIZE, DEFAULT_PORT, MAJOR_VERSION, UI,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+// held by the tests that run all of start_transfer(), which all listen on the same port
+static TRANSFER_PORT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[derive(Clone)]
+struct TestUI {
+    messages: Arc<Mutex<Vec<String>>>,
+    progress_tx: Option<mpsc::UnboundedSender<u8>>,
+    // picks the first device shown, or cancels if there's no device
+    device_tx: Option<mpsc::Sender<Option<String>>>,
+    // answers whether the PIN shown matches the peer's
+    pin_tx: Option<mpsc::Sender<bool>>,
+    confirm_pin: bool,
+    // whether to pick a device at all when asked
+    choose_device: bool,
+    // whether our WiFi card can host a hotspot, as get_wifi_interfaces() would say
+    can_host: Option<bool>,
+}
+
+impl TestUI {
+    fn new() -> Self {
+        TestUI {
+            messages: Arc::new(Mutex::new(vec![])),
+            progress_tx: None,
+            device_tx: None,
+            pin_tx: None,
+            confirm_pin: true,
+            choose_device: true,
+            can_host: None,
+        }
+    }
+
+    fn pin(&self) -> Option<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .find_map(|m| m.strip_prefix("Showing PIN ").map(str::to_string))
+    }
+
+    fn saw(&self, msg: &str) -> bool {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.contains(msg))
+    }
+}
+
+impl UI for TestUI {
+    fn output(&self, msg: &str) {
+        self.messages.lock().unwrap().push(msg.to_string());
+    }
+    fn show_progress_bar(&self) {}
+    fn update_progress_bar(&self, percent: u8) {
+        if let Some(tx) = &self.progress_tx {
+            let _ = tx.send(percent);
+        }
+    }
+    fn enable_ui(&self) {}
+    fn show_pin(&self, pin: &str) {
+        self.output(&format!("Showing PIN {}", pin));
+        if let Some(tx) = &self.pin_tx {
+            tx.try_send(self.confirm_pin).unwrap();
+        }
+    }
+    fn show_bluetooth_devices(&self, devices: &[BluetoothDevice]) {
+        for device in devices {
+            self.output(&format!(
+                "Found {} running {:?}",
+                device.name, device.peer_os
+            ));
+        }
+        if let Some(tx) = &self.device_tx {
+            let choice = devices.first().map(|d| d.address.clone());
+            tx.try_send(choice).unwrap();
+        }
+    }
+    fn show_lan_peers(&self, peers: &[LanPeer]) {
+        for peer in peers {
+            self.output(&format!("Found {} on the network", peer.name));
+        }
+    }
+}
+
+// directory under the system temp folder that's deleted when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "flying_carpet_test_{}_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst),
+            name
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn write(&self, relative: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Side {
+    version: u64,
+    // what the peer advertised over Bluetooth or mDNS
+    advertised: Option<u64>,
+    mode: Mode,
+    password: &'static str,
+    ui: TestUI,
+}
+
+impl Side {
+    fn sending(files: Vec<PathBuf>) -> Self {
+        Side {
+            version: MAJOR_VERSION,
+            advertised: None,
+            mode: Mode::Send(files),
+            password: "password",
+            ui: TestUI::new(),
+        }
+    }
+
+    fn receiving(folder: &Path) -> Self {
+        Side {
+            version: MAJOR_VERSION,
+            advertised: None,
+            mode: Mode::Receive(folder.to_path_buf()),
+            password: "password",
+            ui: TestUI::new(),
+        }
+    }
+
+    async fn run(self, is_host: bool, mut stream: TcpStream) -> Result<(), FCError> {
+        let (key, _ssid) = get_key_and_ssid(self.password);
+        let mut session = negotiate_session(
+            self.version,
+            self.advertised,
+            &self.mode,
+            is_host,
+            &key,
+            &mut stream,
+        )
+        .await?;
+        transfer_files(self.mode, &mut session, &mut stream, &self.ui).await
+    }
+}
+
+async fn connect() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (guest, host) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (host.unwrap().0, guest.unwrap())
+}
+
+async fn spawn_pair(
+    host: Side,
+    guest: Side,
+) -> (
+    tokio::task::JoinHandle<Result<(), FCError>>,
+    tokio::task::JoinHandle<Result<(), FCError>>,
+) {
+    let (host_stream, guest_stream) = connect().await;
+    let host = tokio::spawn(host.run(true, host_stream));
+    let guest = tokio::spawn(guest.run(false, guest_stream));
+    (host, guest)
+}
+
+async fn run_pair(host: Side, guest: Side) -> (Result<(), FCError>, Result<(), FCError>) {
+    let (host, guest) = spawn_pair(host, guest).await;
+    (host.await.unwrap(), guest.await.unwrap())
+}
+
+fn contents(size: usize, seed: u8) -> Vec<u8> {
+    (0..size)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+fn files_in(dir: &Path) -> Vec<String> {
+    let mut found = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(d) = dirs.pop() {
+        for entry in fs::read_dir(d).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let relative = path.strip_prefix(dir).unwrap();
+                found.push(relative.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+#[tokio::test]
+async fn multiple_files() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let data = [
+        ("empty.txt", vec![]),
+        ("small.txt", contents(10, 1)),
+        ("large.bin", contents(CHUNKSIZE * 2 + 5, 2)),
+    ];
+    let files = data
+        .iter()
+        .map(|(name, bytes)| source.write(name, bytes))
+        .collect();
+
+    let (sent, received) = run_pair(Side::receiving(&dest.0), Side::sending(files)).await;
+    sent.unwrap();
+    received.unwrap();
+
+    for (name, bytes) in &data {
+        assert_eq!(&fs::read(dest.0.join(name)).unwrap(), bytes);
+    }
+}
+
+#[tokio::test]
+async fn nested_folders() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let files = vec![
+        source.write("dropped/a/b/deep.txt", b"deep"),
+        source.write("dropped/c/side.txt", b"side"),
+        source.write("dropped/top.txt", b"top"),
+    ];
+
+    // sender hosting this time
+    let (sent, received) = run_pair(Side::sending(files), Side::receiving(&dest.0)).await;
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(
+        files_in(&dest.0),
+        vec!["a/b/deep.txt", "c/side.txt", "top.txt"]
+    );
+    assert_eq!(fs::read(dest.0.join("a/b/deep.txt")).unwrap(), b"deep");
+}
+
+#[test]
+fn common_folder() {
+    let same_dir = vec![PathBuf::from("/x/one.txt"), PathBuf::from("/x/two.txt")];
+    assert_eq!(find_common_folder(&same_dir), PathBuf::from("/x"));
+    let single = vec![PathBuf::from("one.txt")];
+    assert_eq!(find_common_folder(&single), PathBuf::from(""));
+    assert_eq!(find_common_folder(&[]), PathBuf::new());
+    // a file above the others
+    let shallower = vec![PathBuf::from("/x/a/b/one.txt"), PathBuf::from("/x/two.txt")];
+    assert_eq!(find_common_folder(&shallower), PathBuf::from("/x"));
+    // nothing in common but the root
+    let apart = vec![PathBuf::from("/x/one.txt"), PathBuf::from("/y/two.txt")];
+    assert_eq!(find_common_folder(&apart), PathBuf::from("/"));
+}
+
+// the common folder used to be the shallowest parent, which isn't a prefix of files in a sibling folder at the same
+// depth or deeper, so sending them failed to strip it
+#[test]
+fn common_folder_of_siblings() {
+    let siblings = vec![
+        PathBuf::from("/x/a/one.txt"),
+        PathBuf::from("/x/b/c/two.txt"),
+    ];
+    assert_eq!(find_common_folder(&siblings), PathBuf::from("/x"));
+    let same_depth = vec![PathBuf::from("/x/a/one.txt"), PathBuf::from("/x/b/two.txt")];
+    assert_eq!(find_common_folder(&same_depth), PathBuf::from("/x"));
+    // compared by folder, not by characters
+    let shared_prefix = vec![
+        PathBuf::from("/x/ab/one.txt"),
+        PathBuf::from("/x/a/two.txt"),
+    ];
+    assert_eq!(find_common_folder(&shared_prefix), PathBuf::from("/x"));
+    for file in &siblings {
+        assert!(file.strip_prefix(find_common_folder(&siblings)).is_ok());
+    }
+}
+
+#[tokio::test]
+async fn skip_if_present() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(1000, 3);
+    let file = source.write("present.txt", &bytes);
+    dest.write("present.txt", &bytes);
+
+    let receiver = Side::receiving(&dest.0);
+    let receiver_ui = receiver.ui.clone();
+    let (received, sent) = run_pair(receiver, Side::sending(vec![file])).await;
+    sent.unwrap();
+    received.unwrap();
+
+    assert!(receiver_ui.saw("Recipient already has this file, skipping."));
+    assert_eq!(files_in(&dest.0), vec!["present.txt"]);
+}
+
+#[tokio::test]
+async fn same_name_different_contents() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("name.txt", b"new contents");
+    dest.write("name.txt", b"old contents");
+
+    let (received, sent) = run_pair(Side::receiving(&dest.0), Side::sending(vec![file])).await;
+    sent.unwrap();
+    received.unwrap();
+
+    assert_eq!(fs::read(dest.0.join("name.txt")).unwrap(), b"old contents");
+    assert_eq!(
+        fs::read(dest.0.join("(1) name.txt")).unwrap(),
+        b"new contents"
+    );
+}
+
+// there's no partial-file resume, but rerunning an interrupted batch skips the files that already arrived
+// and doesn't leave a truncated copy of the one that was in progress.
+#[tokio::test]
+async fn cancel_and_resume() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let data = [
+        ("first.txt", contents(100, 4)),
+        ("second.txt", contents(200, 5)),
+        ("third.bin", contents(CHUNKSIZE * 4, 6)),
+    ];
+    let files: Vec<PathBuf> = data
+        .iter()
+        .map(|(name, bytes)| source.write(name, bytes))
+        .collect();
+
+    // cancel the sender partway through the third file
+    let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+    let mut sender = Side::sending(files.clone());
+    sender.ui.progress_tx = Some(progress_tx);
+    let (received, sent) = spawn_pair(Side::receiving(&dest.0), sender).await;
+    while let Some(percent) = progress_rx.recv().await {
+        if percent > 0 && percent < 100 {
+            sent.abort();
+            break;
+        }
+    }
+    assert!(sent.await.unwrap_err().is_cancelled());
+    let err = received.await.unwrap().unwrap_err();
+    assert!(err.message.contains("Error receiving file"), "{}", err);
+    assert_eq!(files_in(&dest.0), vec!["first.txt", "second.txt"]);
+
+    // run it again
+    let receiver = Side::receiving(&dest.0);
+    let receiver_ui = receiver.ui.clone();
+    let (received, sent) = run_pair(receiver, Side::sending(files)).await;
+    sent.unwrap();
+    received.unwrap();
+
+    let skipped = receiver_ui
+        .messages
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|m| m.contains("skipping"))
+        .count();
+    assert_eq!(skipped, 2);
+    assert_eq!(
+        files_in(&dest.0),
+        vec!["first.txt", "second.txt", "third.bin"]
+    );
+    assert_eq!(fs::read(dest.0.join("third.bin")).unwrap(), data[2].1);
+}
+
+#[tokio::test]
+async fn both_sending() {
+    let source = TempDir::new("source");
+    let file = source.write("file.txt", b"contents");
+    let (host, guest) =
+        run_pair(Side::sending(vec![file.clone()]), Side::sending(vec![file])).await;
+    assert!(host
+        .unwrap_err()
+        .message
+        .contains("Both ends of the transfer selected send"));
+    assert!(guest
+        .unwrap_err()
+        .message
+        .contains("Both ends of the transfer selected send"));
+}
+
+#[tokio::test]
+async fn both_receiving() {
+    let dest = TempDir::new("dest");
+    let (host, guest) = run_pair(Side::receiving(&dest.0), Side::receiving(&dest.0)).await;
+    assert!(host
+        .unwrap_err()
+        .message
+        .contains("Both ends of the transfer selected receive"));
+    assert!(guest
+        .unwrap_err()
+        .message
+        .contains("Both ends of the transfer selected receive"));
+}
+
+#[tokio::test]
+async fn incompatible_version() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let mut old_sender = Side::sending(vec![file]);
+    old_sender.version = 7;
+    let (host, guest) = run_pair(Side::receiving(&dest.0), old_sender).await;
+    assert!(host
+        .unwrap_err()
+        .message
+        .contains("Peer's version 7 not compatible"));
+    assert!(guest
+        .unwrap_err()
+        .message
+        .contains(&format!("Peer's version {} not compatible", MAJOR_VERSION)));
+}
+
+#[tokio::test]
+async fn legacy_peer() {
+    // versions 8 and 9 predate session keys, so this exercises the original framing that the mobile apps speak
+    for (host_version, guest_version) in [(9, MAJOR_VERSION), (MAJOR_VERSION, 8)] {
+        let source = TempDir::new("source");
+        let dest = TempDir::new("dest");
+        let data = [
+            ("empty.txt", vec![]),
+            ("large.bin", contents(CHUNKSIZE + 1, 7)),
+        ];
+        let files = data
+            .iter()
+            .map(|(name, bytes)| source.write(name, bytes))
+            .collect();
+        let mut receiver = Side::receiving(&dest.0);
+        receiver.version = host_version;
+        receiver.advertised = Some(guest_version);
+        let mut sender = Side::sending(files);
+        sender.version = guest_version;
+        let (received, sent) = run_pair(receiver, sender).await;
+        sent.unwrap();
+        received.unwrap();
+        for (name, bytes) in &data {
+            assert_eq!(&fs::read(dest.0.join(name)).unwrap(), bytes);
+        }
+    }
+}
+
+// someone in the middle rewrites both versions to 9, which the transcript can't catch when it forces the legacy framing,
+// but the version the sender advertised before connecting can
+#[tokio::test]
+async fn version_downgrade() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let mut receiver = Side::receiving(&dest.0);
+    receiver.advertised = Some(MAJOR_VERSION);
+    // what the receiver sees of the sender
+    let mut sender = Side::sending(vec![file]);
+    sender.version = 9;
+    let (host, guest) = run_pair(receiver, sender).await;
+    assert!(host.unwrap_err().message.contains(&format!(
+        "Peer advertised version {} but connected with version 9",
+        MAJOR_VERSION
+    )));
+    assert!(guest.is_err());
+    assert!(files_in(&dest.0).is_empty());
+}
+
+// the mobile apps are still on version 9 and send 5 MB chunks, so this plays their part of the protocol by hand
+#[tokio::test]
+async fn legacy_peer_large_chunks() {
+    let dest = TempDir::new("dest");
+    let bytes = contents(LEGACY_CHUNKSIZE + 10, 10);
+    let (host_stream, mut stream) = connect().await;
+    let receiver = tokio::spawn(Side::receiving(&dest.0).run(true, host_stream));
+
+    let (key, _ssid) = get_key_and_ssid("password");
+    let mode = Mode::Send(vec![]);
+    let mut session = negotiate_session(9, None, &mode, false, &key, &mut stream)
+        .await
+        .unwrap();
+    assert!(session.is_legacy());
+    session.write_u64(&mut stream, 1).await.unwrap();
+    session
+        .write_bytes(&mut stream, b"android.bin")
+        .await
+        .unwrap();
+    session
+        .write_u64(&mut stream, bytes.len() as u64)
+        .await
+        .unwrap();
+    // receiver doesn't have it
+    assert_eq!(session.read_u64(&mut stream).await.unwrap(), 0);
+    for chunk in bytes.chunks(LEGACY_CHUNKSIZE) {
+        let nonce_and_chunk = session.encrypt(chunk, &[]).unwrap();
+        stream
+            .write_u64(nonce_and_chunk.len() as u64)
+            .await
+            .unwrap();
+        stream.write_all(&nonce_and_chunk).await.unwrap();
+    }
+    stream.write_u64(0).await.unwrap();
+    session.read_u64(&mut stream).await.unwrap();
+    session.write_u64(&mut stream, 1).await.unwrap();
+
+    receiver.await.unwrap().unwrap();
+    assert_eq!(fs::read(dest.0.join("android.bin")).unwrap(), bytes);
+}
+
+#[tokio::test]
+async fn wrong_password() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let mut sender = Side::sending(vec![file]);
+    sender.password = "different";
+    let (host, guest) = run_pair(Side::receiving(&dest.0), sender).await;
+    assert!(host
+        .unwrap_err()
+        .message
+        .contains("Error establishing session key"));
+    assert!(guest.is_err());
+    assert!(files_in(&dest.0).is_empty());
+}
+
+// start_transfer() over simulated WiFi and Bluetooth, choosing the first device shown or canceling the choice
+async fn simulated_start(
+    backend: &Simulated,
+    mode: &str,
+    role: BluetoothRole,
+    path: &Path,
+    ui: &mut TestUI,
+    hotspot: Arc<Mutex<Option<PeerResource>>>,
+    ssid: Arc<Mutex<Option<String>>>,
+) -> Option<TransferStream> {
+    let path = path.to_string_lossy().to_string();
+    let (file_list, receive_dir) = if mode == "send" {
+        (Some(vec![path]), None)
+    } else {
+        (None, Some(path))
+    };
+    let (ble_ui_tx, ble_ui_rx) = mpsc::channel(1);
+    ui.pin_tx = Some(ble_ui_tx);
+    let (ble_device_tx, ble_device_rx) = mpsc::channel(1);
+    if ui.choose_device {
+        ui.device_tx = Some(ble_device_tx);
+    } else {
+        ble_device_tx.send(None).await.unwrap();
+    }
+    start_transfer(
+        backend,
+        mode.to_string(),
+        true,
+        role,
+        None,
+        None,
+        None,
+        None,
+        // the peer hears which one over simulated Bluetooth
+        0,
+        WiFiInterface {
+            ap: ui.can_host,
+            ..WiFiInterface::default()
+        },
+        file_list,
+        receive_dir,
+        ui,
+        hotspot,
+        ssid,
+        ble_ui_rx,
+        ble_device_rx,
+    )
+    .await
+}
+
+// start_transfer() on the network both ends are already on, with neither Bluetooth nor a hotspot
+async fn lan_start(
+    mode: &str,
+    lan: LanRole,
+    port: u16,
+    path: &Path,
+    ui: &TestUI,
+    hotspot: Arc<Mutex<Option<PeerResource>>>,
+    ssid: Arc<Mutex<Option<String>>>,
+) -> Option<TransferStream> {
+    let path = path.to_string_lossy().to_string();
+    let (file_list, receive_dir) = if mode == "send" {
+        (Some(vec![path]), None)
+    } else {
+        (None, Some(path))
+    };
+    let (_ble_ui_tx, ble_ui_rx) = mpsc::channel(1);
+    let (_ble_device_tx, ble_device_rx) = mpsc::channel(1);
+    start_transfer(
+        &Simulated::new(),
+        mode.to_string(),
+        false,
+        BluetoothRole::Automatic,
+        None,
+        None,
+        Some("lan password".to_string()),
+        Some(lan),
+        port,
+        WiFiInterface::default(),
+        file_list,
+        receive_dir,
+        ui,
+        hotspot,
+        ssid,
+        ble_ui_rx,
+        ble_device_rx,
+    )
+    .await
+}
+
+// start_transfer() over a simulated hotspot without Bluetooth, where the user picked the peer's OS and shared the password
+async fn manual_start(
+    mode: &str,
+    path: &Path,
+    port: u16,
+    ui: &TestUI,
+    hotspot: Arc<Mutex<Option<PeerResource>>>,
+    ssid: Arc<Mutex<Option<String>>>,
+) -> Option<TransferStream> {
+    let path = path.to_string_lossy().to_string();
+    let (file_list, receive_dir) = if mode == "send" {
+        (Some(vec![path]), None)
+    } else {
+        (None, Some(path))
+    };
+    let (_ble_ui_tx, ble_ui_rx) = mpsc::channel(1);
+    let (_ble_device_tx, ble_device_rx) = mpsc::channel(1);
+    start_transfer(
+        &Simulated::new(),
+        mode.to_string(),
+        false,
+        BluetoothRole::Automatic,
+        None,
+        Some(crate::bluetooth::OS.to_string()),
+        Some("manual password".to_string()),
+        None,
+        port,
+        WiFiInterface::default(),
+        file_list,
+        receive_dir,
+        ui,
+        hotspot,
+        ssid,
+        ble_ui_rx,
+        ble_device_rx,
+    )
+    .await
+}
+
+// the host comes from the peer OS and mode the user chose, and the hotspot and SSID are left for clean_up_transfer()
+#[tokio::test]
+async fn manual_transfer() {
+    let _port = TRANSFER_PORT.lock().await;
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(CHUNKSIZE + 10, 11);
+    let file = source.write("manual.bin", &bytes);
+
+    let sender_ui = TestUI::new();
+    let receiver_ui = TestUI::new();
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+    let sender_ssid = Arc::new(Mutex::new(None));
+    let receiver_ssid = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        manual_start(
+            "send",
+            &file,
+            DEFAULT_PORT,
+            &sender_ui,
+            sender_hotspot.clone(),
+            sender_ssid.clone(),
+        ),
+        manual_start(
+            "receive",
+            &dest.0,
+            DEFAULT_PORT,
+            &receiver_ui,
+            receiver_hotspot.clone(),
+            receiver_ssid.clone(),
+        ),
+    );
+
+    // between two desktops on the same OS, the receiver hosts
+    assert!(receiver_ui.saw("Starting simulated hotspot flyingCarpet_"));
+    assert!(sender_ui.saw("Joining simulated hotspot flyingCarpet_"));
+    assert!(matches!(
+        *receiver_hotspot.lock().unwrap(),
+        Some(PeerResource::SimulatedHotspot)
+    ));
+    assert!(matches!(
+        *sender_hotspot.lock().unwrap(),
+        Some(PeerResource::WifiClient(_))
+    ));
+    let (_key, ssid) = get_key_and_ssid("manual password");
+    assert_eq!(*sender_ssid.lock().unwrap(), Some(ssid.clone()));
+    assert_eq!(*receiver_ssid.lock().unwrap(), Some(ssid));
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    assert_eq!(fs::read(dest.0.join("manual.bin")).unwrap(), bytes);
+
+    let backend = Simulated::new();
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot.clone(),
+        sender_ssid,
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot.clone(),
+        receiver_ssid,
+        &receiver_ui,
+    )
+    .await;
+    assert!(sender_hotspot.lock().unwrap().is_none());
+    assert!(receiver_hotspot.lock().unwrap().is_none());
+}
+
+// without Bluetooth or the network, nothing could tell the guest which free port the host got
+#[tokio::test]
+async fn manual_any_port() {
+    let dest = TempDir::new("dest");
+    let ui = TestUI::new();
+    let hotspot = Arc::new(Mutex::new(None));
+    let stream = manual_start(
+        "receive",
+        &dest.0,
+        0,
+        &ui,
+        hotspot.clone(),
+        Arc::new(Mutex::new(None)),
+    )
+    .await;
+    assert!(stream.is_none());
+    assert!(ui.saw("Enter the same port on both devices instead"));
+    assert!(!ui.saw("Starting simulated hotspot"));
+    assert!(hotspot.lock().unwrap().is_none());
+}
+
+// all of start_transfer() on both ends, with the WiFi and Bluetooth steps simulated
+#[tokio::test]
+async fn simulated_transfer() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(CHUNKSIZE + 10, 8);
+    let file = source.write("simulated.bin", &bytes);
+
+    // only the host's choice counts, and the guest hears it over Bluetooth
+    let mut backend = Simulated::new();
+    backend.hotspot = HotspotChannel {
+        band: Some(WiFiBand::Five),
+        channel: Some(36),
+    };
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI::new();
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+    let sender_ssid = Arc::new(Mutex::new(None));
+    let receiver_ssid = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            sender_hotspot.clone(),
+            sender_ssid.clone(),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            receiver_hotspot.clone(),
+            receiver_ssid.clone(),
+        ),
+    );
+
+    assert!(receiver_ui.saw(&format!(
+        "Found Simulated Flying Carpet running Some(\"{}\")",
+        crate::bluetooth::OS
+    )));
+    let capabilities = format!(
+        "Peer Simulated Flying Carpet supports transfer protocol version {}",
+        MAJOR_VERSION
+    );
+    assert!(sender_ui.saw(&capabilities));
+    assert!(receiver_ui.saw(&capabilities));
+    assert!(receiver_ui.saw("Starting simulated hotspot flyingCarpet_"));
+    assert!(sender_ui.saw("Joining simulated hotspot flyingCarpet_"));
+    assert!(receiver_ui.saw(" on 5 GHz channel 36"));
+    assert!(sender_ui.saw(" on 5 GHz channel 36"));
+    assert!(sender_ui.pin().is_some());
+    assert_eq!(sender_ui.pin(), receiver_ui.pin());
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    // too big to skip WiFi
+    assert!(!sender_ui.saw("Transferring over Bluetooth"));
+    assert_eq!(fs::read(dest.0.join("simulated.bin")).unwrap(), bytes);
+
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot.clone(),
+        sender_ssid,
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot.clone(),
+        receiver_ssid,
+        &receiver_ui,
+    )
+    .await;
+    assert!(sender_hotspot.lock().unwrap().is_none());
+    assert!(receiver_hotspot.lock().unwrap().is_none());
+}
+
+// the receiving end listens on the network it's already on, and the sending end connects to it without a peer OS
+async fn lan_transfer(sender_role: LanRole, port: u16) -> (TestUI, TestUI) {
+    let _port = TRANSFER_PORT.lock().await;
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(CHUNKSIZE + 10, 9);
+    let file = source.write("lan.bin", &bytes);
+
+    let sender_ui = TestUI::new();
+    let receiver_ui = TestUI::new();
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+    let sender_ssid = Arc::new(Mutex::new(None));
+    let receiver_ssid = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        lan_start(
+            "send",
+            sender_role,
+            port,
+            &file,
+            &sender_ui,
+            sender_hotspot.clone(),
+            sender_ssid.clone(),
+        ),
+        lan_start(
+            "receive",
+            LanRole::Listen,
+            port,
+            &dest.0,
+            &receiver_ui,
+            receiver_hotspot.clone(),
+            receiver_ssid.clone(),
+        ),
+    );
+
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    assert!(!sender_ui.saw("Starting simulated hotspot"));
+    assert!(!receiver_ui.saw("Starting simulated hotspot"));
+    assert_eq!(fs::read(dest.0.join("lan.bin")).unwrap(), bytes);
+    // there's no hotspot SSID to delete afterwards
+    assert!(sender_ssid.lock().unwrap().is_none());
+    assert!(receiver_ssid.lock().unwrap().is_none());
+    assert!(matches!(
+        *receiver_hotspot.lock().unwrap(),
+        Some(PeerResource::Lan(LanRole::Listen))
+    ));
+
+    let backend = Simulated::new();
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot,
+        sender_ssid,
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot.clone(),
+        receiver_ssid,
+        &receiver_ui,
+    )
+    .await;
+    assert!(receiver_hotspot.lock().unwrap().is_none());
+    (sender_ui, receiver_ui)
+}
+
+#[tokio::test]
+async fn lan_transfer_by_address() {
+    let (_, receiver_ui) =
+        lan_transfer(LanRole::Connect("127.0.0.1".to_string()), DEFAULT_PORT).await;
+    assert!(receiver_ui.saw("Waiting for connection..."));
+}
+
+// the listener takes IPv6 as well as IPv4
+#[tokio::test]
+async fn lan_transfer_over_ipv6() {
+    let (_, receiver_ui) = lan_transfer(LanRole::Connect("[::1]".to_string()), DEFAULT_PORT).await;
+    assert!(receiver_ui.saw("Connection accepted"));
+}
+
+// needs multicast on at least one interface
+#[tokio::test]
+async fn lan_transfer_discovered() {
+    let (sender_ui, _) = lan_transfer(LanRole::Discover, DEFAULT_PORT).await;
+    assert!(sender_ui.saw("Looking for the other device on this network..."));
+}
+
+// the port the listener got is advertised along with its address
+#[tokio::test]
+async fn lan_transfer_on_any_port() {
+    let (sender_ui, _) = lan_transfer(LanRole::Discover, 0).await;
+    assert!(sender_ui.saw("Looking for the other device on this network..."));
+}
+
+// start_transfer() hands the handshake each end's mode, and whether it's the host from how it connected
+#[tokio::test]
+async fn lan_both_sending() {
+    let _port = TRANSFER_PORT.lock().await;
+    let source = TempDir::new("source");
+    let file = source.write("file.txt", b"contents");
+    let listener_ui = TestUI::new();
+    let connector_ui = TestUI::new();
+    let connector_hotspot = Arc::new(Mutex::new(None));
+
+    let (listener_stream, connector_stream) = tokio::join!(
+        lan_start(
+            "send",
+            LanRole::Listen,
+            DEFAULT_PORT,
+            &file,
+            &listener_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ),
+        lan_start(
+            "send",
+            LanRole::Connect("127.0.0.1".to_string()),
+            DEFAULT_PORT,
+            &file,
+            &connector_ui,
+            connector_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+
+    // both got as far as the handshake
+    assert!(listener_stream.is_some() && connector_stream.is_some());
+    assert!(matches!(
+        *connector_hotspot.lock().unwrap(),
+        Some(PeerResource::Lan(LanRole::Connect(_)))
+    ));
+    for ui in [&listener_ui, &connector_ui] {
+        assert!(ui.saw("Error confirming mode: Both ends of the transfer selected send"));
+        assert!(!ui.saw("Transfer complete"));
+    }
+}
+
+// a listener the peer will be told about moves to a free port, one it won't be told about has to wait for its port
+#[tokio::test]
+async fn busy_port() {
+    let ui = TestUI::new();
+    let taken = bind(0, false, &ui).await.unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    let listener = bind(port, true, &ui).await.unwrap();
+    assert_ne!(listener.local_addr().unwrap().port(), port);
+
+    let e = bind(port, false, &ui).await.unwrap_err();
+    assert!(e.to_string().contains("in use by another program"));
+    assert!(ui.saw(&format!("Port {} is in use, waiting for it...", port)));
+}
+
+// files under both ends' limit go over the simulated data characteristic, without a hotspot
+#[tokio::test]
+async fn bluetooth_only_transfer() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(1000, 3);
+    let file = source.write("small.bin", &bytes);
+
+    let backend = Simulated::new();
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI::new();
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            sender_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            receiver_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+
+    assert!(sender_ui.saw("Transferring over Bluetooth"));
+    assert!(receiver_ui.saw("Transferring over Bluetooth"));
+    assert!(!sender_ui.saw("simulated hotspot"));
+    assert!(!receiver_ui.saw("simulated hotspot"));
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    assert_eq!(fs::read(dest.0.join("small.bin")).unwrap(), bytes);
+    assert!(matches!(sender_stream, Some(TransferStream::Bluetooth(_))));
+    assert!(matches!(
+        receiver_stream,
+        Some(TransferStream::Bluetooth(_))
+    ));
+
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot.clone(),
+        Arc::new(Mutex::new(None)),
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot.clone(),
+        Arc::new(Mutex::new(None)),
+        &receiver_ui,
+    )
+    .await;
+    assert!(!sender_ui.saw("Failed to shut down Bluetooth stream."));
+    assert!(!receiver_ui.saw("Failed to shut down Bluetooth stream."));
+}
+
+// the receiver advertises and the sender scans, for peers that don't work the usual way around
+#[tokio::test]
+async fn bluetooth_roles_swapped() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(CHUNKSIZE + 10, 5);
+    let file = source.write("swapped.bin", &bytes);
+
+    let backend = Simulated::new();
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI::new();
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Central,
+            &file,
+            &mut sender_ui,
+            sender_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Peripheral,
+            &dest.0,
+            &mut receiver_ui,
+            receiver_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+
+    assert!(
+        receiver_ui.saw("Started simulated Bluetooth advertisement, waiting for sending device")
+    );
+    assert!(sender_ui.saw("Started simulated Bluetooth scan, waiting for receiving device"));
+    assert!(sender_ui.pin().is_some());
+    assert_eq!(sender_ui.pin(), receiver_ui.pin());
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    assert_eq!(fs::read(dest.0.join("swapped.bin")).unwrap(), bytes);
+
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot,
+        Arc::new(Mutex::new(None)),
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot,
+        Arc::new(Mutex::new(None)),
+        &receiver_ui,
+    )
+    .await;
+}
+
+// the receiver would host, but its card can't, so the sender hosts instead. if neither can, both give up before WiFi.
+#[tokio::test]
+async fn hosting_negotiated() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let bytes = contents(CHUNKSIZE + 10, 6);
+    let file = source.write("negotiated.bin", &bytes);
+
+    let backend = Simulated::new();
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI {
+        can_host: Some(false),
+        ..TestUI::new()
+    };
+    let sender_hotspot = Arc::new(Mutex::new(None));
+    let receiver_hotspot = Arc::new(Mutex::new(None));
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            sender_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            receiver_hotspot.clone(),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+
+    assert!(sender_ui.saw("Starting simulated hotspot flyingCarpet_"));
+    assert!(receiver_ui.saw("Joining simulated hotspot flyingCarpet_"));
+    assert!(sender_ui.saw("Transfer complete"));
+    assert!(receiver_ui.saw("Transfer complete"));
+    assert_eq!(fs::read(dest.0.join("negotiated.bin")).unwrap(), bytes);
+
+    clean_up_transfer(
+        &backend,
+        sender_stream,
+        sender_hotspot,
+        Arc::new(Mutex::new(None)),
+        &sender_ui,
+    )
+    .await;
+    clean_up_transfer(
+        &backend,
+        receiver_stream,
+        receiver_hotspot,
+        Arc::new(Mutex::new(None)),
+        &receiver_ui,
+    )
+    .await;
+
+    let mut sender_ui = TestUI {
+        can_host: Some(false),
+        ..TestUI::new()
+    };
+    let mut receiver_ui = TestUI {
+        can_host: Some(false),
+        ..TestUI::new()
+    };
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+    assert!(sender_stream.is_none() && receiver_stream.is_none());
+    let error = "Neither device's WiFi card can host a hotspot";
+    assert!(sender_ui.saw(error));
+    assert!(receiver_ui.saw(error));
+    assert!(!sender_ui.saw("simulated hotspot"));
+}
+
+#[tokio::test]
+async fn bluetooth_device_choice_canceled() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let backend = Simulated::new();
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI::new();
+    receiver_ui.choose_device = false;
+
+    // sender keeps advertising, so only the receiver finishes
+    tokio::select! {
+        _ = simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ) => panic!("sender finished without a receiver"),
+        stream = simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ) => assert!(stream.is_none()),
+    }
+    assert!(receiver_ui.saw("Could not establish Bluetooth connection: User canceled."));
+}
+
+#[tokio::test]
+async fn bluetooth_pin_rejected() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let backend = Simulated::new();
+    let mut sender_ui = TestUI::new();
+    let mut receiver_ui = TestUI::new();
+    receiver_ui.confirm_pin = false;
+
+    let (sender_stream, receiver_stream) = tokio::join!(
+        simulated_start(
+            &backend,
+            "send",
+            BluetoothRole::Automatic,
+            &file,
+            &mut sender_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ),
+        simulated_start(
+            &backend,
+            "receive",
+            BluetoothRole::Automatic,
+            &dest.0,
+            &mut receiver_ui,
+            Arc::new(Mutex::new(None)),
+            Arc::new(Mutex::new(None)),
+        ),
+    );
+    assert!(sender_stream.is_none());
+    assert!(receiver_stream.is_none());
+    assert!(receiver_ui.saw("Could not establish Bluetooth connection: PIN was not confirmed"));
+    assert!(!receiver_ui.saw("Peer's SSID"));
+    assert!(!sender_ui.saw("Peer's SSID"));
+}
+
+#[tokio::test]
+async fn bluetooth_timeouts() {
+    let source = TempDir::new("source");
+    let dest = TempDir::new("dest");
+    let file = source.write("file.txt", b"contents");
+    let mut backend = Simulated::new();
+    backend.timeouts = BluetoothTimeouts {
+        scan: Duration::from_millis(300),
+        ..Default::default()
+    };
+
+    // nobody is advertising
+    let mut receiver_ui = TestUI::new();
+    let stream = simulated_start(
+        &backend,
+        "receive",
+        BluetoothRole::Automatic,
+        &dest.0,
+        &mut receiver_ui,
+        Arc::new(Mutex::new(None)),
+        Arc::new(Mutex::new(None)),
+    )
+    .await;
+    assert!(stream.is_none());
+    assert!(receiver_ui.saw(
+        "Could not establish Bluetooth connection: Timed out after 0.3 seconds scanning without finding a sending device"
+    ));
+
+    // nobody is scanning
+    let mut sender_ui = TestUI::new();
+    let stream = simulated_start(
+        &backend,
+        "send",
+        BluetoothRole::Automatic,
+        &file,
+        &mut sender_ui,
+        Arc::new(Mutex::new(None)),
+        Arc::new(Mutex::new(None)),
+    )
+    .await;
+    assert!(stream.is_none());
+    assert!(sender_ui.saw(
+        "Could not establish Bluetooth connection: Timed out after 0.3 seconds waiting for a receiving device to connect and write its capabilities"
+    ));
+}
@@ -2,32 +2,197 @@ use rand::Rng;
 use sha2::{Digest, Sha256};
 use std::{
     ffi::{c_char, CString},
-    fs, io,
+    fs,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV6, UdpSocket},
     path::{Path, PathBuf},
     process,
+    time::Duration,
 };
 
-use crate::FCError;
+use tokio::sync::mpsc;
 
-#[derive(Debug, PartialEq)]
-pub enum BluetoothMessage {
-    Pin(String),
-    PairApproved,
-    PairSuccess,
-    PairFailure,
-    AlreadyPaired,
-    UserCanceled,
-    StartedAdvertising,
-    PeerOS(String),
-    SSID(String),
-    Password(String),
-    PeerReadSsid,
-    PeerReadPassword,
-    OtherError(String),
-}
-
-unsafe impl Send for BluetoothMessage {}
-unsafe impl Sync for BluetoothMessage {}
+use crate::{network, FCError, Mode, UI};
+
+// the other end of the transfer, for telling the user what we're looking for over Bluetooth
+pub(crate) fn peer_description(mode: &Mode) -> &'static str {
+    match mode {
+        Mode::Send(_) => "receiving device",
+        Mode::Receive(_) => "sending device",
+    }
+}
+
+// how long each stage of the Bluetooth negotiation may take before we give up on the peer. scan covers waiting for the
+// other side to show up at all, whether we're scanning for it or advertising to it. connect includes pairing, which on
+// windows waits for the user to confirm the PIN.
+#[derive(Clone, Copy, Debug)]
+pub struct BluetoothTimeouts {
+    pub scan: Duration,
+    pub connect: Duration,
+    pub discovery: Duration,
+    pub exchange_step: Duration,
+}
+
+impl Default for BluetoothTimeouts {
+    fn default() -> Self {
+        BluetoothTimeouts {
+            scan: Duration::from_secs(120),
+            connect: Duration::from_secs(60),
+            discovery: Duration::from_secs(30),
+            exchange_step: Duration::from_secs(30),
+        }
+    }
+}
+
+// how long the central collects advertisers before showing them to the user, so that it doesn't just connect to whoever
+// advertised first
+pub(crate) const SCAN_WINDOW: Duration = Duration::from_secs(5);
+
+// step finishes the sentence "Timed out after n seconds ..."
+pub(crate) async fn with_timeout<T, E: Into<FCError>>(
+    duration: Duration,
+    step: &str,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, FCError> {
+    match tokio::time::timeout(duration, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(timeout_error(duration, step)),
+    }
+}
+
+// shows the PIN from the Bluetooth key agreement, and waits for the user to say whether the peer shows the same one
+pub(crate) async fn confirm_pin<T: UI>(
+    pin: &str,
+    ble_ui_rx: &mut mpsc::Receiver<bool>,
+    timeout: Duration,
+    ui: &T,
+) -> Result<(), FCError> {
+    ui.show_pin(pin);
+    match tokio::time::timeout(timeout, ble_ui_rx.recv()).await {
+        Ok(Some(true)) => {
+            ui.output("PIN confirmed");
+            Ok(())
+        }
+        Ok(_) => Err(FCError {
+            message: "PIN was not confirmed".to_string(),
+        }),
+        Err(_) => Err(timeout_error(
+            timeout,
+            "waiting for the PIN to be confirmed",
+        )),
+    }
+}
+
+// the address the peer can reach us at on the network we're already on. connecting a UDP socket only picks the route, it
+// doesn't send anything. a network without internet access may have no default route, so then we fall back to whatever
+// address our interfaces have.
+pub(crate) fn lan_address() -> Option<IpAddr> {
+    let routed = UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("8.8.8.8:80")?;
+            socket.local_addr()
+        })
+        .map(|a| a.ip());
+    match routed {
+        Ok(ip) => Some(ip),
+        // skipping link-local addresses, which the peer may not be able to route to
+        Err(_) => preferred_address(
+            &if_addrs::get_if_addrs()
+                .ok()?
+                .iter()
+                .filter(|i| !i.is_loopback() && !i.is_link_local())
+                .map(if_addrs::Interface::ip)
+                .collect::<Vec<_>>(),
+        ),
+    }
+}
+
+// the mobile apps only listen on IPv4
+fn preferred_address(addresses: &[IpAddr]) -> Option<IpAddr> {
+    addresses
+        .iter()
+        .find(|a| a.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+// the mobile apps only listen on IPv4, so a joining end waits this long for an IPv4 gateway before settling for IPv6
+pub(crate) const IPV6_GATEWAY_DELAY: Duration = Duration::from_secs(5);
+
+// a link-local IPv6 address means nothing without the interface it's on, so the user or the gateway lookup has to give
+// us one, either as fe80::1%wlan0 or fe80::1%3. brackets are allowed so an address copied from a URL works too.
+// port is used unless the address has its own, which it can only have after an IPv4 address or a bracketed IPv6 one.
+pub(crate) fn peer_socket_addr(address: &str, port: u16) -> Result<SocketAddr, FCError> {
+    let address = address.trim();
+    let (address, port) = match address.rsplit_once(':') {
+        Some((host, p)) if !host.contains(':') || host.ends_with(']') => match p.parse::<u16>() {
+            Ok(p) if p != 0 => (host, p),
+            _ => Err(FCError {
+                message: format!("{} isn't a valid port", p),
+            })?,
+        },
+        _ => (address, port),
+    };
+    let address = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    let (ip, zone) = match address.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (address, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|_| FCError {
+        message: format!("{} isn't an IP address", address),
+    })?;
+    let ip = match ip {
+        IpAddr::V4(ip) => {
+            if zone.is_some() {
+                Err(FCError {
+                    message: format!("{} is an IPv4 address and can't have an interface", address),
+                })?
+            }
+            return Ok(SocketAddr::new(IpAddr::V4(ip), port));
+        }
+        IpAddr::V6(ip) => ip,
+    };
+    let scope_id = match zone {
+        Some(zone) => match zone.parse::<u32>() {
+            Ok(index) => index,
+            Err(_) => network::interface_index(zone)?,
+        },
+        None => 0,
+    };
+    if scope_id == 0 && ip.is_unicast_link_local() {
+        Err(FCError {
+            message: format!(
+                "{} is a link-local address, add the interface it's on, like {}%wlan0",
+                address, address
+            ),
+        })?
+    }
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+pub(crate) fn timeout_error(duration: Duration, step: &str) -> FCError {
+    FCError {
+        message: format!(
+            "Timed out after {} seconds {}",
+            duration.as_secs_f32(),
+            step
+        ),
+    }
+}
+
+// for PairingPolicy::Require
+pub(crate) fn unbonded_error(peer: &str) -> FCError {
+    FCError {
+        message: format!(
+            "{} isn't paired with this computer. Pair with it from your Bluetooth settings first, or stop requiring pairing.",
+            peer
+        ),
+    }
+}
 
 pub fn run_command(
     program: &str,
@@ -120,13 +285,89 @@ pub fn format_time(seconds: f64) -> String {
 }
 
 pub fn is_compatible(peer_version: u64) -> bool {
-    // compatible with version 8. if transferring with higher version, that version will decide compatibility.
+    // compatible with versions 8 and 9, which predate session keys and get the older chunk framing.
+    // if transferring with higher version, that version will decide compatibility.
     peer_version >= 8
 }
 
+// manufacturer data in our BLE advertisement, so that scanners can list and filter peers without connecting.
+// 0xFFFF is the company ID reserved for unregistered use.
+pub(crate) const ADVERTISEMENT_COMPANY_ID: u16 = 0xFFFF;
+const ADVERTISEMENT_FORMAT: u8 = 1;
+const ADVERTISEMENT_HEADER_SIZE: usize = 4;
+const ADVERTISEMENT_SENDING: u8 = 0x01;
+// advertisements and scan responses on controllers without extended advertising
+pub(crate) const LEGACY_ADVERTISEMENT_LENGTH: usize = 31;
+// the length and type bytes in front of the local name in the scan response
+const LOCAL_NAME_OVERHEAD: usize = 2;
+const ADVERTISED_OSES: [&str; 5] = ["android", "ios", "linux", "mac", "windows"];
+
+// format byte, OS, transfer protocol version, flags. the service UUID leaves a legacy advertisement only a couple bytes
+// for the device name, so that goes in the scan response as our local name instead.
+#[derive(Debug, PartialEq)]
+pub(crate) struct AdvertisementInfo {
+    pub os: String,
+    pub protocol_version: u8,
+    pub sending: bool,
+    pub name: Option<String>,
+}
+
+impl AdvertisementInfo {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let os = ADVERTISED_OSES
+            .iter()
+            .position(|os| *os == self.os)
+            .expect("Advertising unknown OS") as u8;
+        let flags = if self.sending {
+            ADVERTISEMENT_SENDING
+        } else {
+            0
+        };
+        vec![ADVERTISEMENT_FORMAT, os, self.protocol_version, flags]
+    }
+
+    // max_length is the controller's maximum scan response length. the name is truncated to fit, or left out.
+    pub(crate) fn local_name(&self, max_length: usize) -> Option<String> {
+        let name = self.name.as_ref()?;
+        let mut end = name
+            .len()
+            .min(max_length.saturating_sub(LOCAL_NAME_OVERHEAD));
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            None
+        } else {
+            Some(name[..end].to_string())
+        }
+    }
+
+    // data is the manufacturer data, and local_name the name from the scan response, if the scanner got one.
+    // None if the data isn't ours or is from a newer format we don't understand.
+    pub(crate) fn decode(data: &[u8], local_name: Option<String>) -> Option<Self> {
+        if data.len() < ADVERTISEMENT_HEADER_SIZE || data[0] != ADVERTISEMENT_FORMAT {
+            return None;
+        }
+        let os = ADVERTISED_OSES.get(data[1] as usize)?.to_string();
+        Some(AdvertisementInfo {
+            os,
+            protocol_version: data[2],
+            sending: data[3] & ADVERTISEMENT_SENDING != 0,
+            name: local_name.filter(|n| !n.is_empty()),
+        })
+    }
+}
+
+// labels for the encrypted credentials, so that one can't be passed off as the other
+pub(crate) const SSID_LABEL: &str = "SSID";
+pub(crate) const PASSWORD_LABEL: &str = "password";
+
 #[cfg(test)]
 mod tests {
-    use crate::utils::make_size_readable;
+    use crate::utils::{
+        make_size_readable, peer_socket_addr, preferred_address, AdvertisementInfo,
+        LEGACY_ADVERTISEMENT_LENGTH, LOCAL_NAME_OVERHEAD,
+    };
 
     #[test]
     fn size_readable() {
@@ -136,6 +377,99 @@ mod tests {
         assert_eq!(&make_size_readable(8_273_591_032), "8.27GB");
     }
 
+    #[test]
+    fn lan_addresses() {
+        let v4 = "192.168.1.20".parse().unwrap();
+        let v6 = "fd00::20".parse().unwrap();
+        assert_eq!(preferred_address(&[v6, v4]), Some(v4));
+        assert_eq!(preferred_address(&[v6]), Some(v6));
+        assert_eq!(preferred_address(&[]), None);
+    }
+
+    #[test]
+    fn advertisement_info() {
+        let info = AdvertisementInfo {
+            os: "linux".to_string(),
+            protocol_version: 10,
+            sending: true,
+            name: Some("théron's laptop".to_string()),
+        };
+        let decoded = AdvertisementInfo::decode(&info.encode(), info.local_name(251));
+        assert_eq!(decoded, Some(info));
+
+        let info = AdvertisementInfo {
+            os: "windows".to_string(),
+            protocol_version: 10,
+            sending: false,
+            name: Some("été".to_string()),
+        };
+        // 3 bytes of room would split the second é
+        assert_eq!(info.local_name(LOCAL_NAME_OVERHEAD + 4).unwrap(), "ét");
+        assert_eq!(info.local_name(LOCAL_NAME_OVERHEAD + 1), None);
+        let decoded = AdvertisementInfo::decode(&info.encode(), None).unwrap();
+        assert_eq!(decoded.name, None);
+        assert!(!decoded.sending);
+        assert_eq!(decoded.os, "windows");
+
+        assert_eq!(AdvertisementInfo::decode(&[2, 2, 10, 1], None), None);
+        assert_eq!(AdvertisementInfo::decode(&[1, 9, 10, 1], None), None);
+        assert_eq!(AdvertisementInfo::decode(&[1, 2], None), None);
+    }
+
+    #[test]
+    fn legacy_advertisement() {
+        // the flags, our 128-bit service UUID, and the manufacturer data's own header
+        const ADVERTISEMENT_OVERHEAD: usize = 3 + 18 + 4;
+        let info = AdvertisementInfo {
+            os: "linux".to_string(),
+            protocol_version: 10,
+            sending: true,
+            name: Some("DESKTOP-7QK2M4N.corp.example.com".to_string()),
+        };
+        let data = info.encode();
+        assert!(ADVERTISEMENT_OVERHEAD + data.len() <= LEGACY_ADVERTISEMENT_LENGTH);
+        let local_name = info.local_name(LEGACY_ADVERTISEMENT_LENGTH).unwrap();
+        assert!(LOCAL_NAME_OVERHEAD + local_name.len() <= LEGACY_ADVERTISEMENT_LENGTH);
+        assert_eq!(local_name, "DESKTOP-7QK2M4N.corp.example.");
+
+        // a typical hostname fits whole
+        let info = AdvertisementInfo {
+            name: Some("theron-thinkpad-x1".to_string()),
+            ..info
+        };
+        let decoded =
+            AdvertisementInfo::decode(&info.encode(), info.local_name(LEGACY_ADVERTISEMENT_LENGTH));
+        assert_eq!(decoded, Some(info));
+    }
+
+    #[test]
+    fn peer_addresses() {
+        let addr = peer_socket_addr("192.168.137.1", 3290).unwrap();
+        assert_eq!(addr.to_string(), "192.168.137.1:3290");
+        let addr = peer_socket_addr("2001:db8::1", 3290).unwrap();
+        assert_eq!(addr.to_string(), "[2001:db8::1]:3290");
+        let addr = peer_socket_addr("[fe80::1%3]", 3290).unwrap();
+        assert_eq!(addr.to_string(), "[fe80::1%3]:3290");
+        #[cfg(target_os = "linux")]
+        {
+            let addr = peer_socket_addr("fe80::1%lo", 3290).unwrap();
+            assert_eq!(addr.to_string(), "[fe80::1%1]:3290");
+        }
+
+        // the address's own port wins
+        let addr = peer_socket_addr("192.168.1.5:50000", 3290).unwrap();
+        assert_eq!(addr.to_string(), "192.168.1.5:50000");
+        let addr = peer_socket_addr("[fe80::1%3]:50000", 3290).unwrap();
+        assert_eq!(addr.to_string(), "[fe80::1%3]:50000");
+
+        assert!(peer_socket_addr("192.168.1.5:0", 3290).is_err());
+        assert!(peer_socket_addr("192.168.1.5:http", 3290).is_err());
+        assert!(peer_socket_addr("fe80::1", 3290).is_err());
+        assert!(peer_socket_addr("fe80::1%no-such-interface", 3290).is_err());
+        assert!(peer_socket_addr("192.168.137.1%3", 3290).is_err());
+        assert!(peer_socket_addr("flyingcarpet.local", 3290).is_err());
+    }
+
     #[test]
     fn utf8_ok() {
         match super::run_command("ipconfig", None) {
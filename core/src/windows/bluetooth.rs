@@ -2,18 +2,40 @@ mod central;
 mod peripheral;
 
 use crate::{
+    backend::Hardware,
+    bluetooth_stream::BluetoothStream,
+    crypto::{BluetoothKeyPair, CredentialCipher},
+    diagnostics::Check,
     error::{fc_error, FCError},
-    network::{self, is_hosting},
-    utils::{generate_password, get_key_and_ssid, BluetoothMessage},
-    Mode, Peer, UI,
+    negotiation::{
+        check_key_exchange, is_ble_transfer, BluetoothSession, Capabilities, Exchange,
+        NegotiationState, PeerHosting, GATT_DONE, NO_SSID,
+    },
+    utils::{
+        confirm_pin, generate_password, get_key_and_ssid, timeout_error, unbonded_error,
+        with_timeout, BluetoothTimeouts, PASSWORD_LABEL, SSID_LABEL,
+    },
+    BluetoothBond, BluetoothInterface, BluetoothRole, Mode, PairingPolicy, Peer, UI,
 };
 use central::BluetoothCentral;
 use peripheral::BluetoothPeripheral;
-use std::mem::discriminant;
-use tokio::{sync::mpsc, time};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    spawn,
+    sync::{mpsc, Mutex},
+    time::{self, Instant},
+};
 use windows::{
-    core::HSTRING,
-    Devices::{Bluetooth::BluetoothAdapter, Radios::RadioState},
+    core::{GUID, HSTRING},
+    Devices::{
+        Bluetooth::{
+            BluetoothAdapter, BluetoothCacheMode, BluetoothLEDevice,
+            GenericAttributeProfile::GattCommunicationStatus,
+        },
+        Enumeration::{DeviceInformation, DeviceUnpairingResultStatus},
+        Radios::RadioState,
+    },
     Storage::Streams::{DataReader, DataWriter, IBuffer, UnicodeEncoding},
 };
 
@@ -30,13 +52,10 @@ const SERVICE_UUID: &str = "A70BF3CA-F708-4314-8A0E-5E37C259BE5C";
 pub(crate) const OS_CHARACTERISTIC_UUID: &str = "BEE14848-CC55-4FDE-8E9D-2E0F9EC45946";
 pub(crate) const SSID_CHARACTERISTIC_UUID: &str = "0D820768-A329-4ED4-8F53-BDF364EDAC75";
 pub(crate) const PASSWORD_CHARACTERISTIC_UUID: &str = "E1FA8F66-CF88-4572-9527-D5125A2E0762";
-// android uses "NONE" to say "the hotspot isn't up yet, so we don't know the SSID yet" because it's given by the android OS
-// do we need this on windows/linux? if we're hosting, we know the SSID because we generate the password.
-// do we need to delay reporting the OS until the hotspot is stood up? no, not necessarily.
-// but do we need this for communicating with android? not necessarily, because windows and linux will both host if communicating with android.
-// however, it might be good to future-proof and allow for this codebase to understand that signal from android,
-// in case hosting rules change, which would mean detecting this when reading ssid and delaying/retrying.
-const NO_SSID: &str = "NONE";
+pub(crate) const CAPABILITIES_CHARACTERISTIC_UUID: &str = "8C6F5D2E-4B1A-4E3F-9D7C-2A5B8E1F6C34";
+pub(crate) const PERIPHERAL_KEY_CHARACTERISTIC_UUID: &str = "3F9A2C71-5E84-4B0D-A6C3-91D7E2F45B18";
+pub(crate) const CENTRAL_KEY_CHARACTERISTIC_UUID: &str = "C4E81B36-7A2F-4D95-8B1E-6F03A9D2C757";
+pub(crate) const DATA_CHARACTERISTIC_UUID: &str = "5B7E2A94-1C3D-4F68-9E0A-B2D4C6F81A3E";
 
 // central goes scan -> bond -> connect -> discoverServices -> read OS -> write OS
 // -> connectToPeer -> start hotspot and write ssid/pw, or read ssid/pw and join hotspot
@@ -63,105 +82,414 @@ pub async fn check_support() -> Result<(), FCError> {
     Ok(())
 }
 
+// the default adapter, its radio, and the roles it supports. unlike check_support(), reports everything it finds
+// instead of stopping at the first problem.
+pub async fn diagnose() -> Vec<Check> {
+    let mut checks = vec![];
+    let adapter = match BluetoothAdapter::GetDefaultAsync().and_then(|a| a.get()) {
+        Ok(a) => a,
+        Err(e) => {
+            checks.push(Check::failed(
+                "Bluetooth adapter",
+                &format!("No adapter found: {}", e),
+            ));
+            return checks;
+        }
+    };
+    match adapter
+        .GetRadioAsync()
+        .and_then(|r| r.get())
+        .and_then(|r| r.State())
+    {
+        Ok(RadioState::On) => checks.push(Check::ok("Bluetooth adapter", "Radio is on.")),
+        Ok(_) => checks.push(Check::failed(
+            "Bluetooth adapter",
+            "Radio is off. Turn on Bluetooth in Settings.",
+        )),
+        Err(e) => checks.push(Check::failed("Bluetooth adapter", &e.to_string())),
+    }
+    for (name, supported, missing) in [
+        (
+            "Bluetooth LE",
+            adapter.IsLowEnergySupported(),
+            "Not supported.",
+        ),
+        (
+            "LE advertising",
+            adapter.IsPeripheralRoleSupported(),
+            "Not supported, so this device can only scan for the other device.",
+        ),
+        (
+            "LE scanning",
+            adapter.IsCentralRoleSupported(),
+            "Not supported, so this device can only advertise.",
+        ),
+    ] {
+        checks.push(match supported {
+            Ok(true) => Check::ok(name, "Supported."),
+            Ok(false) => Check::failed(name, missing),
+            Err(e) => Check::failed(name, &e.to_string()),
+        });
+    }
+    checks
+}
+
+pub async fn get_bluetooth_adapters() -> Result<Vec<BluetoothInterface>, FCError> {
+    let mut adapters = vec![];
+    for info in
+        DeviceInformation::FindAllAsyncAqsFilter(&BluetoothAdapter::GetDeviceSelector()?)?.get()?
+    {
+        let adapter = BluetoothAdapter::FromIdAsync(&info.Id()?)?.get()?;
+        let radio = adapter.GetRadioAsync()?.get()?;
+        adapters.push(BluetoothInterface {
+            name: info.Name()?.to_string(),
+            address: format!("{:012x}", adapter.BluetoothAddress()?),
+            powered: radio.State()? == RadioState::On,
+        });
+    }
+    Ok(adapters)
+}
+
+// the advertisement publisher, watcher, GATT server and pairing all use the default adapter, with no way to pick another
+fn check_default_adapter(interface: Option<&BluetoothInterface>) -> Result<(), FCError> {
+    if let Some(interface) = interface {
+        let default = BluetoothAdapter::GetDefaultAsync()?.get()?;
+        if format!("{:012x}", default.BluetoothAddress()?) != interface.address {
+            fc_error(&format!(
+                "Windows can only use the default Bluetooth adapter, not {}",
+                interface.name
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+// whether the device has offered the Flying Carpet service
+fn is_bond(device: &BluetoothLEDevice) -> Result<bool, FCError> {
+    let services = device
+        .GetGattServicesForUuidWithCacheModeAsync(
+            GUID::from(SERVICE_UUID),
+            BluetoothCacheMode::Cached,
+        )?
+        .get()?;
+    Ok(services.Status()? == GattCommunicationStatus::Success && services.Services()?.Size()? > 0)
+}
+
+// devices we're paired with that have offered the Flying Carpet service
+pub async fn list_bonds(
+    interface: Option<&BluetoothInterface>,
+) -> Result<Vec<BluetoothBond>, FCError> {
+    check_default_adapter(interface)?;
+    let mut bonds = vec![];
+    for info in DeviceInformation::FindAllAsyncAqsFilter(
+        &BluetoothLEDevice::GetDeviceSelectorFromPairingState(true)?,
+    )?
+    .get()?
+    {
+        let device = BluetoothLEDevice::FromIdAsync(&info.Id()?)?.get()?;
+        if is_bond(&device)? {
+            bonds.push(BluetoothBond {
+                name: info.Name()?.to_string(),
+                address: format!("{:012x}", device.BluetoothAddress()?),
+            });
+        }
+    }
+    Ok(bonds)
+}
+
+// only unpairs devices that list_bonds() would show
+pub async fn remove_bond(
+    interface: Option<&BluetoothInterface>,
+    address: &str,
+) -> Result<(), FCError> {
+    check_default_adapter(interface)?;
+    let address = match u64::from_str_radix(address, 16) {
+        Ok(a) => a,
+        Err(_) => Err(FCError {
+            message: format!("Invalid Bluetooth address: {}", address),
+        })?,
+    };
+    let device = BluetoothLEDevice::FromBluetoothAddressAsync(address)?.get()?;
+    let pairing = device.DeviceInformation()?.Pairing()?;
+    if !pairing.IsPaired()? || !is_bond(&device)? {
+        Err(FCError {
+            message: format!(
+                "{:012x} isn't a Flying Carpet device we're paired with",
+                address
+            ),
+        })?;
+    }
+    let status = pairing.UnpairAsync()?.get()?.Status()?;
+    println!("Unpairing result: {:?}", status);
+    match status {
+        DeviceUnpairingResultStatus::Unpaired | DeviceUnpairingResultStatus::AlreadyUnpaired => {
+            Ok(())
+        }
+        _ => Err(FCError {
+            message: format!("Couldn't unpair {:012x}: {:?}", address, status),
+        })?,
+    }
+}
+
+fn paired_addresses() -> Result<Vec<u64>, FCError> {
+    let mut paired = vec![];
+    for info in DeviceInformation::FindAllAsyncAqsFilter(
+        &BluetoothLEDevice::GetDeviceSelectorFromPairingState(true)?,
+    )?
+    .get()?
+    {
+        paired.push(
+            BluetoothLEDevice::FromIdAsync(&info.Id()?)?
+                .get()?
+                .BluetoothAddress()?,
+        );
+    }
+    Ok(paired)
+}
+
 pub async fn negotiate_bluetooth<T: UI>(
     mode: &Mode,
+    role: BluetoothRole,
+    hardware: &Hardware,
+    interface: Option<&BluetoothInterface>,
     ble_ui_rx: mpsc::Receiver<bool>,
+    ble_device_rx: mpsc::Receiver<Option<String>>,
     ui: &T,
-) -> Result<(String, String, String), FCError> {
-    let (tx, mut rx) = mpsc::channel(1);
-    let mut peripheral = BluetoothPeripheral::new(tx.clone())?;
-    let mut central = BluetoothCentral::new(tx.clone())?;
-    if let Mode::Send(_) = mode {
+) -> Result<(String, String, String, PeerHosting, Option<BluetoothStream>), FCError> {
+    let timeouts = &hardware.timeouts;
+    let pairing = hardware.pairing;
+    check_default_adapter(interface)?;
+    let session = BluetoothSession::new(if role.is_peripheral(mode) {
+        NegotiationState::StartingAdvertisement
+    } else {
+        NegotiationState::Scanning
+    });
+    // the central's pairing callback needs this too
+    let ble_ui_rx = Arc::new(Mutex::new(ble_ui_rx));
+    let capabilities = Capabilities::ours(std::env::var("COMPUTERNAME").unwrap_or_default())
+        .with_ble_limit(hardware.ble_transfer_limit, mode)
+        .with_port(hardware.port)
+        .with_hotspot(hardware.hotspot, &hardware.bands)
+        .with_role(hardware.role);
+    let key_pair = BluetoothKeyPair::generate();
+    let mut peripheral =
+        BluetoothPeripheral::new(session.clone(), capabilities.clone(), key_pair.public_hex())?;
+    let mut central = BluetoothCentral::new(session.clone())?;
+    if role.is_peripheral(mode) {
         // acting as peripheral
+        // the central pairs as soon as it touches our characteristics, so note who was paired beforehand
+        let bonded = match pairing {
+            PairingPolicy::Require => paired_addresses()?,
+            _ => vec![],
+        };
         ui.output("Advertising Bluetooth service...");
         peripheral.add_characteristics()?;
         peripheral.start_advertising()?;
 
-        let mut peer_os = String::new();
-        let mut peer_ssid = String::new();
-        let mut peer_password = String::new();
-
         // ensure we started advertising
-        process_bluetooth_message(BluetoothMessage::StartedAdvertising, &mut rx, ui).await?;
+        session
+            .wait_for(
+                |s| *s != NegotiationState::StartingAdvertisement,
+                "advertising to start",
+                timeouts.exchange_step,
+                ui,
+            )
+            .await?;
 
         // get OS of peer
-        let msg =
-            process_bluetooth_message(BluetoothMessage::PeerOS(String::new()), &mut rx, ui).await?;
-        if let BluetoothMessage::PeerOS(os) = msg {
-            peer_os = os;
-        } else {
-            fc_error(&format!(
-                "Peripheral received incorrect BluetoothMessage. Expected peer OS, got {:?}",
-                msg
-            ))?;
+        let peer_os = session
+            .wait_for_exchange(
+                |_| true,
+                "the peer to connect and write its OS",
+                timeouts.scan,
+                ui,
+            )
+            .await?
+            .peer_os;
+
+        if pairing == PairingPolicy::Require {
+            let Some(id) = peripheral.peer_device_id.lock().await.take() else {
+                Err(unbonded_error("The peer"))?
+            };
+            let device = BluetoothLEDevice::FromIdAsync(&id)?.get()?;
+            if !bonded.contains(&device.BluetoothAddress()?) {
+                // otherwise the bond it just made would let it through next time
+                device
+                    .DeviceInformation()?
+                    .Pairing()?
+                    .UnpairAsync()?
+                    .get()?;
+                Err(unbonded_error(&device.Name()?.to_string()))?
+            }
+        }
+
+        // the central writes its capabilities before its OS, unless it's too old to have them
+        let peer_capabilities = peripheral.peer_capabilities.lock().await.take();
+        if let Some(c) = &peer_capabilities {
+            c.check(ui)?;
         }
+        let ble_transfer = peer_capabilities
+            .as_ref()
+            .is_some_and(|c| is_ble_transfer(&capabilities, c));
+        let peer_hosting = PeerHosting::negotiate(
+            &capabilities,
+            peer_capabilities.as_ref(),
+            &Peer::from(peer_os.as_str()),
+            mode,
+        )?;
+
+        // and its public key, unless it's too old to encrypt the credentials
+        let peer_key = peripheral.peer_key.lock().await.take();
+        check_key_exchange(peer_capabilities.as_ref(), peer_key.is_some())?;
+        let cipher = match peer_key {
+            Some(k) => {
+                let cipher = key_pair.agree(&k, false)?;
+                confirm_pin(
+                    &cipher.pin,
+                    &mut *ble_ui_rx.lock().await,
+                    timeouts.connect,
+                    ui,
+                )
+                .await?;
+                Some(cipher)
+            }
+            None => None,
+        };
+        // the peer may still be confirming the PIN before its first read or write
+        let first_step = match cipher {
+            Some(_) => timeouts.connect,
+            None => timeouts.exchange_step,
+        };
 
-        if is_hosting(&Peer::from(peer_os.as_str()), mode) {
+        let (ssid, password) = if !peer_hosting.hosts {
             let password = generate_password();
             let (_, ssid) = get_key_and_ssid(&password);
             {
+                let (served_ssid, served_password) = match &cipher {
+                    Some(c) => (
+                        c.encrypt(SSID_LABEL, &ssid)?,
+                        c.encrypt(PASSWORD_LABEL, &password)?,
+                    ),
+                    None => (ssid.clone(), password.clone()),
+                };
                 let mut peripheral_ssid = peripheral.ssid.lock().await;
-                *peripheral_ssid = Some(ssid.clone());
+                *peripheral_ssid = Some(served_ssid);
                 let mut peripheral_password = peripheral.password.lock().await;
-                *peripheral_password = Some(password.clone());
+                *peripheral_password = Some(served_password);
             }
             println!("set peripheral ssid and password");
             println!("waiting for ssid to be read...");
-            process_bluetooth_message(BluetoothMessage::PeerReadSsid, &mut rx, ui).await?;
+            session
+                .wait_for_exchange(|e| e.read_ssid, "the peer to read our SSID", first_step, ui)
+                .await?;
             println!("waiting for password to be read...");
-            process_bluetooth_message(BluetoothMessage::PeerReadPassword, &mut rx, ui).await?;
-            Ok((peer_os, ssid.clone(), password))
+            session
+                .wait_for_exchange(
+                    |e| e.read_password,
+                    "the peer to read our password",
+                    timeouts.exchange_step,
+                    ui,
+                )
+                .await?;
+            if peer_capabilities.is_some() {
+                wait_for_done(&session, timeouts, ui).await?;
+            }
+            (ssid, password)
         } else {
             // if joining, receive writes
             // receive ssid
-            let msg = process_bluetooth_message(BluetoothMessage::SSID(String::new()), &mut rx, ui)
+            session
+                .wait_for_exchange(
+                    |e| e.ssid.is_some(),
+                    "the peer to write its SSID",
+                    first_step,
+                    ui,
+                )
                 .await?;
-            if let BluetoothMessage::SSID(ssid) = msg {
-                peer_ssid = ssid;
-            } else {
-                fc_error(&format!(
-                    "Peripheral received incorrect BluetoothMessage. Expected SSID, got {:?}",
-                    msg
-                ))?;
-            }
             // receive password
-            let msg =
-                process_bluetooth_message(BluetoothMessage::Password(String::new()), &mut rx, ui)
-                    .await?;
-            if let BluetoothMessage::Password(password) = msg {
-                peer_password = password;
+            let exchange = session
+                .wait_for_exchange(
+                    Exchange::finished,
+                    "the peer to write its password",
+                    timeouts.exchange_step,
+                    ui,
+                )
+                .await?;
+            let mut peer_ssid = exchange.ssid.expect("Peer's SSID missing");
+            let mut peer_password = exchange.password.expect("Peer's password missing");
+            if peer_capabilities.is_some() {
+                wait_for_done(&session, timeouts, ui).await?;
             } else {
-                fc_error(&format!(
-                    "Peripheral received incorrect BluetoothMessage. Expected password, got {:?}",
-                    msg
-                ))?;
+                // keep everything in scope until peer has had a chance to read the password
+                time::sleep(time::Duration::from_secs(1)).await;
             }
-            // keep everything in scope until peer has had a chance to read the password
-            time::sleep(time::Duration::from_secs(1)).await;
-            Ok((peer_os, peer_ssid, peer_password))
+            if let Some(c) = &cipher {
+                peer_ssid = c.decrypt(SSID_LABEL, &peer_ssid)?;
+                peer_password = c.decrypt(PASSWORD_LABEL, &peer_password)?;
+            }
+            (peer_ssid, peer_password)
+        };
+        if !ble_transfer {
+            return Ok((peer_os, ssid, password, peer_hosting, None));
         }
+
+        // keep serving until the transfer is done
+        let stream = with_timeout(
+            timeouts.connect,
+            "waiting for the peer to subscribe to the data characteristic",
+            peripheral.accept_data(),
+        )
+        .await?;
+        Ok((peer_os, ssid, password, peer_hosting, Some(stream)))
     } else {
         // acting as central
         // scan for device advertising flying carpet service
         ui.output("Scanning for Bluetooth peripherals...");
-        central.scan(ble_ui_rx)?;
-
-        central.stop_watching()?;
+        central.scan(matches!(mode, Mode::Send(_)))?;
+        let (address, swaps_keys) = match central.choose(mode, timeouts, ble_device_rx, ui).await {
+            Ok(chosen) => chosen,
+            Err(e) => {
+                central.stop_watching()?;
+                Err(e)?
+            }
+        };
         println!("stopped watching");
+        central.connect(
+            address,
+            pairing == PairingPolicy::Require,
+            swaps_keys,
+            ble_ui_rx.clone(),
+        );
 
-        // if we're looking for Pin or PairSuccess, process_bluetooth_message() will bail when it sees AlreadyPaired
         println!("waiting for callback...");
-        let msg =
-            process_bluetooth_message(BluetoothMessage::Pin("".to_string()), &mut rx, ui).await?;
+        session
+            .wait_for(
+                |s| *s != NegotiationState::Scanning,
+                "the chosen Bluetooth peer to pair",
+                timeouts.connect,
+                ui,
+            )
+            .await?;
 
-        // wait to pair
-        if msg != BluetoothMessage::AlreadyPaired {
-            process_bluetooth_message(BluetoothMessage::PairSuccess, &mut rx, ui).await?;
-        }
+        // wait to pair, which we already are if the peer was bonded
+        session
+            .wait_for(
+                |s| *s == NegotiationState::Paired,
+                "pairing to finish",
+                timeouts.connect,
+                ui,
+            )
+            .await?;
 
         println!("before get_services_and_characteristics");
         // discover service and characteristics once paired
-        if let Err(e) = central.get_services_and_characteristics().await {
+        if let Err(e) = with_timeout(
+            timeouts.discovery,
+            "discovering the peer's Bluetooth characteristics",
+            central.get_services_and_characteristics(),
+        )
+        .await
+        {
             if let Err(unpair_error) = central.unpair().await {
                 println!("Error unpairing: {}", unpair_error);
             }
@@ -169,9 +497,86 @@ pub async fn negotiate_bluetooth<T: UI>(
         }
         println!("after get_services_and_characteristics");
 
+        // swap capabilities, if the peer is new enough to have them
+        let has_capabilities = central.has_characteristic(CAPABILITIES_CHARACTERISTIC_UUID);
+        let mut ble_transfer = false;
+        let mut peer_capabilities = None;
+        if has_capabilities {
+            let swapped = async {
+                let value = with_timeout(
+                    timeouts.exchange_step,
+                    "reading the peer's capabilities",
+                    central.read(CAPABILITIES_CHARACTERISTIC_UUID),
+                )
+                .await?;
+                let peer_capabilities = match Capabilities::decode(&value) {
+                    Some(c) => c,
+                    None => Err(FCError {
+                        message: "Peer sent malformed capabilities".to_string(),
+                    })?,
+                };
+                peer_capabilities.check(ui)?;
+                with_timeout(
+                    timeouts.exchange_step,
+                    "writing our capabilities",
+                    central.write(CAPABILITIES_CHARACTERISTIC_UUID, &capabilities.encode()),
+                )
+                .await?;
+                Ok::<_, FCError>(peer_capabilities)
+            };
+            match swapped.await {
+                Ok(swapped) => {
+                    ble_transfer = central.has_characteristic(DATA_CHARACTERISTIC_UUID)
+                        && is_ble_transfer(&swapped, &capabilities);
+                    peer_capabilities = Some(swapped);
+                }
+                Err(e) => {
+                    if let Err(unpair_error) = central.unpair().await {
+                        println!("Error unpairing: {}", unpair_error);
+                    }
+                    Err(e)?
+                }
+            }
+        }
+
+        // swap public keys, if the peer is new enough to encrypt the credentials
+        let has_keys = central.has_characteristic(PERIPHERAL_KEY_CHARACTERISTIC_UUID)
+            && central.has_characteristic(CENTRAL_KEY_CHARACTERISTIC_UUID);
+        // a peer paired without the OS PIN can only be confirmed by the key exchange's
+        let key_check = if swaps_keys && !has_keys {
+            fc_error("The peer advertised a version of Flying Carpet that exchanges keys, but it didn't. The connection may have been tampered with.")
+        } else {
+            check_key_exchange(peer_capabilities.as_ref(), has_keys)
+        };
+        if let Err(e) = key_check {
+            if let Err(unpair_error) = central.unpair().await {
+                println!("Error unpairing: {}", unpair_error);
+            }
+            Err(e)?
+        }
+        let cipher = if has_keys {
+            match swap_keys(&mut central, key_pair, timeouts).await {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    if let Err(unpair_error) = central.unpair().await {
+                        println!("Error unpairing: {}", unpair_error);
+                    }
+                    Err(e)?
+                }
+            }
+        } else {
+            None
+        };
+
         ui.output("Reading peer's OS");
         // read peer's OS
-        let peer = match central.read(OS_CHARACTERISTIC_UUID).await {
+        let peer = match with_timeout(
+            timeouts.exchange_step,
+            "reading the peer's OS",
+            central.read(OS_CHARACTERISTIC_UUID),
+        )
+        .await
+        {
             Ok(p) => p,
             Err(e) => {
                 if let Err(unpair_error) = central.unpair().await {
@@ -183,7 +588,13 @@ pub async fn negotiate_bluetooth<T: UI>(
         ui.output(&format!("Peer OS: {:?}", peer));
 
         // write OS
-        if let Err(e) = central.write(OS_CHARACTERISTIC_UUID, OS).await {
+        if let Err(e) = with_timeout(
+            timeouts.exchange_step,
+            "writing our OS",
+            central.write(OS_CHARACTERISTIC_UUID, OS),
+        )
+        .await
+        {
             if let Err(unpair_error) = central.unpair().await {
                 println!("Error unpairing: {}", unpair_error);
             }
@@ -191,18 +602,64 @@ pub async fn negotiate_bluetooth<T: UI>(
         };
         println!("wrote OS");
 
+        // the user confirmed the OS pairing's PIN instead, now or when we bonded, unless the peer swaps keys
+        if let Some(c) = cipher.as_ref().filter(|_| swaps_keys) {
+            if let Err(e) =
+                confirm_pin(&c.pin, &mut *ble_ui_rx.lock().await, timeouts.connect, ui).await
+            {
+                if let Err(unpair_error) = central.unpair().await {
+                    println!("Error unpairing: {}", unpair_error);
+                }
+                Err(e)?
+            }
+        }
+
+        let peer_hosting = match PeerHosting::negotiate(
+            &capabilities,
+            peer_capabilities.as_ref(),
+            &Peer::from(peer.as_str()),
+            mode,
+        ) {
+            Ok(h) => h,
+            Err(e) => {
+                if let Err(unpair_error) = central.unpair().await {
+                    println!("Error unpairing: {}", unpair_error);
+                }
+                Err(e)?
+            }
+        };
+
         // read or write ssid and password
-        let (ssid, password) = if network::is_hosting(&Peer::from(peer.as_str()), mode) {
+        let (ssid, password) = if !peer_hosting.hosts {
             println!("hosting, writing wifi info to peer");
             let password = generate_password();
             let (_, ssid) = get_key_and_ssid(&password);
-            if let Err(e) = central.write(SSID_CHARACTERISTIC_UUID, &ssid).await {
+            let (sent_ssid, sent_password) = match &cipher {
+                Some(c) => (
+                    c.encrypt(SSID_LABEL, &ssid)?,
+                    c.encrypt(PASSWORD_LABEL, &password)?,
+                ),
+                None => (ssid.clone(), password.clone()),
+            };
+            if let Err(e) = with_timeout(
+                timeouts.exchange_step,
+                "writing our SSID",
+                central.write(SSID_CHARACTERISTIC_UUID, &sent_ssid),
+            )
+            .await
+            {
                 if let Err(unpair_error) = central.unpair().await {
                     println!("Error unpairing: {}", unpair_error);
                 }
                 Err(e)?
             }
-            if let Err(e) = central.write(PASSWORD_CHARACTERISTIC_UUID, &password).await {
+            if let Err(e) = with_timeout(
+                timeouts.exchange_step,
+                "writing our password",
+                central.write(PASSWORD_CHARACTERISTIC_UUID, &sent_password),
+            )
+            .await
+            {
                 if let Err(unpair_error) = central.unpair().await {
                     println!("Error unpairing: {}", unpair_error);
                 }
@@ -211,7 +668,7 @@ pub async fn negotiate_bluetooth<T: UI>(
             (ssid, password)
         } else {
             println!("joining, reading wifi info from peer");
-            let ssid = match central.read(SSID_CHARACTERISTIC_UUID).await {
+            let ssid = match read_ssid(&mut central, timeouts).await {
                 Ok(s) => s,
                 Err(e) => {
                     if let Err(unpair_error) = central.unpair().await {
@@ -220,7 +677,13 @@ pub async fn negotiate_bluetooth<T: UI>(
                     Err(e)?
                 }
             };
-            let password = match central.read(PASSWORD_CHARACTERISTIC_UUID).await {
+            let password = match with_timeout(
+                timeouts.exchange_step,
+                "reading the peer's password",
+                central.read(PASSWORD_CHARACTERISTIC_UUID),
+            )
+            .await
+            {
                 Ok(p) => p,
                 Err(e) => {
                     if let Err(unpair_error) = central.unpair().await {
@@ -229,65 +692,157 @@ pub async fn negotiate_bluetooth<T: UI>(
                     Err(e)?
                 }
             };
-            (ssid, password)
+            match &cipher {
+                Some(c) => (
+                    c.decrypt(SSID_LABEL, &ssid)?,
+                    c.decrypt(PASSWORD_LABEL, &password)?,
+                ),
+                None => (ssid, password),
+            }
         };
+        // the peripheral stops serving as soon as it sees this, so it may not get to respond
+        if has_capabilities {
+            if let Err(e) = with_timeout(
+                timeouts.exchange_step,
+                "telling the peer we're done",
+                central.write(CAPABILITIES_CHARACTERISTIC_UUID, GATT_DONE),
+            )
+            .await
+            {
+                println!("Error telling peer we're done: {}", e);
+            }
+        }
         // unpair after every transfer because windows has trouble enumerating services of already-paired devices?
-        // if let Err(unpair_error) = central.unpair().await {
-        //     println!("Error unpairing: {}", unpair_error);
-        // }
-        Ok((peer, ssid, password))
+        // macOS has to be paired by hand from its system menu, though, so stay paired with it.
+        central.forget = pairing == PairingPolicy::Forget && peer != "mac";
+        if !ble_transfer {
+            return Ok((peer, ssid, password, peer_hosting, None));
+        }
+
+        // stay connected until the transfer is done
+        let stream = with_timeout(
+            timeouts.exchange_step,
+            "subscribing to the data characteristic",
+            central.open_data(),
+        )
+        .await?;
+        Ok((peer, ssid, password, peer_hosting, Some(stream)))
     }
 }
 
-pub async fn process_bluetooth_message<T: UI>(
-    looking_for: BluetoothMessage,
-    rx: &mut mpsc::Receiver<BluetoothMessage>,
-    ui: &T,
-) -> Result<BluetoothMessage, FCError> {
+// reads the peripheral's public key and writes ours
+async fn swap_keys(
+    central: &mut BluetoothCentral,
+    key_pair: BluetoothKeyPair,
+    timeouts: &BluetoothTimeouts,
+) -> Result<CredentialCipher, FCError> {
+    let peer_key = with_timeout(
+        timeouts.exchange_step,
+        "reading the peer's public key",
+        central.read(PERIPHERAL_KEY_CHARACTERISTIC_UUID),
+    )
+    .await?;
+    with_timeout(
+        timeouts.exchange_step,
+        "writing our public key",
+        central.write(CENTRAL_KEY_CHARACTERISTIC_UUID, &key_pair.public_hex()),
+    )
+    .await?;
+    key_pair.agree(&peer_key, true)
+}
+
+// the peer serves NO_SSID until it's ready, which may be while its user confirms the PIN
+async fn read_ssid(
+    central: &mut BluetoothCentral,
+    timeouts: &BluetoothTimeouts,
+) -> Result<String, FCError> {
+    let deadline = Instant::now() + timeouts.connect;
     loop {
-        println!("waiting for bluetooth message...");
-        let msg = rx
-            .recv()
-            .await
-            .expect("Bluetooth message channel unexpectedly closed.");
-        println!("received {:?}", msg);
-        match &msg {
-            BluetoothMessage::Pin(pin) => {
-                ui.show_pin(pin);
-            }
-            BluetoothMessage::PairApproved => ui.output("Pairing approved."),
-            BluetoothMessage::PairSuccess => {
-                // can use this to represent AlreadyPaired on windows? don't need to emit pin, just need to proceed.
-                // and nothing will be blocked in central because the pairing_handler won't be called.
-                ui.output("Successfully paired");
+        let ssid = with_timeout(
+            timeouts.exchange_step,
+            "reading the peer's SSID",
+            central.read(SSID_CHARACTERISTIC_UUID),
+        )
+        .await?;
+        if ssid != NO_SSID {
+            return Ok(ssid);
+        }
+        if Instant::now() >= deadline {
+            Err(timeout_error(
+                timeouts.connect,
+                "waiting for the peer to share its SSID",
+            ))?
+        }
+        time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+// the central says when it's finished, unless it's too old to
+async fn wait_for_done<T: UI>(
+    session: &BluetoothSession,
+    timeouts: &BluetoothTimeouts,
+    ui: &T,
+) -> Result<(), FCError> {
+    session
+        .wait_for(
+            |s| matches!(s, NegotiationState::Done(_)),
+            "the peer to finish the Bluetooth exchange",
+            timeouts.exchange_step,
+            ui,
+        )
+        .await?;
+    println!("Peer finished the Bluetooth exchange");
+    Ok(())
+}
+
+// the transfer runs over the data characteristic when the files are small enough to skip WiFi. packets the peer sent arrive on incoming,
+// and what the transfer writes goes out through send() in pieces of at most packet_size.
+fn data_stream<F>(
+    mut incoming: mpsc::Receiver<Vec<u8>>,
+    packet_size: usize,
+    mut send: F,
+    connection: Box<dyn Send + Sync>,
+) -> BluetoothStream
+where
+    F: FnMut(&[u8]) -> Result<(), FCError> + Send + 'static,
+{
+    let (reader, mut to_reader) = duplex(packet_size * 16);
+    let (writer, mut from_writer) = duplex(packet_size * 16);
+    spawn(async move {
+        while let Some(packet) = incoming.recv().await {
+            if to_reader.write_all(&packet).await.is_err() {
+                break;
             }
-            BluetoothMessage::PairFailure => fc_error("Pairing failed.")?,
-            BluetoothMessage::AlreadyPaired => {
-                ui.output("Already BLE paired with Bluetooth device");
-                if looking_for == BluetoothMessage::PairSuccess
-                    || discriminant(&looking_for)
-                        == discriminant(&BluetoothMessage::Pin("".to_string()))
-                {
-                    return Ok(msg);
+        }
+    });
+    spawn(async move {
+        let mut packet = vec![0; packet_size];
+        loop {
+            match from_writer.read(&mut packet).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if let Err(e) = send(&packet[..n]) {
+                        println!("Error sending on the data characteristic: {}", e);
+                        break;
+                    }
                 }
             }
-            BluetoothMessage::UserCanceled => fc_error("User canceled.")?,
-            BluetoothMessage::StartedAdvertising => {
-                ui.output("Started advertising Bluetooth service")
-            }
-            BluetoothMessage::PeerOS(os) => ui.output(&format!("Peer's OS is {}", os)),
-            BluetoothMessage::SSID(ssid) => ui.output(&format!("Peer's SSID is {}", ssid)),
-            BluetoothMessage::Password(password) => {
-                ui.output(&format!("Peer's password is {}", password))
-            }
-            BluetoothMessage::PeerReadSsid => ui.output("Peer read our SSID"),
-            BluetoothMessage::PeerReadPassword => ui.output("Peer read our password"),
-            BluetoothMessage::OtherError(s) => fc_error(s.as_str())?,
-        };
-        if discriminant(&msg) == discriminant(&looking_for) {
-            return Ok(msg);
         }
-    }
+    });
+    BluetoothStream::new(reader, writer, connection)
+}
+
+fn ibuffer_to_bytes(ibuffer: IBuffer) -> windows::core::Result<Vec<u8>> {
+    let data_reader = DataReader::FromBuffer(&ibuffer)?;
+    let mut bytes = vec![0; data_reader.UnconsumedBufferLength()? as usize];
+    data_reader.ReadBytes(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn bytes_to_ibuffer(bytes: &[u8]) -> windows::core::Result<IBuffer> {
+    let data_writer = DataWriter::new()?;
+    data_writer.WriteBytes(bytes)?;
+    data_writer.DetachBuffer()
 }
 
 fn ibuffer_to_string(ibuffer: IBuffer) -> windows::core::Result<String> {
@@ -2,18 +2,24 @@ use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
 };
-use tokio::sync::mpsc;
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
 use windows::{
     core::GUID,
     Devices::{
         Bluetooth::{
             Advertisement::{
-                BluetoothLEAdvertisementReceivedEventArgs, BluetoothLEAdvertisementWatcher,
-                BluetoothLEAdvertisementWatcherStatus,
+                BluetoothLEAdvertisement, BluetoothLEAdvertisementReceivedEventArgs,
+                BluetoothLEAdvertisementType, BluetoothLEAdvertisementWatcher,
+                BluetoothLEAdvertisementWatcherStatus, BluetoothLEScanningMode,
             },
             BluetoothCacheMode, BluetoothConnectionStatus, BluetoothLEDevice,
             GenericAttributeProfile::{
-                GattCharacteristic, GattCommunicationStatus, GattDeviceService, GattWriteOption,
+                GattCharacteristic, GattClientCharacteristicConfigurationDescriptorValue,
+                GattCommunicationStatus, GattDeviceService, GattValueChangedEventArgs,
+                GattWriteOption,
             },
         },
         Enumeration::{
@@ -23,54 +29,70 @@ use windows::{
         },
     },
     Foundation::{EventRegistrationToken, TypedEventHandler},
+    Storage::Streams::DataReader,
 };
 
-use super::{FCError, OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID};
+use super::{
+    bytes_to_ibuffer, data_stream, ibuffer_to_bytes, FCError, CAPABILITIES_CHARACTERISTIC_UUID,
+    CENTRAL_KEY_CHARACTERISTIC_UUID, DATA_CHARACTERISTIC_UUID, OS_CHARACTERISTIC_UUID,
+    PASSWORD_CHARACTERISTIC_UUID, PERIPHERAL_KEY_CHARACTERISTIC_UUID,
+};
 use crate::bluetooth::{
     fc_error, ibuffer_to_string, str_to_ibuffer, SERVICE_UUID, SSID_CHARACTERISTIC_UUID,
 };
-use crate::utils::BluetoothMessage;
+use crate::bluetooth_stream::BluetoothStream;
+use crate::negotiation::{BluetoothMessage, BluetoothSession};
+use crate::utils::{
+    is_compatible, peer_description, timeout_error, unbonded_error, AdvertisementInfo,
+    BluetoothTimeouts, ADVERTISEMENT_COMPANY_ID, SCAN_WINDOW,
+};
+use crate::{BluetoothDevice, Mode, MAJOR_VERSION, UI};
 
 type ScanCallback =
     TypedEventHandler<BluetoothLEAdvertisementWatcher, BluetoothLEAdvertisementReceivedEventArgs>;
 
 pub(crate) struct BluetoothCentral {
-    tx: mpsc::Sender<BluetoothMessage>,
+    session: BluetoothSession,
     watcher: BluetoothLEAdvertisementWatcher,
+    // advertisers the user can choose from, by address
+    found: Arc<Mutex<Vec<(u64, BluetoothDevice)>>>,
     custom_pairing: Arc<Mutex<Option<DeviceInformationCustomPairing>>>,
     peer_device: Arc<tokio::sync::Mutex<Option<BluetoothLEDevice>>>,
     peer_service: Option<GattDeviceService>,
     characteristics: HashMap<String, Option<GattCharacteristic>>,
     scan_callback_token: Option<EventRegistrationToken>,
     pair_callback_token: Arc<Mutex<Option<EventRegistrationToken>>>,
+    // whether to unpair once we're done with the peer
+    pub forget: bool,
 }
 
 impl BluetoothCentral {
-    pub fn new(tx: mpsc::Sender<BluetoothMessage>) -> windows::core::Result<Self> {
+    pub fn new(session: BluetoothSession) -> windows::core::Result<Self> {
         let mut characteristics = HashMap::new();
         characteristics.insert(OS_CHARACTERISTIC_UUID.to_string(), None);
         characteristics.insert(SSID_CHARACTERISTIC_UUID.to_string(), None);
         characteristics.insert(PASSWORD_CHARACTERISTIC_UUID.to_string(), None);
+        characteristics.insert(CAPABILITIES_CHARACTERISTIC_UUID.to_string(), None);
+        characteristics.insert(PERIPHERAL_KEY_CHARACTERISTIC_UUID.to_string(), None);
+        characteristics.insert(CENTRAL_KEY_CHARACTERISTIC_UUID.to_string(), None);
+        characteristics.insert(DATA_CHARACTERISTIC_UUID.to_string(), None);
         Ok(BluetoothCentral {
-            tx,
+            session,
             watcher: BluetoothLEAdvertisementWatcher::new()?,
+            found: Arc::new(Mutex::new(vec![])),
             custom_pairing: Arc::new(Mutex::new(None)),
             peer_device: Arc::new(tokio::sync::Mutex::new(None)),
             peer_service: None,
             characteristics,
             scan_callback_token: None,
             pair_callback_token: Arc::new(Mutex::new(None)),
+            forget: false,
         })
     }
 
-    // start thread to send on tx, return handle to thread and rx?
-    pub fn scan(&mut self, ble_ui_rx: mpsc::Receiver<bool>) -> windows::core::Result<()> {
-        let ble_ui_rx = Arc::new(Mutex::new(ble_ui_rx));
-        let thread_peer_device = self.peer_device.clone();
-        let thread_tx = self.tx.clone();
-        // let thread_scan_callback_token = self.scan_callback_token.clone();
-        let thread_custom_pairing = self.custom_pairing.clone();
-        let thread_pair_callback_token = self.pair_callback_token.clone();
+    // the watcher's callback collects the peers that could be transferring with us, for choose() to show to the user
+    pub fn scan(&mut self, sending: bool) -> windows::core::Result<()> {
+        let thread_found = self.found.clone();
         let received_handler = ScanCallback::new(move |_watcher, received_event_args| {
             let received_event_args = received_event_args
                 .as_ref()
@@ -78,139 +100,51 @@ impl BluetoothCentral {
             let advertisement = received_event_args
                 .Advertisement()
                 .expect("Could not get Advertisement from received_event_args.");
-            let address = received_event_args.BluetoothAddress()?; // TODO: write scan result failed to tx if the ?s in this function fail?
+            let address = received_event_args.BluetoothAddress()?;
+            let local_name = advertisement.LocalName()?.to_string();
+            // scan responses only carry the local name, so fill it in for the advertiser it belongs to
+            if received_event_args.AdvertisementType()?
+                == BluetoothLEAdvertisementType::ScanResponse
+            {
+                let mut found = thread_found.lock().expect("Could not lock found devices");
+                if let Some((_, device)) = found.iter_mut().find(|(a, _)| *a == address) {
+                    if !local_name.is_empty() {
+                        device.name = local_name;
+                    }
+                }
+                return Ok(());
+            }
             let service_uuids = advertisement.ServiceUuids()?;
             for uuid in service_uuids {
                 if uuid == GUID::from(SERVICE_UUID) {
-                    // stop watching
-                    if let Some(watcher) = _watcher {
-                        watcher.Stop()?;
-                        println!("Stopped watching inside received_handler")
+                    let info = advertised_info(&advertisement, &local_name)?;
+                    if !is_listed(info.as_ref(), sending) {
+                        println!(
+                            "Skipping {:12x}, its advertisement says it can't transfer with us",
+                            address
+                        );
+                        return Ok(());
                     }
-                    // device is advertising flying carpet service, we want to pair with it
                     println!("found bluetooth {:12x}", address);
-                    let device = BluetoothLEDevice::FromBluetoothAddressAsync(address)?.get()?;
-                    let info = device
-                        .DeviceInformation()
-                        .expect("Could not get DeviceInformation for peer peripheral.");
-                    {
-                        // drop this lock once we've stored the device
-                        let mut peer_device = thread_peer_device.blocking_lock();
-                        if *peer_device == None {
-                            *peer_device = Some(device.clone());
-                        } else {
-                            println!("Found another device advertising service but we've already initiated pairing");
-                            return Ok(());
-                        }
-                    }
-                    // determine if we're already paired
-
-                    // let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
-                    // let paired_devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.get()?;
-                    // for paired_device in paired_devices {
-                    //     println!("1 paired device: {:?}", paired_device.Id()?);
-                    //     let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.Id()?)?.get()?;
-                    //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
-                    //         Ok(ar) => ar,
-                    //         Err(e) => {
-                    //             // thread_tx.blocking_send(BluetoothMessage::UserCanceled);
-                    //             println!("oh no: {e}");
-                    //             return Ok(());
-                    //         }
-                    //     };
-                    //     println!("yeah");
-                    //     // let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.)
-                    //     println!("paired device: {:?}", _paired_device.Name()?);
-                    //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
-                    //         Ok(ar) => ar,
-                    //         Err(e) => {
-                    //             thread_tx.blocking_send(BluetoothMessage::UserCanceled);
-                    //             println!("{e}");
-                    //             return Ok(());
-                    //         }
-                    //     };
-                    //     let services_result = async_result.get()?;
-                    //     println!("get services result: {:?}", services_result.Status());
-                    //     let services = services_result.Services()?;
-                    //     for service in services {
-                    //         println!("UUID: {:?}", service.Uuid()?);
-                    //     }
-                    // }
-
-                    // let res = thread_tx.blocking_send(BluetoothMessage::AlreadyPaired);
-                    // if res.is_err() {
-                    //     println!("Could not send on channel");
-                    // }
-
-                    let connection_status = device.ConnectionStatus()?;
-                    if connection_status == BluetoothConnectionStatus::Connected {
-                        let secure_connection_used = device.WasSecureConnectionUsedForPairing()?;
-                        if secure_connection_used {
-                            if thread_tx
-                                .blocking_send(BluetoothMessage::AlreadyPaired)
-                                .is_err()
-                            {
-                                println!(
-                                    "Could not send on Bluetooth tx when we've already paired"
-                                );
-                                return Ok(());
-                            }
-                            return Ok(());
-                        } else {
-                            println!("secure connection was not used")
-                        }
-                    } else {
-                        println!("weren't connected");
-                        // TODO: connect here
-                        // try to read services here?
-                        let x = device.RequestAccessAsync()?.get()?;
-                        println!("{:?}", x);
-                        println!("requested access");
-                        // let id = device.DeviceId()?;
-                        // let id = BluetoothDeviceId::FromId(&id)?;
-                        // let session = GattSession::FromDeviceIdAsync(&id)?.get()?;
-                        // session.SetMaintainConnection(true)?;
-                        // println!("set maintain connection to true");
-
-                        // let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
-                        // let paired_devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.get()?;
-                        // // let paired_devices = DeviceInformation::FindAllAsync()?.get()?;
-                        // for paired_device in paired_devices {
-                        //     println!("paired device: {:?}", paired_device.Id()?);
-                        //     let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.Id()?)?.get()?;
-                        //     // let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.)
-                        //     println!("paired device: {:?}", _paired_device.Name()?);
-                        //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
-                        //         Ok(ar) => ar,
-                        //         Err(e) => {
-                        //             thread_tx.blocking_send(BluetoothMessage::UserCanceled);
-                        //             println!("{e}");
-                        //             return Ok(());
-                        //         }
-                        //     };
-                        //     let services_result = async_result.get()?;
-                        //     println!("get services result: {:?}", services_result.Status());
-                        //     let services = services_result.Services()?;
-                        //     for service in services {
-                        //         println!("UUID: {:?}", service.Uuid()?);
-                        //     }
-                        // }
-
-                        // let res = thread_tx.blocking_send(BluetoothMessage::AlreadyPaired);
-                        // if res.is_err() {
-                        //     println!("Could not send on channel");
-                        // }
-                        // return Ok(());
-                    }
-
-                    // if we weren't paired, do so
-                    BluetoothCentral::pair_device(
-                        &info,
-                        ble_ui_rx.clone(),
-                        thread_tx.clone(),
-                        thread_custom_pairing.clone(),
-                        thread_pair_callback_token.clone(),
-                    )?;
+                    let mut found = thread_found.lock().expect("Could not lock found devices");
+                    // advertisements repeat, so keep the latest signal strength for each peer, and the name from its
+                    // scan response
+                    let name = match (
+                        info.as_ref().and_then(|i| i.name.clone()),
+                        found.iter().find(|(a, _)| *a == address),
+                    ) {
+                        (Some(name), _) => name,
+                        (None, Some((_, device))) => device.name.clone(),
+                        (None, None) => format!("{:012x}", address),
+                    };
+                    let device = BluetoothDevice {
+                        name,
+                        address: format!("{:012x}", address),
+                        rssi: Some(received_event_args.RawSignalStrengthInDBm()?),
+                        peer_os: info.map(|i| i.os),
+                    };
+                    found.retain(|(a, _)| *a != address);
+                    found.push((address, device));
                 }
             }
             Ok(())
@@ -218,18 +152,231 @@ impl BluetoothCentral {
         let scan_callback_token = self.watcher.Received(&received_handler)?;
         self.scan_callback_token = Some(scan_callback_token);
         println!("self.scan_callback_token is set");
+        // peers' names are in their scan responses
+        self.watcher
+            .SetScanningMode(BluetoothLEScanningMode::Active)?;
         self.watcher.Start()?;
         Ok(())
     }
 
+    // waits for SCAN_WINDOW, or until at least one device is found after that, then asks the user which one is the peer.
+    // gives up if nobody has shown up by the scan timeout. also returns whether the peer advertised our info, which only
+    // versions that swap keys do.
+    pub async fn choose<T: UI>(
+        &self,
+        mode: &Mode,
+        timeouts: &BluetoothTimeouts,
+        mut ble_device_rx: mpsc::Receiver<Option<String>>,
+        ui: &T,
+    ) -> Result<(u64, bool), FCError> {
+        let scan_end = Instant::now() + timeouts.scan;
+        let mut window_end = Instant::now() + SCAN_WINDOW;
+        loop {
+            sleep_until(window_end).await;
+            // keep scanning until someone shows up
+            if !self.found_devices().is_empty() || Instant::now() >= scan_end {
+                break;
+            }
+            window_end = scan_end.min(Instant::now() + SCAN_WINDOW);
+        }
+        self.stop_watching()?;
+        let mut devices = self.found_devices();
+        if devices.is_empty() {
+            Err(timeout_error(
+                timeouts.scan,
+                &format!("scanning without finding a {}", peer_description(mode)),
+            ))?
+        }
+        // strongest signal first
+        devices.sort_by_key(|d| std::cmp::Reverse(d.rssi));
+        ui.show_bluetooth_devices(&devices);
+
+        let chosen = match ble_device_rx.recv().await {
+            Some(Some(address)) => address,
+            _ => Err(FCError {
+                message: "User canceled.".to_string(),
+            })?,
+        };
+        let device = devices.iter().find(|d| d.address == chosen);
+        match (u64::from_str_radix(&chosen, 16), device) {
+            (Ok(address), Some(device)) => Ok((address, device.peer_os.is_some())),
+            _ => Err(FCError {
+                message: format!("Chosen device {} was not found", chosen),
+            }),
+        }
+    }
+
+    fn found_devices(&self) -> Vec<BluetoothDevice> {
+        let found = self.found.lock().expect("Could not lock found devices");
+        found.iter().map(|(_, device)| device.clone()).collect()
+    }
+
+    // pairs with the chosen peer off the async runtime, since pairing blocks until the user confirms the PIN, and
+    // reports how that goes to the session. a peer that swaps keys is confirmed with the key exchange's PIN instead of
+    // the OS's, so that the user only sees one.
+    pub fn connect(
+        &self,
+        address: u64,
+        require_bond: bool,
+        swaps_keys: bool,
+        ble_ui_rx: Arc<tokio::sync::Mutex<mpsc::Receiver<bool>>>,
+    ) {
+        let peer_device = self.peer_device.clone();
+        let session = self.session.clone();
+        let custom_pairing = self.custom_pairing.clone();
+        let pair_callback_token = self.pair_callback_token.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = BluetoothCentral::pair_peer(
+                address,
+                require_bond,
+                (!swaps_keys).then_some(ble_ui_rx),
+                peer_device,
+                session.clone(),
+                custom_pairing,
+                pair_callback_token,
+            );
+            if let Err(e) = result {
+                let _ = session.advance(BluetoothMessage::OtherError(format!(
+                    "Error pairing with {:012x}: {}",
+                    address, e
+                )));
+            }
+        });
+    }
+
+    // ble_ui_rx is None when the OS PIN isn't shown
+    fn pair_peer(
+        address: u64,
+        require_bond: bool,
+        ble_ui_rx: Option<Arc<tokio::sync::Mutex<mpsc::Receiver<bool>>>>,
+        peer_device: Arc<tokio::sync::Mutex<Option<BluetoothLEDevice>>>,
+        session: BluetoothSession,
+        custom_pairing: Arc<Mutex<Option<DeviceInformationCustomPairing>>>,
+        pair_callback_token: Arc<Mutex<Option<EventRegistrationToken>>>,
+    ) -> windows::core::Result<()> {
+        let device = BluetoothLEDevice::FromBluetoothAddressAsync(address)?.get()?;
+        let info = device
+            .DeviceInformation()
+            .expect("Could not get DeviceInformation for peer peripheral.");
+        *peer_device.blocking_lock() = Some(device.clone());
+        // determine if we're already paired
+
+        // let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
+        // let paired_devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.get()?;
+        // for paired_device in paired_devices {
+        //     println!("1 paired device: {:?}", paired_device.Id()?);
+        //     let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.Id()?)?.get()?;
+        //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
+        //         Ok(ar) => ar,
+        //         Err(e) => {
+        //             // thread_tx.blocking_send(BluetoothMessage::UserCanceled);
+        //             println!("oh no: {e}");
+        //             return Ok(());
+        //         }
+        //     };
+        //     println!("yeah");
+        //     // let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.)
+        //     println!("paired device: {:?}", _paired_device.Name()?);
+        //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
+        //         Ok(ar) => ar,
+        //         Err(e) => {
+        //             thread_tx.blocking_send(BluetoothMessage::UserCanceled);
+        //             println!("{e}");
+        //             return Ok(());
+        //         }
+        //     };
+        //     let services_result = async_result.get()?;
+        //     println!("get services result: {:?}", services_result.Status());
+        //     let services = services_result.Services()?;
+        //     for service in services {
+        //         println!("UUID: {:?}", service.Uuid()?);
+        //     }
+        // }
+
+        // let res = thread_tx.blocking_send(BluetoothMessage::AlreadyPaired);
+        // if res.is_err() {
+        //     println!("Could not send on channel");
+        // }
+
+        let connection_status = device.ConnectionStatus()?;
+        if connection_status == BluetoothConnectionStatus::Connected {
+            let secure_connection_used = device.WasSecureConnectionUsedForPairing()?;
+            if secure_connection_used {
+                // the session has already failed and said why if this is an error
+                let _ = session.advance(BluetoothMessage::AlreadyPaired);
+                return Ok(());
+            } else {
+                println!("secure connection was not used")
+            }
+        } else {
+            println!("weren't connected");
+            // TODO: connect here
+            // try to read services here?
+            let x = device.RequestAccessAsync()?.get()?;
+            println!("{:?}", x);
+            println!("requested access");
+            // let id = device.DeviceId()?;
+            // let id = BluetoothDeviceId::FromId(&id)?;
+            // let session = GattSession::FromDeviceIdAsync(&id)?.get()?;
+            // session.SetMaintainConnection(true)?;
+            // println!("set maintain connection to true");
+
+            // let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
+            // let paired_devices = DeviceInformation::FindAllAsyncAqsFilter(&selector)?.get()?;
+            // // let paired_devices = DeviceInformation::FindAllAsync()?.get()?;
+            // for paired_device in paired_devices {
+            //     println!("paired device: {:?}", paired_device.Id()?);
+            //     let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.Id()?)?.get()?;
+            //     // let _paired_device = BluetoothLEDevice::FromIdAsync(&paired_device.)
+            //     println!("paired device: {:?}", _paired_device.Name()?);
+            //     let async_result = match _paired_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Uncached) {
+            //         Ok(ar) => ar,
+            //         Err(e) => {
+            //             thread_tx.blocking_send(BluetoothMessage::UserCanceled);
+            //             println!("{e}");
+            //             return Ok(());
+            //         }
+            //     };
+            //     let services_result = async_result.get()?;
+            //     println!("get services result: {:?}", services_result.Status());
+            //     let services = services_result.Services()?;
+            //     for service in services {
+            //         println!("UUID: {:?}", service.Uuid()?);
+            //     }
+            // }
+
+            // let res = thread_tx.blocking_send(BluetoothMessage::AlreadyPaired);
+            // if res.is_err() {
+            //     println!("Could not send on channel");
+            // }
+            // return Ok(());
+        }
+
+        if require_bond && !info.Pairing()?.IsPaired()? {
+            let error = unbonded_error(&info.Name()?.to_string());
+            let _ = session.advance(BluetoothMessage::OtherError(error.message));
+            return Ok(());
+        }
+
+        // if we weren't paired, do so
+        BluetoothCentral::pair_device(
+            &info,
+            ble_ui_rx,
+            session.clone(),
+            custom_pairing.clone(),
+            pair_callback_token.clone(),
+        )?;
+        Ok(())
+    }
+
     pub fn pair_device(
         device_info: &DeviceInformation,
-        ble_ui_rx: Arc<Mutex<mpsc::Receiver<bool>>>,
-        tx: mpsc::Sender<BluetoothMessage>,
+        ble_ui_rx: Option<Arc<tokio::sync::Mutex<mpsc::Receiver<bool>>>>,
+        session: BluetoothSession,
         out_custom_pairing: Arc<Mutex<Option<DeviceInformationCustomPairing>>>,
         out_pair_callback_token: Arc<Mutex<Option<EventRegistrationToken>>>,
     ) -> windows::core::Result<()> {
-        let thread_tx = tx.clone();
+        let thread_session = session.clone();
         println!("Pairing {}", device_info.Name()?);
         let pairing = device_info.Pairing()?;
         let custom_pairing = pairing.Custom()?;
@@ -246,35 +393,29 @@ impl BluetoothCentral {
         >::new(move |_custom_pairing, _event_args| {
             println!("Custom pairing requested");
             let args = _event_args.clone().unwrap();
+            // the key exchange's PIN, which the peer shows too, confirms this connection once we've paired
+            let ble_ui_rx = match &ble_ui_rx {
+                Some(rx) => rx,
+                None => {
+                    args.Accept()?;
+                    return Ok(());
+                }
+            };
             let pin = args.Pin()?.to_string();
             // emit this pin to js
-            if let Err(e) = thread_tx.blocking_send(BluetoothMessage::Pin(pin)) {
-                println!(
-                    "Could not send on Bluetooth tx when PIN was generated: {}",
-                    e
-                );
+            if thread_session.advance(BluetoothMessage::Pin(pin)).is_err() {
+                return Ok(());
             }
             // we need to receive javascript's answer here... which means we need ble_ui_rx here, which means we can't use it from the struct and clone it, which means we have to wrap it in an arc<mutex>?
             let approved = ble_ui_rx
-                .lock()
-                .expect("Could not lock ble_ui_rx mutex.")
+                .blocking_lock()
                 .blocking_recv()
                 .expect("ble_ui_rx reply from js was None");
             if approved {
                 args.Accept()?;
-                if thread_tx
-                    .blocking_send(BluetoothMessage::PairApproved)
-                    .is_err()
-                {
-                    println!("Could not send on Bluetooth tx in pairing callback");
-                };
+                let _ = thread_session.advance(BluetoothMessage::PairApproved);
             } else {
-                if thread_tx
-                    .blocking_send(BluetoothMessage::UserCanceled)
-                    .is_err()
-                {
-                    println!("Could not send on Bluetooth tx in pairing callback");
-                };
+                let _ = thread_session.advance(BluetoothMessage::UserCanceled);
             }
             Ok(())
         });
@@ -305,13 +446,7 @@ impl BluetoothCentral {
             DevicePairingResultStatus::Paired => BluetoothMessage::PairSuccess,
             _ => BluetoothMessage::OtherError(error_msg.to_string()),
         };
-        let res = tx.blocking_send(msg);
-        if res.is_err() {
-            println!(
-                "pair_device() was called but transfer thread has stopped listening: {}",
-                res.unwrap_err()
-            );
-        }
+        let _ = session.advance(msg);
         Ok(())
     }
 
@@ -379,6 +514,10 @@ impl BluetoothCentral {
                     OS_CHARACTERISTIC_UUID,
                     SSID_CHARACTERISTIC_UUID,
                     PASSWORD_CHARACTERISTIC_UUID,
+                    CAPABILITIES_CHARACTERISTIC_UUID,
+                    PERIPHERAL_KEY_CHARACTERISTIC_UUID,
+                    CENTRAL_KEY_CHARACTERISTIC_UUID,
+                    DATA_CHARACTERISTIC_UUID,
                 ] {
                     let characteristics = service
                         .GetCharacteristicsForUuidAsync(GUID::from(characteristic))?
@@ -417,6 +556,11 @@ impl BluetoothCentral {
         Ok(())
     }
 
+    // older peers and the mobile apps don't have every characteristic
+    pub fn has_characteristic(&self, characteristic_uuid: &str) -> bool {
+        matches!(self.characteristics.get(characteristic_uuid), Some(Some(_)))
+    }
+
     pub async fn read(&mut self, characteristic_uuid: &str) -> windows::core::Result<String> {
         // tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         println!("reading {}", characteristic_uuid);
@@ -436,13 +580,12 @@ impl BluetoothCentral {
         let ibuffer = res.unwrap();
         println!("before ibuffer_to_string");
         let data_string = ibuffer_to_string(ibuffer)?;
-        println!("IBuffer contents: {:?}", data_string);
         Ok(data_string)
     }
 
     pub async fn write(&mut self, characteristic_uuid: &str, value: &str) -> Result<(), FCError> {
         // tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        println!("writing value: {}", value);
+        println!("writing {}", characteristic_uuid);
         let characteristic = self.characteristics[characteristic_uuid]
             .as_ref()
             .expect(&format!("Missing characteristic {}", characteristic_uuid));
@@ -462,6 +605,58 @@ impl BluetoothCentral {
         Ok(())
     }
 
+    // subscribes to the data characteristic, then stays connected until the transfer is done
+    pub async fn open_data(self) -> Result<BluetoothStream, FCError> {
+        let characteristic = self.characteristics[DATA_CHARACTERISTIC_UUID]
+            .clone()
+            .expect("Missing data characteristic");
+        // the ATT header takes 3 bytes of each PDU
+        let packet_size = match &self.peer_service {
+            Some(service) => service.Session()?.MaxPduSize()? as usize - 3,
+            None => 20,
+        };
+        let (tx, rx) = mpsc::channel(64);
+        let value_changed_callback = TypedEventHandler::<
+            GattCharacteristic,
+            GattValueChangedEventArgs,
+        >::new(move |_characteristic, args| {
+            let args = args.as_ref().expect("No args in value changed callback");
+            let packet = ibuffer_to_bytes(args.CharacteristicValue()?)?;
+            if let Err(e) = tx.blocking_send(packet) {
+                println!("Could not send on data tx: {}", e);
+            }
+            Ok(())
+        });
+        characteristic.ValueChanged(&value_changed_callback)?;
+        let status = characteristic
+            .WriteClientCharacteristicConfigurationDescriptorAsync(
+                GattClientCharacteristicConfigurationDescriptorValue::Notify,
+            )?
+            .get()?;
+        if status != GattCommunicationStatus::Success {
+            fc_error(&format!(
+                "Error subscribing to Bluetooth peripheral: {:?}",
+                status
+            ))?;
+        }
+        let send = move |packet: &[u8]| {
+            let status = characteristic
+                .WriteValueWithOptionAsync(
+                    &bytes_to_ibuffer(packet)?,
+                    GattWriteOption::WriteWithoutResponse,
+                )?
+                .get()?;
+            if status != GattCommunicationStatus::Success {
+                fc_error(&format!(
+                    "Error writing to Bluetooth peripheral: {:?}",
+                    status
+                ))?;
+            }
+            Ok(())
+        };
+        Ok(data_stream(rx, packet_size, send, Box::new(self)))
+    }
+
     // used higher up if reads/writes fail
     pub async fn unpair(&self) -> windows::core::Result<()> {
         let device = self.peer_device.lock().await;
@@ -474,6 +669,23 @@ impl BluetoothCentral {
     }
 }
 
+impl Drop for BluetoothCentral {
+    fn drop(&mut self) {
+        if !self.forget {
+            return;
+        }
+        let Ok(device) = self.peer_device.try_lock() else {
+            println!("Couldn't lock peer device to unpair from it");
+            return;
+        };
+        if let Some(info) = device.as_ref().and_then(|d| d.DeviceInformation().ok()) {
+            if let Err(e) = unpair(info) {
+                println!("Error unpairing: {}", e);
+            }
+        }
+    }
+}
+
 // used within BluetoothCentral because get_services_and_characteristics() will already have locked the peer device mutex
 fn unpair(info: DeviceInformation) -> windows::core::Result<()> {
     let pairing = info.Pairing()?;
@@ -550,3 +762,30 @@ const ERRORS: [(i32, &str); 20] = [
     ),
     (DevicePairingResultStatus::Failed.0, "Failed"),
 ];
+
+fn advertised_info(
+    advertisement: &BluetoothLEAdvertisement,
+    local_name: &str,
+) -> windows::core::Result<Option<AdvertisementInfo>> {
+    for data in advertisement.GetManufacturerDataByCompanyId(ADVERTISEMENT_COMPANY_ID)? {
+        let buffer = data.Data()?;
+        let mut bytes = vec![0u8; buffer.Length()? as usize];
+        DataReader::FromBuffer(&buffer)?.ReadBytes(&mut bytes)?;
+        if let Some(info) = AdvertisementInfo::decode(&bytes, Some(local_name.to_string())) {
+            return Ok(Some(info));
+        }
+    }
+    Ok(None)
+}
+
+// older versions and the mobile apps don't advertise any info, so only skip peers that say they're going the same
+// direction as us or are too old
+fn is_listed(info: Option<&AdvertisementInfo>, sending: bool) -> bool {
+    match info {
+        Some(info) => {
+            let version = info.protocol_version as u64;
+            info.sending != sending && (version >= MAJOR_VERSION || is_compatible(version))
+        }
+        None => true,
+    }
+}
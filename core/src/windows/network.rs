@@ -1,18 +1,24 @@
-use crate::{fc_error, FCError, Mode, Peer, PeerResource, WiFiInterface, UI};
+use crate::diagnostics::Check;
+use crate::hotspot::{HotspotChannel, WiFiBand};
+use crate::utils::IPV6_GATEWAY_DELAY;
+use crate::{fc_error, FCError, Mode, Peer, PeerResource, WiFiInterface, DEFAULT_PORT, UI};
 use regex::Regex;
 use std::env::current_exe;
 use std::ffi::{c_void, CString};
+use std::net::Ipv6Addr;
 use std::os::windows::process::CommandExt;
 use std::sync::mpsc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{process, thread};
 use wifidirect_legacy_ap::WlanHostedNetworkHelper;
 use windows::core::{GUID, HSTRING, PCSTR, PCWSTR, PSTR};
 use windows::Win32::Foundation::{GetLastError, ERROR_SUCCESS, HANDLE, WIN32_ERROR};
 use windows::Win32::NetworkManagement::IpHelper;
+use windows::Win32::NetworkManagement::Ndis::NET_LUID_LH;
 use windows::Win32::NetworkManagement::WiFi::{
     self, WLAN_INTERFACE_INFO, WLAN_INTERFACE_INFO_LIST,
 };
+use windows::Win32::Networking::WinSock::{ADDRESS_FAMILY, AF_INET, AF_INET6, SOCKADDR_IN6};
 use windows::Win32::System::Com::CoInitialize;
 use windows::Win32::System::Diagnostics::Debug::{
     self, FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
@@ -25,20 +31,20 @@ pub struct WindowsHotspot {
 }
 
 pub async fn connect_to_peer<T: UI>(
-    peer: Peer,
-    mode: Mode,
+    hosting: bool,
     ssid: String,
     password: String,
     interface: WiFiInterface,
+    port: u16,
+    hotspot: HotspotChannel,
     ui: &T,
 ) -> Result<PeerResource, FCError> {
-    let hosting = is_hosting(&peer, &mode);
     if hosting {
-        if !check_for_firewall_rule()? {
+        if !check_for_firewall_rule(port)? {
             // open firewall
             let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<String>>(1);
             tokio::spawn(async move {
-                let res = add_firewall_rule();
+                let res = add_firewall_rule(port);
                 tx.send(res)
                     .await
                     .expect("couldn't send firewall UAC prompt response");
@@ -55,12 +61,15 @@ pub async fn connect_to_peer<T: UI>(
             ui.output("Firewall rule already in place.");
         }
 
-        // start hotspot
+        // start hotspot. WiFi Direct picks its own band and channel.
+        if hotspot != HotspotChannel::default() {
+            ui.output("Windows chooses the hotspot's band and channel itself");
+        }
         let hosted_network = start_wifi_direct(&ssid, &password, ui)?;
         Ok(PeerResource::WindowsHotspot(hosted_network))
     } else {
         let guid =
-            u128::from_str_radix(&interface.1, 10).expect("couldn't get u128 guid from string");
+            u128::from_str_radix(&interface.guid, 10).expect("couldn't get u128 guid from string");
         let guid = GUID::from_u128(guid);
         loop {
             tokio::task::yield_now().await;
@@ -71,10 +80,14 @@ pub async fn connect_to_peer<T: UI>(
             }
             thread::sleep(Duration::from_secs(2));
         }
+        let joined = Instant::now();
         let mut gateway = None;
         while gateway == None {
             tokio::task::yield_now().await;
-            gateway = find_gateway()?;
+            gateway = find_gateway(AF_INET)?;
+            if gateway.is_none() && joined.elapsed() > IPV6_GATEWAY_DELAY {
+                gateway = find_gateway(AF_INET6)?;
+            }
             if let Some(g) = gateway.clone() {
                 ui.output(&format!("WifiClient: {}", g));
             }
@@ -174,9 +187,9 @@ fn run_shell_execute(
 }
 
 // returns Ok(Some(gateway)) if gateway found, Ok(None) if no gateway found but no error, and Err otherwise.
-fn find_gateway() -> Result<Option<String>, FCError> {
+// IPv6 gateways are scoped to the adapter, e.g. fe80::1%12, since they're usually link-local.
+fn find_gateway(family: ADDRESS_FAMILY) -> Result<Option<String>, FCError> {
     let working_buffer_size = 15_000;
-    let family = 2; // IPv4
     let flags = IpHelper::GAA_FLAG_INCLUDE_GATEWAYS;
     let mut ip_adapter_addresses_lh = vec![0u8; working_buffer_size];
     let mut pip_ip_adapter_addresses_lh =
@@ -185,7 +198,7 @@ fn find_gateway() -> Result<Option<String>, FCError> {
 
     unsafe {
         let res = IpHelper::GetAdaptersAddresses(
-            family,
+            family.0 as u32,
             flags,
             None,
             Some(pip_ip_adapter_addresses_lh),
@@ -202,6 +215,12 @@ fn find_gateway() -> Result<Option<String>, FCError> {
                 let gateway = (*pip_ip_adapter_addresses_lh).FirstGatewayAddress;
                 if !gateway.is_null() {
                     let address = (*gateway).Address;
+                    if family == AF_INET6 {
+                        let sockaddr = address.lpSockaddr as *const SOCKADDR_IN6;
+                        let ip = Ipv6Addr::from((*sockaddr).sin6_addr.u.Byte);
+                        let index = (*pip_ip_adapter_addresses_lh).Ipv6IfIndex;
+                        return Ok(Some(format!("{}%{}", ip, index)));
+                    }
                     let sa_data = (*address.lpSockaddr).sa_data;
 
                     // for some reason after the windows-rs version upgrade, sa_data were signed bytes
@@ -228,6 +247,27 @@ fn find_gateway() -> Result<Option<String>, FCError> {
     Ok(None)
 }
 
+// takes the name shown in network settings, e.g. Wi-Fi
+pub fn interface_index(name: &str) -> Result<u32, FCError> {
+    let mut luid = NET_LUID_LH::default();
+    let mut index = 0;
+    unsafe {
+        let res = IpHelper::ConvertInterfaceAliasToLuid(&HSTRING::from(name), &mut luid);
+        if res != ERROR_SUCCESS {
+            fc_error(&format!("No network interface named {}", name))?;
+        }
+        let res = IpHelper::ConvertInterfaceLuidToIndex(&luid, &mut index);
+        if res != ERROR_SUCCESS {
+            fc_error(&format!(
+                "Could not get index of {}: {}",
+                name,
+                get_windows_error(res.0)?
+            ))?;
+        }
+    }
+    Ok(index)
+}
+
 // This is a hacky way to get information on all interfaces from Windows,
 // not just the one that windows-rs's WLAN_INTERFACE_INFO_LIST gives you
 unsafe fn wlan_enum_multiple_interfaces(
@@ -250,6 +290,63 @@ unsafe fn wlan_enum_multiple_interfaces(
     Ok(interfaces.to_vec())
 }
 
+// we join with temporary profiles, and the WiFi Direct hotspot goes away with the process, so there's nothing to leave behind
+pub fn remove_stale_hotspots() -> Result<Vec<String>, FCError> {
+    Ok(vec![])
+}
+
+// WLAN only lists PHY types, which don't map cleanly onto bands, so this is empty and the peer looks everywhere
+pub fn supported_bands(_interface: &str) -> Result<Vec<WiFiBand>, FCError> {
+    Ok(vec![])
+}
+
+// WLAN interfaces and the firewall rule. whether WiFi Direct can host is only known once a hotspot starts.
+pub fn diagnose(port: u16) -> Vec<Check> {
+    let mut checks = vec![];
+    match get_wifi_interfaces() {
+        Ok(interfaces) if interfaces.is_empty() => {
+            checks.push(Check::failed("WiFi interface", "No WiFi interface found."))
+        }
+        Ok(interfaces) => {
+            for interface in interfaces {
+                checks.push(Check::ok(
+                    &format!("WiFi interface {}", interface.name),
+                    "Found. Windows hosts hotspots with WiFi Direct, which picks its own band.",
+                ));
+            }
+        }
+        Err(e) => checks.push(Check::failed(
+            "WiFi interface",
+            &format!("Couldn't list WiFi interfaces: {}", e),
+        )),
+    }
+
+    // a transfer on a free port opens that port when it hosts, so only the default port can be checked ahead of time
+    let rule_port = if port == 0 { DEFAULT_PORT } else { port };
+    checks.push(match check_for_firewall_rule(rule_port) {
+        Ok(true) if port == 0 => Check::ok(
+            "Firewall",
+            &format!(
+                "Flying Carpet's rule for TCP port {} is in place. A transfer that picks another port asks to open it when hosting.",
+                DEFAULT_PORT
+            ),
+        ),
+        Ok(true) => Check::ok(
+            "Firewall",
+            &format!("Flying Carpet's rule for TCP port {} is in place.", port),
+        ),
+        Ok(false) => Check::warning(
+            "Firewall",
+            &format!(
+                "No rule for Flying Carpet on TCP port {} yet. It will ask to add one the first time it hosts there.",
+                rule_port
+            ),
+        ),
+        Err(e) => Check::failed("Firewall", &e.to_string()),
+    });
+    checks
+}
+
 pub fn get_wifi_interfaces() -> Result<Vec<WiFiInterface>, FCError> {
     unsafe {
         // get client handle
@@ -271,7 +368,11 @@ pub fn get_wifi_interfaces() -> Result<Vec<WiFiInterface>, FCError> {
                 .to_string();
             let guid = wlan_interface.InterfaceGuid.to_u128();
             let guid = format!("{}", guid); // store u128 GUID formatted as string because javascript can't handle 128-bit numbers
-            interfaces.push(WiFiInterface(name, guid));
+            interfaces.push(WiFiInterface {
+                name,
+                guid,
+                ..Default::default()
+            });
         }
         WiFi::WlanFreeMemory(p_interface_list as *const c_void);
         WiFi::WlanCloseHandle(client_handle, None);
@@ -437,46 +538,68 @@ fn join_hotspot(ssid: &str, password: &str, guid: &GUID) -> Result<bool, FCError
     }
 }
 
-fn check_for_firewall_rule() -> Result<bool, FCError> {
+// DEFAULT_PORT keeps the rule it always had. any other port we host on gets one more rule, which is moved to whichever
+// port we host on next rather than adding a rule for every free port.
+fn firewall_rule_name(port: u16) -> Result<String, FCError> {
     let path = &current_exe()?;
     let file_name = path
         .file_name()
         .expect("Error: couldn't convert path to string.")
         .to_string_lossy();
-    let name = format!("name=\"{}\"", file_name);
+    Ok(match port {
+        DEFAULT_PORT => file_name.to_string(),
+        _ => format!("{} other port", file_name),
+    })
+}
+
+// what netsh says about the rule, which has no "Rule Name:" if there's no such rule
+fn show_firewall_rule(name: &str) -> Result<String, FCError> {
+    let name = format!("name=\"{}\"", name);
     const CREATE_NO_WINDOW: u32 = 0x08000000; // https://learn.microsoft.com/en-us/windows/win32/procthread/process-creation-flags
     let mut command = process::Command::new("netsh");
     let command = command
         .args(vec!["advfirewall", "firewall", "show", "rule", &name])
         .creation_flags(CREATE_NO_WINDOW);
-    match command.output() {
-        Ok(output) => {
-            // if output contains enabled: true, return true
-            let output_string = String::from_utf8_lossy(&output.stdout).to_string();
-            let regex = Regex::new(r"Action:\s+Block")?;
-            if regex.is_match(&output_string) {
-                fc_error("a Windows Firewall rule is blocking Flying Carpet connections. Please delete or modify the rule to allow incoming connections on TCP port 3290.")?;
-            }
-            let regex = Regex::new(r"Enabled:\s+Yes")?;
-            Ok(regex.is_match(&output_string))
-        }
-        Err(e) => Err(e)?,
+    let output = command.output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn check_for_firewall_rule(port: u16) -> Result<bool, FCError> {
+    let output_string = show_firewall_rule(&firewall_rule_name(port)?)?;
+    let regex = Regex::new(r"Action:\s+Block")?;
+    if regex.is_match(&output_string) {
+        fc_error(&format!("a Windows Firewall rule is blocking Flying Carpet connections. Please delete or modify the rule to allow incoming connections on TCP port {}.", port))?;
     }
+    // if output contains enabled: true for our port, return true
+    let enabled = Regex::new(r"Enabled:\s+Yes")?;
+    let local_port = Regex::new(&format!(r"LocalPort:\s+{}\s", port))?;
+    Ok(enabled.is_match(&output_string) && local_port.is_match(&output_string))
 }
 
-fn add_firewall_rule() -> Option<String> {
+fn add_firewall_rule(port: u16) -> Option<String> {
     let path = &current_exe().expect("Error: couldn't get path to current executable.");
-    let file_name = path
-        .file_name()
-        .expect("Error: couldn't convert path to string.")
-        .to_string_lossy();
+    let name = match firewall_rule_name(port) {
+        Ok(n) => n,
+        Err(e) => return Some(e.to_string()),
+    };
+    let exists = show_firewall_rule(&name).is_ok_and(|r| r.contains("Rule Name:"));
 
     let program = "netsh";
-    let parameters = "advfirewall firewall add rule name=\"".to_string()
-        + &file_name
-        + "\" dir=in action=allow program=\""
-        + &path.to_string_lossy()
-        + "\" enable=yes profile=any localport=3290 protocol=tcp";
+    let parameters = if exists {
+        format!(
+            "advfirewall firewall set rule name=\"{}\" new program=\"{}\" enable=yes localport={}",
+            name,
+            path.to_string_lossy(),
+            port
+        )
+    } else {
+        format!(
+            "advfirewall firewall add rule name=\"{}\" dir=in action=allow program=\"{}\" enable=yes profile=any localport={} protocol=tcp",
+            name,
+            path.to_string_lossy(),
+            port
+        )
+    };
     match run_shell_execute(program, Some(&parameters), true) {
         Ok(_) => None,
         Err(e) => Some(e.to_string()),
@@ -522,6 +645,7 @@ pub fn rust_to_pcstr(s: &str) -> PCSTR {
 #[cfg(test)]
 mod test {
     use crate::network::add_firewall_rule;
+    use crate::DEFAULT_PORT;
     use windows::core::GUID;
 
     #[test]
@@ -540,13 +664,13 @@ mod test {
 
     #[test]
     fn check_for_firewall_rule() {
-        if !super::check_for_firewall_rule().unwrap() {
-            add_firewall_rule();
+        if !super::check_for_firewall_rule(DEFAULT_PORT).unwrap() {
+            add_firewall_rule(DEFAULT_PORT);
         } else {
             println!("firewall rule present");
         }
         std::thread::sleep(std::time::Duration::from_secs(2));
-        let rule_present = super::check_for_firewall_rule().unwrap();
+        let rule_present = super::check_for_firewall_rule(DEFAULT_PORT).unwrap();
         assert!(rule_present);
     }
 
@@ -1,20 +1,27 @@
-use super::{fc_error, ibuffer_to_string, NO_SSID};
+use super::{bytes_to_ibuffer, data_stream, fc_error, ibuffer_to_bytes, ibuffer_to_string};
 use crate::bluetooth::{
-    OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID, SERVICE_UUID, SSID_CHARACTERISTIC_UUID,
+    CAPABILITIES_CHARACTERISTIC_UUID, CENTRAL_KEY_CHARACTERISTIC_UUID, DATA_CHARACTERISTIC_UUID,
+    OS_CHARACTERISTIC_UUID, PASSWORD_CHARACTERISTIC_UUID, PERIPHERAL_KEY_CHARACTERISTIC_UUID,
+    SERVICE_UUID, SSID_CHARACTERISTIC_UUID,
+};
+use crate::bluetooth_stream::BluetoothStream;
+use crate::negotiation::{BluetoothMessage, BluetoothSession, Capabilities, GATT_DONE, NO_SSID};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::sleep,
 };
-use crate::utils::BluetoothMessage;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
 use windows::{
     core::{Result, GUID, HSTRING},
     Devices::Bluetooth::{
         BluetoothError,
         GenericAttributeProfile::{
-            GattCharacteristicProperties, GattLocalCharacteristic,
-            GattLocalCharacteristicParameters, GattProtectionLevel, GattReadRequestedEventArgs,
-            GattServiceProvider, GattServiceProviderAdvertisementStatus,
+            GattCharacteristicProperties, GattCommunicationStatus, GattLocalCharacteristic,
+            GattLocalCharacteristicParameters, GattProtectionLevel, GattProtocolError,
+            GattReadRequestedEventArgs, GattServiceProvider,
+            GattServiceProviderAdvertisementStatus,
             GattServiceProviderAdvertisementStatusChangedEventArgs,
-            GattServiceProviderAdvertisingParameters, GattWriteRequestedEventArgs,
+            GattServiceProviderAdvertisingParameters, GattWriteOption, GattWriteRequestedEventArgs,
         },
     },
     Foundation::TypedEventHandler,
@@ -27,17 +34,32 @@ type CharacteristicWriteHandler =
     TypedEventHandler<GattLocalCharacteristic, GattWriteRequestedEventArgs>;
 
 pub(crate) struct BluetoothPeripheral {
-    tx: mpsc::Sender<BluetoothMessage>,
+    session: BluetoothSession,
     service_provider: GattServiceProvider,
     // ssid and password fields are set by main thread if we're hosting, so peer can read these.
-    // if we're joining and peer is writing wifi info to us, we'll write those details back to
-    // the main thread with tx.
+    // if we're joining and peer is writing wifi info to us, we'll advance the session with those details.
     pub ssid: Arc<Mutex<Option<String>>>,
     pub password: Arc<Mutex<Option<String>>>,
+    capabilities: Capabilities,
+    // set when the central writes its capabilities, which older versions and the mobile apps don't
+    pub peer_capabilities: Arc<Mutex<Option<Capabilities>>>,
+    public_key: String,
+    // set when the central writes its public key, which older versions and the mobile apps don't
+    pub peer_key: Arc<Mutex<Option<String>>>,
+    // set when the central writes its OS
+    pub peer_device_id: Arc<Mutex<Option<HSTRING>>>,
+    // what the central writes to the data characteristic, if the files are small enough to skip WiFi
+    data_tx: mpsc::Sender<Vec<u8>>,
+    data_rx: Option<mpsc::Receiver<Vec<u8>>>,
+    data_characteristic: Option<GattLocalCharacteristic>,
 }
 
 impl BluetoothPeripheral {
-    pub fn new(tx: mpsc::Sender<BluetoothMessage>) -> Result<Self> {
+    pub fn new(
+        session: BluetoothSession,
+        capabilities: Capabilities,
+        public_key: String,
+    ) -> Result<Self> {
         // create service provider
         let result = GattServiceProvider::CreateAsync(GUID::from(SERVICE_UUID))?.get()?;
         if result.Error()? != BluetoothError::Success {
@@ -48,11 +70,20 @@ impl BluetoothPeripheral {
             result.Error()?;
         }
         let service_provider = result.ServiceProvider()?;
+        let (data_tx, data_rx) = mpsc::channel(64);
         Ok(BluetoothPeripheral {
-            tx,
+            session,
             service_provider,
             ssid: Arc::new(Mutex::new(None)),
             password: Arc::new(Mutex::new(None)),
+            capabilities,
+            peer_capabilities: Arc::new(Mutex::new(None)),
+            public_key,
+            peer_key: Arc::new(Mutex::new(None)),
+            peer_device_id: Arc::new(Mutex::new(None)),
+            data_tx,
+            data_rx: Some(data_rx),
+            data_characteristic: None,
         })
     }
 
@@ -109,6 +140,68 @@ impl BluetoothPeripheral {
         }
         let password_characteristic = result.Characteristic()?;
 
+        // make capabilities characteristic
+        let result = self
+            .service_provider
+            .Service()?
+            .CreateCharacteristicAsync(
+                CAPABILITIES_CHARACTERISTIC_UUID.into(),
+                &gatt_operand_parameters,
+            )?
+            .get()?;
+        let e = result.Error()?;
+        if e != BluetoothError::Success {
+            fc_error(&format!("Error creating characteristic: {:?}", e))?;
+        }
+        let capabilities_characteristic = result.Characteristic()?;
+
+        // make key characteristics
+        let result = self
+            .service_provider
+            .Service()?
+            .CreateCharacteristicAsync(
+                PERIPHERAL_KEY_CHARACTERISTIC_UUID.into(),
+                &gatt_operand_parameters,
+            )?
+            .get()?;
+        let e = result.Error()?;
+        if e != BluetoothError::Success {
+            fc_error(&format!("Error creating characteristic: {:?}", e))?;
+        }
+        let peripheral_key_characteristic = result.Characteristic()?;
+        let result = self
+            .service_provider
+            .Service()?
+            .CreateCharacteristicAsync(
+                CENTRAL_KEY_CHARACTERISTIC_UUID.into(),
+                &gatt_operand_parameters,
+            )?
+            .get()?;
+        let e = result.Error()?;
+        if e != BluetoothError::Success {
+            fc_error(&format!("Error creating characteristic: {:?}", e))?;
+        }
+        let central_key_characteristic = result.Characteristic()?;
+
+        // make data characteristic. the central subscribes to it for what we send, and writes what it sends.
+        let data_parameters = GattLocalCharacteristicParameters::new()?;
+        data_parameters.SetCharacteristicProperties(
+            GattCharacteristicProperties::Notify
+                | GattCharacteristicProperties::WriteWithoutResponse,
+        )?;
+        data_parameters
+            .SetWriteProtectionLevel(GattProtectionLevel::EncryptionAndAuthenticationRequired)?;
+        let result = self
+            .service_provider
+            .Service()?
+            .CreateCharacteristicAsync(DATA_CHARACTERISTIC_UUID.into(), &data_parameters)?
+            .get()?;
+        let e = result.Error()?;
+        if e != BluetoothError::Success {
+            fc_error(&format!("Error creating characteristic: {:?}", e))?;
+        }
+        let data_characteristic = result.Characteristic()?;
+
         // OS read handler: write "windows" to peer
         let os_read_callback = CharacteristicReadHandler::new(
             move |_gatt_local_characteristic, gatt_read_requested_event_args| {
@@ -129,7 +222,8 @@ impl BluetoothPeripheral {
         os_characteristic.ReadRequested(&os_read_callback)?;
 
         // OS write handler: send peer's OS back to main thread so that it can decide if we're starting or joining hotspot
-        let os_write_tx = self.tx.clone();
+        let os_write_session = self.session.clone();
+        let callback_peer_device_id = self.peer_device_id.clone();
         let os_write_callback = CharacteristicWriteHandler::new(
             move |_gatt_local_characteristic, gatt_write_requested_event_args| {
                 println!("received os write request");
@@ -140,10 +234,13 @@ impl BluetoothPeripheral {
                 let request = args.GetRequestAsync()?.get()?;
                 let ibuffer = request.Value()?;
                 let peer_os = ibuffer_to_string(ibuffer)?;
-                if let Err(e) = os_write_tx.blocking_send(BluetoothMessage::PeerOS(peer_os)) {
-                    println!("Could not send on Bluetooth tx: {}", e);
-                };
-                request.Respond()?;
+                *callback_peer_device_id.blocking_lock() = Some(args.Session()?.DeviceId()?.Id()?);
+                match os_write_session.advance(BluetoothMessage::PeerOS(peer_os)) {
+                    Ok(_) => request.Respond()?,
+                    Err(_) => {
+                        request.RespondWithProtocolError(GattProtocolError::UnlikelyError()?)?
+                    }
+                }
                 deferral.Complete()?;
                 Ok(())
             },
@@ -152,7 +249,7 @@ impl BluetoothPeripheral {
 
         // ssid read handler
         let callback_ssid = self.ssid.clone();
-        let callback_tx = self.tx.clone();
+        let callback_session = self.session.clone();
         let ssid_read_callback = CharacteristicReadHandler::new(
             move |_gatt_local_characteristic, gatt_read_requested_event_args| {
                 println!("received ssid read request");
@@ -169,11 +266,9 @@ impl BluetoothPeripheral {
                 };
                 writer.WriteBytes(ssid.as_bytes())?;
                 request.RespondWithValue(&writer.DetachBuffer()?)?;
-                println!("peer read our ssid: {}", ssid);
                 if ssid != NO_SSID {
-                    if let Err(e) = callback_tx.blocking_send(BluetoothMessage::PeerReadSsid) {
-                        println!("Could not send on Bluetooth tx: {}", e);
-                    };
+                    println!("peer read our ssid");
+                    let _ = callback_session.advance(BluetoothMessage::PeerReadSsid);
                 }
                 deferral.Complete()?;
                 Ok(())
@@ -182,7 +277,7 @@ impl BluetoothPeripheral {
         ssid_characteristic.ReadRequested(&ssid_read_callback)?;
 
         // ssid write handler
-        let callback_tx = self.tx.clone();
+        let callback_session = self.session.clone();
         let ssid_write_callback = CharacteristicWriteHandler::new(
             move |_gatt_local_characteristic, gatt_write_requested_event_args| {
                 println!("received ssid write request");
@@ -194,10 +289,12 @@ impl BluetoothPeripheral {
                 // get value
                 let ibuffer = request.Value()?;
                 let ssid = ibuffer_to_string(ibuffer)?;
-                callback_tx
-                    .blocking_send(BluetoothMessage::SSID(ssid.to_string()))
-                    .expect("Could not send to main thread from SSID write handler");
-                request.Respond()?;
+                match callback_session.advance(BluetoothMessage::SSID(ssid)) {
+                    Ok(_) => request.Respond()?,
+                    Err(_) => {
+                        request.RespondWithProtocolError(GattProtocolError::UnlikelyError()?)?
+                    }
+                }
                 deferral.Complete()?;
                 Ok(())
             },
@@ -206,7 +303,7 @@ impl BluetoothPeripheral {
 
         // password read handler
         let callback_password = self.password.clone();
-        let callback_tx = self.tx.clone();
+        let callback_session = self.session.clone();
         let password_read_callback = CharacteristicReadHandler::new(
             move |_gatt_local_characteristic, gatt_read_requested_event_args| {
                 println!("received password read request");
@@ -223,10 +320,8 @@ impl BluetoothPeripheral {
                 };
                 writer.WriteBytes(callback_password.as_bytes())?;
                 request.RespondWithValue(&writer.DetachBuffer()?)?;
-                println!("peer read our password: {}", callback_password);
-                if let Err(e) = callback_tx.blocking_send(BluetoothMessage::PeerReadPassword) {
-                    println!("Could not send on Bluetooth tx: {}", e);
-                };
+                println!("peer read our password");
+                let _ = callback_session.advance(BluetoothMessage::PeerReadPassword);
                 deferral.Complete()?;
                 Ok(())
             },
@@ -234,7 +329,7 @@ impl BluetoothPeripheral {
         password_characteristic.ReadRequested(&password_read_callback)?;
 
         // password write handler
-        let callback_tx = self.tx.clone();
+        let callback_session = self.session.clone();
         let password_write_callback = CharacteristicWriteHandler::new(
             move |_gatt_local_characteristic, gatt_write_requested_event_args| {
                 println!("received password write request");
@@ -246,22 +341,165 @@ impl BluetoothPeripheral {
                 // get value
                 let ibuffer = request.Value()?;
                 let password = ibuffer_to_string(ibuffer)?;
-                callback_tx
-                    .blocking_send(BluetoothMessage::Password(password.to_string()))
-                    .expect("Could not send to main thread from password write handler");
-                request.Respond()?;
+                match callback_session.advance(BluetoothMessage::Password(password)) {
+                    Ok(_) => request.Respond()?,
+                    Err(_) => {
+                        request.RespondWithProtocolError(GattProtocolError::UnlikelyError()?)?
+                    }
+                }
                 deferral.Complete()?;
                 Ok(())
             },
         );
         password_characteristic.WriteRequested(&password_write_callback)?;
 
+        // capabilities read handler
+        let value = self.capabilities.encode();
+        let capabilities_read_callback = CharacteristicReadHandler::new(
+            move |_gatt_local_characteristic, gatt_read_requested_event_args| {
+                println!("received capabilities read request");
+                let args = gatt_read_requested_event_args
+                    .as_ref()
+                    .expect("No args in read callback");
+                let deferral = args.GetDeferral()?;
+                let request = args.GetRequestAsync()?.get()?;
+                let writer = DataWriter::new()?;
+                writer.WriteBytes(value.as_bytes())?;
+                request.RespondWithValue(&writer.DetachBuffer()?)?;
+                deferral.Complete()?;
+                Ok(())
+            },
+        );
+        capabilities_characteristic.ReadRequested(&capabilities_read_callback)?;
+
+        // capabilities write handler: the central's capabilities, then GATT_DONE once it has the wifi details
+        let callback_peer_capabilities = self.peer_capabilities.clone();
+        let callback_session = self.session.clone();
+        let capabilities_write_callback = CharacteristicWriteHandler::new(
+            move |_gatt_local_characteristic, gatt_write_requested_event_args| {
+                println!("received capabilities write request");
+                let args = gatt_write_requested_event_args
+                    .as_ref()
+                    .expect("No args in write callback");
+                let deferral = args.GetDeferral()?;
+                let request = args.GetRequestAsync()?.get()?;
+                let value = ibuffer_to_string(request.Value()?)?;
+                if value == GATT_DONE {
+                    let _ = callback_session.advance(BluetoothMessage::PeerDone);
+                } else {
+                    match Capabilities::decode(&value) {
+                        Some(c) => *callback_peer_capabilities.blocking_lock() = Some(c),
+                        None => println!("Peer wrote malformed capabilities: {}", value),
+                    }
+                }
+                request.Respond()?;
+                deferral.Complete()?;
+                Ok(())
+            },
+        );
+        capabilities_characteristic.WriteRequested(&capabilities_write_callback)?;
+
+        // peripheral key read handler
+        let value = self.public_key.clone();
+        let peripheral_key_read_callback = CharacteristicReadHandler::new(
+            move |_gatt_local_characteristic, gatt_read_requested_event_args| {
+                println!("received peripheral key read request");
+                let args = gatt_read_requested_event_args
+                    .as_ref()
+                    .expect("No args in read callback");
+                let deferral = args.GetDeferral()?;
+                let request = args.GetRequestAsync()?.get()?;
+                let writer = DataWriter::new()?;
+                writer.WriteBytes(value.as_bytes())?;
+                request.RespondWithValue(&writer.DetachBuffer()?)?;
+                deferral.Complete()?;
+                Ok(())
+            },
+        );
+        peripheral_key_characteristic.ReadRequested(&peripheral_key_read_callback)?;
+
+        // central key write handler: keep the central's public key for the main thread
+        let callback_peer_key = self.peer_key.clone();
+        let central_key_write_callback = CharacteristicWriteHandler::new(
+            move |_gatt_local_characteristic, gatt_write_requested_event_args| {
+                println!("received central key write request");
+                let args = gatt_write_requested_event_args
+                    .as_ref()
+                    .expect("No args in write callback");
+                let deferral = args.GetDeferral()?;
+                let request = args.GetRequestAsync()?.get()?;
+                let value = ibuffer_to_string(request.Value()?)?;
+                *callback_peer_key.blocking_lock() = Some(value);
+                request.Respond()?;
+                deferral.Complete()?;
+                Ok(())
+            },
+        );
+        central_key_characteristic.WriteRequested(&central_key_write_callback)?;
+
+        // data write handler: pass what the central sent along to the transfer
+        let callback_tx = self.data_tx.clone();
+        let data_write_callback = CharacteristicWriteHandler::new(
+            move |_gatt_local_characteristic, gatt_write_requested_event_args| {
+                let args = gatt_write_requested_event_args
+                    .as_ref()
+                    .expect("No args in write callback");
+                let deferral = args.GetDeferral()?;
+                let request = args.GetRequestAsync()?.get()?;
+                let packet = ibuffer_to_bytes(request.Value()?)?;
+                if let Err(e) = callback_tx.blocking_send(packet) {
+                    println!("Could not send on data tx: {}", e);
+                };
+                if request.Option()? == GattWriteOption::WriteWithResponse {
+                    request.Respond()?;
+                }
+                deferral.Complete()?;
+                Ok(())
+            },
+        );
+        data_characteristic.WriteRequested(&data_write_callback)?;
+        self.data_characteristic = Some(data_characteristic);
+
         Ok(())
     }
 
+    // waits for the central to subscribe to the data characteristic, then keeps serving until the transfer is done
+    pub async fn accept_data(mut self) -> std::result::Result<BluetoothStream, super::FCError> {
+        let characteristic = self
+            .data_characteristic
+            .clone()
+            .expect("Data characteristic was not added");
+        let client = loop {
+            let clients = characteristic.SubscribedClients()?;
+            if clients.Size()? > 0 {
+                break clients.GetAt(0)?;
+            }
+            sleep(Duration::from_millis(100)).await;
+        };
+        println!("peer subscribed to the data characteristic");
+        let packet_size = client.MaxNotificationSize()? as usize;
+        let incoming = self
+            .data_rx
+            .take()
+            .expect("Data characteristic was already opened");
+        let send = move |packet: &[u8]| {
+            let result = characteristic
+                .NotifyValueForSubscribedClientAsync(&bytes_to_ibuffer(packet)?, &client)?
+                .get()?;
+            if result.Status()? != GattCommunicationStatus::Success {
+                fc_error(&format!(
+                    "Error notifying Bluetooth central: {:?}",
+                    result.Status()?
+                ))?;
+            }
+            Ok(())
+        };
+        Ok(data_stream(incoming, packet_size, send, Box::new(self)))
+    }
+
     pub fn start_advertising(&mut self) -> Result<()> {
-        // get tx so we can tell main thread we've paired
-        let thread_tx = self.tx.clone();
+        // so we can tell the main thread we've started
+        let thread_session = self.session.clone();
 
         // make service connectable and discoverable
         let adv_parameters = GattServiceProviderAdvertisingParameters::new()?;
@@ -285,9 +523,7 @@ impl BluetoothPeripheral {
                 GattServiceProviderAdvertisementStatus::Started
                 | GattServiceProviderAdvertisementStatus::StartedWithoutAllAdvertisementData => {
                     // TODO: have to worry about StartedWithoutAllAdvertisementData case?
-                    thread_tx
-                        .blocking_send(BluetoothMessage::StartedAdvertising)
-                        .expect("Could not send on Bluetooth tx");
+                    let _ = thread_session.advance(BluetoothMessage::StartedAdvertising);
                 }
                 GattServiceProviderAdvertisementStatus::Aborted => {
                     println!("Advertisement aborted")